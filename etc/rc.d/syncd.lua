@@ -16,6 +16,11 @@ end
 local syncd = {}
 syncd.__index = syncd
 
+-- has to match the server's, otherwise the two can't sync
+local protocolVersion = 1
+-- optional parts of the protocol this side understands, announced in Hello
+local features = { "chunked", "batch", "hash-xxh64" }
+
 function syncd.new(address, syncedDir, channel, backend)
     local self = {
         _address = address,
@@ -35,7 +40,11 @@ function syncd:connect()
     
     local res, err = self._backend:connect(self._address)
     if res then
-        return self:_join(self._channel)
+        res, err = self:_join(self._channel)
+        if res then
+            self:hello()
+        end
+        return res, err
     else
         return nil, err
     end
@@ -59,6 +68,14 @@ function syncd:pong()
     self:_send(self._channel, { type = "Pong" })
 end
 
+function syncd:hello()
+    self:_send(self._channel, { type = "Hello", version = protocolVersion, features = features })
+end
+
+function syncd:helloResp()
+    self:_send(self._channel, { type = "HelloResp", version = protocolVersion, features = features })
+end
+
 function syncd:list(path)
     self:_send(self._channel, { type = "List", path = path })
 end
@@ -75,6 +92,10 @@ function syncd:getResp(path, contents)
     self:_send(self._channel, { type = "GetResp", path = path, contents = contents})
 end
 
+function syncd:getChunk(path, offset, len)
+    self:_send(self._channel, { type = "GetChunk", path = path, offset = offset, len = len})
+end
+
 function syncd:fsEventCreate(path, entity)
     self:_send(self._channel, { type = "FsEventCreate", path = path, entity = entity})
 end
@@ -116,6 +137,9 @@ function syncd:_send(channel, msg)
     return self._backend:send(channel, cbor.encode(msg))
 end
 
+-- same chunk size the server uses when splitting large files
+local transferChunkSize = 32 * 1024
+
 local function fileHash(path)
     local f, err = io.open(path, "rb")
     if f then
@@ -154,17 +178,40 @@ function syncd.handlers:Pong(msg)
     self._commsEstablished = true
 end
 
+function syncd:_checkVersion(msg)
+    if msg.version ~= protocolVersion then
+        log.error("Server speaks protocol version %s, this side only %d, refusing to sync", tostring(msg.version), protocolVersion)
+        self:disconnect()
+        return
+    end
+    self._commsEstablished = true
+    log.info("Server supports features: %s", table.concat(msg.features or {}, ", "))
+end
+
+function syncd.handlers:Hello(msg)
+    self:helloResp()
+    self:_checkVersion(msg)
+end
+
+function syncd.handlers:HelloResp(msg)
+    self:_checkVersion(msg)
+end
+
 function syncd.handlers:ListResp(msg)
     for _, entry in ipairs(msg.entries) do
         local path = getSafeCanonical(self._syncedDir, entry.path)
-        if entry.entity == "File" then
+        if entry.skipped then
+            log.debug("Skipping %s, it's over the server's size limit", path)
+        elseif entry.entity == "File" then
             if fs.exists(path) then
                 if not fs.isDirectory(path) then
                     local localHash = fileHash(path)
+                    -- a server hashing with something other than xxHash64 sends a table,
+                    -- which never matches so the file is always downloaded
                     if localHash ~= entry.hash then
                         -- both files exist but local file is different, download
                         log.debug(
-                            "Local and remote hash of %s differ: local hash is %d, remote is %d, requesting file contents",
+                            "Local and remote hash of %s differ: local hash is %d, remote is %s, requesting file contents",
                             path, localHash, entry.hash
                         )
                         self:get(entry.path)
@@ -184,6 +231,15 @@ function syncd.handlers:ListResp(msg)
                 self:get(entry.path)
             end
         elseif entry.entity == "Directory" then
+            -- created right away so directories that turn out empty exist too
+            if not fs.exists(path) then
+                local ok, err = fs.makeDirectory(path)
+                if ok then
+                    log.info("Created directory %s", path)
+                else
+                    log.error("Failed creating directory %s: %s", path, err)
+                end
+            end
             log.debug("Path %s is a directory, requesting directory contents", path)
             self:list(entry.path)
         end
@@ -202,6 +258,28 @@ function syncd.handlers:GetResp(msg)
     end
 end
 
+function syncd.handlers:GetChunkResp(msg)
+    local path = getSafeCanonical(self._syncedDir, msg.path)
+    -- first chunk truncates the file, following ones are appended to it
+    local f, err = io.open(path, msg.offset == 0 and "wb" or "ab")
+    if f then
+        f:write(msg.contents)
+        f:close()
+    else
+        log.error("Failed opening file %s for writing: %s", msg.path, err)
+        return
+    end
+    if msg.eof then
+        log.info("Updated file %s with new contents", path)
+    else
+        self:getChunk(msg.path, msg.offset + #msg.contents, transferChunkSize)
+    end
+end
+
+function syncd.handlers:Error(msg)
+    log.error("Peer failed answering %s for %s: %s", msg.request, msg.path or "-", msg.message)
+end
+
 function syncd.handlers:FsEventCreate(msg)
     local path = getSafeCanonical(self._syncedDir, msg.path)
     if msg.entity == "File" then
@@ -257,6 +335,20 @@ function syncd.handlers:FsEventUnknown(msg)
     log.warning("Unimplemented FsEventUnknown")
 end
 
+function syncd.handlers:FsEventBatch(msg)
+    for _, event in ipairs(msg.events) do
+        local handler = self.handlers[event.type]
+        if handler then
+            local ok, err = pcall(handler, self, event)
+            if not ok then
+                log.error("Failed processing batched %s event: %s", event.type, err)
+            end
+        else
+            log.warning("Received unknown batched event type %s", event.type)
+        end
+    end
+end
+
 
 function syncd:_listener(channel, rawMsg)
     if channel == self._channel then
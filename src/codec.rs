@@ -1,69 +1,137 @@
 use tokio_util::codec::{Decoder, Encoder};
-use tokio_util::bytes::{BytesMut, BufMut, Buf};
+use tokio_util::bytes::{Bytes, BytesMut, BufMut, Buf};
+use std::hash::Hasher;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use twox_hash::XxHash32;
+use crate::stats::SyncStats;
 
-#[derive(Debug, Clone)]
+/// Longest channel id the one byte length prefix of the STEM protocol can describe
+pub const MAX_CHANNEL_ID_LEN: usize = u8::MAX as usize;
+
+// First byte of a checksummed message, never the first byte of a CBOR message
+const CHECKSUM_MARKER: u8 = 0xff;
+/// Bytes a checksum adds to a message, the marker in front of it and the xxHash32 after it
+const CHECKSUM_OVERHEAD: usize = 1 + 4;
+
+/// Largest message payload that still fits in a frame along with the longest possible channel
+/// id and a checksum
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize - 2 - MAX_CHANNEL_ID_LEN - CHECKSUM_OVERHEAD;
+
+/// Contents are Bytes so cloning a channel id or handing a payload around never copies it
+#[derive(Debug, Clone, PartialEq)]
 pub enum Package {
-    Message(BytesMut, BytesMut),
-    Subscribe(BytesMut),
-    Unsubscribe(BytesMut),
-    Ping(BytesMut),
-    Pong(BytesMut)
+    Message(Bytes, Bytes),
+    Subscribe(Bytes),
+    Unsubscribe(Bytes),
+    Ping(Bytes),
+    Pong(Bytes)
+}
+
+/// STEM framing, counting the bytes that go through it into the connection's stats
+///
+/// With checksums, messages sent while the flag is set get a checksum of their channel id
+/// and payload, and received messages carrying one are checked against it. Encrypted
+/// connections go without, the cipher's tag already catches any corruption
+pub struct Codec {
+    stats: Arc<SyncStats>,
+    checksums: Option<Arc<AtomicBool>>,
 }
 
-pub struct Codec;
+impl Codec {
+    pub fn new(stats: Arc<SyncStats>, checksums: Option<Arc<AtomicBool>>) -> Self {
+        Codec { stats, checksums }
+    }
+
+    fn sends_checksums(&self) -> bool {
+        self.checksums.as_ref().is_some_and(|enabled| enabled.load(Ordering::Relaxed))
+    }
+}
+
+fn checksum(id: &[u8], message: &[u8]) -> u32 {
+    let mut hasher = XxHash32::with_seed(0);
+    hasher.write(id);
+    hasher.write(message);
+    hasher.finish() as u32
+}
+
+/// Strips the checksum off a received message, an error if it doesn't match
+fn verify_checksum(id: &[u8], mut message: BytesMut) -> io::Result<BytesMut> {
+    if message.len() < CHECKSUM_OVERHEAD {
+        return Err(invalid_data(format!("checksummed message of {} bytes is too short for its checksum", message.len())))
+    }
+    let trailer = message.split_off(message.len() - 4);
+    message.advance(1);
+    let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    if checksum(id, &message) != expected {
+        return Err(invalid_data(format!("checksum of a {} byte message doesn't match, it was corrupted on the way", message.len())))
+    }
+    Ok(message)
+}
+
+fn channel_id_len(id: &[u8]) -> io::Result<u8> {
+    u8::try_from(id.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("channel id is {} bytes long, at most {} are supported", id.len(), MAX_CHANNEL_ID_LEN)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
 
 impl Decoder for Codec {
     type Item = Package;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // the first two bytes are the following package length
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            } else {
-                src.get_u16() as usize
+        loop {
+            // the first two bytes are the following package length, left in place until
+            // the whole package has arrived
+            let Some(&[high, low]) = src.get(..2) else {
+                return Ok(None)
+            };
+            let size = u16::from_be_bytes([high, low]) as usize;
+            if src.len() < 2 + size {
+                src.reserve(2 + size - src.len());
+                return Ok(None)
             }
-        };
-
-        if size > 0 && src.len() >= size {
+            src.advance(2);
             let mut buf = src.split_to(size);
+            self.stats.add_received(2 + size);
 
-            let package_type = buf.first().map(|&v| v);
-            buf.advance(1);
-
-            match package_type {
-                Some(value) => {
-                    match value {
-                        // message and subscriptions operate with channel ID
-                        0 | 1 | 2 => {
-                            let id_size = match buf.first() {
-                                None => 0,
-                                Some(x) => *x
-                            } as usize;
-                            buf.advance(1);
-
-                            if buf.len() < id_size { return Ok(None); }
-                            let id = buf.split_to(id_size);
-
-                            match value {
-                                0 => Ok(Some(Package::Message(id, buf))),
-                                1 => Ok(Some(Package::Subscribe(id))),
-                                2 => Ok(Some(Package::Unsubscribe(id))),
-                                _ => Ok(None)
-                            }
-                        }
-                        // ping and pong need only content
-                        3 => Ok(Some(Package::Ping(buf))),
-                        4 => Ok(Some(Package::Pong(buf))),
-                        _ => Ok(None)
+            // an empty package doesn't even have a type, there is nothing to it
+            if buf.is_empty() {
+                continue
+            }
+            let package_type = buf.get_u8();
+            return match package_type {
+                // message and subscriptions operate with channel ID
+                0..=2 => {
+                    let Some(&id_size) = buf.first() else {
+                        return Err(invalid_data(format!("package of type {} is missing its channel id", package_type)))
+                    };
+                    buf.advance(1);
+                    let id_size = id_size as usize;
+                    if buf.len() < id_size {
+                        return Err(invalid_data(format!("channel id of {} bytes doesn't fit in a package of {}", id_size, size)))
                     }
+                    let id = buf.split_to(id_size).freeze();
+                    if package_type == 0 && self.checksums.is_some() && buf.first() == Some(&CHECKSUM_MARKER) {
+                        buf = verify_checksum(&id, buf)?;
+                    }
+
+                    Ok(Some(match package_type {
+                        0 => Package::Message(id, buf.freeze()),
+                        1 => Package::Subscribe(id),
+                        _ => Package::Unsubscribe(id),
+                    }))
                 }
-                None => Ok(None)
+                // ping and pong need only content
+                3 => Ok(Some(Package::Ping(buf.freeze()))),
+                4 => Ok(Some(Package::Pong(buf.freeze()))),
+                // types this side doesn't know about are skipped
+                _ => continue
             }
-        } else {
-            Ok(None)
         }
     }
 }
@@ -72,44 +140,162 @@ impl Encoder<Package> for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, pkg: Package, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut bytes = BytesMut::new();
-
-        match pkg {
-            Package::Message(id, message) => {
-                bytes.reserve(2 + id.len() + message.len());
-                bytes.put_u8(0);
-                bytes.put_u8(id.len() as u8);
-                bytes.put_slice(id.as_ref());
-                bytes.put_slice(message.as_ref());
-            }
-            Package::Subscribe(id) => {
-                bytes.reserve(2 + id.len());
-                bytes.put_u8(1);
-                bytes.put_u8(id.len() as u8);
-                bytes.put_slice(id.as_ref());
-            }
-            Package::Unsubscribe(id) => {
-                bytes.reserve(2 + id.len());
-                bytes.put_u8(2);
-                bytes.put_u8(id.len() as u8);
-                bytes.put_slice(id.as_ref());
-            }
-            Package::Ping(content) => {
-                bytes.reserve(1 + content.len());
-                bytes.put_u8(3);
-                bytes.put_slice(content.as_ref());
-            }
-            Package::Pong(content) => {
-                bytes.reserve(1 + content.len());
-                bytes.put_u8(4);
-                bytes.put_slice(content.as_ref());
+        let (package_type, id, content) = match pkg {
+            Package::Message(id, message) => (0, Some(id), message),
+            Package::Subscribe(id) => (1, Some(id), Bytes::new()),
+            Package::Unsubscribe(id) => (2, Some(id), Bytes::new()),
+            Package::Ping(content) => (3, None, content),
+            Package::Pong(content) => (4, None, content),
+        };
+        // checked up front so nothing of a package that can't be sent ends up in dst
+        let id_len = id.as_deref().map(channel_id_len).transpose()?;
+        let checksummed = package_type == 0 && self.sends_checksums();
+        let size = 1 + id.as_ref().map_or(0, |id| 1 + id.len()) + content.len() + if checksummed { CHECKSUM_OVERHEAD } else { 0 };
+        let Ok(len) = u16::try_from(size) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("package is {} bytes long, at most {} fit in a frame", size, u16::MAX)))
+        };
+        self.stats.add_sent(2 + size);
+        // written straight into the output buffer, the payload is copied exactly once
+        dst.reserve(2 + size);
+        dst.put_u16(len);
+        dst.put_u8(package_type);
+        if let (Some(id), Some(id_len)) = (&id, id_len) {
+            dst.put_u8(id_len);
+            dst.put_slice(id);
+        }
+        if checksummed {
+            dst.put_u8(CHECKSUM_MARKER);
+            dst.put_slice(&content);
+            dst.put_u32(checksum(id.as_deref().unwrap_or_default(), &content));
+        } else {
+            dst.put_slice(&content);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn codec() -> Codec {
+        Codec::new(Arc::new(SyncStats::default()), None)
+    }
+
+    fn encoded(package: Package) -> BytesMut {
+        let mut frame = BytesMut::new();
+        codec().encode(package, &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn long_channel_ids_and_payloads_survive_a_round_trip() {
+        let id = Bytes::from(vec![b'c'; MAX_CHANNEL_ID_LEN]);
+        // a payload past what a single length byte could describe
+        let payload = Bytes::from((0..300).map(|i| i as u8).collect::<Vec<u8>>());
+        for package in [Package::Message(id.clone(), payload.clone()), Package::Subscribe(id.clone()), Package::Unsubscribe(id), Package::Ping(payload)] {
+            let mut frame = encoded(package.clone());
+            assert_eq!(codec().decode(&mut frame).unwrap(), Some(package));
+            assert!(frame.is_empty());
+        }
+    }
+
+    #[test]
+    fn channel_id_too_long_for_its_length_byte_is_refused() {
+        let mut frame = BytesMut::new();
+        let id = Bytes::from(vec![b'c'; 300]);
+        let err = codec().encode(Package::Subscribe(id), &mut frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(frame.is_empty(), "part of the package was written");
+    }
+
+    #[test]
+    fn frame_split_across_reads_is_decoded_once_whole() {
+        let package = Package::Message(Bytes::from_static(b"channel"), Bytes::from(vec![7; 300]));
+        let frame = encoded(package.clone());
+        let mut codec = codec();
+        let mut received = BytesMut::new();
+        for (i, byte) in frame.iter().enumerate() {
+            received.put_u8(*byte);
+            let decoded = codec.decode(&mut received).unwrap();
+            if i + 1 < frame.len() {
+                assert_eq!(decoded, None);
+            } else {
+                assert_eq!(decoded, Some(package.clone()));
             }
         }
+    }
+
+    #[test]
+    fn flipped_byte_in_a_checksummed_message_is_an_error() {
+        let mut codec = Codec::new(Arc::new(SyncStats::default()), Some(Arc::new(AtomicBool::new(true))));
+        let content = Bytes::from_static(b"\xa1dpathhfile.txt");
+        let mut frame = BytesMut::new();
+        codec.encode(Package::Message(Bytes::from_static(b"channel"), content.clone()), &mut frame).unwrap();
+        // every byte of the message and of its checksum, past the marker
+        for i in frame.len() - 4 - content.len()..frame.len() {
+            let mut corrupted = frame.clone();
+            corrupted[i] ^= 0x10;
+            let err = codec.decode(&mut corrupted).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "byte {i} flipped");
+        }
+        assert!(codec.decode(&mut frame).unwrap().is_some());
+    }
 
-        dst.reserve(bytes.len() + 2);
-        dst.put_u16(bytes.len() as u16);
-        dst.put(bytes);
+    fn package() -> impl Strategy<Value = Package> {
+        let id = || proptest::collection::vec(any::<u8>(), 0..=MAX_CHANNEL_ID_LEN).prop_map(Bytes::from);
+        let payload = || proptest::collection::vec(any::<u8>(), 0..2048).prop_map(Bytes::from);
+        // like every CBOR message, so it isn't taken for a checksummed one
+        let message = proptest::collection::vec(any::<u8>(), 0..2048)
+            .prop_filter("starts like a checksummed message", |message| message.first() != Some(&CHECKSUM_MARKER))
+            .prop_map(Bytes::from);
+        prop_oneof![
+            (id(), message).prop_map(|(id, payload)| Package::Message(id, payload)),
+            id().prop_map(Package::Subscribe),
+            id().prop_map(Package::Unsubscribe),
+            payload().prop_map(Package::Ping),
+            payload().prop_map(Package::Pong),
+        ]
+    }
 
-        Ok(())
+    /// Bytes that are mostly framed right, so they get past the length to what's in a package
+    fn input() -> impl Strategy<Value = Vec<u8>> {
+        let framed = proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..300), 0..16).prop_map(|bodies| {
+            bodies.iter().flat_map(|body| (body.len() as u16).to_be_bytes().into_iter().chain(body.iter().copied())).collect()
+        });
+        prop_oneof![proptest::collection::vec(any::<u8>(), 0..4096), framed]
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_bytes_are_decoded_without_panicking(input in input(), checksums: bool, split in 1usize..64) {
+            let mut codec = Codec::new(Arc::new(SyncStats::default()), Some(Arc::new(AtomicBool::new(checksums))));
+            let mut received = BytesMut::new();
+            'reads: for read in input.chunks(split) {
+                received.extend_from_slice(read);
+                loop {
+                    let before = received.len();
+                    match codec.decode(&mut received) {
+                        // every package takes at least its length off the input
+                        Ok(Some(_)) => prop_assert!(received.len() + 2 <= before),
+                        Ok(None) => break,
+                        // the connection is closed on an error, nothing follows it
+                        Err(_) => break 'reads,
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn every_package_survives_a_round_trip(package in package(), checksums: bool) {
+            let mut codec = Codec::new(Arc::new(SyncStats::default()), Some(Arc::new(AtomicBool::new(checksums))));
+            let mut frame = BytesMut::new();
+            codec.encode(package.clone(), &mut frame).unwrap();
+            prop_assert_eq!(codec.decode(&mut frame).unwrap(), Some(package));
+            prop_assert!(frame.is_empty());
+        }
     }
 }
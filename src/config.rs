@@ -0,0 +1,180 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+use syncd::fs::FsyncPolicy;
+use syncd::hash::HashAlgo;
+use syncd::protocol::{ConflictMode, DeleteMode};
+use crate::{parse_channel, parse_pair, parse_psk, parse_size, parse_subpath, Args, WatchMode};
+
+/// Options read from the file passed with --config, named like their command line flags
+/// with underscores. Every option is optional, unset ones keep their command line value
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    address: Option<String>,
+    prefer_ipv4: Option<bool>,
+    bind: Option<IpAddr>,
+    tls: Option<bool>,
+    ca_cert: Option<PathBuf>,
+    insecure_skip_verify: Option<bool>,
+    psk: Option<String>,
+    progress: Option<bool>,
+    metrics_addr: Option<SocketAddr>,
+    dry_run: Option<bool>,
+    read_only: Option<bool>,
+    watch_mode: Option<WatchMode>,
+    channel: Option<String>,
+    syncdir: Option<PathBuf>,
+    debounce_ms: Option<u64>,
+    min_resync_interval_ms: Option<u64>,
+    initial_sync: Option<bool>,
+    once: Option<bool>,
+    verify: Option<bool>,
+    event_buffer: Option<NonZeroUsize>,
+    max_concurrent_transfers: Option<NonZeroUsize>,
+    max_concurrent_lists: Option<NonZeroUsize>,
+    get_timeout_secs: Option<u64>,
+    dedup: Option<bool>,
+    log_level: Option<String>,
+    keepalive_secs: Option<u64>,
+    max_reconnect_attempts: Option<u32>,
+    max_idle_secs: Option<u64>,
+    compress: Option<bool>,
+    max_upload_kbps: Option<u64>,
+    delete_mode: Option<DeleteMode>,
+    fsync: Option<FsyncPolicy>,
+    skip_hidden: Option<bool>,
+    no_builtin_ignores: Option<bool>,
+    max_file_size: Option<String>,
+    subpath: Option<String>,
+    hash_algo: Option<HashAlgo>,
+    conflict: Option<ConflictMode>,
+    #[serde(default)]
+    pair: Vec<String>,
+    #[serde(default)]
+    ignore: Vec<String>,
+    #[serde(default)]
+    include: Vec<String>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed reading config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+    }
+}
+
+/// Fills in options from the config file that weren't given on the command line, so flags
+/// override the file and the file overrides the defaults. Pairs, ignore and include patterns
+/// from both are used
+pub fn merge(args: &mut Args, matches: &ArgMatches, file: FileConfig) -> Result<(), String> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    macro_rules! merge {
+        ($($field:ident),*) => {
+            $(
+                if let Some(value) = file.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            )*
+        };
+    }
+    merge!(address, prefer_ipv4, tls, insecure_skip_verify, progress, dry_run, read_only, watch_mode, syncdir, debounce_ms, min_resync_interval_ms, initial_sync, once, verify, event_buffer, max_concurrent_transfers, max_concurrent_lists, get_timeout_secs, dedup, keepalive_secs, max_reconnect_attempts, max_idle_secs, compress, max_upload_kbps, delete_mode, fsync, skip_hidden, no_builtin_ignores, hash_algo, conflict);
+    if let Some(channel) = file.channel {
+        if !from_cli("channel") {
+            args.channel = Some(parse_channel(&channel)?);
+        }
+    }
+    if let Some(bind) = file.bind {
+        if !from_cli("bind") {
+            args.bind = Some(bind);
+        }
+    }
+    if let Some(ca_cert) = file.ca_cert {
+        if !from_cli("ca_cert") {
+            args.ca_cert = Some(ca_cert);
+        }
+    }
+    if let Some(metrics_addr) = file.metrics_addr {
+        if !from_cli("metrics_addr") {
+            args.metrics_addr = Some(metrics_addr);
+        }
+    }
+    if let Some(psk) = file.psk {
+        if !from_cli("psk") {
+            args.psk = Some(parse_psk(&psk)?);
+        }
+    }
+    if let Some(subpath) = file.subpath {
+        if !from_cli("subpath") {
+            args.subpath = Some(parse_subpath(&subpath)?);
+        }
+    }
+    if let Some(size) = file.max_file_size {
+        if !from_cli("max_file_size") {
+            args.max_file_size = Some(parse_size(&size)?);
+        }
+    }
+    if let Some(level) = file.log_level {
+        if !from_cli("log_level") {
+            args.log_level = Some(level.parse().map_err(|_| format!("invalid log_level {}", level))?);
+        }
+    }
+    let mut pairs = file.pair.iter().map(|pair| parse_pair(pair)).collect::<Result<Vec<_>, _>>()?;
+    pairs.append(&mut args.pair);
+    args.pair = pairs;
+    let mut ignore = file.ignore;
+    ignore.append(&mut args.ignore);
+    args.ignore = ignore;
+    let mut include = file.include;
+    include.append(&mut args.include);
+    args.include = include;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn merged(cli: &[&str], file: &str) -> Result<Args, String> {
+        // naming a config file makes --channel optional, its contents are passed in instead
+        let matches = Args::command().try_get_matches_from(["syncd", "--config", "syncd.toml"].into_iter().chain(cli.iter().copied())).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        merge(&mut args, &matches, toml::from_str(file).unwrap())?;
+        Ok(args)
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file_which_overrides_defaults() {
+        let file = r#"
+            address = "file.example:5733"
+            keepalive_secs = 10
+            channel = "from-file"
+            compress = true
+            ignore = ["*.tmp"]
+        "#;
+        let args = merged(&["--address", "cli.example:5733", "--ignore", "*.bak"], file).unwrap();
+        assert_eq!(args.address, "cli.example:5733");
+        assert_eq!(args.keepalive_secs, 10);
+        assert_eq!(args.channel.as_deref(), Some("from-file"));
+        assert!(args.compress);
+        // patterns from both are used
+        assert_eq!(args.ignore, ["*.tmp", "*.bak"]);
+        // an option set in neither keeps its default
+        assert_eq!(args.debounce_ms, merged(&[], "").unwrap().debounce_ms);
+    }
+
+    #[test]
+    fn invalid_value_in_the_config_file_is_reported() {
+        assert!(merged(&[], r#"log_level = "loud""#).is_err());
+        assert!(toml::from_str::<FileConfig>(r#"keepalive_secs = "lots""#).is_err());
+        assert!(toml::from_str::<FileConfig>("no_such_option = 1").is_err());
+    }
+}
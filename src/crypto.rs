@@ -0,0 +1,202 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures::{Sink, Stream, StreamExt};
+use tokio_util::bytes::Bytes;
+use tracing::warn;
+use crate::codec::Package;
+use crate::error::SyncError;
+use crate::transport::PackageConn;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Bytes encryption adds to a message, the nonce in front of it and the tag after it
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+// Keys of different channels differ even with the same passphrase
+const SALT_PREFIX: &str = "syncd psk ";
+
+/// Passphrase given with --psk, kept out of the logged configuration
+#[derive(Clone)]
+pub struct Passphrase(pub String);
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Encrypts messages to the peer and decrypts its messages with a key both sides derived
+/// from the same passphrase, so the broker relaying them can neither read nor alter them
+pub struct PayloadCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl PayloadCipher {
+    /// Derives the key of a channel from a passphrase with Argon2id, salted with the channel
+    pub fn new(passphrase: &Passphrase, channel: &str) -> Result<Self, String> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.0.as_bytes(), format!("{}{}", SALT_PREFIX, channel).as_bytes(), &mut key)
+            .map_err(|e| format!("failed deriving a key from the passphrase: {}", e))?;
+        Ok(PayloadCipher{cipher: ChaCha20Poly1305::new(&key)})
+    }
+
+    /// Encrypts a message sent on channel under a random nonce, prepended to the ciphertext
+    pub fn seal(&self, channel: &[u8], message: &[u8]) -> Bytes {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload{msg: message, aad: channel})
+            .expect("messages fit in a frame, far below the size ChaCha20Poly1305 can encrypt");
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Bytes::from(sealed)
+    }
+
+    /// Decrypts a message received on channel, failing if it was encrypted with another key,
+    /// for another channel or was altered since
+    pub fn open(&self, channel: &[u8], sealed: &[u8]) -> Result<Bytes, SyncError> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(SyncError::Decrypt)
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload{msg: ciphertext, aad: channel})
+            .map(Bytes::from)
+            .map_err(|_| SyncError::Decrypt)
+    }
+}
+
+/// A broker connection encrypting the messages sent over it and decrypting the received
+/// ones if there is a cipher, passing them through as they are otherwise. Received
+/// messages that fail decrypting are dropped
+pub struct SealedConn<C> {
+    inner: C,
+    cipher: Option<Arc<PayloadCipher>>,
+}
+
+impl<C> SealedConn<C> {
+    pub fn new(inner: C, cipher: Option<Arc<PayloadCipher>>) -> Self {
+        SealedConn{inner, cipher}
+    }
+}
+
+impl<C: PackageConn> Stream for SealedConn<C> {
+    type Item = io::Result<Package>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let package = ready!(self.inner.poll_next_unpin(cx));
+            match (package, &self.cipher) {
+                (Some(Ok(Package::Message(channel, sealed))), Some(cipher)) => match cipher.open(&channel, &sealed) {
+                    Ok(message) => return Poll::Ready(Some(Ok(Package::Message(channel, message)))),
+                    Err(e) => warn!(error = %e, size = sealed.len(), "Dropping message"),
+                },
+                (package, _) => return Poll::Ready(package),
+            }
+        }
+    }
+}
+
+impl<C: PackageConn> Sink<Package> for SealedConn<C> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, package: Package) -> io::Result<()> {
+        let package = match (package, &self.cipher) {
+            (Package::Message(channel, message), Some(cipher)) => {
+                let sealed = cipher.seal(&channel, &message);
+                Package::Message(channel, sealed)
+            }
+            (package, _) => package,
+        };
+        Pin::new(&mut self.inner).start_send(package)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use futures::SinkExt;
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+    use crate::codec::Codec;
+    use crate::fs::hash_bytes;
+    use crate::hash::HashAlgo;
+    use crate::protocol::Protocol;
+    use crate::stats::SyncStats;
+
+    const CHANNEL: &[u8] = b"sync";
+
+    fn framed(stream: DuplexStream) -> Framed<DuplexStream, Codec> {
+        Framed::new(stream, Codec::new(Arc::new(SyncStats::default()), None))
+    }
+
+    /// Connection sealing with cipher, along with the broker's end of it
+    fn sealed(cipher: &Arc<PayloadCipher>) -> (SealedConn<Framed<DuplexStream, Codec>>, Framed<DuplexStream, Codec>) {
+        let (conn, broker) = tokio::io::duplex(1 << 20);
+        (SealedConn::new(framed(conn), Some(Arc::clone(cipher))), framed(broker))
+    }
+
+    async fn next_message(broker: &mut Framed<DuplexStream, Codec>) -> (Bytes, Bytes) {
+        match broker.next().await.unwrap().unwrap() {
+            Package::Message(channel, payload) => (channel, payload),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn message_arrives_as_sent_without_the_broker_seeing_it() {
+        let cipher = Arc::new(PayloadCipher::new(&Passphrase("secret".to_string()), "sync").unwrap());
+        let ((mut sender, mut from), (mut receiver, mut to)) = (sealed(&cipher), sealed(&cipher));
+        let contents = b"contents the broker mustn't see".to_vec();
+        let message = Protocol::GetResp{
+            path: PathBuf::from("dir/file.txt"), contents: contents.clone(), compressed: false,
+            hash: Some(hash_bytes(&contents, HashAlgo::Xxh64)), mode: Some(0o644), mtime: Some(1_700_000_000_000), link_target: None,
+        };
+        let mut payload = Vec::new();
+        ciborium::into_writer(&message, &mut payload).unwrap();
+        sender.send(Package::Message(Bytes::from_static(CHANNEL), Bytes::from(payload.clone()))).await.unwrap();
+        let (channel, relayed) = next_message(&mut from).await;
+        assert_eq!(relayed.len(), payload.len() + SEAL_OVERHEAD);
+        assert!(!relayed.windows(contents.len()).any(|window| window == contents));
+        to.send(Package::Message(channel, relayed)).await.unwrap();
+        let Some(Ok(Package::Message(channel, opened))) = receiver.next().await else {
+            panic!("sealed message wasn't received")
+        };
+        assert_eq!(channel, CHANNEL);
+        let opened: Protocol = ciborium::from_reader(opened.as_ref()).unwrap();
+        assert_eq!(opened, message);
+    }
+
+    #[tokio::test]
+    async fn altered_or_misdirected_messages_are_dropped() {
+        let cipher = Arc::new(PayloadCipher::new(&Passphrase("secret".to_string()), "sync").unwrap());
+        let ((mut sender, mut from), (mut receiver, mut to)) = (sealed(&cipher), sealed(&cipher));
+        sender.send(Package::Message(Bytes::from_static(CHANNEL), Bytes::from_static(b"payload"))).await.unwrap();
+        let (channel, relayed) = next_message(&mut from).await;
+        let mut flipped = relayed.to_vec();
+        flipped[NONCE_LEN] ^= 1;
+        to.send(Package::Message(channel.clone(), Bytes::from(flipped))).await.unwrap();
+        // sealed for another channel than the one it arrives on
+        to.send(Package::Message(Bytes::from_static(b"other"), relayed.clone())).await.unwrap();
+        to.send(Package::Message(channel, relayed.slice(..SEAL_OVERHEAD - 1))).await.unwrap();
+        to.send(Package::Ping(Bytes::from_static(b"after"))).await.unwrap();
+        assert_eq!(receiver.next().await.unwrap().unwrap(), Package::Ping(Bytes::from_static(b"after")));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use serde_with::{serde_as, Bytes};
+use twox_hash::XxHash64;
+
+// Blocks are at least this large, small blocks find more matches but make the signature bigger
+const MIN_BLOCK_SIZE: u64 = 4096;
+// Most blocks a signature describes, so it fits in a single message along with its path
+const MAX_BLOCKS: u64 = 4096;
+/// Largest block size a peer may ask for, what a signature of the largest file handled
+/// with deltas gets
+pub const MAX_BLOCK_SIZE: u32 = (MAX_FILE_SIZE / MAX_BLOCKS) as u32;
+/// Files larger than this are sent whole, the sender holds the file in memory while
+/// computing a delta
+pub const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+// Literal data goes out in pieces of at most this size, so a delta can be split across messages
+const MAX_DATA_LEN: usize = 32 * 1024;
+// Bytes of a signature describing each block, the weak and the strong checksum
+const BLOCK_SIG_LEN: usize = 4 + 8;
+
+/// One step of rebuilding a file from the receiver's old copy
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum DeltaOp {
+    /// Bytes the old copy has at offset
+    Copy {offset: u64, len: u64},
+    /// Bytes the old copy doesn't have
+    Data {#[serde_as(as = "Bytes")] data: Vec<u8>},
+}
+
+impl DeltaOp {
+    /// Number of bytes the op adds to the rebuilt file
+    pub fn len(&self) -> u64 {
+        match self {
+            DeltaOp::Copy {len, ..} => *len,
+            DeltaOp::Data {data} => data.len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Block size a signature of a file of the given size is computed with
+pub fn block_size(file_size: u64) -> u32 {
+    file_size.div_ceil(MAX_BLOCKS).clamp(MIN_BLOCK_SIZE, u64::from(MAX_BLOCK_SIZE)) as u32
+}
+
+/// rsync's rolling checksum, cheap to move along the data one byte at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (a, b) = block.iter().enumerate().fold((0u32, 0u32), |(a, b), (i, byte)| {
+            (a.wrapping_add(u32::from(*byte)), b.wrapping_add((len - i as u32).wrapping_mul(u32::from(*byte))))
+        });
+        Rolling {a, b, len}
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Moves the window one byte further, dropping out and taking in
+    fn roll(&mut self, out: u8, taken: u8) {
+        self.a = self.a.wrapping_sub(u32::from(out)).wrapping_add(u32::from(taken));
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(u32::from(out))).wrapping_add(self.a);
+    }
+}
+
+fn strong_checksum(block: &[u8]) -> u64 {
+    let mut hasher = XxHash64::default();
+    hasher.write(block);
+    hasher.finish()
+}
+
+/// Checksums of every whole block of a file, a weak and a strong one after another for each.
+/// A trailing partial block is left out, its contents are sent as data
+pub fn signature(path: &Path, block_size: u32) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut block = vec![0; block_size as usize];
+    let mut sig = Vec::new();
+    loop {
+        match reader.read_exact(&mut block) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        sig.extend_from_slice(&Rolling::new(&block).value().to_be_bytes());
+        sig.extend_from_slice(&strong_checksum(&block).to_be_bytes());
+    }
+    Ok(sig)
+}
+
+/// Describes data in terms of the blocks of a signature, None if that wouldn't save
+/// much over sending the data whole
+pub fn delta(data: &[u8], block_size: u32, sig: &[u8]) -> Option<Vec<DeltaOp>> {
+    let block_size = block_size as usize;
+    // block indexes for each weak checksum, along with the strong one
+    let mut blocks: HashMap<u32, Vec<(u64, u64)>> = HashMap::new();
+    for (index, block) in sig.chunks_exact(BLOCK_SIG_LEN).enumerate() {
+        let (weak, strong) = block.split_at(4);
+        let weak = u32::from_be_bytes(weak.try_into().ok()?);
+        let strong = u64::from_be_bytes(strong.try_into().ok()?);
+        blocks.entry(weak).or_default().push((strong, index as u64));
+    }
+    let mut ops = Vec::new();
+    let mut literal = 0;
+    let mut sent = 0;
+    let push_data = |ops: &mut Vec<DeltaOp>, data: &[u8]| {
+        ops.extend(data.chunks(MAX_DATA_LEN).map(|chunk| DeltaOp::Data{data: chunk.to_vec()}));
+    };
+    let mut pos = 0;
+    let mut rolling = (data.len() >= block_size).then(|| Rolling::new(&data[..block_size]));
+    while let Some(current) = rolling.as_mut() {
+        let window = &data[pos..pos + block_size];
+        let matched = blocks.get(&current.value()).and_then(|candidates| {
+            let strong = strong_checksum(window);
+            candidates.iter().find(|(candidate, _)| *candidate == strong).map(|(_, index)| *index)
+        });
+        if let Some(index) = matched {
+            push_data(&mut ops, &data[literal..pos]);
+            sent += pos - literal;
+            let offset = index * block_size as u64;
+            match ops.last_mut() {
+                // runs of matching blocks become a single copy
+                Some(DeltaOp::Copy {offset: last, len}) if *last + *len == offset => *len += block_size as u64,
+                _ => ops.push(DeltaOp::Copy{offset, len: block_size as u64}),
+            }
+            pos += block_size;
+            literal = pos;
+            rolling = (data.len() >= pos + block_size).then(|| Rolling::new(&data[pos..pos + block_size]));
+        } else if pos + block_size < data.len() {
+            current.roll(data[pos], data[pos + block_size]);
+            pos += 1;
+        } else {
+            break
+        }
+    }
+    push_data(&mut ops, &data[literal..]);
+    sent += data.len() - literal;
+    (sent <= data.len() / 2).then_some(ops)
+}
+
+/// Writes the bytes ops describe to tmppath at offset, taking copied ones from the old file
+pub fn apply(old: &Path, tmppath: &Path, offset: u64, ops: &[DeltaOp]) -> io::Result<()> {
+    let mut old = fs::File::open(old)?;
+    let mut out = if offset == 0 {
+        fs::File::create(tmppath)?
+    } else {
+        fs::OpenOptions::new().write(true).open(tmppath)?
+    };
+    if out.metadata()?.len() != offset {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("delta continues at {} but {} bytes were written", offset, out.metadata()?.len())))
+    }
+    out.seek(SeekFrom::Start(offset))?;
+    for op in ops {
+        match op {
+            DeltaOp::Copy {offset, len} => {
+                old.seek(SeekFrom::Start(*offset))?;
+                let copied = io::copy(&mut (&mut old).take(*len), &mut out)?;
+                if copied != *len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "delta copies past the end of the old file"))
+                }
+            }
+            DeltaOp::Data {data} => out.write_all(data)?,
+        }
+    }
+    Ok(())
+}
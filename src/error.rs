@@ -0,0 +1,43 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("I/O error on {}: {source}", path.display())]
+    Fs { path: PathBuf, source: io::Error },
+    #[error("malformed message: {0}")]
+    Decode(#[from] ciborium::de::Error<io::Error>),
+    #[error("message of unknown type {0}, the peer may run a newer version")]
+    UnknownMessage(String),
+    #[error("failed serializing message: {0}")]
+    Encode(#[from] ciborium::ser::Error<io::Error>),
+    #[error("path {} escapes the synced directory", .0.display())]
+    PathEscapes(PathBuf),
+    #[error("path {} is excluded from syncing", .0.display())]
+    Excluded(PathBuf),
+    #[error("file {} is {size} bytes, over the {limit} byte size limit", path.display())]
+    TooLarge { path: PathBuf, size: u64, limit: u64 },
+    #[error("{} is not a regular file", .0.display())]
+    NotAFile(PathBuf),
+    #[error("failed decrypting message, the peer's passphrase differs or it was tampered with")]
+    Decrypt,
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+impl SyncError {
+    pub fn fs(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        SyncError::Fs { path: path.into(), source }
+    }
+
+    /// Whether the disk or the quota is full, which only freeing space fixes
+    pub fn is_storage_full(&self) -> bool {
+        match self {
+            SyncError::Io(e) | SyncError::Fs { source: e, .. } => matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded),
+            _ => false,
+        }
+    }
+}
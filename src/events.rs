@@ -0,0 +1,945 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use notify::{Event, EventHandler, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::{debug, info, warn};
+use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode};
+use crate::fs::try_hash_file;
+use crate::hash::{Digest, HashAlgo};
+use crate::protocol::{EntityType, Protocol};
+use crate::stats::SyncStats;
+
+// How long the From half of a rename waits for its To half before the
+// path is considered moved out of the watched directory
+const RENAME_PAIR_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How often the watched directory is checked for having been deleted and recreated
+const ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Free slots left in the event buffer below which it's considered close to full
+const BUFFER_LOW_WATERMARK_DIVISOR: usize = 5;
+
+/// What the watcher thread hands over to the event handler
+#[derive(Debug)]
+pub enum WatcherMsg {
+    Event(Event),
+    /// Events under the directory were lost, it has to be compared with what was last
+    /// sent to find the changes
+    Overflow(PathBuf),
+}
+
+/// Identifies a directory across renames, None if path isn't one
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().filter(|meta| meta.is_dir()).map(|meta| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().filter(|meta| meta.is_dir()).map(|_| (0, 0))
+}
+
+/// Keeps watching dir after it was deleted and created again, like checkout tools do to
+/// the directories they switch. The watch stays with the deleted directory and nothing
+/// reports the new one, so it's polled for. The new directory is watched and rescanned.
+/// The new directory may get the old one's inode, the watcher reporting the old one's
+/// removal in removed tells it apart
+pub async fn keep_watching(mut watcher: RecommendedWatcher, dir: PathBuf, mode: RecursiveMode, removed: Arc<AtomicBool>, tx: mpsc::Sender<WatcherMsg>) {
+    let mut watched = dir_id(&dir);
+    let mut interval = tokio::time::interval(ROOT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if removed.swap(false, Ordering::Relaxed) {
+            watched = None;
+        }
+        let current = dir_id(&dir);
+        if current == watched {
+            continue
+        }
+        if current.is_none() {
+            warn!(path = %dir.display(), "Watched directory is gone, waiting for it to be created again");
+            watched = current;
+            continue
+        }
+        let _ = watcher.unwatch(&dir);
+        if let Err(e) = watcher.watch(&dir, mode) {
+            // tried again on the next check
+            warn!(path = %dir.display(), error = %e, "Failed watching recreated directory");
+            continue
+        }
+        info!(path = %dir.display(), "Watched directory was created again, rescanning it");
+        watched = current;
+        if tx.send(WatcherMsg::Overflow(dir.clone())).await.is_err() {
+            return
+        }
+    }
+}
+
+/// Hands events from the watcher thread over to the event handler, dropping events rather
+/// than blocking the watcher when the handler falls behind and the buffer fills up. Lost
+/// events are made up for with an Overflow once there's room again
+pub struct EventForwarder {
+    tx: mpsc::Sender<WatcherMsg>,
+    /// Watched directory, the one to rescan when it isn't known where events were lost
+    root: PathBuf,
+    /// Directory an Overflow is still to be sent for
+    rescan: Option<PathBuf>,
+    /// Set once the watched directory itself was removed
+    removed: Arc<AtomicBool>,
+    stats: Arc<SyncStats>,
+    near_full: bool,
+}
+
+impl EventForwarder {
+    pub fn new(tx: mpsc::Sender<WatcherMsg>, root: PathBuf, removed: Arc<AtomicBool>, stats: Arc<SyncStats>) -> Self {
+        EventForwarder {
+            tx,
+            root,
+            rescan: None,
+            removed,
+            stats,
+            near_full: false,
+        }
+    }
+
+    /// Asks for a rescan of dir, or of the whole root if another directory is waiting for one
+    fn lost_events(&mut self, dir: Option<&Path>) {
+        self.rescan = match (self.rescan.take(), dir) {
+            (None, Some(dir)) => Some(dir.to_path_buf()),
+            (Some(pending), Some(dir)) if pending == dir => Some(pending),
+            _ => Some(self.root.clone()),
+        };
+    }
+}
+
+impl EventHandler for EventForwarder {
+    fn handle_event(&mut self, event: notify::Result<Event>) {
+        let event = match event {
+            // the kernel's queue overflowed (or the backend lost track otherwise), nothing
+            // tells what happened in the meantime
+            Ok(event) if event.need_rescan() => {
+                warn!(path = ?event.paths.first(), "Watcher lost events, rescanning");
+                self.lost_events(event.paths.first().map(PathBuf::as_path));
+                None
+            }
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Remove(_)) && event.paths.contains(&self.root) {
+                    self.removed.store(true, Ordering::Relaxed);
+                }
+                Some(event)
+            }
+            // directories created later need watches of their own too
+            Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                warn!(error = %e, "Out of inotify watches, changes in new directories go unnoticed. Raise fs.inotify.max_user_watches with sysctl");
+                return
+            }
+            Err(e) => {
+                warn!(error = %e, "Watcher error");
+                return
+            }
+        };
+        if let Some(dir) = self.rescan.take() {
+            match self.tx.try_send(WatcherMsg::Overflow(dir)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(WatcherMsg::Overflow(dir))) => self.rescan = Some(dir),
+                Err(_) => return,
+            }
+        }
+        let Some(event) = event else {
+            return
+        };
+        match self.tx.try_send(WatcherMsg::Event(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+                self.stats.record_dropped_event();
+                let path = match &msg {
+                    WatcherMsg::Event(event) => event.paths.first().map(|path| path.display().to_string()).unwrap_or_default(),
+                    WatcherMsg::Overflow(dir) => dir.display().to_string(),
+                };
+                warn!(path, dropped = self.stats.dropped_events(), "Event buffer full, dropped event, rescanning once there's room");
+                self.lost_events(None);
+            }
+            Err(TrySendError::Closed(_)) => return,
+        }
+        let max = self.tx.max_capacity();
+        let free = self.tx.capacity();
+        if !self.near_full && free <= max / BUFFER_LOW_WATERMARK_DIVISOR {
+            warn!(used = max - free, capacity = max, "Event buffer almost full, events are arriving faster than they can be sent");
+            self.near_full = true;
+        } else if self.near_full && free > max / 2 {
+            self.near_full = false;
+        }
+    }
+}
+
+/// Chains the preprocessing stages events go through before being turned into protocol messages
+pub struct EventPipeline {
+    renames: RenameTracker,
+    debouncer: Debouncer,
+}
+
+impl EventPipeline {
+    pub fn new(debounce: Duration, min_resync: Duration) -> Self {
+        EventPipeline {
+            renames: RenameTracker::new(),
+            debouncer: Debouncer::new(debounce, min_resync),
+        }
+    }
+
+    /// Feeds an event from the watcher, returning events that should be handled right away
+    pub fn push(&mut self, event: Event) -> Vec<Event> {
+        self.renames.push(event).into_iter()
+            .flat_map(|event| self.debouncer.push(event))
+            .collect()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        [self.renames.next_deadline(), self.debouncer.next_deadline()].into_iter().flatten().min()
+    }
+
+    /// Returns events held back by any of the stages whose time has come
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Event> {
+        let mut ready: Vec<Event> = self.renames.pop_expired(now).into_iter()
+            .flat_map(|event| self.debouncer.push(event))
+            .collect();
+        ready.extend(self.debouncer.pop_expired(now));
+        ready
+    }
+}
+
+/// Pairs up the separate From and To halves some backends report renames as,
+/// identified by a shared tracker (the inotify cookie on Linux)
+#[derive(Default)]
+pub struct RenameTracker {
+    pending: HashMap<usize, (PathBuf, Instant)>,
+    // renames already emitted when their To half arrived, so the Both event
+    // inotify sends afterwards isn't emitted a second time
+    paired: HashMap<usize, Instant>,
+}
+
+impl RenameTracker {
+    pub fn new() -> Self {
+        RenameTracker {
+            pending: HashMap::new(),
+            paired: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: Event) -> Vec<Event> {
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                // without a tracker there's no telling where the path went (e.g. a watched
+                // directory reporting its own move), the parent's events cover that case
+                if let (Some(tracker), Some(path)) = (event.tracker(), event.paths.first()) {
+                    self.pending.insert(tracker, (path.clone(), Instant::now() + RENAME_PAIR_TIMEOUT));
+                }
+                Vec::new()
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                let Some(path_to) = event.paths.first().cloned() else {
+                    return Vec::new()
+                };
+                let from = event.tracker().and_then(|tracker| {
+                    self.pending.remove(&tracker).map(|(path_from, _)| (tracker, path_from))
+                });
+                match from {
+                    Some((tracker, path_from)) => {
+                        self.paired.insert(tracker, Instant::now() + RENAME_PAIR_TIMEOUT);
+                        vec![Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                            .set_tracker(tracker)
+                            .add_path(path_from)
+                            .add_path(path_to)]
+                    }
+                    // moved in from outside of the watched directory
+                    None => {
+                        let kind = if path_to.is_dir() { CreateKind::Folder } else { CreateKind::File };
+                        vec![Event::new(EventKind::Create(kind)).add_path(path_to)]
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                match event.tracker() {
+                    Some(tracker) if self.paired.remove(&tracker).is_some() => Vec::new(),
+                    Some(tracker) => {
+                        self.pending.remove(&tracker);
+                        vec![event]
+                    }
+                    None => vec![event],
+                }
+            }
+            _ => vec![event],
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, deadline)| *deadline)
+            .chain(self.paired.values().copied())
+            .min()
+    }
+
+    /// Turns renames whose To half never arrived into deletes, the path was moved
+    /// out of the watched directory
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Event> {
+        self.paired.retain(|_, deadline| *deadline > now);
+        let mut expired: Vec<(PathBuf, Instant)> = Vec::new();
+        self.pending.retain(|_, (path, deadline)| {
+            if *deadline <= now {
+                expired.push((path.clone(), *deadline));
+                false
+            } else {
+                true
+            }
+        });
+        expired.sort_by_key(|(_, deadline)| *deadline);
+        expired.into_iter()
+            .map(|(path, _)| Event::new(EventKind::Remove(RemoveKind::Any)).add_path(path))
+            .collect()
+    }
+}
+
+/// Coalesces bursts of content modifications of the same path, only letting
+/// the last one through once the path has been quiet for the whole window. A file's
+/// creation is held the same way, so contents written right after it go out as a single
+/// modification the peer fetches the file for, rather than a create followed by one.
+///
+/// A file's deletion is held too, and the events of a path within the window are folded into
+/// their net effect: a file created and deleted again goes out as nothing, one deleted and
+/// created again as a modification, and one created and then renamed as the creation of
+/// where it was renamed to. Held events under a renamed directory move along with it.
+///
+/// With a minimum resync interval, a path's modifications are let through at most once per
+/// interval however often it's written, the latest one at the end of it. A file that never
+/// stays quiet for the whole window (e.g. a log being appended to) still goes out then
+pub struct Debouncer {
+    window: Duration,
+    min_interval: Duration,
+    pending: HashMap<PathBuf, Held>,
+    /// When a modification of each path was last let through, within the last interval
+    resynced: HashMap<PathBuf, Instant>,
+}
+
+/// An event the debouncer holds back
+struct Held {
+    event: Event,
+    /// When it's let through
+    deadline: Instant,
+    /// Since when the path has been held
+    held_since: Instant,
+    /// The path was created while held, so the peer doesn't know about it yet
+    created: bool,
+}
+
+/// What a file replacing another at path is sent as: a modification, unless it's a symlink,
+/// which the peer only fetches the target of for a creation
+fn replacement(path: &Path) -> Event {
+    let kind = if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        EventKind::Create(CreateKind::File)
+    } else {
+        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+    };
+    Event::new(kind).add_path(path.to_path_buf())
+}
+
+impl Debouncer {
+    pub fn new(window: Duration, min_interval: Duration) -> Self {
+        Debouncer {
+            window,
+            min_interval,
+            pending: HashMap::new(),
+            resynced: HashMap::new(),
+        }
+    }
+
+    /// When a modification of path seen now is let through
+    fn modify_deadline(&self, path: &Path, now: Instant, held_since: Instant) -> Instant {
+        let quiet = now + self.window;
+        if self.min_interval.is_zero() {
+            return quiet
+        }
+        match self.resynced.get(path) {
+            Some(resynced) if *resynced + self.min_interval > now => *resynced + self.min_interval,
+            _ => quiet.min(held_since + self.min_interval),
+        }
+    }
+
+    /// Holds event for the whole window
+    fn hold(&mut self, event: Event, created: bool) {
+        let now = Instant::now();
+        self.pending.insert(event.paths[0].clone(), Held{event, deadline: now + self.window, held_since: now, created});
+    }
+
+    fn is_held_delete(&self, path: &Path) -> bool {
+        self.pending.get(path).is_some_and(|held| matches!(held.event.kind, EventKind::Remove(_)))
+    }
+
+    /// Removes and returns the held events of paths and everything under them, oldest first
+    fn flush(&mut self, paths: &[PathBuf]) -> Vec<Event> {
+        self.resynced.retain(|path, _| !paths.iter().any(|parent| path.starts_with(parent)));
+        let flushed: Vec<PathBuf> = self.pending.keys()
+            .filter(|path| paths.iter().any(|parent| path.starts_with(parent)))
+            .cloned()
+            .collect();
+        let mut ready: Vec<Held> = flushed.iter().filter_map(|path| self.pending.remove(path)).collect();
+        ready.sort_by_key(|held| held.deadline);
+        ready.into_iter().map(|held| held.event).collect()
+    }
+
+    /// Moves the held events of from and everything under it to where it was renamed to
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<PathBuf> = self.pending.keys().filter(|path| path.starts_with(from)).cloned().collect();
+        for path in moved {
+            if let Some(mut held) = self.pending.remove(&path) {
+                let path_to = to.join(path.strip_prefix(from).unwrap_or(&path));
+                held.event.paths = vec![path_to.clone()];
+                self.pending.insert(path_to, held);
+            }
+        }
+    }
+
+    /// Feeds an event from the watcher, returning events that should be handled right away
+    pub fn push(&mut self, event: Event) -> Vec<Event> {
+        if self.window.is_zero() && self.min_interval.is_zero() {
+            return vec![event]
+        }
+        match event.kind {
+            // a deleted file's modification reported after its deletion
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) if event.paths.len() == 1 && self.is_held_delete(&event.paths[0]) => Vec::new(),
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) if event.paths.len() == 1 => {
+                let path = event.paths[0].clone();
+                let now = Instant::now();
+                let (held_since, created) = self.pending.get(&path).map_or((now, false), |held| (held.held_since, held.created));
+                let deadline = self.modify_deadline(&path, now, held_since);
+                if deadline <= now {
+                    self.pending.remove(&path);
+                    self.resynced.insert(path, now);
+                    return vec![event]
+                }
+                self.pending.insert(path, Held{event, deadline, held_since, created});
+                Vec::new()
+            }
+            EventKind::Create(CreateKind::File) if event.paths.len() == 1 && !self.window.is_zero() => {
+                let path = event.paths[0].clone();
+                // deleted and created again, for the peer only its contents changed
+                if self.is_held_delete(&path) {
+                    self.hold(replacement(&path), false);
+                    return Vec::new()
+                }
+                let ready = self.flush(&event.paths);
+                self.hold(event, true);
+                ready
+            }
+            EventKind::Remove(kind) if event.paths.len() == 1 && !self.window.is_zero() => {
+                let path = event.paths[0].clone();
+                // the file never made it to the peer, or its modification is moot now
+                if self.pending.remove(&path).is_some_and(|held| held.created) {
+                    self.resynced.remove(&path);
+                    return Vec::new()
+                }
+                let mut ready = self.flush(&event.paths);
+                if kind == RemoveKind::File {
+                    self.hold(event, false);
+                } else {
+                    ready.push(event);
+                }
+                ready
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let (from, to) = (event.paths[0].clone(), event.paths[1].clone());
+                // whatever was where the path went is replaced, it's sent again from there
+                let mut ready = self.flush(&event.paths[1..]);
+                if self.pending.get(&from).is_some_and(|held| held.created) {
+                    self.pending.remove(&from);
+                    self.resynced.remove(&from);
+                    ready.retain(|flushed| flushed.paths[0] != to);
+                    self.hold(replacement(&to), false);
+                    return ready
+                }
+                ready.retain(|flushed| !flushed.paths[0].starts_with(&to) || matches!(flushed.kind, EventKind::Remove(_)));
+                self.resynced.remove(&from);
+                self.rename(&from, &to);
+                ready.push(event);
+                ready
+            }
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+                // events changing what a path refers to first flush its pending
+                // events so the events keep their original order
+                let mut ready = self.flush(&event.paths);
+                ready.push(event);
+                ready
+            }
+            _ => vec![event]
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|held| held.deadline).min()
+    }
+
+    /// Removes and returns events whose window has passed, oldest first
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Event> {
+        let min_interval = self.min_interval;
+        self.resynced.retain(|_, resynced| *resynced + min_interval > now);
+        let mut expired: Vec<(Event, Instant)> = Vec::new();
+        self.pending.retain(|_, held| {
+            if held.deadline <= now {
+                expired.push((held.event.clone(), held.deadline));
+                false
+            } else {
+                true
+            }
+        });
+        expired.sort_by_key(|(_, deadline)| *deadline);
+        if !min_interval.is_zero() {
+            for (event, _) in &expired {
+                if matches!(event.kind, EventKind::Modify(_)) {
+                    self.resynced.insert(event.paths[0].clone(), now);
+                }
+            }
+        }
+        expired.into_iter().map(|(event, _)| event).collect()
+    }
+}
+
+// How long a change syncd made itself waits for the watcher to report it
+const ECHO_SUPPRESS_TTL: Duration = Duration::from_secs(3);
+
+/// Remembers paths syncd just changed on behalf of the peer, so the watcher events
+/// those changes cause aren't sent back to where they came from
+#[derive(Default)]
+pub struct EchoSuppressor {
+    paths: HashMap<PathBuf, Instant>,
+}
+
+impl EchoSuppressor {
+    pub fn new() -> Self {
+        EchoSuppressor {
+            paths: HashMap::new(),
+        }
+    }
+
+    /// Expects a watcher event for a path relative to the sync root
+    pub fn suppress(&mut self, path: &Path) {
+        self.paths.insert(path.to_path_buf(), Instant::now() + ECHO_SUPPRESS_TTL);
+    }
+
+    /// Checks whether an event for the path was caused by syncd itself. An exact match is
+    /// cleared since the expected event arrived. The removal of a path below a suppressed
+    /// one matches too (a directory removed along with its contents) until it expires, any
+    /// other event below it is a change of its own
+    pub fn take(&mut self, path: &Path, removal: bool) -> bool {
+        let now = Instant::now();
+        self.paths.retain(|_, expiry| *expiry > now);
+        self.paths.remove(path).is_some()
+            || removal && path.ancestors().skip(1).any(|parent| self.paths.contains_key(parent))
+    }
+}
+
+// How long a deleted file waits for a create with the same contents to show up
+// before the delete is sent, the create then being sent as a rename instead
+const MOVE_DETECT_TIMEOUT: Duration = Duration::from_millis(500);
+// How many file hashes are remembered for telling whether a created file is a deleted one
+const MOVE_DETECT_KNOWN_HASHES: usize = 4096;
+
+/// Recognizes moves that reach syncd as a delete followed by a create, like those done by
+/// tools copying a file and removing the original, by the created file having the contents
+/// the deleted one was last seen with. Works on outgoing messages since those carry hashes
+pub struct MoveDetector {
+    root: PathBuf,
+    algo: HashAlgo,
+    // last hash sent for each file and when it was, the oldest is forgotten first
+    known: HashMap<PathBuf, (Digest, Instant)>,
+    pending: Vec<(PathBuf, Digest, Instant)>,
+}
+
+impl MoveDetector {
+    pub fn new(root: PathBuf, algo: HashAlgo) -> Self {
+        MoveDetector {
+            root,
+            algo,
+            known: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Hashes created files with algo from now on, forgetting hashes taken with another one
+    /// as they'd never match
+    pub fn set_algo(&mut self, algo: HashAlgo) {
+        if algo != self.algo {
+            self.algo = algo;
+            self.known.clear();
+        }
+    }
+
+    fn remember(&mut self, path: PathBuf, hash: Digest) {
+        if self.known.len() >= MOVE_DETECT_KNOWN_HASHES && !self.known.contains_key(&path) {
+            if let Some(oldest) = self.known.iter().min_by_key(|(_, (_, seen))| *seen).map(|(path, _)| path.clone()) {
+                self.known.remove(&oldest);
+            }
+        }
+        self.known.insert(path, (hash, Instant::now()));
+    }
+
+    /// Takes back a delete held for path so it's sent before a message touching the same path
+    fn flush_path(&mut self, path: &Path, ready: &mut Vec<Protocol>) {
+        if let Some(index) = self.pending.iter().position(|(pending, _, _)| pending == path) {
+            let (path, _, _) = self.pending.remove(index);
+            ready.push(Protocol::FsEventDelete{path, entity: Some(EntityType::File)});
+        }
+    }
+
+    /// Takes back a held delete of a file with the same contents as the new one at path,
+    /// sending a rename in its place unless it's the path itself, returning whether there was one
+    fn detect_move(&mut self, path: &Path, hash: Digest, ready: &mut Vec<Protocol>) -> bool {
+        let Some(index) = self.pending.iter().rposition(|(_, pending_hash, _)| *pending_hash == hash) else {
+            return false
+        };
+        let (path_from, _, _) = self.pending.remove(index);
+        self.flush_path(path, ready);
+        // deleted and created again with the same contents, nothing changed for the peer
+        if path_from != path {
+            debug!(path_from = %path_from.display(), path_to = %path.display(), "Detected move");
+            ready.push(Protocol::FsEventRename{path_from, path_to: path.to_path_buf()});
+        }
+        true
+    }
+
+    /// Feeds a message about to be sent, returning messages that should be sent right away
+    pub fn push(&mut self, message: Protocol) -> Vec<Protocol> {
+        let mut ready = Vec::new();
+        match message {
+            Protocol::FsEventModify {ref path, hash} => {
+                // a file written right after its creation only comes as a modification
+                let is_new = !self.known.contains_key(path);
+                self.remember(path.clone(), hash);
+                if is_new && self.detect_move(path, hash, &mut ready) {
+                    return ready
+                }
+                self.flush_path(path, &mut ready);
+            }
+            Protocol::FsEventDelete {path, entity} => {
+                self.flush_path(&path, &mut ready);
+                let known = self.known.remove(&path);
+                // a deleted directory takes the files in it along, including their held deletes
+                self.known.retain(|known, _| !known.starts_with(&path));
+                self.pending.retain(|(pending, _, _)| !pending.starts_with(&path));
+                match known {
+                    Some((hash, _)) => self.pending.push((path, hash, Instant::now() + MOVE_DETECT_TIMEOUT)),
+                    None => ready.push(Protocol::FsEventDelete{path, entity}),
+                }
+                return ready
+            }
+            Protocol::FsEventCreate {ref path, entity: EntityType::File} => {
+                if let Ok(hash) = try_hash_file(&self.root.join(path), self.algo) {
+                    self.remember(path.clone(), hash);
+                    if self.detect_move(path, hash, &mut ready) {
+                        return ready
+                    }
+                }
+                self.flush_path(path, &mut ready);
+            }
+            Protocol::FsEventRename {ref path_from, ref path_to} => {
+                self.flush_path(path_from, &mut ready);
+                self.flush_path(path_to, &mut ready);
+                // a renamed directory takes the files in it along
+                let moved: Vec<(PathBuf, Digest)> = self.known.iter()
+                    .filter_map(|(known, (hash, _))| {
+                        let rest = known.strip_prefix(path_from).ok()?;
+                        let moved = if rest.as_os_str().is_empty() { path_to.clone() } else { path_to.join(rest) };
+                        Some((moved, *hash))
+                    })
+                    .collect();
+                self.known.retain(|known, _| !known.starts_with(path_from));
+                for (path, hash) in moved {
+                    self.remember(path, hash);
+                }
+            }
+            Protocol::FsEventCreate {ref path, ..} | Protocol::FsEventUnknown {ref path, ..} | Protocol::FsEventAttrs {ref path, ..} => {
+                self.flush_path(path, &mut ready)
+            }
+            _ => {}
+        }
+        ready.push(message);
+        ready
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|(_, _, deadline)| *deadline).min()
+    }
+
+    /// Sends the deletes no matching create showed up for
+    pub fn pop_expired(&mut self, now: Instant) -> Vec<Protocol> {
+        let mut expired = Vec::new();
+        self.pending.retain(|(path, _, deadline)| {
+            if *deadline <= now {
+                expired.push(Protocol::FsEventDelete{path: path.clone(), entity: Some(EntityType::File)});
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{DataChange, RemoveKind, RenameMode};
+
+    const WINDOW: Duration = Duration::from_millis(300);
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    fn modify(path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), path)
+    }
+
+    /// Advances the paused clock past everything being held, returning what comes out
+    async fn pop_all(pipeline: &mut EventPipeline) -> Vec<Event> {
+        let mut ready = Vec::new();
+        while let Some(deadline) = pipeline.next_deadline() {
+            tokio::time::advance(deadline.saturating_duration_since(Instant::now())).await;
+            ready.extend(pipeline.pop_expired(Instant::now()));
+        }
+        ready
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_of_modifications_goes_out_once() {
+        let mut pipeline = EventPipeline::new(WINDOW, Duration::ZERO);
+        for _ in 0..10 {
+            assert!(pipeline.push(modify("/root/file")).is_empty());
+            tokio::time::advance(Duration::from_millis(10)).await;
+        }
+        assert!(pipeline.pop_expired(Instant::now()).is_empty(), "held until the file stays quiet for the window");
+        let ready = pop_all(&mut pipeline).await;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].paths, [PathBuf::from("/root/file")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rename_flushes_the_pending_modification() {
+        let mut pipeline = EventPipeline::new(WINDOW, Duration::ZERO);
+        pipeline.push(modify("/root/file"));
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/root/file"))
+            .add_path(PathBuf::from("/root/renamed"));
+        let ready = pipeline.push(rename);
+        assert_eq!(ready.len(), 1);
+        assert!(matches!(ready[0].kind, EventKind::Modify(ModifyKind::Name(RenameMode::Both))));
+        // the modification moved along with the file and goes out for where it is now
+        let ready = pop_all(&mut pipeline).await;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].paths, [PathBuf::from("/root/renamed")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delete_flushes_the_pending_modification() {
+        let mut pipeline = EventPipeline::new(WINDOW, Duration::ZERO);
+        pipeline.push(modify("/root/dir/file"));
+        let ready = pipeline.push(event(EventKind::Remove(RemoveKind::Folder), "/root/dir"));
+        let kinds: Vec<_> = ready.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, [EventKind::Modify(ModifyKind::Data(DataChange::Content)), EventKind::Remove(RemoveKind::Folder)]);
+        assert!(pop_all(&mut pipeline).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn file_modified_continuously_goes_out_once_per_interval() {
+        let interval = Duration::from_secs(1);
+        let mut pipeline = EventPipeline::new(WINDOW, interval);
+        let mut resyncs = 0;
+        // written every 50 ms for 5 seconds, never quiet for the whole window
+        for _ in 0..100 {
+            resyncs += pipeline.push(modify("/root/log")).len();
+            tokio::time::advance(Duration::from_millis(50)).await;
+            resyncs += pipeline.pop_expired(Instant::now()).len();
+        }
+        resyncs += pop_all(&mut pipeline).await.len();
+        assert!((4..=6).contains(&resyncs), "sent {resyncs} times over 5 intervals");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_of_a_path_within_the_window_fold_into_their_net_effect() {
+        let mut pipeline = EventPipeline::new(WINDOW, Duration::ZERO);
+        let created = || event(EventKind::Create(CreateKind::File), "/root/file");
+        let deleted = || event(EventKind::Remove(RemoveKind::File), "/root/file");
+        let kinds = |events: Vec<Event>| events.into_iter().map(|event| event.kind).collect::<Vec<_>>();
+        // created and deleted again, the peer never needs to hear of it
+        assert!(pipeline.push(created()).is_empty());
+        assert!(pipeline.push(deleted()).is_empty());
+        assert!(pop_all(&mut pipeline).await.is_empty());
+        // created and written to, fetched once
+        pipeline.push(created());
+        for _ in 0..3 {
+            assert!(pipeline.push(modify("/root/file")).is_empty());
+        }
+        assert_eq!(kinds(pop_all(&mut pipeline).await), [EventKind::Modify(ModifyKind::Data(DataChange::Content))]);
+        // deleted and created again, only its contents changed
+        pipeline.push(deleted());
+        pipeline.push(created());
+        assert_eq!(kinds(pop_all(&mut pipeline).await), [EventKind::Modify(ModifyKind::Data(DataChange::Any))]);
+        // a modification reported after the deletion doesn't bring it back
+        pipeline.push(deleted());
+        pipeline.push(modify("/root/file"));
+        assert_eq!(kinds(pop_all(&mut pipeline).await), [EventKind::Remove(RemoveKind::File)]);
+        // created and renamed, only where it ended up is sent
+        pipeline.push(created());
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/root/file"))
+            .add_path(PathBuf::from("/root/renamed"));
+        assert!(pipeline.push(rename).is_empty());
+        let ready = pop_all(&mut pipeline).await;
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].paths, [PathBuf::from("/root/renamed")]);
+    }
+
+    fn rename_half(mode: RenameMode, tracker: usize, path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Name(mode)), path).set_tracker(tracker)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rename_halves_pair_up_into_one_rename() {
+        let mut renames = RenameTracker::new();
+        assert!(renames.push(rename_half(RenameMode::From, 7, "/root/old")).is_empty());
+        let ready = renames.push(rename_half(RenameMode::To, 7, "/root/new"));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].kind, EventKind::Modify(ModifyKind::Name(RenameMode::Both)));
+        assert_eq!(ready[0].paths, [PathBuf::from("/root/old"), PathBuf::from("/root/new")]);
+        // the combined event inotify sends after the halves was already covered
+        let both = rename_half(RenameMode::Both, 7, "/root/old").add_path(PathBuf::from("/root/new"));
+        assert!(renames.push(both).is_empty());
+        tokio::time::advance(RENAME_PAIR_TIMEOUT).await;
+        assert!(renames.pop_expired(Instant::now()).is_empty());
+        assert_eq!(renames.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rename_without_its_destination_becomes_a_delete() {
+        let mut renames = RenameTracker::new();
+        assert!(renames.push(rename_half(RenameMode::From, 7, "/root/old")).is_empty());
+        tokio::time::advance(RENAME_PAIR_TIMEOUT / 2).await;
+        assert!(renames.pop_expired(Instant::now()).is_empty(), "the destination may still arrive");
+        tokio::time::advance(RENAME_PAIR_TIMEOUT / 2).await;
+        let ready = renames.pop_expired(Instant::now());
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].kind, EventKind::Remove(RemoveKind::Any));
+        assert_eq!(ready[0].paths, [PathBuf::from("/root/old")]);
+        // a destination arriving too late counts as a path moved in from outside
+        let ready = renames.push(rename_half(RenameMode::To, 7, "/root/new"));
+        assert!(matches!(ready[0].kind, EventKind::Create(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_removals_below_a_suppressed_directory_are_echoes() {
+        let mut echoes = EchoSuppressor::new();
+        echoes.suppress(Path::new("dir"));
+        assert!(echoes.take(Path::new("dir/file"), true), "removed along with the directory");
+        assert!(!echoes.take(Path::new("dir/file"), false), "created in the directory since");
+        assert!(echoes.take(Path::new("dir"), false));
+        assert!(!echoes.take(Path::new("dir"), false), "the expected event arrived already");
+        echoes.suppress(Path::new("file"));
+        tokio::time::advance(ECHO_SUPPRESS_TTL).await;
+        assert!(!echoes.take(Path::new("file"), false));
+    }
+
+    #[test]
+    fn events_beyond_a_full_buffer_are_counted_as_dropped() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let stats = Arc::new(SyncStats::default());
+        let mut forwarder = EventForwarder::new(tx, PathBuf::from("/root"), Arc::new(AtomicBool::new(false)), Arc::clone(&stats));
+        for i in 0..5 {
+            forwarder.handle_event(Ok(modify(&format!("/root/file{i}"))));
+        }
+        assert_eq!(stats.dropped_events(), 3);
+        // what was dropped is found again by a rescan once there's room
+        while rx.try_recv().is_ok() {}
+        forwarder.handle_event(Ok(modify("/root/file5")));
+        assert!(matches!(rx.try_recv(), Ok(WatcherMsg::Overflow(dir)) if dir == Path::new("/root")));
+        assert_eq!(stats.dropped_events(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delete_and_create_of_the_same_contents_is_a_move() {
+        let root = tempfile::tempdir().unwrap();
+        let mut moves = MoveDetector::new(root.path().to_path_buf(), HashAlgo::Xxh64);
+        for (path, contents) in [("moved", b"contents"), ("replaced", b"replaced")] {
+            moves.push(Protocol::FsEventModify{path: PathBuf::from(path), hash: crate::fs::hash_bytes(contents, HashAlgo::Xxh64)});
+            assert!(moves.push(Protocol::FsEventDelete{path: PathBuf::from(path), entity: Some(EntityType::File)}).is_empty(), "delete of {path} not held");
+        }
+        std::fs::write(root.path().join("new"), b"contents").unwrap();
+        let ready = moves.push(Protocol::FsEventCreate{path: PathBuf::from("new"), entity: EntityType::File});
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0], Protocol::FsEventRename{path_from: PathBuf::from("moved"), path_to: PathBuf::from("new")});
+        // the other delete goes out once no create with its contents showed up in time
+        std::fs::write(root.path().join("different"), b"different").unwrap();
+        let ready = moves.push(Protocol::FsEventCreate{path: PathBuf::from("different"), entity: EntityType::File});
+        assert!(matches!(ready.as_slice(), [Protocol::FsEventCreate {..}]));
+        tokio::time::advance(MOVE_DETECT_TIMEOUT).await;
+        let expired = moves.pop_expired(Instant::now());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0], Protocol::FsEventDelete{path: PathBuf::from("replaced"), entity: Some(EntityType::File)});
+    }
+
+    #[test]
+    fn watcher_errors_are_passed_over() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut forwarder = EventForwarder::new(tx, PathBuf::from("/root"), Arc::new(AtomicBool::new(false)), Arc::new(SyncStats::default()));
+        forwarder.handle_event(Err(notify::Error::generic("injected")));
+        forwarder.handle_event(Err(notify::Error::new(notify::ErrorKind::MaxFilesWatch)));
+        assert!(rx.try_recv().is_err());
+        forwarder.handle_event(Ok(modify("/root/file")));
+        assert!(matches!(rx.try_recv(), Ok(WatcherMsg::Event(event)) if event.paths == [PathBuf::from("/root/file")]));
+    }
+
+    #[test]
+    fn events_lost_by_the_watcher_are_made_up_for_with_a_rescan() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut forwarder = EventForwarder::new(tx, PathBuf::from("/root"), Arc::new(AtomicBool::new(false)), Arc::new(SyncStats::default()));
+        let overflow = Event::new(EventKind::Other).set_flag(notify::event::Flag::Rescan).add_path(PathBuf::from("/root/dir"));
+        forwarder.handle_event(Ok(overflow));
+        assert!(matches!(rx.try_recv(), Ok(WatcherMsg::Overflow(dir)) if dir == Path::new("/root/dir")));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Waits for a message the watcher sends, giving it a few checks of the watched directory
+    async fn wait_for(rx: &mut mpsc::Receiver<WatcherMsg>, found: impl Fn(&WatcherMsg) -> bool) -> Result<(), tokio::time::error::Elapsed> {
+        tokio::time::timeout(ROOT_CHECK_INTERVAL * 5, async {
+            while let Some(msg) = rx.recv().await {
+                if found(&msg) {
+                    return
+                }
+            }
+            panic!("watcher went away")
+        }).await
+    }
+
+    #[tokio::test]
+    async fn events_resume_once_the_watched_directory_is_created_again() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(parent.path()).unwrap().join("root");
+        fs::create_dir(&root).unwrap();
+        let (tx, mut rx) = mpsc::channel(64);
+        let removed = Arc::new(AtomicBool::new(false));
+        let forwarder = EventForwarder::new(tx.clone(), root.clone(), Arc::clone(&removed), Arc::new(SyncStats::default()));
+        let mut watcher = RecommendedWatcher::new(forwarder, notify::Config::default()).unwrap();
+        watcher.watch(&root, RecursiveMode::Recursive).unwrap();
+        let watching = tokio::spawn(keep_watching(watcher, root.clone(), RecursiveMode::Recursive, removed, tx));
+        fs::remove_dir(&root).unwrap();
+        fs::create_dir(&root).unwrap();
+        wait_for(&mut rx, |msg| matches!(msg, WatcherMsg::Overflow(dir) if *dir == root)).await
+            .expect("recreated directory wasn't rescanned");
+        fs::write(root.join("new.txt"), "new").unwrap();
+        wait_for(&mut rx, |msg| matches!(msg, WatcherMsg::Event(event) if event.paths.contains(&root.join("new.txt")))).await
+            .expect("no events from the recreated directory");
+        watching.abort();
+    }
+}
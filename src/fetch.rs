@@ -0,0 +1,538 @@
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::hash::Digest;
+use crate::protocol::{ListRespEntry, Protocol};
+
+// How many times a file the peer didn't answer for in time is requested again
+const MAX_TIMEOUT_RETRIES: u32 = 2;
+// How long fetching stays paused after a file couldn't be written because the disk was full
+const DISK_FULL_PAUSE: Duration = Duration::from_secs(30);
+
+/// Files the initial sync fetches from the peer, only so many of them are requested at a
+/// time so a large tree doesn't leave every transfer's contents in flight at once. Hard links
+/// of a file being fetched wait for it and are linked to it, with dedup files with the same
+/// contents wait for it as well and are copied from it.
+/// A file the peer sends nothing for within the timeout is requested again, or given up on
+#[derive(Debug)]
+pub struct FetchQueue {
+    limit: NonZeroUsize,
+    dedup: bool,
+    timeout: Option<Duration>,
+    queued: VecDeque<PathBuf>,
+    /// Files requested, with when they time out unless the peer sends something for them
+    in_flight: HashMap<PathBuf, Option<Instant>>,
+    /// Times each file timed out
+    timeouts: HashMap<PathBuf, u32>,
+    /// File fetched for each hash of listed files
+    by_hash: HashMap<Digest, PathBuf>,
+    /// File fetched for each link group of listed files
+    by_group: HashMap<u64, PathBuf>,
+    /// Listed files waiting for the fetched file with the same hash or link group
+    duplicates: HashMap<PathBuf, Vec<Duplicate>>,
+    /// Local file with each hash, once the local tree was hashed for dedup
+    local: Option<HashMap<Digest, PathBuf>>,
+    /// Listed files waiting for the local tree to be hashed, and whether it's being hashed
+    awaiting_local: Vec<ListRespEntry>,
+    hashing: bool,
+    pub fetched: usize,
+    pub failed: usize,
+}
+
+/// A listed file waiting for one being fetched, to be linked to it if they're hard links of
+/// the same file or copied from it otherwise
+#[derive(Debug)]
+pub struct Duplicate {
+    pub entry: ListRespEntry,
+    pub link: bool,
+}
+
+impl FetchQueue {
+    pub fn new(limit: NonZeroUsize, dedup: bool, timeout: Option<Duration>) -> Self {
+        FetchQueue {
+            limit,
+            dedup,
+            timeout,
+            queued: VecDeque::new(),
+            in_flight: HashMap::new(),
+            timeouts: HashMap::new(),
+            by_hash: HashMap::new(),
+            by_group: HashMap::new(),
+            duplicates: HashMap::new(),
+            local: None,
+            awaiting_local: Vec::new(),
+            hashing: false,
+            fetched: 0,
+            failed: 0,
+        }
+    }
+
+    pub fn push(&mut self, path: PathBuf) {
+        if !self.in_flight.contains_key(&path) && !self.queued.contains(&path) {
+            self.queued.push_back(path);
+        }
+    }
+
+    /// Queues a file from the peer's listing, unless another hard link of it or, with dedup,
+    /// another one with the same hash is fetched already and it can be made from that
+    pub fn push_listed(&mut self, entry: ListRespEntry) {
+        let linked = entry.link_group.and_then(|group| self.by_group.get(&group)).filter(|source| **source != entry.path);
+        if let Some(source) = linked {
+            self.duplicates.entry(source.clone()).or_default().push(Duplicate{entry, link: true});
+            return
+        }
+        if self.dedup {
+            match self.by_hash.get(&entry.hash) {
+                Some(source) if *source != entry.path => {
+                    self.duplicates.entry(source.clone()).or_default().push(Duplicate{entry, link: false});
+                    return
+                }
+                Some(_) => {}
+                None => {
+                    self.by_hash.insert(entry.hash, entry.path.clone());
+                }
+            }
+        }
+        if let Some(group) = entry.link_group {
+            self.by_group.insert(group, entry.path.clone());
+        }
+        self.push(entry.path);
+    }
+
+    /// Stops making other files from one that's done being fetched, returning those waiting
+    /// for it
+    fn release(&mut self, path: &Path) -> Vec<Duplicate> {
+        self.by_hash.retain(|_, source| source != path);
+        self.by_group.retain(|_, source| source != path);
+        self.duplicates.remove(path).unwrap_or_default()
+    }
+
+    /// Gets for queued files while fewer than the limit are in flight
+    pub fn next_requests(&mut self) -> Vec<Protocol> {
+        let mut requests = Vec::new();
+        while self.in_flight.len() < self.limit.get() {
+            let Some(path) = self.queued.pop_front() else {
+                break
+            };
+            self.in_flight.insert(path.clone(), self.deadline());
+            requests.push(Protocol::Get{path});
+        }
+        requests
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Gives a file being fetched the whole timeout again when the peer sent part of it
+    pub fn touch(&mut self, message: &Protocol) {
+        let Some(path) = transfer_path(message) else {
+            return
+        };
+        let deadline = self.deadline();
+        if let Some(in_flight) = self.in_flight.get_mut(path) {
+            *in_flight = deadline;
+        }
+    }
+
+    /// When the next file being fetched times out
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.in_flight.values().flatten().min().copied()
+    }
+
+    /// Takes files the peer didn't send anything for in time off the files in flight,
+    /// queueing them again unless they timed out too often already. Returns them all, any
+    /// part of them received is of no use anymore
+    pub fn expire(&mut self, now: Instant) -> Vec<PathBuf> {
+        let expired: Vec<_> = self.in_flight.iter()
+            .filter(|(_, deadline)| deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &expired {
+            self.in_flight.remove(path);
+            let timeouts = self.timeouts.entry(path.clone()).or_insert(0);
+            *timeouts += 1;
+            if *timeouts <= MAX_TIMEOUT_RETRIES {
+                warn!(path = %path.display(), attempt = *timeouts, "Peer didn't send file in time, requesting it again");
+                self.queued.push_front(path.clone());
+                continue
+            }
+            warn!(path = %path.display(), "Peer didn't send file in time, giving up on it");
+            self.timeouts.remove(path);
+            self.failed += 1;
+            for duplicate in self.release(path) {
+                self.push_listed(duplicate.entry);
+            }
+        }
+        expired
+    }
+
+    /// Takes a file the peer sent the last message for off the files in flight, unless the
+    /// responses to that message ask for it again or for its next part. Returns the files
+    /// waiting to be made from it, those of a failed fetch are fetched themselves instead
+    pub fn track(&mut self, completed: Option<&Path>, responses: &[Protocol], failed: bool) -> Vec<Duplicate> {
+        let Some(path) = completed else {
+            return Vec::new()
+        };
+        let requested = responses.iter().any(|response| matches!(response,
+            Protocol::Get {path: requested} | Protocol::GetChunk {path: requested, ..} | Protocol::BlockSig {path: requested, ..} if requested == path));
+        if requested || self.in_flight.remove(path).is_none() {
+            return Vec::new()
+        }
+        self.timeouts.remove(path);
+        let duplicates = self.release(path);
+        if failed {
+            self.failed += 1;
+            for duplicate in duplicates {
+                self.push_listed(duplicate.entry);
+            }
+            return Vec::new()
+        }
+        self.fetched += 1;
+        duplicates
+    }
+
+    /// Whether the local tree still has to be hashed to find copies of listed files in it
+    pub fn needs_local(&self) -> bool {
+        self.dedup && self.local.is_none()
+    }
+
+    /// Holds a listed file back until the local tree is hashed to look for a copy of it in
+    pub fn await_local(&mut self, entry: ListRespEntry) {
+        self.awaiting_local.push(entry);
+    }
+
+    /// Whether the local tree has to be hashed for the listed files waiting for it, true
+    /// only the first time so it's hashed once
+    pub fn start_hashing(&mut self) -> bool {
+        let start = !self.hashing && !self.awaiting_local.is_empty();
+        self.hashing |= start;
+        start
+    }
+
+    /// Sets the hashes of the local tree, returning the listed files that waited for them
+    pub fn set_local(&mut self, local: HashMap<Digest, PathBuf>) -> Vec<ListRespEntry> {
+        self.local = Some(local);
+        self.hashing = false;
+        std::mem::take(&mut self.awaiting_local)
+    }
+
+    /// Local file a listed one can be copied from
+    pub fn local_copy(&self, entry: &ListRespEntry) -> Option<PathBuf> {
+        self.local.as_ref()?.get(&entry.hash).filter(|source| **source != entry.path).cloned()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.in_flight.is_empty() && self.awaiting_local.is_empty()
+    }
+
+    /// Forgets everything, the requests of a previous connection are never answered
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.in_flight.clear();
+        self.timeouts.clear();
+        self.by_hash.clear();
+        self.by_group.clear();
+        self.duplicates.clear();
+        self.local = None;
+        self.awaiting_local.clear();
+        self.hashing = false;
+        self.fetched = 0;
+        self.failed = 0;
+    }
+}
+
+/// Directories the peer is asked to list recursively to compare them with, only so many at
+/// a time so a burst of them doesn't leave the peer walking every one at once. Queued ones
+/// are requested shallowest first, a directory below one waiting to be requested is covered
+/// by that one's listing and isn't requested on its own
+#[derive(Debug)]
+pub struct ListQueue {
+    limit: NonZeroUsize,
+    queued: Vec<PathBuf>,
+    /// Listings requested, oldest first
+    in_flight: VecDeque<PathBuf>,
+}
+
+impl ListQueue {
+    pub fn new(limit: NonZeroUsize) -> Self {
+        ListQueue {
+            limit,
+            queued: Vec::new(),
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Takes the listings out of requests about to be sent, to be requested as earlier ones
+    /// are answered
+    pub fn take(&mut self, requests: &mut Vec<Protocol>) {
+        requests.retain(|request| match request {
+            Protocol::List {path, recursive: true, max_depth: None} => {
+                self.push(path.clone());
+                false
+            }
+            _ => true,
+        });
+    }
+
+    fn push(&mut self, path: PathBuf) {
+        if self.queued.iter().any(|queued| path.starts_with(queued)) {
+            return
+        }
+        self.queued.retain(|queued| !queued.starts_with(&path));
+        self.queued.push(path);
+    }
+
+    /// Lists for queued directories while fewer than the limit are in flight
+    pub fn next_requests(&mut self) -> Vec<Protocol> {
+        let mut requests = Vec::new();
+        while self.in_flight.len() < self.limit.get() && !self.queued.is_empty() {
+            let (shallowest, _) = self.queued.iter().enumerate()
+                .min_by_key(|(_, path)| path.components().count())
+                .expect("queue isn't empty");
+            let path = self.queued.remove(shallowest);
+            self.in_flight.push_back(path.clone());
+            requests.push(Protocol::List{path, recursive: true, max_depth: None});
+        }
+        requests
+    }
+
+    /// Takes a listing off the ones in flight once its last part or an error for it arrived.
+    /// Older peers don't say what they listed, the oldest listing is taken as answered then
+    pub fn track(&mut self, message: &Protocol) {
+        let listed = match message {
+            Protocol::ListResp {more: true, ..} => return,
+            Protocol::ListResp {path, ..} => path.as_deref(),
+            Protocol::Error {request, path, ..} if request == "List" => path.as_deref(),
+            _ => return,
+        };
+        match listed {
+            Some(listed) => self.in_flight.retain(|path| path != listed),
+            None => {
+                self.in_flight.pop_front();
+            }
+        }
+    }
+
+    /// Forgets everything, the requests of a previous connection are never answered
+    pub fn clear(&mut self) {
+        self.queued.clear();
+        self.in_flight.clear();
+    }
+}
+
+/// Path of the file a message from the peer carries part of or fails sending, if any
+fn transfer_path(message: &Protocol) -> Option<&Path> {
+    match message {
+        Protocol::GetResp {path, ..} | Protocol::BlockSigReq {path} | Protocol::GetChunkResp {path, ..} | Protocol::DeltaResp {path, ..} => Some(path),
+        Protocol::Error {path, ..} => path.as_deref(),
+        _ => None,
+    }
+}
+
+/// Pauses fetching while the disk is full, rather than fetching files only to fail writing
+/// them. Files that couldn't be written, or weren't requested because of the pause, are fetched
+/// again once it's over, pausing again if there's still no room
+#[derive(Debug, Default)]
+pub struct DiskFull {
+    until: Option<Instant>,
+    unwritten: Vec<PathBuf>,
+}
+
+impl DiskFull {
+    /// Pauses fetching after path couldn't be written for lack of space
+    pub fn hit(&mut self, path: PathBuf) {
+        if self.until.is_none() {
+            warn!(path = %path.display(), pause_secs = DISK_FULL_PAUSE.as_secs(), "Disk is full, pausing fetching until there's room");
+            self.until = Some(Instant::now() + DISK_FULL_PAUSE);
+        }
+        if !self.unwritten.contains(&path) {
+            self.unwritten.push(path);
+        }
+    }
+
+    /// While paused, takes the requests for files out of requests to send them once it's over.
+    /// Parts of files being fetched are still asked for, those being written is what tells
+    /// whether there's room again
+    pub fn hold(&mut self, requests: &mut Vec<Protocol>) {
+        if self.until.is_none() {
+            return
+        }
+        requests.retain(|request| match request {
+            Protocol::Get {path} => {
+                if !self.unwritten.contains(path) {
+                    self.unwritten.push(path.clone());
+                }
+                false
+            }
+            _ => true,
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.until.is_some()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.until
+    }
+
+    /// Ends the pause once it's over, returning the files to fetch again
+    pub fn resume(&mut self, now: Instant) -> Vec<PathBuf> {
+        if self.until.is_none_or(|until| until > now) {
+            return Vec::new()
+        }
+        self.until = None;
+        info!(files = self.unwritten.len(), "Fetching again after the disk was full");
+        std::mem::take(&mut self.unwritten)
+    }
+}
+
+/// Path of the file a message from the peer is the last one the peer sends for, if any
+pub fn completed_path(message: &Protocol) -> Option<PathBuf> {
+    match message {
+        Protocol::GetResp {path, ..} | Protocol::BlockSigReq {path} => Some(path.clone()),
+        Protocol::GetChunkResp {path, eof: true, ..} | Protocol::DeltaResp {path, eof: true, ..} => Some(path.clone()),
+        Protocol::Error {path, ..} => path.clone(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::EntityType;
+
+    fn requested(requests: &[Protocol]) -> Vec<PathBuf> {
+        requests.iter().map(|request| match request {
+            Protocol::Get {path} => path.clone(),
+            request => panic!("{request:?} isn't a fetch"),
+        }).collect()
+    }
+
+    #[test]
+    fn no_more_than_the_limit_of_fetches_is_in_flight() {
+        let mut fetches = FetchQueue::new(NonZeroUsize::new(2).unwrap(), false, None);
+        for i in 0..5 {
+            fetches.push(PathBuf::from(format!("file{i}")));
+        }
+        let mut in_flight = requested(&fetches.next_requests());
+        assert_eq!(in_flight, [PathBuf::from("file0"), PathBuf::from("file1")]);
+        assert!(fetches.next_requests().is_empty());
+        while let Some(done) = in_flight.pop() {
+            fetches.track(Some(&done), &[], false);
+            in_flight.extend(requested(&fetches.next_requests()));
+            assert!(in_flight.len() <= 2, "{in_flight:?} in flight");
+        }
+        assert_eq!(fetches.fetched, 5);
+        assert!(fetches.is_idle());
+    }
+
+    #[test]
+    fn no_more_than_the_limit_of_listings_is_in_flight() {
+        let mut lists = ListQueue::new(NonZeroUsize::new(3).unwrap());
+        // a wide tree, directories below another one are covered by its listing
+        let mut requests: Vec<Protocol> = (0..20).flat_map(|i| [format!("dir{i}/sub"), format!("dir{i}")])
+            .map(|path| Protocol::List{path: PathBuf::from(path), recursive: true, max_depth: None})
+            .chain([Protocol::Get{path: PathBuf::from("file")}])
+            .collect();
+        lists.take(&mut requests);
+        assert_eq!(requests, [Protocol::Get{path: PathBuf::from("file")}]);
+        let listing = |request: Protocol| match request {
+            Protocol::List {path, ..} => path,
+            request => panic!("{request:?} isn't a listing"),
+        };
+        let mut in_flight: VecDeque<PathBuf> = lists.next_requests().into_iter().map(listing).collect();
+        let mut listed = Vec::new();
+        while let Some(done) = in_flight.pop_front() {
+            // a listing in several parts is only answered with its last one
+            lists.track(&Protocol::ListResp{entries: Vec::new(), path: Some(done.clone()), more: true});
+            assert!(lists.next_requests().is_empty());
+            lists.track(&Protocol::ListResp{entries: Vec::new(), path: Some(done.clone()), more: false});
+            listed.push(done);
+            in_flight.extend(lists.next_requests().into_iter().map(listing));
+            assert!(in_flight.len() <= 3, "{in_flight:?} in flight");
+        }
+        listed.sort();
+        let mut expected: Vec<PathBuf> = (0..20).map(|i| PathBuf::from(format!("dir{i}"))).collect();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    fn listed(path: &str, hash: u64) -> ListRespEntry {
+        ListRespEntry {
+            path: PathBuf::from(path),
+            hash: Digest::Xxh64(hash),
+            entity: EntityType::File,
+            mode: None,
+            mtime: None,
+            link_target: None,
+            skipped: false,
+            link_group: None,
+        }
+    }
+
+    #[test]
+    fn files_with_the_same_contents_are_fetched_once_with_dedup() {
+        for dedup in [false, true] {
+            let mut fetches = FetchQueue::new(NonZeroUsize::new(4).unwrap(), dedup, None);
+            for (path, hash) in [("a", 1), ("copy_of_a", 1), ("b", 2)] {
+                fetches.push_listed(listed(path, hash));
+            }
+            let requests = requested(&fetches.next_requests());
+            if !dedup {
+                assert_eq!(requests.len(), 3);
+                continue
+            }
+            assert_eq!(requests, [PathBuf::from("a"), PathBuf::from("b")]);
+            let duplicates = fetches.track(Some(Path::new("a")), &[], false);
+            assert_eq!(duplicates.iter().map(|duplicate| duplicate.entry.path.as_path()).collect::<Vec<_>>(), [Path::new("copy_of_a")]);
+            assert!(!duplicates[0].link);
+            assert!(fetches.next_requests().is_empty());
+        }
+    }
+
+    #[test]
+    fn listed_files_wait_for_the_local_tree_to_be_hashed_once() {
+        let mut fetches = FetchQueue::new(NonZeroUsize::new(4).unwrap(), true, None);
+        assert!(fetches.needs_local() && !fetches.start_hashing());
+        for (path, hash) in [("a", 1), ("b", 2)] {
+            fetches.await_local(listed(path, hash));
+        }
+        assert!(!fetches.is_idle());
+        assert!(fetches.start_hashing());
+        fetches.await_local(listed("c", 3));
+        assert!(!fetches.start_hashing(), "hashed twice");
+        let waited = fetches.set_local(HashMap::from([(Digest::Xxh64(1), PathBuf::from("local_a"))]));
+        assert_eq!(waited.iter().map(|entry| entry.path.as_path()).collect::<Vec<_>>(), [Path::new("a"), Path::new("b"), Path::new("c")]);
+        assert!(!fetches.needs_local() && fetches.is_idle());
+        assert_eq!(fetches.local_copy(&waited[0]), Some(PathBuf::from("local_a")));
+        assert_eq!(fetches.local_copy(&waited[1]), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetch_never_answered_times_out() {
+        let timeout = Duration::from_secs(10);
+        let mut fetches = FetchQueue::new(NonZeroUsize::new(2).unwrap(), false, Some(timeout));
+        fetches.push(PathBuf::from("file"));
+        assert_eq!(requested(&fetches.next_requests()), [PathBuf::from("file")]);
+        // part of the file arriving gives it the whole timeout again
+        tokio::time::advance(timeout / 2).await;
+        fetches.touch(&Protocol::GetChunkResp{path: PathBuf::from("file"), offset: 0, contents: Vec::new(), eof: false, size: None, hash: None, mode: None, mtime: None});
+        tokio::time::advance(timeout / 2).await;
+        assert!(fetches.expire(Instant::now()).is_empty());
+        for _ in 0..MAX_TIMEOUT_RETRIES {
+            tokio::time::advance(fetches.next_deadline().unwrap() - Instant::now()).await;
+            assert_eq!(fetches.expire(Instant::now()), [PathBuf::from("file")]);
+            assert_eq!(requested(&fetches.next_requests()), [PathBuf::from("file")]);
+        }
+        tokio::time::advance(timeout).await;
+        assert_eq!(fetches.expire(Instant::now()), [PathBuf::from("file")]);
+        assert!(fetches.next_requests().is_empty());
+        assert_eq!((fetches.fetched, fetches.failed), (0, 1));
+        assert!(fetches.is_idle());
+    }
+}
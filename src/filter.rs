@@ -0,0 +1,188 @@
+use std::path::{Component, Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tracing::warn;
+
+pub const IGNORE_FILE: &str = ".syncignore";
+pub const INCLUDE_FILE: &str = ".syncinclude";
+/// Where deleted paths are moved to in the trash delete mode, relative to the sync root
+pub const TRASH_DIR: &str = ".syncd-trash";
+/// Where syncd keeps what it remembers across restarts, relative to the sync root
+pub const STATE_DIR: &str = ".syncd-state";
+
+// temporary files syncd itself writes before renaming them into place, the trash
+// directory, whose contents would otherwise be synced back as new files, and the state
+// directory
+const BUILTIN_PATTERNS: &[&str] = &["*.syncd.tmp", "/.syncd-trash/", "/.syncd-state/"];
+
+// files editors keep next to the ones being edited or write while saving, which come and go
+// again: vim's swap files and the file it checks the directory is writable with, backups,
+// emacs' lock and auto-save files, and temporary files of editors saving atomically
+const EDITOR_PATTERNS: &[&str] = &[
+    "[._]*.sw[a-p]", "[._]sw[a-p]", "4913", "*~", ".#*", "\\#*\\#",
+    ".goutputstream-*", "*___jb_tmp___", "*___jb_old___", "*.kate-swp", ".~lock.*#", "*.crswap",
+];
+
+/// Decides which paths under the sync root are excluded from syncing
+pub struct PathFilter {
+    ignore: Gitignore,
+    include: Option<Include>,
+    skip_hidden: bool,
+    subpath: Option<PathBuf>,
+}
+
+/// Paths that are synced when there's an allowlist, everything else is excluded
+struct Include {
+    patterns: Gitignore,
+    /// Directories every pattern is anchored below, the directories leading to them stay so
+    /// walks from the root reach them, along with those below them for patterns with a
+    /// wildcard in a directory. None if some pattern can match in any directory
+    prefixes: Option<Vec<(PathBuf, bool)>>,
+}
+
+impl Include {
+    fn load(syncdir: &Path, patterns: &[String]) -> Option<Self> {
+        let include_path = syncdir.join(INCLUDE_FILE);
+        let mut lines: Vec<String> = patterns.to_vec();
+        if include_path.is_file() {
+            match std::fs::read_to_string(&include_path) {
+                Ok(contents) => lines.extend(contents.lines().map(str::to_string)),
+                Err(e) => warn!(path = %include_path.display(), error = %e, "Failed reading include file"),
+            }
+        }
+        let mut builder = GitignoreBuilder::new(syncdir);
+        let mut prefixes = Some(Vec::new());
+        for line in &lines {
+            let pattern = line.trim_end();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue
+            }
+            if let Err(e) = builder.add_line(None, pattern) {
+                warn!(pattern, error = %e, "Invalid include pattern");
+                continue
+            }
+            match (anchored_prefix(pattern), prefixes.as_mut()) {
+                (Some(prefix), Some(prefixes)) => prefixes.push(prefix),
+                _ => prefixes = None,
+            }
+        }
+        let patterns = builder.build().unwrap_or_else(|e| {
+            warn!(error = %e, "Invalid include pattern, syncing nothing");
+            Gitignore::empty()
+        });
+        // with neither flags nor a file there's no allowlist, an empty one syncs nothing
+        (!lines.is_empty()).then_some(Include{patterns, prefixes})
+    }
+
+    fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        if self.patterns.matched_path_or_any_parents(path, is_dir).is_ignore() {
+            return true
+        }
+        is_dir && self.prefixes.as_ref().is_none_or(|prefixes| prefixes.iter()
+            .any(|(prefix, below)| prefix.starts_with(path) || (*below && path.starts_with(prefix))))
+    }
+}
+
+/// Directory a gitignore-style pattern is anchored below, made of its parts before the first
+/// one with a wildcard, and whether a directory part had one. None for patterns that match
+/// in any directory, those without a slash other than a trailing one, and for negated ones
+fn anchored_prefix(pattern: &str) -> Option<(PathBuf, bool)> {
+    if pattern.starts_with('!') {
+        return None
+    }
+    let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+    if !pattern.contains('/') {
+        return None
+    }
+    let mut parts: Vec<_> = pattern.trim_start_matches('/').split('/').collect();
+    // the last part may name a file
+    parts.pop();
+    let literal = parts.iter().take_while(|part| !part.contains(['*', '?', '[', '\\'])).count();
+    Some((parts[..literal].iter().collect(), literal < parts.len()))
+}
+
+impl PathFilter {
+    /// Builds a filter from the given gitignore-style patterns followed by the ones in the
+    /// root's .syncignore file, if there is one. With skip_hidden, paths with a component
+    /// starting with a dot below the root are excluded as well. With a subpath, relative to
+    /// the root, everything outside of it is too. With include patterns or a .syncinclude
+    /// file only paths matching those are synced, less the ones excluded otherwise. With
+    /// editor_ignores, temporary files of common editors are excluded too
+    pub fn load(syncdir: &Path, patterns: &[String], include: &[String], skip_hidden: bool, editor_ignores: bool, subpath: Option<PathBuf>) -> Self {
+        let mut builder = GitignoreBuilder::new(syncdir);
+        let editor_patterns = if editor_ignores { EDITOR_PATTERNS } else { &[] };
+        for pattern in BUILTIN_PATTERNS.iter().chain(editor_patterns) {
+            let _ = builder.add_line(None, pattern);
+        }
+        for pattern in patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                warn!(pattern, error = %e, "Invalid ignore pattern");
+            }
+        }
+        let ignore_path = syncdir.join(IGNORE_FILE);
+        if ignore_path.is_file() {
+            if let Some(e) = builder.add(&ignore_path) {
+                warn!(path = %ignore_path.display(), error = %e, "Failed reading ignore file");
+            }
+        }
+        let ignore = builder.build().unwrap_or_else(|e| {
+            warn!(path = %ignore_path.display(), error = %e, "Invalid ignore pattern");
+            Gitignore::empty()
+        });
+        PathFilter { ignore, include: Include::load(syncdir, include), skip_hidden, subpath }
+    }
+
+    /// Checks a path relative to the sync root, a path is also excluded if any of its parents is
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let path: PathBuf = path.components().filter(|component| *component != Component::CurDir).collect();
+        if let Some(subpath) = &self.subpath {
+            // the directories leading to the subpath stay so walks from the root reach it
+            if !path.starts_with(subpath) && !subpath.starts_with(&path) {
+                return true
+            }
+        }
+        if self.skip_hidden && path.components().any(|component| is_hidden(&component)) {
+            return true
+        }
+        // ignoring a path wins over including it
+        self.ignore.matched_path_or_any_parents(&path, is_dir).is_ignore()
+            || self.include.as_ref().is_some_and(|include| !include.allows(&path, is_dir))
+    }
+}
+
+fn is_hidden(component: &Component) -> bool {
+    match component {
+        Component::Normal(name) => name.as_encoded_bytes().starts_with(b"."),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_excludes_everything_it_doesnt_match() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let filter = PathFilter::load(syncdir.path(), &[], &["src/**".to_string()], false, true, None);
+        for (path, is_dir) in [("src", true), ("src/main.rs", false), ("src/deep/nested/mod.rs", false), ("src/deep", true)] {
+            assert!(!filter.is_excluded(Path::new(path), is_dir), "{path} excluded");
+        }
+        for (path, is_dir) in [("README.md", false), ("docs", true), ("docs/src/guide.md", false), ("srcs/main.rs", false)] {
+            assert!(filter.is_excluded(Path::new(path), is_dir), "{path} synced");
+        }
+    }
+
+    #[test]
+    fn editor_temporary_files_are_only_excluded_with_the_builtin_ignores() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let (with, without) = (PathFilter::load(syncdir.path(), &[], &[], false, true, None), PathFilter::load(syncdir.path(), &[], &[], false, false, None));
+        for path in ["dir/.file.txt.swp", ".swp", "4913", "file.txt~", ".#file.txt", "#file.txt#", ".goutputstream-ABC123"] {
+            assert!(with.is_excluded(Path::new(path), false), "{path} synced");
+            assert!(!without.is_excluded(Path::new(path), false), "{path} excluded");
+        }
+        for path in ["file.txt", "swp", "file.txt.sw"] {
+            assert!(!with.is_excluded(Path::new(path), false), "{path} excluded");
+        }
+        assert!(without.is_excluded(Path::new("file.txt.syncd.tmp"), false));
+    }
+}
@@ -0,0 +1,599 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::FileType;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use clap::ValueEnum;
+use filetime::FileTime;
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::error::SyncError;
+use crate::filter::{PathFilter, TRASH_DIR};
+use crate::hash::{Digest, HashAlgo};
+use crate::protocol::TRANSFER_CHUNK_SIZE;
+use crate::util::{retry_with_backoff, Backoff};
+
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+// How many times a read failing with a transient error is attempted and how long to wait in between
+const READ_ATTEMPTS: u32 = 3;
+const READ_RETRY_DELAY: Duration = Duration::from_millis(50);
+// Appended to the name of the file received contents are written to before being renamed
+// into place, the filter excludes these
+const TMP_SUFFIX: &str = ".syncd.tmp";
+
+/// Errors that may go away by themselves, like a read interrupted by a signal or a file
+/// another process briefly holds locked
+fn is_transient(e: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    if matches!(e.raw_os_error(), Some(32 | 33)) {
+        return true
+    }
+    matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+/// Runs a read, attempting it again after a short wait if it fails with a transient error.
+/// Meant for reads of a single file, the wait blocks the calling thread so files sent to the
+/// peer are read on the blocking thread pool
+pub fn retry_read<T>(path: &Path, read: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let backoff = Backoff::new(READ_RETRY_DELAY, READ_RETRY_DELAY, 1).with_max_attempts(READ_ATTEMPTS);
+    let mut attempt = 0;
+    retry_with_backoff(backoff, read, |e, _| {
+        attempt += 1;
+        is_transient(e) && {
+            warn!(path = %path.display(), error = %e, attempt, "Failed reading file, retrying");
+            true
+        }
+    }, std::thread::sleep)
+}
+
+/// Hashes file contents in fixed-size chunks so memory use doesn't depend on file size
+pub fn try_hash_file(path: &Path, algo: HashAlgo) -> io::Result<Digest> {
+    retry_read(path, || {
+        let mut reader = BufReader::with_capacity(HASH_CHUNK_SIZE, fs::File::open(path)?);
+        let mut hasher = algo.hasher();
+        loop {
+            let chunk = reader.fill_buf()?;
+            if chunk.is_empty() {
+                break
+            }
+            hasher.update(chunk);
+            let len = chunk.len();
+            reader.consume(len);
+        }
+        Ok(hasher.finish())
+    })
+}
+
+pub fn hash_bytes(data: &[u8], algo: HashAlgo) -> Digest {
+    let mut hasher = algo.hasher();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Hash listed for an entry: the contents for files, the sorted names of children that
+/// aren't excluded for directories and the target for symlinks, which aren't followed.
+/// Directory and symlink hashes are salted with their kind so an empty directory doesn't
+/// look like an empty file
+pub fn entry_hash(path: &Path, ftype: &FileType, root: &Path, filter: &PathFilter, algo: HashAlgo) -> Result<Digest, SyncError> {
+    let mut hasher = algo.hasher();
+    if ftype.is_symlink() {
+        let target = fs::read_link(path).map_err(|e| SyncError::fs(path, e))?;
+        hasher.update(b"l");
+        hasher.update(target.as_os_str().as_encoded_bytes());
+    } else if ftype.is_dir() {
+        let mut names = Vec::new();
+        for (child, childtype) in list_path(path)? {
+            let excluded = child.strip_prefix(root)
+                .is_ok_and(|strippath| filter.is_excluded(strippath, childtype.is_dir()));
+            if let (false, Some(name)) = (excluded, child.file_name()) {
+                names.push(name.to_os_string());
+            }
+        }
+        names.sort();
+        hasher.update(b"d");
+        for name in names {
+            hasher.update(name.as_encoded_bytes());
+            hasher.update(&[0]);
+        }
+    } else {
+        return try_hash_file(path, algo).map_err(|e| SyncError::fs(path, e))
+    }
+    Ok(hasher.finish())
+}
+
+/// Checks whether a symlink target could point outside the sync root, resolved from the
+/// directory of a link at path relative to the root. Absolute targets always might, they
+/// wouldn't point at the same thing on the peer
+pub fn link_target_escapes(path: &Path, target: &Path) -> bool {
+    let mut depth: usize = 0;
+    for component in path.parent().unwrap_or(Path::new("")).join(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Reads the target of a symlink at path relative to the sync root, refusing targets outside of it
+pub fn read_link_target(path: &Path, linkpath: &Path) -> Result<PathBuf, SyncError> {
+    let target = fs::read_link(linkpath).map_err(|e| SyncError::fs(linkpath, e))?;
+    if link_target_escapes(path, &target) {
+        return Err(SyncError::PathEscapes(linkpath.join(target)))
+    }
+    Ok(target)
+}
+
+#[cfg(unix)]
+pub fn make_symlink(target: &Path, linkpath: &Path, _target_is_dir: bool) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, linkpath)
+}
+
+#[cfg(windows)]
+pub fn make_symlink(target: &Path, linkpath: &Path, target_is_dir: bool) -> io::Result<()> {
+    if target_is_dir {
+        std::os::windows::fs::symlink_dir(target, linkpath)
+    } else {
+        std::os::windows::fs::symlink_file(target, linkpath)
+    }
+}
+
+/// Checks whether a lexically clean path points outside of dir, either by its components or
+/// through a symlink on the way. Paths that don't exist yet are checked through their deepest
+/// existing ancestor, which is where they'd be created
+pub fn path_escapes_dir(path: &Path, dir: &Path) -> bool {
+    if !path.starts_with(dir) {
+        return true
+    }
+    let Some(existing) = path.ancestors().find(|ancestor| ancestor.exists()) else {
+        return false
+    };
+    match (existing.canonicalize(), dir.canonicalize()) {
+        (Ok(existing), Ok(dir)) => !existing.starts_with(dir),
+        _ => true,
+    }
+}
+
+pub fn list_path(path: &Path) -> Result<Vec<(PathBuf, FileType)>, SyncError> {
+    let dirents = fs::read_dir(path).map_err(|e| SyncError::fs(path, e))?;
+    let mut paths = Vec::new();
+    for dirent in dirents {
+        // a single unreadable entry shouldn't fail the whole listing
+        let entry = dirent.and_then(|dirent| Ok((dirent.path(), dirent.file_type()?)));
+        match entry {
+            Ok(entry) => paths.push(entry),
+            Err(e) => warn!(path = %path.display(), error = %e, "Skipping unreadable entry"),
+        }
+    }
+    Ok(paths)
+}
+
+/// Lists the tree under path breadth-first, without following symlinks so links to
+/// a parent directory can't cause a cycle. Directories the filter excludes aren't descended into
+pub fn list_tree(path: &Path, max_depth: Option<u32>, root: &Path, filter: &PathFilter) -> Result<Vec<(PathBuf, FileType)>, SyncError> {
+    let mut paths = Vec::new();
+    let mut level = vec![path.to_path_buf()];
+    let mut depth = 0;
+    while !level.is_empty() && max_depth.is_none_or(|max| depth < max) {
+        let mut next = Vec::new();
+        for dir in level {
+            // only a failure to read the requested directory itself is an error
+            let entries = match list_path(&dir) {
+                Ok(entries) => entries,
+                Err(e) if depth > 0 => {
+                    warn!(error = %e, "Skipping unreadable directory");
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            for (entrypath, ftype) in entries {
+                if ftype.is_dir() {
+                    let excluded = entrypath.strip_prefix(root)
+                        .map_or(true, |strippath| filter.is_excluded(strippath, true));
+                    if !excluded {
+                        next.push(entrypath.clone());
+                    }
+                }
+                paths.push((entrypath, ftype));
+            }
+        }
+        level = next;
+        depth += 1;
+    }
+    Ok(paths)
+}
+
+/// Reads at most len bytes (capped to TRANSFER_CHUNK_SIZE) starting at offset,
+/// also reporting whether the read reached the end of the file
+pub fn read_chunk(path: &Path, offset: u64, len: u64) -> io::Result<(Vec<u8>, bool)> {
+    retry_read(path, || {
+        let mut file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut contents = Vec::new();
+        file.take(len.min(TRANSFER_CHUNK_SIZE)).read_to_end(&mut contents)?;
+        // an empty read also ends the transfer in case the file shrunk in the meantime
+        let eof = contents.is_empty() || offset + contents.len() as u64 >= size;
+        Ok((contents, eof))
+    })
+}
+
+/// Metadata sent along with file contents and applied to the written file, fields
+/// the peer didn't send are left as they are
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileAttrs {
+    pub mode: Option<u32>,
+    pub mtime: Option<i64>,
+}
+
+impl FileAttrs {
+    pub fn of(meta: &fs::Metadata) -> Self {
+        let mtime = FileTime::from_last_modification_time(meta);
+        FileAttrs {
+            mode: file_mode(meta),
+            mtime: Some(mtime.unix_seconds() * 1000 + i64::from(mtime.nanoseconds() / 1_000_000)),
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        // before the mode, which might make the file read-only
+        if let Some(mtime) = self.mtime {
+            let nanos = (mtime.rem_euclid(1000) * 1_000_000) as u32;
+            filetime::set_file_mtime(path, FileTime::from_unix_time(mtime.div_euclid(1000), nanos))?;
+        }
+        if let Some(mode) = self.mode {
+            apply_mode(path, mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// Permission bits sent to the peer. Windows only has a read-only attribute, which is
+/// mapped to the matching unix mode
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(meta: &fs::Metadata) -> Option<u32> {
+    Some(if meta.permissions().readonly() { 0o444 } else { 0o644 })
+}
+
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn apply_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Number shared by the paths of a file with several hard links, None for a file with a
+/// single one. Only the same within a listing
+#[cfg(unix)]
+pub fn link_group(meta: &fs::Metadata) -> Option<u64> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::os::unix::fs::MetadataExt;
+    if !meta.is_file() || meta.nlink() < 2 {
+        return None
+    }
+    let mut hasher = DefaultHasher::new();
+    (meta.dev(), meta.ino()).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(not(unix))]
+pub fn link_group(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+pub fn write_chunk(tmppath: &Path, offset: u64, contents: &[u8]) -> io::Result<()> {
+    let mut file = if offset == 0 {
+        fs::File::create(tmppath)?
+    } else {
+        fs::OpenOptions::new().write(true).open(tmppath)?
+    };
+    if file.metadata()?.len() != offset {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("chunk continues at {} but {} bytes were written", offset, file.metadata()?.len())))
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(contents)
+}
+
+/// When files received from the peer are flushed to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Flush every file and the directory it's renamed into before going on
+    Always,
+    /// Flush every file before it's renamed into place, and the directories they're
+    /// renamed into once after each group of writes
+    Batch,
+    /// Leave flushing to the OS
+    Never,
+}
+
+/// Flushes written files and the directories they're renamed into as the policy says
+pub struct Fsync {
+    policy: FsyncPolicy,
+    /// Directories files were renamed into since the last flush
+    dirs: HashSet<PathBuf>,
+    /// Flushes a file or directory to disk
+    sync: fn(&Path) -> io::Result<()>,
+}
+
+fn sync_path(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+impl Fsync {
+    pub fn new(policy: FsyncPolicy) -> Self {
+        Fsync::with_sync(policy, sync_path)
+    }
+
+    fn with_sync(policy: FsyncPolicy, sync: fn(&Path) -> io::Result<()>) -> Self {
+        Fsync {
+            policy,
+            dirs: HashSet::new(),
+            sync,
+        }
+    }
+
+    /// Flushes the contents of a written temporary file before it's renamed over its target,
+    /// otherwise a crash could leave the rename on disk without the contents
+    fn file(&self, tmppath: &Path) -> io::Result<()> {
+        match self.policy {
+            FsyncPolicy::Always | FsyncPolicy::Batch => (self.sync)(tmppath),
+            FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Makes the rename of a file to path survive a crash, now or with the next flush
+    pub fn renamed(&mut self, path: &Path) {
+        let Some(dir) = path.parent() else {
+            return
+        };
+        match self.policy {
+            FsyncPolicy::Always => self.sync_dir(dir),
+            FsyncPolicy::Batch => {
+                self.dirs.insert(dir.to_path_buf());
+            }
+            FsyncPolicy::Never => {}
+        }
+    }
+
+    /// Flushes the directories files were renamed into since the last time, ending a group of writes
+    pub fn flush(&mut self) {
+        for dir in std::mem::take(&mut self.dirs) {
+            self.sync_dir(&dir);
+        }
+    }
+
+    /// The file is already in place by the time its directory is flushed, failing that isn't
+    /// failing the write
+    fn sync_dir(&self, dir: &Path) {
+        // directories can only be opened for flushing on unix
+        if cfg!(unix) {
+            if let Err(e) = (self.sync)(dir) {
+                warn!(error = %SyncError::fs(dir, e), "Failed flushing directory to disk");
+            }
+        }
+    }
+}
+
+/// Temporary file contents for writepath are written to, next to it
+pub fn tmp_path(writepath: &Path, filename: &OsStr) -> PathBuf {
+    let mut tmpname = filename.to_os_string();
+    tmpname.push(TMP_SUFFIX);
+    writepath.with_file_name(tmpname)
+}
+
+/// Flushes a fully written temporary file to disk if the policy says so, applies the metadata
+/// it should have and renames it over its target, removing it if any of that fails. The
+/// temporary file lives next to the target so the rename is atomic
+pub fn finish_write(tmppath: &Path, writepath: &Path, attrs: FileAttrs, fsync: &mut Fsync) -> Result<(), SyncError> {
+    let finished = fsync.file(tmppath)
+        .and_then(|_| attrs.apply(tmppath))
+        .and_then(|_| fs::rename(tmppath, writepath));
+    if let Err(e) = finished {
+        let _ = fs::remove_file(tmppath);
+        return Err(SyncError::fs(writepath, e))
+    }
+    fsync.renamed(writepath);
+    info!(path = %writepath.display(), "Updated file");
+    Ok(())
+}
+
+/// Replaces a file's contents without readers or a crash ever observing a partial write
+pub fn write_atomic(writepath: &Path, tmppath: &Path, contents: &[u8], attrs: FileAttrs, fsync: &mut Fsync) -> Result<(), SyncError> {
+    if let Err(e) = fs::write(tmppath, contents) {
+        let _ = fs::remove_file(tmppath);
+        return Err(SyncError::fs(writepath, e))
+    }
+    finish_write(tmppath, writepath, attrs, fsync)
+}
+
+/// Moves a deleted path into the trash directory under root instead of removing it, keeping
+/// its place in the tree below a directory named after the time it was deleted at
+pub fn move_to_trash(path: &Path, root: &Path, relative: &Path) -> Result<PathBuf, SyncError> {
+    let deleted_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let trashpath = root.join(TRASH_DIR).join(deleted_at.to_string()).join(relative);
+    if let Some(parent) = trashpath.parent() {
+        fs::create_dir_all(parent).map_err(|e| SyncError::fs(parent, e))?;
+    }
+    fs::rename(path, &trashpath).map_err(|e| SyncError::fs(path, e))?;
+    Ok(trashpath)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_hash_matches_hashing_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        // several hashing chunks and a partial one
+        let contents: Vec<u8> = (0..5 * HASH_CHUNK_SIZE + 1234).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        for algo in [HashAlgo::Xxh64, HashAlgo::Blake3] {
+            assert_eq!(try_hash_file(&path, algo).unwrap(), hash_bytes(&fs::read(&path).unwrap(), algo));
+        }
+    }
+
+    #[test]
+    fn unreadable_file_is_told_apart_from_an_empty_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty");
+        fs::write(&empty, b"").unwrap();
+        assert_eq!(try_hash_file(&empty, HashAlgo::Xxh64).unwrap(), hash_bytes(b"", HashAlgo::Xxh64));
+        assert_eq!(try_hash_file(&dir.path().join("missing"), HashAlgo::Xxh64).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn read_failing_transiently_is_retried_until_it_succeeds() {
+        let path = Path::new("locked");
+        let mut failures = [io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock].into_iter();
+        let mut reads = 0;
+        let read = retry_read(path, || {
+            reads += 1;
+            match failures.next() {
+                Some(kind) => Err(io::Error::from(kind)),
+                None => Ok(b"contents".to_vec()),
+            }
+        });
+        assert_eq!(read.unwrap(), b"contents");
+        assert_eq!(reads, 3);
+    }
+
+    #[test]
+    fn read_failing_permanently_or_too_often_is_given_up_on() {
+        let path = Path::new("missing");
+        let mut reads = 0;
+        let read: io::Result<()> = retry_read(path, || {
+            reads += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!((read.unwrap_err().kind(), reads), (io::ErrorKind::NotFound, 1));
+        let mut reads = 0;
+        let read: io::Result<()> = retry_read(path, || {
+            reads += 1;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        assert_eq!((read.unwrap_err().kind(), reads), (io::ErrorKind::Interrupted, READ_ATTEMPTS));
+    }
+
+    #[test]
+    fn interrupted_write_leaves_the_original_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let writepath = dir.path().join("file");
+        let tmppath = tmp_path(&writepath, OsStr::new("file"));
+        fs::write(&writepath, b"original").unwrap();
+        let mut fsync = Fsync::new(FsyncPolicy::Never);
+        // what a crash after writing the temporary file leaves behind
+        fs::write(&tmppath, b"partial").unwrap();
+        assert_eq!(fs::read(&writepath).unwrap(), b"original");
+        // a write failing before the rename
+        let unwritable = dir.path().join("missing").join("file");
+        assert!(write_atomic(&writepath, &unwritable, b"replaced", FileAttrs::default(), &mut fsync).is_err());
+        assert_eq!(fs::read(&writepath).unwrap(), b"original");
+        // the next write starts over from a fresh temporary file
+        write_atomic(&writepath, &tmppath, b"replaced", FileAttrs::default(), &mut fsync).unwrap();
+        assert_eq!(fs::read(&writepath).unwrap(), b"replaced");
+        assert!(!tmppath.exists());
+    }
+
+    thread_local! {
+        static SYNCED: std::cell::RefCell<Vec<PathBuf>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record_sync(path: &Path) -> io::Result<()> {
+        SYNCED.with_borrow_mut(|synced| synced.push(path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Paths flushed writing files a and b into dir with policy, then ending the group of writes
+    fn synced_writing(dir: &Path, policy: FsyncPolicy) -> Vec<PathBuf> {
+        SYNCED.with_borrow_mut(Vec::clear);
+        let mut fsync = Fsync::with_sync(policy, record_sync);
+        for name in ["a", "b"] {
+            let writepath = dir.join(name);
+            write_atomic(&writepath, &tmp_path(&writepath, OsStr::new(name)), name.as_bytes(), FileAttrs::default(), &mut fsync).unwrap();
+        }
+        fsync.flush();
+        SYNCED.with_borrow_mut(std::mem::take)
+    }
+
+    #[test]
+    fn files_are_flushed_as_the_policy_says() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        assert!(synced_writing(dir, FsyncPolicy::Never).is_empty());
+        let (a, b) = (tmp_path(&dir.join("a"), OsStr::new("a")), tmp_path(&dir.join("b"), OsStr::new("b")));
+        let dirs = |dirs: usize| if cfg!(unix) { vec![dir.to_path_buf(); dirs] } else { Vec::new() };
+        assert_eq!(synced_writing(dir, FsyncPolicy::Always), [vec![a.clone()], dirs(1), vec![b.clone()], dirs(1)].concat());
+        // the files still are before they're renamed, their directory only once
+        assert_eq!(synced_writing(dir, FsyncPolicy::Batch), [vec![a, b], dirs(1)].concat());
+    }
+
+    #[test]
+    fn directories_and_symlinks_hash_stably_and_apart_from_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let filter = PathFilter::load(root, &[], &[], false, true, None);
+        let hash = |name: &str| {
+            let path = root.join(name);
+            let ftype = fs::symlink_metadata(&path).unwrap().file_type();
+            entry_hash(&path, &ftype, root, &filter, HashAlgo::Xxh64).unwrap()
+        };
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/a"), b"a").unwrap();
+        fs::create_dir(root.join("other")).unwrap();
+        fs::write(root.join("other/a"), b"different contents").unwrap();
+        make_symlink(Path::new("dir/a"), &root.join("link"), false).unwrap();
+        make_symlink(Path::new("dir"), &root.join("dirlink"), true).unwrap();
+        assert_eq!(hash("dir"), hash("dir"));
+        assert_eq!(hash("dir"), hash("other"), "a directory is hashed by the names in it");
+        assert_eq!(hash("link"), hash("link"));
+        assert_ne!(hash("link"), hash("dirlink"));
+        // the link itself is hashed rather than what it points to
+        assert_ne!(hash("link"), hash("dir/a"));
+        for name in ["dir", "link", "dirlink"] {
+            assert_ne!(hash(name), HashAlgo::Xxh64.zero(), "{name} hashes like an unreadable file");
+        }
+        fs::write(root.join("dir/b"), b"b").unwrap();
+        assert_ne!(hash("dir"), hash("other"));
+    }
+
+    #[test]
+    fn paths_leading_out_of_the_directory_escape_it() {
+        use path_clean::PathClean;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("root");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::create_dir(tmp.path().join("outside")).unwrap();
+        make_symlink(&tmp.path().join("outside"), &dir.join("out"), true).unwrap();
+        make_symlink(Path::new("sub"), &dir.join("in"), true).unwrap();
+        let escapes = |path: &str| path_escapes_dir(&dir.join(path).clean(), &dir);
+        assert!(!escapes("sub/file"));
+        assert!(!escapes("new/dirs/file"), "checked through where it would be created");
+        assert!(!escapes("sub/../file"));
+        assert!(!escapes("in/file"), "a symlink staying inside");
+        assert!(escapes("../outside/file"));
+        assert!(escapes("sub/../../outside"));
+        assert!(escapes("/etc/passwd"), "joining an absolute path replaces the directory");
+        assert!(escapes("out/file"));
+        assert!(escapes("out/new/file"));
+    }
+}
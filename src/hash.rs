@@ -0,0 +1,178 @@
+use std::fmt;
+use std::hash::Hasher as _;
+use clap::ValueEnum;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as _;
+use serde_with::{serde_as, Bytes};
+use twox_hash::XxHash64;
+
+/// Algorithm file contents are hashed with to tell whether both sides have the same file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    /// Fast, but not collision resistant, what the OC side computes
+    #[default]
+    Xxh64,
+    /// Slower, but a collision is practically impossible
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgo::Xxh64 => Box::new(Xxh64Hasher(XxHash64::default())),
+            HashAlgo::Blake3 => Box::new(Blake3Hasher(blake3::Hasher::new())),
+        }
+    }
+
+    /// Name of the feature advertised in Hello by peers understanding the algorithm
+    pub fn feature(self) -> &'static str {
+        match self {
+            HashAlgo::Xxh64 => "hash-xxh64",
+            HashAlgo::Blake3 => "hash-blake3",
+        }
+    }
+
+    /// Digest standing in for a hash that couldn't be computed
+    pub fn zero(self) -> Digest {
+        match self {
+            HashAlgo::Xxh64 => Digest::Xxh64(0),
+            HashAlgo::Blake3 => Digest::Blake3([0; 32]),
+        }
+    }
+}
+
+/// Incrementally computes a Digest of data fed to it
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    fn finish(&self) -> Digest;
+}
+
+struct Xxh64Hasher(XxHash64);
+
+impl Hasher for Xxh64Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.write(data);
+    }
+
+    fn finish(&self) -> Digest {
+        Digest::Xxh64(self.0.finish())
+    }
+}
+
+struct Blake3Hasher(blake3::Hasher);
+
+impl Hasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(&self) -> Digest {
+        Digest::Blake3(*self.0.finalize().as_bytes())
+    }
+}
+
+/// Hash tagged with the algorithm that produced it. Digests of different algorithms
+/// never compare equal. Ordered only so both sides can break ties the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Digest {
+    Xxh64(u64),
+    Blake3([u8; 32]),
+}
+
+impl Digest {
+    pub fn algo(&self) -> HashAlgo {
+        match self {
+            Digest::Xxh64(_) => HashAlgo::Xxh64,
+            Digest::Blake3(_) => HashAlgo::Blake3,
+        }
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Digest::Xxh64(hash) => write!(f, "xxh64:{:016x}", hash),
+            Digest::Blake3(hash) => {
+                write!(f, "blake3:")?;
+                hash.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+/// On the wire xxh64 digests are a bare integer, which is all peers predating other
+/// algorithms understand, anything else is the algorithm's name along with the digest bytes
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WireDigest {
+    Bare(u64),
+    Tagged {algo: HashAlgo, #[serde_as(as = "Bytes")] digest: Vec<u8>},
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Digest::Xxh64(hash) => WireDigest::Bare(*hash),
+            Digest::Blake3(hash) => WireDigest::Tagged {algo: HashAlgo::Blake3, digest: hash.to_vec()},
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let wrong_len = |len: usize| D::Error::custom(format!("digest is {} bytes long", len));
+        match WireDigest::deserialize(deserializer)? {
+            WireDigest::Bare(hash) => Ok(Digest::Xxh64(hash)),
+            WireDigest::Tagged {algo: HashAlgo::Xxh64, digest} => digest.as_slice().try_into()
+                .map(|bytes| Digest::Xxh64(u64::from_be_bytes(bytes)))
+                .map_err(|_| wrong_len(digest.len())),
+            WireDigest::Tagged {algo: HashAlgo::Blake3, digest} => digest.as_slice().try_into()
+                .map(Digest::Blake3)
+                .map_err(|_| wrong_len(digest.len())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algo: HashAlgo, parts: &[&[u8]]) -> Digest {
+        let mut hasher = algo.hasher();
+        parts.iter().for_each(|part| hasher.update(part));
+        hasher.finish()
+    }
+
+    #[test]
+    fn digests_are_stable_and_differ_between_algorithms() {
+        let xxh64 = digest(HashAlgo::Xxh64, &[b"file contents"]);
+        let blake3 = digest(HashAlgo::Blake3, &[b"file contents"]);
+        // stable across runs and however the contents are fed in
+        assert_eq!(digest(HashAlgo::Xxh64, &[b""]), Digest::Xxh64(0xef46db3751d8e999));
+        assert_eq!(digest(HashAlgo::Blake3, &[b""]).to_string(), "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+        assert_eq!(digest(HashAlgo::Xxh64, &[b"file ", b"contents"]), xxh64);
+        assert_eq!(digest(HashAlgo::Blake3, &[b"file ", b"contents"]), blake3);
+        assert_ne!(xxh64, blake3);
+        assert_eq!((xxh64.algo(), blake3.algo()), (HashAlgo::Xxh64, HashAlgo::Blake3));
+        for algo in [HashAlgo::Xxh64, HashAlgo::Blake3] {
+            assert_ne!(digest(algo, &[b"file contents"]), digest(algo, &[b"other contents"]));
+            assert_ne!(digest(algo, &[b"file contents"]), algo.zero());
+        }
+    }
+
+    #[test]
+    fn digests_survive_the_wire() {
+        for algo in [HashAlgo::Xxh64, HashAlgo::Blake3] {
+            let sent = digest(algo, &[b"file contents"]);
+            let mut wire = Vec::new();
+            ciborium::ser::into_writer(&sent, &mut wire).unwrap();
+            assert_eq!(ciborium::de::from_reader::<Digest, _>(wire.as_slice()).unwrap(), sent);
+        }
+        // what peers predating other algorithms send
+        let mut bare = Vec::new();
+        ciborium::ser::into_writer(&42u64, &mut bare).unwrap();
+        assert_eq!(ciborium::de::from_reader::<Digest, _>(bare.as_slice()).unwrap(), Digest::Xxh64(42));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use filetime::FileTime;
+use serde::{Serialize, Deserialize};
+use serde_with::{serde_as, Same};
+use tracing::{debug, warn};
+use crate::filter::STATE_DIR;
+use crate::fs::try_hash_file;
+use crate::hash::{Digest, HashAlgo};
+use crate::protocol::WirePath;
+
+const INDEX_FILE: &str = "index.cbor";
+
+/// What a file looked like when it was last hashed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct IndexEntry {
+    hash: Digest,
+    /// Modification time in nanoseconds since the unix epoch
+    mtime: i64,
+    size: u64,
+}
+
+impl IndexEntry {
+    fn new(hash: Digest, meta: &fs::Metadata) -> Self {
+        let mtime = FileTime::from_last_modification_time(meta);
+        IndexEntry {
+            hash,
+            mtime: mtime.unix_seconds() * 1_000_000_000 + i64::from(mtime.nanoseconds()),
+            size: meta.len(),
+        }
+    }
+
+    fn matches(&self, meta: &fs::Metadata, algo: HashAlgo) -> bool {
+        let current = IndexEntry::new(self.hash, meta);
+        self.hash.algo() == algo && self.mtime == current.mtime && self.size == current.size
+    }
+}
+
+// paths are stored like they're sent to the peer, which keeps ones that aren't valid unicode
+#[serde_as]
+#[derive(Default, Serialize, Deserialize)]
+struct IndexData {
+    #[serde(default)]
+    #[serde_as(as = "HashMap<WirePath, Same>")]
+    files: HashMap<PathBuf, IndexEntry>,
+    #[serde(default)]
+    #[serde_as(as = "HashMap<WirePath, Same>")]
+    synced: HashMap<PathBuf, Digest>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+/// Hashes of the files under the sync root along with the size and modification time they
+/// were computed at, kept in the state directory so a file that hasn't changed since isn't
+/// hashed again, by a listing or after a restart. Also remembers the hash of each file when
+/// both sides last had the same contents, which conflicts are told by. Paths are relative
+/// to the root
+pub struct FileIndex {
+    root: PathBuf,
+    data: Mutex<IndexData>,
+    read_only: bool,
+}
+
+/// Key a path is stored under, "./a" and "a" being the same file. Off unix paths that
+/// aren't valid unicode can't be stored and are never remembered
+fn key(path: &Path) -> Option<PathBuf> {
+    #[cfg(not(unix))]
+    path.to_str()?;
+    Some(path.components().filter(|component| *component != Component::CurDir).collect())
+}
+
+impl FileIndex {
+    /// Loads the index kept under root, starting out empty if there is none or it can't be
+    /// read. A read only index is never written back
+    pub fn load(root: &Path, read_only: bool) -> Self {
+        let file = root.join(STATE_DIR).join(INDEX_FILE);
+        let data = match fs::File::open(&file) {
+            Ok(reader) => ciborium::de::from_reader(io::BufReader::new(reader)).unwrap_or_else(|e| {
+                warn!(path = %file.display(), error = %e, "Failed reading index, hashing every file again");
+                IndexData::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => IndexData::default(),
+            Err(e) => {
+                warn!(path = %file.display(), error = %e, "Failed opening index, hashing every file again");
+                IndexData::default()
+            }
+        };
+        debug!(path = %file.display(), files = data.files.len(), "Loaded index");
+        FileIndex {
+            root: root.to_path_buf(),
+            data: Mutex::new(data),
+            read_only,
+        }
+    }
+
+    fn data(&self) -> MutexGuard<'_, IndexData> {
+        // the data stays consistent even if a holder panicked, every change is a single insert or remove
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Hashes a file, reusing the hash it had last time if neither its size nor its
+    /// modification time changed since
+    pub fn hash(&self, path: &Path, algo: HashAlgo) -> io::Result<Digest> {
+        let fullpath = self.root.join(path);
+        let meta = fs::metadata(&fullpath)?;
+        let key = key(path);
+        if let Some(key) = &key {
+            let cached = self.data().files.get(key).copied();
+            if let Some(entry) = cached.filter(|entry| entry.matches(&meta, algo)) {
+                return Ok(entry.hash)
+            }
+        }
+        debug!(path = %path.display(), "Hashing file");
+        let hash = try_hash_file(&fullpath, algo)?;
+        if let Some(key) = key {
+            let mut data = self.data();
+            data.files.insert(key, IndexEntry::new(hash, &meta));
+            data.dirty = true;
+        }
+        Ok(hash)
+    }
+
+    /// Whether the file's size and modification time are still those it was last hashed at
+    pub fn is_current(&self, path: &Path, meta: &fs::Metadata, algo: HashAlgo) -> bool {
+        key(path).and_then(|key| self.data().files.get(&key).copied()).is_some_and(|entry| entry.matches(meta, algo))
+    }
+
+    /// Files below dir that were hashed and not forgotten since
+    pub fn files_under(&self, dir: &Path) -> Vec<PathBuf> {
+        let Some(dir) = key(dir) else {
+            return Vec::new()
+        };
+        self.data().files.keys().filter(|known| known.starts_with(&dir)).cloned().collect()
+    }
+
+    /// Takes a hash known for a file's contents, like those of a file just written, so
+    /// it doesn't have to be computed
+    pub fn record(&self, path: &Path, hash: Digest) {
+        let (Some(key), Ok(meta)) = (key(path), fs::metadata(self.root.join(path))) else {
+            return
+        };
+        let mut data = self.data();
+        data.files.insert(key, IndexEntry::new(hash, &meta));
+        data.dirty = true;
+    }
+
+    /// Forgets everything known about a path and the paths below it
+    pub fn forget(&self, path: &Path) {
+        let Some(key) = key(path) else {
+            return
+        };
+        let mut data = self.data();
+        data.files.retain(|known, _| !known.starts_with(&key));
+        data.synced.retain(|known, _| !known.starts_with(&key));
+        data.dirty = true;
+    }
+
+    /// Hash of the file's contents when both sides last had the same
+    pub fn synced(&self, path: &Path) -> Option<Digest> {
+        self.data().synced.get(&key(path)?).copied()
+    }
+
+    pub fn set_synced(&self, path: &Path, hash: Digest) {
+        if let Some(key) = key(path) {
+            let mut data = self.data();
+            data.synced.insert(key, hash);
+            data.dirty = true;
+        }
+    }
+
+    /// Writes the index to disk if anything changed since it was last written, replacing
+    /// the previous one atomically
+    pub fn save(&self) {
+        let mut data = self.data();
+        if !data.dirty || self.read_only {
+            return
+        }
+        let dir = self.root.join(STATE_DIR);
+        let file = dir.join(INDEX_FILE);
+        let tmpfile = dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut encoded = Vec::new();
+        let written = ciborium::ser::into_writer(&*data, &mut encoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            .and_then(|_| fs::create_dir_all(&dir))
+            .and_then(|_| fs::write(&tmpfile, &encoded))
+            .and_then(|_| fs::rename(&tmpfile, &file));
+        match written {
+            Ok(()) => {
+                data.dirty = false;
+                debug!(path = %file.display(), files = data.files.len(), "Saved index");
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmpfile);
+                warn!(path = %file.display(), error = %e, "Failed saving index");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::hash_bytes;
+
+    /// Replaces the contents of a file with others of the same size, keeping its modification
+    /// time, which the index can't tell apart from the file not having changed
+    fn replace_unnoticed(path: &Path, contents: &[u8]) {
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+        fs::write(path, contents).unwrap();
+        filetime::set_file_mtime(path, mtime).unwrap();
+    }
+
+    #[test]
+    fn unchanged_files_arent_hashed_again_after_a_restart() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file.txt");
+        fs::write(&file, "before").unwrap();
+        let index = FileIndex::load(root.path(), false);
+        let before = index.hash(Path::new("file.txt"), HashAlgo::Xxh64).unwrap();
+        assert_eq!(before, hash_bytes(b"before", HashAlgo::Xxh64));
+        index.set_synced(Path::new("file.txt"), before);
+        index.save();
+        drop(index);
+        replace_unnoticed(&file, b"after!");
+        let index = FileIndex::load(root.path(), false);
+        // the hash from before the restart is taken, the file isn't read
+        assert_eq!(index.hash(Path::new("./file.txt"), HashAlgo::Xxh64).unwrap(), before);
+        assert_eq!(index.synced(Path::new("file.txt")), Some(before));
+        // another algorithm's hash is of no use
+        assert_eq!(index.hash(Path::new("file.txt"), HashAlgo::Blake3).unwrap(), hash_bytes(b"after!", HashAlgo::Blake3));
+    }
+
+    #[test]
+    fn changed_modification_time_makes_the_file_hashed_again() {
+        let root = tempfile::tempdir().unwrap();
+        let file = root.path().join("file.txt");
+        fs::write(&file, "before").unwrap();
+        let index = FileIndex::load(root.path(), false);
+        index.hash(Path::new("file.txt"), HashAlgo::Xxh64).unwrap();
+        index.save();
+        replace_unnoticed(&file, b"after!");
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&file).unwrap());
+        filetime::set_file_mtime(&file, FileTime::from_unix_time(mtime.unix_seconds() + 1, mtime.nanoseconds())).unwrap();
+        let index = FileIndex::load(root.path(), false);
+        assert_eq!(index.hash(Path::new("file.txt"), HashAlgo::Xxh64).unwrap(), hash_bytes(b"after!", HashAlgo::Xxh64));
+    }
+
+    #[test]
+    fn read_only_index_isnt_saved() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("file.txt"), "file").unwrap();
+        let index = FileIndex::load(root.path(), true);
+        index.hash(Path::new("file.txt"), HashAlgo::Xxh64).unwrap();
+        index.save();
+        assert!(!root.path().join(STATE_DIR).join(INDEX_FILE).exists());
+    }
+}
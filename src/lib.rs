@@ -0,0 +1,18 @@
+pub mod codec;
+pub mod crypto;
+pub mod delta;
+pub mod error;
+pub mod events;
+pub mod fetch;
+pub mod filter;
+pub mod fs;
+pub mod hash;
+pub mod index;
+pub mod metrics;
+pub mod protocol;
+pub mod stats;
+pub mod throttle;
+pub mod transport;
+pub mod util;
+pub mod verify;
+pub mod writer;
@@ -1,231 +1,2892 @@
-use tokio;
 use tokio::sync::mpsc;
-use tokio::net::TcpStream;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
-use notify::event::{ModifyKind::*, CreateKind::*, RenameMode::*};
+use notify::event::{CreateKind, MetadataKind, ModifyKind, RemoveKind, RenameMode, ModifyKind::*, CreateKind::*, RenameMode::*};
 use tokio::runtime::Builder;
-use tokio_util::codec::Framed;
-use tokio_util::bytes::BytesMut;
+use tokio_util::sync::CancellationToken;
+use tokio_util::bytes::{BufMut, Bytes, BytesMut};
+use futures::future::Either;
 use futures::{SinkExt, StreamExt};
-use serde::{Serialize, Deserialize};
-use ciborium;
-use twox_hash::XxHash64;
-use std::hash::Hasher;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use std::fs::FileType;
-use serde_with::{serde_as, Bytes};
-use path_clean::PathClean;
-use std::env;
-use clap::Parser;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
+use clap::error::ErrorKind;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use tokio_rustls::rustls::ClientConfig;
 
-mod codec;
-use crate::codec::{Codec, Package};
+mod config;
+use syncd::codec::{Package, MAX_CHANNEL_ID_LEN, MAX_MESSAGE_SIZE};
+use crate::config::FileConfig;
+use syncd::crypto::{Passphrase, PayloadCipher, SealedConn, SEAL_OVERHEAD};
+use syncd::delta::DeltaOp;
+use syncd::error::SyncError;
+use syncd::events::{keep_watching, EchoSuppressor, EventForwarder, EventPipeline, MoveDetector, WatcherMsg};
+use syncd::fetch::{completed_path, DiskFull, FetchQueue, ListQueue};
+use syncd::filter::PathFilter;
+use syncd::fs::{FileAttrs, Fsync, FsyncPolicy};
+use syncd::hash::{Digest, HashAlgo};
+use syncd::index::FileIndex;
+use syncd::metrics::serve_metrics;
+use syncd::protocol::{
+    abandon_partial_write, answer_read, copy_identical, decode_message, error_response, resume_request, list_entries, local_features, local_hostname, order_batch, rescan,
+    resolve_path, status, ConflictMode, DeleteMode, EntityType, EventBatch, ListOptions, ListRespEntry, PeerFeatures, Protocol, ReadOptions, SyncContext, PROTOCOL_VERSION,
+};
+use syncd::stats::{human_size, SyncStats};
+use syncd::throttle::Throttle;
+use syncd::writer::WriteQueue;
+use syncd::util::Backoff;
+use syncd::verify::Report;
+use syncd::transport::{tls_config, PackageConn, TcpTransport, Transport, UNIX_ADDRESS_PREFIX};
+#[cfg(unix)]
+use syncd::transport::UnixTransport;
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Doubles the delay with every failed attempt, jittered so clients that lost the broker at
+/// the same time don't all come back at once
+fn reconnect_backoff() -> Backoff {
+    Backoff::new(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX, 2).with_jitter()
+}
+// How often the file index is written to disk if it changed, it's written on exit too
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+// Generous estimate of what a message carrying file contents takes besides its path and
+// contents: its type, the field names, the hash and the metadata
+const MESSAGE_FIELDS_SIZE: usize = 256;
+// Watch limit suggested when the default one runs out, what distributions that raised it use
+const RAISED_INOTIFY_WATCHES: u32 = 524288;
+// Payload of the ping sent right after subscribing. The broker handles packages in order, so
+// its pong confirms the subscription went through, STEM has no acknowledgement of its own
+const SUBSCRIBE_PROBE: &[u8] = b"subscribe";
+// How often the progress of files being fetched is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+// Exit status once the broker was silent for longer than --max-idle-secs, apart from the 1
+// of failed syncs and the 2 of bad arguments
+const EXIT_IDLE: i32 = 3;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
+    /// TOML file to read options from, options given on the command line take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Broker to connect to as host:port, or unix:PATH for a unix domain socket
     #[arg(long, default_value = "stem.fomalhaut.me:5733")]
     address: String,
+    /// Try the broker's IPv4 addresses before its IPv6 ones
+    #[arg(long)]
+    prefer_ipv4: bool,
+    /// Local IP address to connect to the broker from, picking the interface traffic goes
+    /// out through on hosts with several
+    #[arg(long, value_name = "IP")]
+    bind: Option<IpAddr>,
+    /// Connect to the broker with TLS
+    #[arg(long)]
+    tls: bool,
+    /// PEM file of the certificates the broker's certificate is checked against with --tls,
+    /// instead of the Mozilla root certificates
+    #[arg(long, value_name = "FILE")]
+    ca_cert: Option<PathBuf>,
+    /// Don't check the broker's certificate with --tls, for brokers with a self-signed one
+    #[arg(long)]
+    insecure_skip_verify: bool,
+    /// How the sync directory is watched for changes. Watching only its top level takes a
+    /// single inotify watch, changes below it are only picked up by --initial-sync
+    #[arg(long, value_enum, default_value_t = WatchMode::Recursive)]
+    watch_mode: WatchMode,
+    /// Only log what would be changed in the sync directory instead of changing it, requests
+    /// from the peer are still answered
+    #[arg(long)]
+    dry_run: bool,
+    /// Never change the sync directory, changes from the peer are ignored while its requests
+    /// are still answered and local changes still sent, for a mirror others only sync from
     #[arg(long)]
-    channel: String,
+    read_only: bool,
+    /// Log a summary of how far files being fetched got every few seconds
+    #[arg(long)]
+    progress: bool,
+    /// Serve the counters of every pair in the Prometheus text format at /metrics on this
+    /// address, like 127.0.0.1:9100
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// Encrypt messages to the peer with a key derived from this passphrase, so the broker
+    /// can't read them. The peer has to use the same one
+    #[arg(long, value_name = "PASSPHRASE", value_parser = parse_psk)]
+    psk: Option<Passphrase>,
+    #[arg(long, value_parser = parse_channel, required_unless_present_any = ["config", "pair"])]
+    channel: Option<String>,
     #[arg(long, default_value = ".")]
     syncdir: PathBuf,
+    /// Another directory to sync on its own channel, given as SYNCDIR:CHANNEL. Can be
+    /// repeated, each pair is synced over its own connection
+    #[arg(long, value_name = "SYNCDIR:CHANNEL", value_parser = parse_pair)]
+    pair: Vec<(PathBuf, String)>,
+    /// Only sync this directory, relative to the sync directory. Paths sent to the peer stay
+    /// relative to the sync directory, so the peer's copy has the subpath in the same place
+    #[arg(long, value_name = "PATH", value_parser = parse_subpath)]
+    subpath: Option<PathBuf>,
+    /// Time in milliseconds a file has to stay unmodified before its changes are sent
+    #[arg(long, default_value_t = 300)]
+    debounce_ms: u64,
+    /// Milliseconds a file's changes are sent at most once per, with its latest contents,
+    /// however often it's modified. Keeps files being written continuously, like logs, from
+    /// being fetched on every write. 0 sends every change
+    #[arg(long, default_value_t = 0)]
+    min_resync_interval_ms: u64,
+    /// After connecting, fetch files that are missing or differ from the peer's copy
+    #[arg(long)]
+    initial_sync: bool,
+    /// Files the initial sync fetches from the peer at a time
+    #[arg(long, default_value = "4")]
+    max_concurrent_transfers: NonZeroUsize,
+    /// Directories the peer is asked to list at a time, to compare them after events that
+    /// didn't tell what changed in them
+    #[arg(long, default_value = "4")]
+    max_concurrent_lists: NonZeroUsize,
+    /// Seconds the peer has to send a file the initial sync fetches, or the next part of it,
+    /// before it's requested again. 0 waits forever
+    #[arg(long, default_value_t = 60)]
+    get_timeout_secs: u64,
+    /// Copy files the initial sync would fetch from local files with the same contents,
+    /// or from the first of several listed ones once it arrived
+    #[arg(long)]
+    dedup: bool,
+    /// Fetch files that are missing or differ from the peer's copy like --initial-sync, then
+    /// exit instead of watching for changes. Exits with 1 if any couldn't be fetched
+    #[arg(long)]
+    once: bool,
+    /// Compare the sync directory with the peer's without changing either, logging every path
+    /// missing on one side or differing, then exit. Exits with 1 if any does
+    #[arg(long)]
+    verify: bool,
+    /// Number of filesystem events buffered while waiting to be sent, events past that are dropped
+    #[arg(long, default_value = "32")]
+    event_buffer: NonZeroUsize,
+    /// Only log messages of this level and above, overrides RUST_LOG when given
+    #[arg(long)]
+    log_level: Option<tracing::Level>,
+    /// Seconds between keepalive pings to the broker, the connection is considered dead if
+    /// one isn't answered before the next is due. 0 disables keepalive
+    #[arg(long, default_value_t = 30)]
+    keepalive_secs: u64,
+    /// Stop syncing a directory after connecting to the broker failed this many times in a
+    /// row. 0 keeps trying forever
+    #[arg(long, default_value_t = 0)]
+    max_reconnect_attempts: u32,
+    /// Exit with status 3 when nothing arrived from the broker for this many seconds, for a
+    /// supervisor to restart the watcher. Has to be longer than --keepalive-secs, whose pongs
+    /// are what's heard from a quiet broker. 0 waits forever
+    #[arg(long, default_value_t = 0)]
+    max_idle_secs: u64,
+    /// Compress file contents sent to the peer with zstd, the peer has to support it
+    #[arg(long)]
+    compress: bool,
+    /// Limits the rate file contents are sent to the peer at, in kilobits per second. Other
+    /// messages aren't held back. 0 disables the limit
+    #[arg(long, default_value_t = 0)]
+    max_upload_kbps: u64,
+    /// What to do with paths the peer deleted
+    #[arg(long, value_enum, default_value_t = DeleteMode::Propagate)]
+    delete_mode: DeleteMode,
+    /// When received files are flushed to disk. Flushing fewer of them is faster with many
+    /// small files, but a crash may lose those written last
+    #[arg(long, value_enum, default_value_t = FsyncPolicy::Batch)]
+    fsync: FsyncPolicy,
+    /// Gitignore-style pattern of paths to exclude from syncing, in addition to .syncignore
+    #[arg(long, value_name = "PATTERN")]
+    ignore: Vec<String>,
+    /// Gitignore-style pattern of paths to sync, in addition to .syncinclude. Given any,
+    /// paths matching none of them are excluded
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Exclude files and directories whose name starts with a dot
+    #[arg(long)]
+    skip_hidden: bool,
+    /// Sync the temporary files of editors like vim's swap files or emacs' lock files as
+    /// well, which are excluded otherwise
+    #[arg(long)]
+    no_builtin_ignores: bool,
+    /// Don't send files larger than this many bytes, a K, M or G suffix multiplies by 1024s
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    /// Algorithm file contents are hashed with in messages sent to the peer
+    #[arg(long, value_enum, default_value_t = HashAlgo::Xxh64)]
+    hash_algo: HashAlgo,
+    /// What to do with a file the peer sent that was changed locally as well since both
+    /// sides last had the same contents
+    #[arg(long, value_enum, default_value_t = ConflictMode::Newest)]
+    conflict: ConflictMode,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum EntityType {
-    File,
-    Directory,
-    Symlink,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ListRespEntry {
-    path: PathBuf,
-    hash: u64,
-    entity: EntityType,
-}
-
-#[serde_as]
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-enum Protocol {
-    Ping,
-    Pong,
-    List {path: PathBuf},
-    ListResp {entries: Vec<ListRespEntry>},
-    Get {path: PathBuf},
-    GetResp {path: PathBuf, #[serde_as(as = "Bytes")] contents: Vec<u8>},
-    FsEventCreate {path: PathBuf, entity: EntityType},
-    FsEventModify {path: PathBuf, hash: u64},
-    FsEventRename {path_from: PathBuf, path_to: PathBuf},
-    FsEventDelete {path: PathBuf},
-    FsEventUnknown {path: PathBuf, entity: EntityType, hash: u64}
-}
-
-fn hash_file(path: &Path) -> u64 {
-    let mut hasher = XxHash64::default();
-    match fs::read(path) {
-        Ok(data) => {
-            hasher.write(&data);
-            hasher.finish()
-        },
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WatchMode {
+    /// Watch the whole tree, with an inotify watch for every directory
+    Recursive,
+    /// Only watch the files directly inside the sync directory
+    Flat,
+}
+
+impl WatchMode {
+    fn recursive_mode(self) -> RecursiveMode {
+        match self {
+            WatchMode::Recursive => RecursiveMode::Recursive,
+            WatchMode::Flat => RecursiveMode::NonRecursive,
+        }
+    }
+}
+
+/// Channels are sent as is in the subscribe frame, so they have to fit its one byte length
+/// and stay clear of control characters, which brokers and their logs can't be trusted with
+fn parse_channel(channel: &str) -> Result<String, String> {
+    if channel.is_empty() {
+        return Err("channel is empty, pick a string both sides use".to_string())
+    }
+    if channel.len() > MAX_CHANNEL_ID_LEN {
+        return Err(format!("channel is {} bytes long, at most {} are supported, pick a shorter one", channel.len(), MAX_CHANNEL_ID_LEN))
+    }
+    if let Some(c) = channel.chars().find(|c| c.is_control()) {
+        return Err(format!("channel contains the control character {:?}, use printable characters only", c))
+    }
+    Ok(channel.to_string())
+}
+
+fn parse_psk(psk: &str) -> Result<Passphrase, String> {
+    if psk.is_empty() {
+        return Err("passphrase is empty".to_string())
+    }
+    Ok(Passphrase(psk.to_string()))
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&size[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&size[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|count| count.checked_mul(multiplier))
+        .ok_or_else(|| format!("{} isn't a size in bytes, optionally followed by K, M or G", size))
+}
+
+fn parse_subpath(subpath: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(subpath);
+    if path.as_os_str().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("{} isn't a path below the sync directory", subpath))
+    }
+    Ok(path.components().filter(|component| *component != Component::CurDir).collect())
+}
+
+/// Splits on the last colon so directories with one in their path (like Windows drives) work
+fn parse_pair(pair: &str) -> Result<(PathBuf, String), String> {
+    match pair.rsplit_once(':') {
+        Some((syncdir, channel)) if !syncdir.is_empty() && !channel.is_empty() => Ok((PathBuf::from(syncdir), parse_channel(channel)?)),
+        _ => Err(format!("{} isn't of the form SYNCDIR:CHANNEL", pair)),
+    }
+}
+
+fn entity_of(path: &Path) -> EntityType {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => EntityType::Directory,
+        Ok(meta) if meta.is_symlink() => EntityType::Symlink,
+        _ => EntityType::File,
+    }
+}
+
+fn is_oversized(path: &Path, ctx: &SyncContext) -> bool {
+    let oversized = ctx.max_file_size.is_some_and(|limit| std::fs::metadata(path).is_ok_and(|meta| meta.len() > limit));
+    if oversized {
+        debug!(path = %path.display(), "Not sending modification of file over the size limit");
+    }
+    oversized
+}
+
+/// Hash of a file announced to the peer, a zero digest if the file can't be read. None if
+/// the file is gone already, deleted or moved away since the event, its own event follows
+fn index_hash(path: &Path, ctx: &SyncContext) -> Option<Digest> {
+    match ctx.index.hash(path, ctx.peer.hash_algo) {
+        Ok(hash) => Some(hash),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(path = %path.display(), "File is gone before it could be hashed, dropping its event");
+            None
+        }
         Err(e) => {
-            eprintln!("Failed to read file '{}': {}", path.display(), e);
-            0
+            warn!(path = %path.display(), error = %e, "Failed to read file");
+            Some(ctx.peer.hash_algo.zero())
         }
     }
 }
 
-fn path_escapes_dir(path: &Path, dir: &Path) -> bool {
-    !path.starts_with(dir)
+fn strip_syncdir(path: &Path, syncdir: &Path) -> Result<PathBuf, SyncError> {
+    path.strip_prefix(syncdir)
+        .map(Path::to_path_buf)
+        .map_err(|_| SyncError::PathEscapes(path.to_path_buf()))
 }
 
-fn list_path(path: &Path) -> Vec<(PathBuf, FileType)> {
-    let dirents = fs::read_dir(path).unwrap();
-    let mut paths = Vec::new();
-    for dirent in dirents {
-        let dirent = dirent.unwrap();
-        paths.push((dirent.path(), dirent.file_type().unwrap()));
+fn handle_fs_event(event: Event, ctx: &mut SyncContext) -> Result<Option<Protocol>, SyncError> {
+    // notify doesn't guarantee the number of paths matches what the event kind implies
+    let Some(path) = event.paths.first() else {
+        debug!(event_kind = ?event.kind, "Ignoring event without any paths");
+        return Ok(None)
+    };
+    let strippath = strip_syncdir(path, &ctx.syncdir)?;
+    // opening, reading or closing a path changes nothing on it, and mustn't use up the
+    // echo of a write of ours. Writes are reported by their own modify events
+    if let EventKind::Access(kind) = event.kind {
+        debug!(access_kind = ?kind, path = %strippath.display(), "Ignoring access event");
+        return Ok(None)
+    }
+
+    debug!(event_kind = ?event.kind, path = %strippath.display(), "FS event");
+    let is_echo = ctx.echoes.take(&strippath, matches!(event.kind, EventKind::Remove(_)));
+    // the watcher watches only the subpath, but paths outside it still show up in renames
+    let excluded = !path.starts_with(&ctx.scope) || ctx.filter.is_excluded(&strippath, matches!(event.kind, EventKind::Create(Folder)) || path.is_dir());
+    if let EventKind::Modify(Name(Both)) = event.kind {
+        let Some(path_to) = event.paths.get(1) else {
+            debug!(path = %path.display(), "Ignoring rename event without a target path");
+            return Ok(None)
+        };
+        let strippath_to = strip_syncdir(path_to, &ctx.syncdir)?;
+        ctx.index.forget(&strippath);
+        ctx.index.forget(&strippath_to);
+        if ctx.echoes.take(&strippath_to, false) || is_echo {
+            return Ok(None)
+        }
+        // moving a path in or out of the excluded set looks like a create or delete to the peer
+        return Ok(match (excluded, !path_to.starts_with(&ctx.scope) || ctx.filter.is_excluded(&strippath_to, path_to.is_dir())) {
+            (false, false) => Some(Protocol::FsEventRename{path_from: strippath, path_to: strippath_to}),
+            (false, true) => Some(Protocol::FsEventDelete{entity: Some(entity_of(path_to)), path: strippath}),
+            (true, false) => Some(Protocol::FsEventCreate{entity: entity_of(path_to), path: strippath_to}),
+            (true, true) => None,
+        })
     }
-    paths
+    if excluded || is_echo {
+        return Ok(None)
+    }
+    Ok(match event.kind {
+        EventKind::Create(File) => Some(Protocol::FsEventCreate{path: strippath, entity: entity_of(path)}),
+        EventKind::Create(Folder) => Some(Protocol::FsEventCreate{path: strippath, entity: EntityType::Directory}),
+        // backends that can't tell what was created leave it to be looked up, a path
+        // that's already gone again is left to its remove event
+        EventKind::Create(CreateKind::Any | CreateKind::Other) => std::fs::symlink_metadata(path).is_ok()
+            .then(|| Protocol::FsEventCreate{path: strippath, entity: entity_of(path)}),
+        EventKind::Modify(Data(_) | ModifyKind::Any) if is_oversized(path, ctx) => None,
+        EventKind::Modify(Data(_)) => index_hash(&strippath, ctx).map(|hash| Protocol::FsEventModify{hash, path: strippath}),
+        // only a file's contents can have changed in a way worth sending
+        EventKind::Modify(ModifyKind::Any) if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.is_file()) => {
+            index_hash(&strippath, ctx).map(|hash| Protocol::FsEventModify{hash, path: strippath})
+        }
+        EventKind::Modify(ModifyKind::Any) => None,
+        // access time, ownership and extended attributes aren't synced. Backends that can't
+        // tell what changed (inotify) report Any, which is sent in case it was the mode
+        EventKind::Modify(Metadata(MetadataKind::Any | MetadataKind::Permissions | MetadataKind::WriteTime)) if ctx.peer.attrs => {
+            std::fs::symlink_metadata(path).ok().filter(|meta| meta.is_file()).map(|meta| {
+                let attrs = FileAttrs::of(&meta);
+                Protocol::FsEventAttrs{path: strippath, mode: attrs.mode, mtime: attrs.mtime}
+            })
+        }
+        // the path is gone, what it was can only be told from the event
+        EventKind::Remove(kind) => {
+            ctx.index.forget(&strippath);
+            Some(Protocol::FsEventDelete{path: strippath, entity: match kind {
+                RemoveKind::File => Some(EntityType::File),
+                RemoveKind::Folder => Some(EntityType::Directory),
+                RemoveKind::Any | RemoveKind::Other => None,
+            }})
+        }
+        // backends that can't tell what happened, or a rename they couldn't pair up, leave
+        // the peer to look at the path again
+        EventKind::Any | EventKind::Other | EventKind::Modify(ModifyKind::Other | Name(RenameMode::Any | RenameMode::Other)) => {
+            unknown_event(path, strippath, ctx)
+        }
+        _ => None
+    })
 }
 
-fn handle_message(message: Protocol, syncdir: &Path) -> Option<Protocol> {
-    match message {
-        Protocol::Ping => Some(Protocol::Pong),
-        Protocol::List {path} => {
-            println!("path is {}", path.display());
-            let watchpath = syncdir.join(&path).clean();
-            if path_escapes_dir(&watchpath, syncdir) {
+/// What's at a path an event couldn't be classified for, sent as deleted once it's gone
+fn unknown_event(path: &Path, strippath: PathBuf, ctx: &SyncContext) -> Option<Protocol> {
+    let deleted = |strippath: PathBuf| {
+        ctx.index.forget(&strippath);
+        Protocol::FsEventDelete{path: strippath, entity: None}
+    };
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_file() => {
+            if is_oversized(path, ctx) {
                 return None
             }
-            let paths = list_path(watchpath.as_ref());
-            let mut entries = Vec::new();
-            for (listpath, ftype) in paths.iter() {
-                let entity = if ftype.is_file() {
-                    EntityType::File
-                } else if ftype.is_dir() {
-                    EntityType::Directory
-                } else if ftype.is_symlink() {
-                    EntityType::Symlink
+            Some(match index_hash(&strippath, ctx) {
+                Some(hash) => Protocol::FsEventUnknown{path: strippath, entity: EntityType::File, hash},
+                None => deleted(strippath),
+            })
+        }
+        // the hash is only looked at for files
+        Ok(meta) => Some(Protocol::FsEventUnknown{
+            path: strippath,
+            entity: if meta.is_dir() { EntityType::Directory } else { EntityType::Symlink },
+            hash: ctx.peer.hash_algo.zero(),
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Some(deleted(strippath)),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "Failed to look up path of unknown event");
+            None
+        }
+    }
+}
+
+/// Filesystem events on their way from the watcher to the peer, kept across connections
+/// so events keep queueing up while disconnected and get sent once the connection is back
+struct OutgoingEvents {
+    rx: mpsc::Receiver<WatcherMsg>,
+    pipeline: EventPipeline,
+    moves: MoveDetector,
+    batch: EventBatch,
+}
+
+/// How connections to the broker are kept up
+#[derive(Clone)]
+struct ConnectionSettings {
+    keepalive: Option<Duration>,
+    /// Upload limit for file contents in kilobits per second
+    max_upload_kbps: Option<u64>,
+    /// Encrypts messages to the peer, specific to the channel
+    cipher: Option<Arc<PayloadCipher>>,
+    /// Log progress of transfers at info instead of debug
+    progress: bool,
+    /// Stop once the initial sync is done
+    once: bool,
+    /// Whether messages to the peer are checksummed, None for encrypted connections
+    checksums: Option<Arc<AtomicBool>>,
+    /// Delays between attempts to connect to the broker
+    reconnect: Backoff,
+    /// How long the broker may stay silent before the watcher gives up on it
+    max_idle: Option<Duration>,
+}
+
+/// How syncing a directory came to an end
+enum PairEnd {
+    /// Asked to stop or, with --once, everything was fetched
+    Done,
+    /// The broker or the peer refused syncing, reconnecting gave up or, with --once, files
+    /// couldn't be fetched
+    Failed,
+    /// Nothing arrived from the broker for longer than --max-idle-secs
+    Idle,
+}
+
+/// Why a single broker connection stopped being serviced
+enum ConnectionEnd {
+    /// The broker closed the connection or it failed, reconnecting makes sense
+    Disconnected,
+    /// The filesystem watcher went away, there is nothing left to sync
+    WatcherClosed,
+    /// The process was asked to stop
+    Shutdown,
+    /// The peer speaks a different protocol version, reconnecting wouldn't change that
+    Incompatible,
+    /// The broker dropped the connection before confirming the subscription, which is how
+    /// it refuses a channel
+    Refused,
+    /// The pass made with --once is done, failing to fetch that many files
+    Synced {failed: usize},
+    /// Nothing arrived from the broker for longer than --max-idle-secs
+    Idle,
+}
+
+/// Room a serialized message takes up at least, file contents make up nearly all of it
+/// when there are any. Serializing them into a buffer this large allocates it only once
+fn serialized_size_hint(msg: &Protocol) -> usize {
+    match msg {
+        Protocol::GetResp {path, contents, ..} | Protocol::GetChunkResp {path, contents, ..} => {
+            contents.len() + path.as_os_str().len() + MESSAGE_FIELDS_SIZE
+        }
+        _ => 0,
+    }
+}
+
+/// Serializes a message, splitting it into several when it doesn't fit in a single frame.
+/// Each payload is serialized into a buffer of its own that's handed on without copying
+fn encode_message(msg: &Protocol, out: &mut Vec<Bytes>) {
+    let mut serialized = BytesMut::with_capacity(serialized_size_hint(msg)).writer();
+    if let Err(e) = ciborium::ser::into_writer(msg, &mut serialized) {
+        error!(error = %SyncError::from(e), "Failed serializing message");
+        return
+    }
+    let serialized = serialized.into_inner();
+    // leaving room for encryption, whether or not messages are encrypted
+    if serialized.len() <= MAX_MESSAGE_SIZE - SEAL_OVERHEAD {
+        out.push(serialized.freeze());
+        return
+    }
+    match msg {
+        Protocol::ListResp {entries, path, more} if entries.len() > 1 => {
+            let (first, second) = entries.split_at(entries.len() / 2);
+            encode_message(&Protocol::ListResp{entries: first.to_vec(), path: path.clone(), more: true}, out);
+            encode_message(&Protocol::ListResp{entries: second.to_vec(), path: path.clone(), more: *more}, out);
+        }
+        Protocol::FsEventBatch {events} if events.len() > 1 => {
+            let (first, second) = events.split_at(events.len() / 2);
+            encode_message(&Protocol::FsEventBatch{events: first.to_vec()}, out);
+            encode_message(&Protocol::FsEventBatch{events: second.to_vec()}, out);
+        }
+        // the first part only carries the ops, the second one continues where it ends
+        Protocol::DeltaResp {path, offset, ops, eof, mode, mtime, hash} if ops.len() > 1 => {
+            let (first, second) = ops.split_at(ops.len() / 2);
+            let next = offset + first.iter().map(DeltaOp::len).sum::<u64>();
+            encode_message(&Protocol::DeltaResp{path: path.clone(), offset: *offset, ops: first.to_vec(), eof: false, mode: None, mtime: None, hash: None}, out);
+            encode_message(&Protocol::DeltaResp{path: path.clone(), offset: next, ops: second.to_vec(), eof: *eof, mode: *mode, mtime: *mtime, hash: *hash}, out);
+        }
+        _ => warn!(size = serialized.len(), "Dropping message that doesn't fit in a frame"),
+    }
+}
+
+/// Sends a response to the peer, file contents going through the throttle if there is one
+async fn send_response(framed_conn: &mut impl PackageConn, channel: Bytes, msg: &Protocol, throttle: Option<&mut Throttle>) -> io::Result<()> {
+    match (msg, throttle) {
+        (Protocol::GetResp{..} | Protocol::GetChunkResp{..} | Protocol::DeltaResp{..}, Some(throttle)) => {
+            let mut payloads = Vec::new();
+            encode_message(msg, &mut payloads);
+            for payload in payloads {
+                throttle.push(channel.clone(), payload);
+            }
+            Ok(())
+        }
+        _ => send_protocol(framed_conn, channel, msg).await,
+    }
+}
+
+async fn send_protocol(framed_conn: &mut impl PackageConn, channel: Bytes, msg: &Protocol) -> io::Result<()> {
+    let mut payloads = Vec::new();
+    encode_message(msg, &mut payloads);
+    for payload in payloads {
+        framed_conn.send(Package::Message(channel.clone(), payload)).await?;
+    }
+    Ok(())
+}
+
+/// Turns events into messages added to the batch, sending it as soon as it fills up
+async fn send_fs_events(framed_conn: &mut impl PackageConn, ctx: &mut SyncContext, chan: &Bytes, outgoing: &mut OutgoingEvents, events: Vec<Event>) -> io::Result<()> {
+    let mut messages = Vec::new();
+    for event in events {
+        match handle_fs_event(event, ctx) {
+            Ok(Some(message)) => messages.extend(outgoing.moves.push(message)),
+            Ok(None) => {}
+            Err(e) => warn!(error = %e, "Failed handling filesystem event"),
+        }
+    }
+    queue_messages(framed_conn, chan, &mut outgoing.batch, messages).await
+}
+
+async fn queue_messages(framed_conn: &mut impl PackageConn, chan: &Bytes, batch: &mut EventBatch, messages: Vec<Protocol>) -> io::Result<()> {
+    for message in messages {
+        if batch.push(message) {
+            flush_batch(framed_conn, chan, batch).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn flush_batch(framed_conn: &mut impl PackageConn, chan: &Bytes, batch: &mut EventBatch) -> io::Result<()> {
+    match batch.take() {
+        Some(message) => send_protocol(framed_conn, chan.clone(), &message).await,
+        None => Ok(()),
+    }
+}
+
+/// Compares the peer's listing with the local tree, returning the changes to apply and the
+/// files to fetch
+fn reconcile_listing(entries: Vec<ListRespEntry>, ctx: &mut SyncContext) -> (Vec<Protocol>, Vec<ListRespEntry>) {
+    let mut changes = Vec::new();
+    let mut fetches = Vec::new();
+    for entry in entries {
+        let is_dir = matches!(entry.entity, EntityType::Directory);
+        let localpath = match resolve_path(&entry.path, is_dir, ctx) {
+            Ok(localpath) => localpath,
+            // the peer may sync more than this side does
+            Err(e @ SyncError::Excluded(_)) => {
+                debug!(error = %e, "Skipping listed path");
+                continue
+            }
+            Err(e) => {
+                warn!(error = %e, "Skipping listed path");
+                continue
+            }
+        };
+        match entry.entity {
+            EntityType::File if entry.skipped => debug!(path = %localpath.display(), "Skipping file the peer won't send"),
+            EntityType::File => {
+                let remote_attrs = FileAttrs{mode: entry.mode, mtime: entry.mtime};
+                let local_attrs = fs::metadata(&localpath).map(|meta| FileAttrs::of(&meta)).unwrap_or_default();
+                // a matching modification time is taken as the file being unchanged, sparing hashing it
+                let local_hash = if remote_attrs.mtime.is_some() && remote_attrs.mtime == local_attrs.mtime {
+                    Ok(entry.hash)
                 } else {
-                    EntityType::File
+                    ctx.index.hash(&entry.path, entry.hash.algo())
                 };
-                let strippath = listpath.strip_prefix(&syncdir).expect("Path does not contain syncdir prefix");
-                println!("Returning path {}", strippath.display());
-                entries.push(ListRespEntry {
-                    path: strippath.to_path_buf(),
-                    hash: hash_file(listpath.as_ref()),
-                    entity: entity
-                });
-            }
-            Some(Protocol::ListResp{entries: entries})
-        },
-        Protocol::Get {path} => {
-            let watchpath = syncdir.join(&path).clean();
-            if path_escapes_dir(&watchpath, syncdir) {
-                println!("Path escapes {}", watchpath.display());
-                return None
+                match local_hash {
+                    Ok(hash) if hash == entry.hash => {
+                        let differs = remote_attrs.mode.is_some_and(|mode| Some(mode) != local_attrs.mode)
+                            || remote_attrs.mtime.is_some_and(|mtime| Some(mtime) != local_attrs.mtime);
+                        if differs {
+                            changes.push(Protocol::FsEventAttrs{path: entry.path.clone(), mode: entry.mode, mtime: entry.mtime});
+                        }
+                        ctx.index.set_synced(&entry.path, hash);
+                    }
+                    Ok(hash) => {
+                        info!(path = %localpath.display(), local_hash = %hash, remote_hash = %entry.hash, "Local and remote hash differ, requesting file");
+                        fetches.push(entry);
+                    }
+                    Err(_) => {
+                        info!(path = %localpath.display(), "Path does not exist locally, requesting file");
+                        fetches.push(entry);
+                    }
+                }
+            },
+            // the listing is recursive so the directory's contents are part of it too
+            EntityType::Directory if !localpath.is_dir() => {
+                changes.push(Protocol::FsEventCreate{path: entry.path, entity: EntityType::Directory});
             }
-            match fs::read::<&Path>(watchpath.as_ref()) {
-                Ok(data) => Some(Protocol::GetResp{path: path, contents: data}),
-                Err(_) => {
-                    println!("failed reading file {}", path.display());
-                    None // TODO: report error?
+            EntityType::Directory => {}
+            EntityType::Symlink => match entry.link_target {
+                Some(target) if fs::read_link(&localpath).ok().as_ref() != Some(&target) => {
+                    changes.push(Protocol::GetResp{path: entry.path, contents: Vec::new(), compressed: false, hash: None, mode: None, mtime: None, link_target: Some(target)});
+                }
+                Some(_) => {}
+                None => debug!(path = %localpath.display(), "Skipping listed symlink without a target"),
+            },
+        }
+    }
+    (changes, fetches)
+}
+
+/// What's done on the blocking thread pool hands back to the connection
+enum Blocking {
+    /// A message to send on the channel, like the answer to a request of the peer
+    Response(Bytes, Protocol),
+    /// Hashes of the local tree, for listed files to be copied from with --dedup
+    LocalHashes(HashMap<Digest, PathBuf>),
+}
+
+/// Answers a List request on the blocking thread pool, hashing a large tree would otherwise
+/// hold up everything else the connection has to do, like answering pings
+fn spawn_listing(path: PathBuf, recursive: bool, max_depth: Option<u32>, ctx: &SyncContext, channel: Bytes, tx: mpsc::UnboundedSender<Blocking>) {
+    debug!(path = %path.display(), recursive, "Listing");
+    let root = ctx.syncdir.clone();
+    let filter = Arc::clone(&ctx.filter);
+    let index = Arc::clone(&ctx.index);
+    let max_file_size = ctx.max_file_size;
+    let algo = ctx.peer.hash_algo;
+    let watchpath = resolve_path(&path, true, ctx);
+    tokio::task::spawn_blocking(move || {
+        let opts = ListOptions{root: &root, filter: &filter, index: &index, max_file_size, algo};
+        let listing = match watchpath.and_then(|watchpath| list_entries(&watchpath, recursive, max_depth, &opts)) {
+            Ok(entries) => Protocol::ListResp{entries, path: Some(path), more: false},
+            Err(e) => error_response("List", Some(path), &e),
+        };
+        let _ = tx.send(Blocking::Response(channel, listing));
+    });
+}
+
+/// Answers a request for file contents on the blocking thread pool, the last chunk of a
+/// file carries the hash of all of it, which would otherwise hold up pings like a listing
+fn spawn_read(request: Protocol, ctx: &SyncContext, channel: Bytes, tx: mpsc::UnboundedSender<Blocking>) {
+    let root = ctx.syncdir.clone();
+    let scope = ctx.scope.clone();
+    let filter = Arc::clone(&ctx.filter);
+    let index = Arc::clone(&ctx.index);
+    let (max_file_size, compress, peer) = (ctx.max_file_size, ctx.compress, ctx.peer);
+    tokio::task::spawn_blocking(move || {
+        let opts = ReadOptions{root: &root, scope: &scope, filter: &filter, index: &index, max_file_size, compress, peer};
+        if let Some(response) = answer_read(request, &opts) {
+            let _ = tx.send(Blocking::Response(channel, response));
+        }
+    });
+}
+
+/// Answers a Status request on the blocking thread pool, counting the synced paths takes a walk of the tree
+fn spawn_status(ctx: &SyncContext, channel: Bytes, tx: mpsc::UnboundedSender<Blocking>) {
+    let root = ctx.syncdir.clone();
+    let filter = Arc::clone(&ctx.filter);
+    let stats = Arc::clone(&ctx.stats);
+    tokio::task::spawn_blocking(move || {
+        let _ = tx.send(Blocking::Response(channel, status(&root, &filter, &stats).unwrap_or_else(|e| error_response("Status", None, &e))));
+    });
+}
+
+/// Finds the changes the watcher lost under dir on the blocking thread pool and sends them
+/// like the watcher's own, hashing changed files takes a while. A rescan the peer asked for
+/// with request is answered with an Error if it fails
+fn spawn_rescan(dir: PathBuf, request: Option<PathBuf>, ctx: &SyncContext, channel: Bytes, tx: mpsc::UnboundedSender<Blocking>) {
+    info!(path = %dir.display(), "Rescanning for changes the watcher missed");
+    let root = ctx.syncdir.clone();
+    let filter = Arc::clone(&ctx.filter);
+    let index = Arc::clone(&ctx.index);
+    let max_file_size = ctx.max_file_size;
+    let algo = ctx.peer.hash_algo;
+    let batch = ctx.peer.batch;
+    tokio::task::spawn_blocking(move || {
+        let opts = ListOptions{root: &root, filter: &filter, index: &index, max_file_size, algo};
+        match rescan(&dir, &opts) {
+            Ok(events) if events.is_empty() => debug!(path = %dir.display(), "Rescan found no missed changes"),
+            Ok(events) => {
+                info!(path = %dir.display(), changes = events.len(), "Rescan found missed changes");
+                if batch {
+                    let _ = tx.send(Blocking::Response(channel, Protocol::FsEventBatch{events}));
+                } else {
+                    for event in events {
+                        let _ = tx.send(Blocking::Response(channel.clone(), event));
+                    }
                 }
             }
-        },
-        _ => None
+            Err(e) => match request {
+                Some(path) => {
+                    let _ = tx.send(Blocking::Response(channel, error_response("Rescan", Some(path), &e)));
+                }
+                None => warn!(path = %dir.display(), error = %e, "Failed rescanning"),
+            },
+        }
+    });
+}
+
+fn root_listing() -> Protocol {
+    Protocol::List{path: PathBuf::from("."), recursive: true, max_depth: None}
+}
+
+/// Hashes the local tree on the blocking thread pool to find copies of listed files in, like
+/// listing it for the peer. Files hashed before, like those compared with the peer's listing,
+/// aren't hashed again
+fn spawn_local_hashes(ctx: &SyncContext, tx: mpsc::UnboundedSender<Blocking>) {
+    let root = ctx.syncdir.clone();
+    let scope = ctx.scope.clone();
+    let filter = Arc::clone(&ctx.filter);
+    let index = Arc::clone(&ctx.index);
+    let max_file_size = ctx.max_file_size;
+    let algo = ctx.peer.hash_algo;
+    tokio::task::spawn_blocking(move || {
+        let opts = ListOptions{root: &root, filter: &filter, index: &index, max_file_size, algo};
+        let local = match list_entries(&scope, true, None, &opts) {
+            Ok(entries) => entries.into_iter()
+                .filter(|entry| matches!(entry.entity, EntityType::File) && !entry.skipped)
+                .map(|entry| (entry.hash, entry.path))
+                .collect(),
+            Err(e) => {
+                warn!(error = %e, "Failed hashing local files, fetching copies of them");
+                HashMap::new()
+            }
+        };
+        let _ = tx.send(Blocking::LocalHashes(local));
+    });
+}
+
+/// Copies the listed files that waited for the local tree to be hashed from local files with
+/// the same contents, queueing the others to be fetched
+fn copy_local(local: HashMap<Digest, PathBuf>, ctx: &mut SyncContext) {
+    for entry in ctx.fetches.set_local(local) {
+        match ctx.fetches.local_copy(&entry) {
+            Some(source) => copy_or_fetch(entry, &source, false, ctx),
+            None => ctx.fetches.push_listed(entry),
+        }
     }
 }
 
-fn handle_fs_event(event: Event, syncdir: &Path) -> Option<Protocol> {
-    let fullpath = env::current_dir().expect("Failed getting cwd").join(syncdir);
-    let path = &event.paths[0];
-    let strippath = path.strip_prefix(&fullpath).expect("Path escapes watched directory").to_path_buf();
+/// Requests for the files the initial sync fetches next, continuing interrupted transfers
+fn fetch_requests(ctx: &mut SyncContext) -> Vec<Protocol> {
+    if ctx.disk_full.is_paused() {
+        return Vec::new()
+    }
+    ctx.fetches.next_requests().into_iter().map(|request| resume_request(request, ctx)).collect()
+}
 
-    println!("FS event, path {}, stripped path {}", path.display(), strippath.display());
-    match event.kind {
-        EventKind::Create(File) => Some(Protocol::FsEventCreate{path: strippath, entity: EntityType::File}),
-        EventKind::Create(Folder) => Some(Protocol::FsEventCreate{path: strippath, entity: EntityType::Directory}),
-        EventKind::Modify(Data(_)) => Some(Protocol::FsEventModify{hash: hash_file(path.as_ref()), path: strippath}), 
-        EventKind::Modify(Name(Both)) => {
-            let path_to = &event.paths[1];
-            let strippath_to = path_to.strip_prefix(&fullpath).expect("Target path escapes watched directory").to_path_buf();
-            Some(Protocol::FsEventRename{path_from: strippath, path_to: strippath_to})
+/// Copies a listed file from a local one with the same hash or, with link, makes it a hard
+/// link of it, fetching it if that fails
+fn copy_or_fetch(entry: ListRespEntry, source: &Path, link: bool, ctx: &mut SyncContext) {
+    match copy_identical(&entry.path, source, entry.hash, FileAttrs{mode: entry.mode, mtime: entry.mtime}, link, ctx) {
+        Ok(true) => return,
+        Ok(false) => debug!(path = %entry.path.display(), source = %source.display(), "Local file changed since it was hashed, fetching instead"),
+        Err(e) => warn!(error = %e, "Failed copying identical local file, fetching instead"),
+    }
+    ctx.fetches.push(entry.path);
+}
+
+fn handle_incoming(message: Protocol, ctx: &mut SyncContext) -> Result<Vec<Protocol>, SyncError> {
+    match message {
+        // only compared once all of it arrived, nothing is fetched
+        Protocol::ListResp {entries, ..} if ctx.verify.is_some() => {
+            ctx.verify.get_or_insert_with(Vec::new).extend(entries);
+            Ok(Vec::new())
         }
-        EventKind::Remove(_) => Some(Protocol::FsEventDelete{path: strippath}),
-        _ => None
+        // changes go through handle_message like the peer's own, so nothing bypasses dry runs
+        // directories are created before any of the files in them are requested
+        Protocol::ListResp {entries, ..} => {
+            let mut groups: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+            for entry in &entries {
+                if let Some(group) = entry.link_group {
+                    groups.entry(group).or_default().push(entry.path.clone());
+                }
+            }
+            let (changes, fetches) = reconcile_listing(entries, ctx);
+            for change in changes {
+                if let Err(e) = ctx.handle_message(change) {
+                    warn!(error = %e, "Failed applying listed entry");
+                }
+            }
+            // requested as earlier fetches make room
+            let fetched: HashSet<PathBuf> = fetches.iter().map(|entry| entry.path.clone()).collect();
+            for entry in fetches {
+                // a hard link of a file that's the same here already is linked to that
+                let linked = entry.link_group.and_then(|group| groups.get(&group))
+                    .and_then(|paths| paths.iter().find(|path| !fetched.contains(*path)))
+                    .cloned();
+                match (linked, ctx.fetches.local_copy(&entry)) {
+                    (Some(source), _) => copy_or_fetch(entry, &source, true, ctx),
+                    // the local tree is hashed off the connection task first
+                    (None, _) if ctx.fetches.needs_local() => ctx.fetches.await_local(entry),
+                    (None, Some(source)) => copy_or_fetch(entry, &source, false, ctx),
+                    (None, None) => ctx.fetches.push_listed(entry),
+                }
+            }
+            Ok(Vec::new())
+        }
+        Protocol::FsEventBatch {mut events} => {
+            order_batch(&mut events);
+            let mut responses = Vec::new();
+            for event in events {
+                match handle_incoming(event, ctx) {
+                    Ok(mut event_responses) => responses.append(&mut event_responses),
+                    Err(e) => warn!(error = %e, "Failed handling batched event"),
+                }
+            }
+            Ok(responses)
+        }
+        message => Ok(ctx.handle_message(message)?.into_iter().collect()),
     }
 }
 
-async fn event_handler<'a>(addr: String, syncdir: PathBuf, channel: String, mut rx_watcher: mpsc::Receiver<Event>) {
-    let conn = TcpStream::connect(addr).await.unwrap();
-    let mut framed_conn = Framed::new(conn, Codec);
+/// Takes on the features announced in a peer's Hello or HelloResp, returning false if its
+/// protocol version isn't ours
+fn negotiate(version: u32, features: &[String], hostname: Option<String>, ctx: &mut SyncContext, outgoing: &mut OutgoingEvents, settings: &ConnectionSettings) -> bool {
+    if version != PROTOCOL_VERSION {
+        error!(version, supported = PROTOCOL_VERSION, "Peer speaks an incompatible protocol version, refusing to sync");
+        return false
+    }
+    ctx.peer = PeerFeatures::negotiate(features, ctx.hash_algo);
+    ctx.peer_hostname = hostname;
+    if ctx.peer.hash_algo != ctx.hash_algo {
+        warn!(algo = ?ctx.hash_algo, "Peer doesn't support the hash algorithm, falling back to xxh64");
+    }
+    outgoing.batch.set_batching(ctx.peer.batch);
+    outgoing.moves.set_algo(ctx.peer.hash_algo);
+    if let Some(checksums) = &settings.checksums {
+        checksums.store(ctx.peer.checksum, Ordering::Relaxed);
+    }
+    info!(peer_features = ?features, peer_hostname = ?ctx.peer_hostname, "Negotiated features with peer");
+    true
+}
+
+fn hello() -> Protocol {
+    Protocol::Hello{version: PROTOCOL_VERSION, features: local_features(), hostname: Some(local_hostname())}
+}
+
+/// Ends the pass made with --once or --verify once the peer's listing arrived in full and
+/// nothing is being fetched anymore
+async fn once_done(ctx: &mut SyncContext) -> ConnectionEnd {
+    let Some(listed) = ctx.verify.take() else {
+        ctx.writes.drain().await;
+        info!(fetched = ctx.fetches.fetched, failed = ctx.fetches.failed, "Initial sync done, exiting");
+        return ConnectionEnd::Synced{failed: ctx.fetches.failed}
+    };
+    let opts = ListOptions{root: &ctx.syncdir, filter: &ctx.filter, index: &ctx.index, max_file_size: ctx.max_file_size, algo: ctx.peer.hash_algo};
+    match list_entries(&ctx.syncdir, true, None, &opts) {
+        Ok(local) => {
+            let report = Report::compare(local, listed, &ctx.filter, &ctx.index);
+            report.log();
+            ConnectionEnd::Synced{failed: report.divergent()}
+        }
+        Err(e) => {
+            error!(error = %e, "Failed listing the sync directory");
+            ConnectionEnd::Synced{failed: 1}
+        }
+    }
+}
 
-    let chan = BytesMut::from(channel.as_str());
-    let _ = framed_conn.send(Package::Subscribe(chan.clone())).await;
+/// Next package to handle, the oldest deferred message if there's room for its contents
+/// by now, along with whether it was deferred
+async fn next_incoming(conn: &mut impl PackageConn, deferred: &mut VecDeque<(Bytes, Bytes)>, has_room: bool) -> (Option<io::Result<Package>>, bool) {
+    if has_room {
+        if let Some((channel, payload)) = deferred.pop_front() {
+            return (Some(Ok(Package::Message(channel, payload))), true)
+        }
+    }
+    (conn.next().await, false)
+}
 
-    while let true = tokio::select! {
-        Some(result) = framed_conn.next() => {
-            match result {
-                // Respond to pings with pongs with the same payload
-                Ok(Package::Ping(payload)) => {
-                    let _  = framed_conn.send(Package::Pong(payload)).await;
+async fn run_connection(framed_conn: &mut impl PackageConn, ctx: &mut SyncContext, chan: &Bytes, outgoing: &mut OutgoingEvents, shutdown: &CancellationToken, settings: &ConnectionSettings, last_heard: &mut Instant) -> ConnectionEnd {
+    // until the peer answers it's treated as one from before features were negotiated
+    ctx.peer = PeerFeatures::default();
+    ctx.peer_hostname = None;
+    // transfers of the previous connection aren't continued
+    ctx.stats.transfers_done();
+    ctx.fetches.clear();
+    ctx.lists.clear();
+    if let Some(listed) = &mut ctx.verify {
+        listed.clear();
+    }
+    outgoing.batch.set_batching(false);
+    outgoing.moves.set_algo(ctx.peer.hash_algo);
+    if let Some(checksums) = &settings.checksums {
+        checksums.store(false, Ordering::Relaxed);
+    }
+    if send_protocol(framed_conn, chan.clone(), &hello()).await.is_err() {
+        return ConnectionEnd::Disconnected
+    }
+    if ctx.initial_sync {
+        // the peer might not be listening yet, in which case the sync
+        // starts once it announces itself with a ping
+        for msg in [Protocol::Ping, root_listing()] {
+            if send_protocol(framed_conn, chan.clone(), &msg).await.is_err() {
+                return ConnectionEnd::Disconnected
+            }
+        }
+    }
+    let mut awaiting_listing = ctx.initial_sync;
+    // the answer to the request sent after the listing arrived, with --once
+    let mut once_listed = false;
+    // a half-open connection never reports an error, only unanswered pings reveal it
+    let mut next_ping = settings.keepalive.map(|period| Instant::now() + period);
+    let mut pong_pending = false;
+    // responses to requests answered on the blocking thread pool
+    let (blocking_tx, mut blocking_rx) = mpsc::unbounded_channel();
+    let mut throttle = settings.max_upload_kbps.map(Throttle::new);
+    let mut next_index_save = Instant::now() + INDEX_SAVE_INTERVAL;
+    let mut next_progress = Instant::now() + PROGRESS_INTERVAL;
+    // until the pong to the subscribe probe or a message on the channel arrives
+    let mut subscribed = false;
+    // messages read while too much received contents wait to be written, handled once
+    // there's room. The peer only sends contents asked for, and the next parts are asked
+    // for as these are handled, so this doesn't grow past the transfers in flight
+    let mut deferred = VecDeque::new();
+    loop {
+        for path in ctx.writes.take_unwritten() {
+            ctx.disk_full.hit(path);
+        }
+        // the peer takes both sides as having the file only once it's written here
+        for (path, hash) in ctx.writes.take_written() {
+            if ctx.peer.ack && send_protocol(framed_conn, chan.clone(), &Protocol::Written{path, hash}).await.is_err() {
+                return ConnectionEnd::Disconnected
+            }
+        }
+        let disk_deadline = ctx.disk_full.next_deadline();
+        let next_upload = throttle.as_mut().and_then(|throttle| throttle.next_send());
+        let deadline = [outgoing.pipeline.next_deadline(), outgoing.moves.next_deadline()].into_iter().flatten().min();
+        let flush_deadline = outgoing.batch.next_deadline();
+        let fetch_deadline = ctx.fetches.next_deadline();
+        let idle_deadline = settings.max_idle.map(|max_idle| *last_heard + max_idle);
+        let has_room = ctx.writes.has_room();
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                // events already seen are still worth delivering before leaving
+                let _ = flush_batch(framed_conn, chan, &mut outgoing.batch).await;
+                return ConnectionEnd::Shutdown
+            }
+            // anything heard from the broker, its pongs included, resets the idle deadline
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                return ConnectionEnd::Idle
+            }
+            _ = tokio::time::sleep_until(next_index_save) => {
+                ctx.index.save();
+                next_index_save = Instant::now() + INDEX_SAVE_INTERVAL;
+            }
+            _ = tokio::time::sleep_until(next_progress) => {
+                log_progress(&ctx.stats, settings.progress);
+                next_progress = Instant::now() + PROGRESS_INTERVAL;
+            }
+            _ = tokio::time::sleep_until(next_ping.unwrap_or_else(Instant::now)), if next_ping.is_some() => {
+                if pong_pending {
+                    warn!("Broker didn't answer keepalive ping in time");
+                    return ConnectionEnd::Disconnected
+                }
+                if framed_conn.send(Package::Ping(Bytes::from_static(b"keepalive"))).await.is_err() {
+                    return ConnectionEnd::Disconnected
+                }
+                pong_pending = true;
+                next_ping = settings.keepalive.map(|period| Instant::now() + period);
+            }
+            (result, was_deferred) = next_incoming(framed_conn, &mut deferred, has_room) => {
+                // whatever the broker sends, its pings included, shows the connection is alive
+                if !was_deferred && matches!(result, Some(Ok(_))) {
+                    *last_heard = Instant::now();
+                }
+                match result {
+                    // Respond to pings with pongs with the same payload
+                    Some(Ok(Package::Ping(payload))) => {
+                        if framed_conn.send(Package::Pong(payload)).await.is_err() {
+                            return ConnectionEnd::Disconnected
+                        }
+                    }
+                    Some(Ok(Package::Pong(payload))) if payload.as_ref() == SUBSCRIBE_PROBE => {
+                        if !subscribed {
+                            subscribed = true;
+                            debug!("Broker confirmed subscription");
+                        }
+                    }
+                    Some(Ok(Package::Pong(_))) => pong_pending = false,
+                    // every pair subscribes to its channel on a connection of its own, anything
+                    // else the broker delivers on it isn't meant for this sync directory
+                    Some(Ok(Package::Message(channel, _))) if channel != *chan => {
+                        warn!(channel = %String::from_utf8_lossy(&channel), "Dropping message for a channel that wasn't subscribed to");
+                    }
+                    // pings keep being answered meanwhile, only messages wait for the writes
+                    Some(Ok(Package::Message(channel, payload))) if !was_deferred && (!has_room || !deferred.is_empty()) => {
+                        subscribed = true;
+                        deferred.push_back((channel, payload));
+                    }
+                    Some(Ok(Package::Message(channel, payload))) => {
+                        // only subscribers are sent messages
+                        subscribed = true;
+                        // dropping what can't be decoded keeps the connection, the peer's
+                        // next messages may well be fine
+                        let message = match decode_message(&payload) {
+                            Ok(message) => message,
+                            Err(e @ SyncError::UnknownMessage(_)) => {
+                                warn!(error = %e, "Ignoring message");
+                                continue
+                            }
+                            Err(e) => {
+                                warn!(error = %e, len = payload.len(), "Dropping malformed message");
+                                continue
+                            }
+                        };
+                        let mut resend_listing = false;
+                        let mut listed = false;
+                        if awaiting_listing {
+                            match message {
+                                Protocol::ListResp{..} => listed = true,
+                                // asking again would fail the same way
+                                Protocol::Error{ref request, ..} if request == "List" => {
+                                    listed = true;
+                                    if settings.once {
+                                        ctx.fetches.failed += 1;
+                                    }
+                                }
+                                // the peer just joined and missed the initial listing request
+                                Protocol::Ping => resend_listing = true,
+                                _ => {}
+                            }
+                            awaiting_listing = !listed;
+                        }
+                        let completed = completed_path(&message);
+                        ctx.fetches.touch(&message);
+                        ctx.lists.track(&message);
+                        let is_error = matches!(message, Protocol::Error{..});
+                        if settings.once {
+                            once_listed |= matches!(message, Protocol::StatusResp{..}) || matches!(message, Protocol::Error{ref request, ..} if request == "Status");
+                        }
+                        match message {
+                            // a peer joining later announces itself, the one already there answers
+                            Protocol::Hello{version, features, hostname} => {
+                                let resp = Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: Some(local_hostname())};
+                                if send_protocol(framed_conn, channel, &resp).await.is_err() {
+                                    return ConnectionEnd::Disconnected
+                                }
+                                if !negotiate(version, &features, hostname, ctx, outgoing, settings) {
+                                    return ConnectionEnd::Incompatible
+                                }
+                                continue
+                            }
+                            Protocol::HelloResp{version, features, hostname} => {
+                                if !negotiate(version, &features, hostname, ctx, outgoing, settings) {
+                                    return ConnectionEnd::Incompatible
+                                }
+                                continue
+                            }
+                            Protocol::List{path, recursive, max_depth} => {
+                                spawn_listing(path, recursive, max_depth, ctx, channel, blocking_tx.clone());
+                                continue
+                            }
+                            Protocol::Status => {
+                                spawn_status(ctx, channel, blocking_tx.clone());
+                                continue
+                            }
+                            request @ (Protocol::Get{..} | Protocol::GetChunk{..} | Protocol::BlockSig{..}) => {
+                                // the file may be one still waiting to be written
+                                for path in ctx.writes.drain().await {
+                                    ctx.echoes.suppress(&path);
+                                }
+                                spawn_read(request, ctx, channel, blocking_tx.clone());
+                                continue
+                            }
+                            Protocol::Rescan{path} => {
+                                let path = path.unwrap_or_else(|| PathBuf::from("."));
+                                match resolve_path(&path, true, ctx) {
+                                    Ok(dir) => spawn_rescan(dir, Some(path), ctx, channel, blocking_tx.clone()),
+                                    Err(e) => {
+                                        if send_protocol(framed_conn, channel, &error_response("Rescan", Some(path), &e)).await.is_err() {
+                                            return ConnectionEnd::Disconnected
+                                        }
+                                    }
+                                }
+                                continue
+                            }
+                            _ => {}
+                        }
+                        // changes after a write may touch the file being written
+                        let independent = match &message {
+                            Protocol::GetResp{path, link_target: None, ..} => !ctx.writes.is_queued(path),
+                            Protocol::Ping | Protocol::Pong | Protocol::Written{..} => true,
+                            _ => false,
+                        };
+                        if !independent {
+                            for path in ctx.writes.drain().await {
+                                ctx.echoes.suppress(&path);
+                            }
+                        }
+                        match handle_incoming(message, ctx) {
+                            Ok(mut responses) => {
+                                if ctx.fetches.start_hashing() {
+                                    spawn_local_hashes(ctx, blocking_tx.clone());
+                                }
+                                if resend_listing {
+                                    responses.push(root_listing());
+                                }
+                                // listings go out as earlier ones make room
+                                ctx.lists.take(&mut responses);
+                                responses.append(&mut ctx.lists.next_requests());
+                                // queued fetches are requested as finished ones make room
+                                let duplicates = ctx.fetches.track(completed.as_deref(), &responses, is_error);
+                                // copies are made from the file just received, once it's written
+                                if !duplicates.is_empty() {
+                                    for path in ctx.writes.drain().await {
+                                        ctx.echoes.suppress(&path);
+                                    }
+                                }
+                                if let Some(source) = completed.as_deref() {
+                                    for duplicate in duplicates {
+                                        copy_or_fetch(duplicate.entry, source, duplicate.link, ctx);
+                                    }
+                                }
+                                responses.append(&mut fetch_requests(ctx));
+                                ctx.disk_full.hold(&mut responses);
+                                // a long listing comes in several parts, the answer to a
+                                // request sent after the first one follows the last one
+                                if settings.once && listed {
+                                    responses.push(Protocol::Status);
+                                }
+                                for response in responses {
+                                    if send_response(framed_conn, channel.clone(), &response, throttle.as_mut()).await.is_err() {
+                                        return ConnectionEnd::Disconnected
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                warn!(error = %e, "Failed handling message");
+                                if let Some(path) = completed.clone().filter(|_| e.is_storage_full()) {
+                                    ctx.disk_full.hit(path);
+                                }
+                                ctx.fetches.track(completed.as_deref(), &[], true);
+                                for request in fetch_requests(ctx).into_iter().chain(ctx.lists.next_requests()) {
+                                    if send_protocol(framed_conn, channel.clone(), &request).await.is_err() {
+                                        return ConnectionEnd::Disconnected
+                                    }
+                                }
+                            }
+                        }
+                        ctx.fsync.flush();
+                        if once_listed && ctx.fetches.is_idle() && !ctx.disk_full.is_paused() {
+                            return once_done(ctx).await
+                        }
+                    }
+                    // Do nothing for other messages (client is not interested in them)
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        error!(error = %e, "Connection error");
+                        return if subscribed { ConnectionEnd::Disconnected } else { ConnectionEnd::Refused }
+                    }
+                    None if !subscribed => return ConnectionEnd::Refused,
+                    None => return ConnectionEnd::Disconnected
+                }
+            }
+            _ = tokio::time::sleep_until(fetch_deadline.unwrap_or_else(Instant::now)), if fetch_deadline.is_some() => {
+                for path in ctx.fetches.expire(Instant::now()) {
+                    abandon_partial_write(&path, ctx);
                 }
-                Ok(Package::Message(channel, payload)) => {
-                    let deserialized: Protocol = ciborium::de::from_reader(payload.as_ref()).unwrap();
-                    if let Some(response) = handle_message(deserialized, syncdir.as_path()) {
-                        let mut msg = Vec::new();
-                        let _ = ciborium::ser::into_writer(&response, &mut msg);
-                        let _ = framed_conn.send(Package::Message(channel, BytesMut::from(msg.as_slice()))).await;
+                for request in fetch_requests(ctx) {
+                    if send_protocol(framed_conn, chan.clone(), &request).await.is_err() {
+                        return ConnectionEnd::Disconnected
                     }
                 }
-                // Do nothing for other messages (client is not interested in them)
-                Ok(_) => {}
-                Err(e) => {
-                    println!("error {:?}", e);
+                if once_listed && ctx.fetches.is_idle() && !ctx.disk_full.is_paused() {
+                    return once_done(ctx).await
                 }
-            };
-            true
+            }
+            _ = tokio::time::sleep_until(disk_deadline.unwrap_or_else(Instant::now)), if disk_deadline.is_some() => {
+                for path in ctx.disk_full.resume(Instant::now()) {
+                    ctx.fetches.push(path);
+                }
+                for request in fetch_requests(ctx) {
+                    if send_protocol(framed_conn, chan.clone(), &request).await.is_err() {
+                        return ConnectionEnd::Disconnected
+                    }
+                }
+                if once_listed && ctx.fetches.is_idle() && !ctx.disk_full.is_paused() {
+                    return once_done(ctx).await
+                }
+            }
+            // the watcher reports the write only now, which may be later than expected
+            Some(path) = ctx.writes.next_written() => ctx.echoes.suppress(&path),
+            Some(done) = blocking_rx.recv() => match done {
+                Blocking::Response(channel, response) => {
+                    if send_response(framed_conn, channel, &response, throttle.as_mut()).await.is_err() {
+                        return ConnectionEnd::Disconnected
+                    }
+                }
+                Blocking::LocalHashes(local) => {
+                    // copies may be made from files still waiting to be written
+                    for path in ctx.writes.drain().await {
+                        ctx.echoes.suppress(&path);
+                    }
+                    copy_local(local, ctx);
+                    for request in fetch_requests(ctx) {
+                        if send_protocol(framed_conn, chan.clone(), &request).await.is_err() {
+                            return ConnectionEnd::Disconnected
+                        }
+                    }
+                    if once_listed && ctx.fetches.is_idle() && !ctx.disk_full.is_paused() {
+                        return once_done(ctx).await
+                    }
+                }
+            },
+            msg = outgoing.rx.recv() => {
+                let event = match msg {
+                    Some(WatcherMsg::Event(event)) => event,
+                    Some(WatcherMsg::Overflow(dir)) => {
+                        spawn_rescan(dir, None, ctx, chan.clone(), blocking_tx.clone());
+                        continue
+                    }
+                    None => return ConnectionEnd::WatcherClosed,
+                };
+                ctx.stats.record_event();
+                let ready = outgoing.pipeline.push(event);
+                if send_fs_events(framed_conn, ctx, chan, outgoing, ready).await.is_err() {
+                    return ConnectionEnd::Disconnected
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+                let expired = outgoing.pipeline.pop_expired(now);
+                if send_fs_events(framed_conn, ctx, chan, outgoing, expired).await.is_err() {
+                    return ConnectionEnd::Disconnected
+                }
+                let deletes = outgoing.moves.pop_expired(now);
+                if queue_messages(framed_conn, chan, &mut outgoing.batch, deletes).await.is_err() {
+                    return ConnectionEnd::Disconnected
+                }
+            }
+            _ = tokio::time::sleep_until(next_upload.unwrap_or_else(Instant::now)), if next_upload.is_some() => {
+                let Some(throttle) = throttle.as_mut() else { continue };
+                while let Some((channel, payload)) = throttle.pop(Instant::now()) {
+                    if framed_conn.send(Package::Message(channel, payload)).await.is_err() {
+                        return ConnectionEnd::Disconnected
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(flush_deadline.unwrap_or_else(Instant::now)), if flush_deadline.is_some() => {
+                if flush_batch(framed_conn, chan, &mut outgoing.batch).await.is_err() {
+                    return ConnectionEnd::Disconnected
+                }
+            }
         }
-        Some(event) = rx_watcher.recv() => {
-            if let Some(response) = handle_fs_event(event, syncdir.as_path()) {
-                let mut serialized = Vec::new();
-                let _ = ciborium::ser::into_writer(&response, &mut serialized);
-                let _ = framed_conn.send(Package::Message(chan.clone(), BytesMut::from(serialized.as_slice()))).await;
+    }
+}
+
+/// Logs how far each file being fetched got, along with a summary of all of them at info
+/// if asked for with --progress
+fn log_progress(stats: &SyncStats, summary: bool) {
+    let transfers = stats.transfers();
+    if transfers.is_empty() {
+        return
+    }
+    for transfer in &transfers {
+        debug!(path = %transfer.path.display(), received = transfer.received, total = transfer.total, "Transfer progress");
+    }
+    if summary {
+        let described: Vec<_> = transfers.iter().map(|transfer| match transfer.total {
+            Some(total) if total > 0 => format!("{} {} of {} ({}%)", transfer.path.display(), human_size(transfer.received), human_size(total), transfer.received * 100 / total),
+            _ => format!("{} {}", transfer.path.display(), human_size(transfer.received)),
+        }).collect();
+        info!(files = transfers.len(), "Transferring {}", described.join(", "));
+    }
+}
+
+/// Leaves the channel and flushes everything still buffered before the connection is dropped
+async fn unsubscribe(framed_conn: &mut impl PackageConn, chan: &Bytes) {
+    if let Err(e) = framed_conn.send(Package::Unsubscribe(chan.clone())).await {
+        warn!(error = %e, "Failed unsubscribing");
+        return
+    }
+    if let Err(e) = framed_conn.close().await {
+        warn!(error = %e, "Failed closing connection");
+    }
+}
+
+/// Removes temporary files of transfers that won't be completed anymore
+fn discard_partial_writes(ctx: &mut SyncContext) {
+    for (tmppath, _) in ctx.partial_writes.drain() {
+        match fs::remove_file(&tmppath) {
+            Ok(()) => info!(path = %tmppath.display(), "Discarded incomplete transfer"),
+            Err(e) => warn!(error = %SyncError::fs(tmppath, e), "Failed discarding incomplete transfer"),
+        }
+    }
+}
+
+/// Syncs a directory over connections to the broker until asked to stop, failing if the
+/// broker or the peer refused syncing or, with --once, files couldn't be fetched
+async fn event_handler(transport: impl Transport, channel: String, mut ctx: SyncContext, pipeline: EventPipeline, settings: ConnectionSettings, rx_watcher: mpsc::Receiver<WatcherMsg>, shutdown: CancellationToken) -> PairEnd {
+    let chan = Bytes::copy_from_slice(channel.as_bytes());
+    let mut backoff = settings.reconnect.clone();
+    let mut failed = false;
+    let mut idle = false;
+    // carried over reconnects, a broker that accepts connections but never answers on them
+    // is as silent as one that can't be reached
+    let mut last_heard = Instant::now();
+    let mut outgoing = OutgoingEvents {
+        rx: rx_watcher,
+        pipeline,
+        moves: MoveDetector::new(ctx.syncdir.clone(), ctx.hash_algo),
+        batch: EventBatch::new(),
+    };
+
+    loop {
+        let idle_deadline = settings.max_idle.map(|max_idle| last_heard + max_idle);
+        let connected = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                idle = true;
+                break
+            }
+            connected = transport.connect() => connected,
+        };
+        match connected {
+            Ok(framed_conn) => {
+                let mut framed_conn = SealedConn::new(framed_conn, settings.cipher.clone());
+                info!(address = %transport.address(), channel = %channel, "Connected");
+                let subscribe = async {
+                    framed_conn.send(Package::Subscribe(chan.clone())).await?;
+                    framed_conn.send(Package::Ping(Bytes::from_static(SUBSCRIBE_PROBE))).await
+                };
+                if subscribe.await.is_ok() {
+                    backoff.reset();
+                    match run_connection(&mut framed_conn, &mut ctx, &chan, &mut outgoing, &shutdown, &settings, &mut last_heard).await {
+                        ConnectionEnd::WatcherClosed => break,
+                        ConnectionEnd::Shutdown => {
+                            unsubscribe(&mut framed_conn, &chan).await;
+                            break
+                        }
+                        ConnectionEnd::Incompatible => {
+                            unsubscribe(&mut framed_conn, &chan).await;
+                            failed = true;
+                            break
+                        }
+                        ConnectionEnd::Synced {failed: unfetched} => {
+                            unsubscribe(&mut framed_conn, &chan).await;
+                            failed = unfetched > 0;
+                            break
+                        }
+                        ConnectionEnd::Idle => {
+                            unsubscribe(&mut framed_conn, &chan).await;
+                            idle = true;
+                            break
+                        }
+                        ConnectionEnd::Disconnected => warn!(address = %transport.address(), "Connection lost"),
+                        ConnectionEnd::Refused => {
+                            error!(address = %transport.address(), channel = %channel, "Broker closed the connection without confirming the subscription, it refused the channel");
+                            failed = true;
+                            break
+                        }
+                    }
+                }
             }
-            true
+            Err(e) => warn!(address = %transport.address(), error = %e, "Failed connecting"),
         }
-        else => {
-            false
+        let Some(delay) = backoff.next_delay() else {
+            error!(address = %transport.address(), channel = %channel, "Giving up connecting to the broker");
+            failed = true;
+            break
+        };
+        info!(backoff_ms = delay.as_millis() as u64, "Reconnecting");
+        ctx.stats.record_reconnect();
+        let idle_deadline = settings.max_idle.map(|max_idle| last_heard + max_idle);
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                idle = true;
+                break
+            }
+            _ = tokio::time::sleep(delay) => {}
         }
-    } {}
+    }
+    if idle {
+        error!(address = %transport.address(), channel = %channel, idle_secs = last_heard.elapsed().as_secs(), "Nothing heard from the broker for too long, exiting");
+        // the process exits for a supervisor to start it over, the other pairs with it
+        shutdown.cancel();
+    }
+    ctx.writes.drain().await;
+    discard_partial_writes(&mut ctx);
+    ctx.index.save();
+    match (idle, failed) {
+        (true, _) => PairEnd::Idle,
+        (false, true) => PairEnd::Failed,
+        (false, false) => PairEnd::Done,
+    }
+}
+
+/// Resolves once the process is asked to stop with Ctrl-C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            },
+            Err(e) => {
+                warn!(error = %e, "Failed installing SIGTERM handler");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Transport to the broker at address, through a unix domain socket if it's a unix:PATH one
+#[cfg(unix)]
+fn broker_transport(address: &str, prefer_ipv4: bool, bind: Option<IpAddr>, tls: Option<Arc<ClientConfig>>, stats: Arc<SyncStats>, checksums: Option<Arc<AtomicBool>>) -> impl Transport {
+    match address.strip_prefix(UNIX_ADDRESS_PREFIX) {
+        Some(path) => Either::Right(UnixTransport::new(PathBuf::from(path), stats, checksums)),
+        None => Either::Left(TcpTransport::new(address.to_string(), prefer_ipv4, bind, tls, stats, checksums)),
+    }
+}
+
+#[cfg(not(unix))]
+fn broker_transport(address: &str, prefer_ipv4: bool, bind: Option<IpAddr>, tls: Option<Arc<ClientConfig>>, stats: Arc<SyncStats>, checksums: Option<Arc<AtomicBool>>) -> impl Transport {
+    TcpTransport::new(address.to_string(), prefer_ipv4, bind, tls, stats, checksums)
+}
+
+/// Starts watching the scope for changes, exiting if the watcher can't be set up
+fn watch(scope: &Path, forwarder: EventForwarder, recursive: RecursiveMode) -> RecommendedWatcher {
+    RecommendedWatcher::new(forwarder, Config::default())
+        .and_then(|mut watcher| watcher.watch(scope, recursive).map(|_| watcher))
+        .unwrap_or_else(|e| {
+            let hint = match e.kind {
+                notify::ErrorKind::MaxFilesWatch => format!(
+                    ", every directory below it needs an inotify watch. Raise the limit with e.g. `sysctl fs.inotify.max_user_watches={}` or only watch the top level with --watch-mode flat",
+                    RAISED_INOTIFY_WATCHES),
+                _ => String::new(),
+            };
+            Args::command().error(ErrorKind::Io, format!("can't watch {}: {}{}", scope.display(), e, hint)).exit()
+        })
 }
 
 fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = args.config.clone() {
+        let merged = FileConfig::load(&path).and_then(|file| config::merge(&mut args, &matches, file));
+        if let Err(e) = merged {
+            Args::command().error(ErrorKind::InvalidValue, e).exit();
+        }
+    }
+    let mut pairs = args.pair.clone();
+    if let Some(channel) = args.channel.clone() {
+        pairs.insert(0, (args.syncdir.clone(), channel));
+    }
+    if pairs.is_empty() {
+        Args::command().error(ErrorKind::MissingRequiredArgument, "no channel given on the command line or in the config file").exit()
+    }
+    // connections on the same channel would receive each other's messages
+    let mut channels = HashSet::new();
+    if let Some((_, channel)) = pairs.iter().find(|(_, channel)| !channels.insert(channel)) {
+        Args::command().error(ErrorKind::ValueValidation, format!("channel {} is used by more than one sync directory", channel)).exit()
+    }
+    let log_filter = match args.log_level {
+        Some(level) => EnvFilter::new(level.as_str()),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    if !args.tls && (args.ca_cert.is_some() || args.insecure_skip_verify) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--ca-cert and --insecure-skip-verify only apply with --tls").exit()
+    }
+    if args.read_only && (args.initial_sync || args.once) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--read-only can't fetch files for --initial-sync or --once").exit()
+    }
+    if args.verify && (args.initial_sync || args.once) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--verify doesn't fetch anything, it can't be combined with --initial-sync or --once").exit()
+    }
+    // a broker with nothing to deliver is only heard from through the pongs to keepalive pings
+    if args.max_idle_secs > 0 && (args.keepalive_secs == 0 || args.max_idle_secs <= args.keepalive_secs) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--max-idle-secs needs --keepalive-secs pings to hear from a quiet broker, and has to be longer than the time between them").exit()
+    }
+    let unix_socket = args.address.starts_with(UNIX_ADDRESS_PREFIX);
+    if cfg!(not(unix)) && unix_socket {
+        Args::command().error(ErrorKind::InvalidValue, "unix domain sockets aren't supported on this platform").exit()
+    }
+    if args.tls && unix_socket {
+        Args::command().error(ErrorKind::ArgumentConflict, "--tls doesn't apply to unix domain sockets").exit()
+    }
+    if let Some(bind) = args.bind {
+        if unix_socket {
+            Args::command().error(ErrorKind::ArgumentConflict, "--bind doesn't apply to unix domain sockets").exit()
+        }
+        // an address that isn't one of this host's can't be bound to
+        if let Err(e) = std::net::TcpListener::bind((bind, 0)) {
+            Args::command().error(ErrorKind::ValueValidation, format!("can't connect from {}, it isn't an address of this host: {}", bind, e)).exit()
+        }
+    }
+    let tls = args.tls.then(|| tls_config(args.ca_cert.as_deref(), args.insecure_skip_verify)
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit()));
+    tracing_subscriber::fmt().with_env_filter(log_filter).init();
+    debug!(?args, "Configuration");
     let rt = Builder::new_multi_thread()
         .worker_threads(1)
         .enable_all()
         .build()
         .unwrap();
+    let metrics_listener = args.metrics_addr.map(|addr| rt.block_on(tokio::net::TcpListener::bind(addr)).unwrap_or_else(|e| {
+        Args::command().error(ErrorKind::Io, format!("can't serve metrics on {}: {}", addr, e)).exit()
+    }));
+
+    let shutdown = CancellationToken::new();
+    let signal_token = shutdown.clone();
+    rt.spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        signal_token.cancel();
+    });
+    let settings = ConnectionSettings {
+        keepalive: (args.keepalive_secs > 0).then(|| Duration::from_secs(args.keepalive_secs)),
+        max_upload_kbps: (args.max_upload_kbps > 0).then_some(args.max_upload_kbps),
+        cipher: None,
+        progress: args.progress,
+        once: args.once || args.verify,
+        checksums: None,
+        reconnect: match args.max_reconnect_attempts {
+            0 => reconnect_backoff(),
+            attempts => reconnect_backoff().with_max_attempts(attempts),
+        },
+        max_idle: (args.max_idle_secs > 0).then(|| Duration::from_secs(args.max_idle_secs)),
+    };
+    // each pair gets its own watcher, context and connection so nothing is shared between them
+    // with --once nothing is watched, the senders stand in for the watchers so the handlers
+    // don't take the watcher as gone
+    let mut unwatched = Vec::new();
+    let mut handles = Vec::new();
+    let mut pair_stats = Vec::new();
+    for (syncdir, channel) in pairs {
+        // the watcher reports paths under the directory it was given, resolving it once
+        // makes those and the paths built from the root in handlers agree whatever the
+        // working directory is or however the directory was spelled
+        let syncdir = std::fs::canonicalize(&syncdir).unwrap_or_else(|e| {
+            Args::command().error(ErrorKind::ValueValidation, format!("can't use sync directory {}: {}", syncdir.display(), e)).exit()
+        });
+        let scope = match &args.subpath {
+            Some(subpath) => std::fs::canonicalize(syncdir.join(subpath))
+                .map_err(|e| e.to_string())
+                .and_then(|scope| if scope.starts_with(&syncdir) { Ok(scope) } else { Err("it leads outside the sync directory".to_string()) })
+                .unwrap_or_else(|e| {
+                    Args::command().error(ErrorKind::ValueValidation, format!("can't use subpath {}: {}", subpath.display(), e)).exit()
+                }),
+            None => syncdir.clone(),
+        };
+        let (tx, rx) = mpsc::channel(args.event_buffer.get());
+        let stats = Arc::new(SyncStats::default());
+        if args.once || args.verify {
+            unwatched.push(tx);
+        } else {
+            let recursive = args.watch_mode.recursive_mode();
+            // the task keeps the watcher for as long as it runs
+            let removed = Arc::new(AtomicBool::new(false));
+            let watcher = watch(&scope, EventForwarder::new(tx.clone(), scope.clone(), Arc::clone(&removed), Arc::clone(&stats)), recursive);
+            rt.spawn(keep_watching(watcher, scope.clone(), recursive, removed, tx));
+        }
+
+        let index = Arc::new(FileIndex::load(&syncdir, args.dry_run || args.verify));
+        let ctx = SyncContext {
+            filter: Arc::new(PathFilter::load(&syncdir, &args.ignore, &args.include, args.skip_hidden, !args.no_builtin_ignores, args.subpath.clone())),
+            syncdir: syncdir.clone(),
+            scope,
+            initial_sync: args.initial_sync || args.once || args.verify,
+            echoes: EchoSuppressor::new(),
+            partial_writes: HashMap::new(),
+            compress: args.compress,
+            get_retries: HashMap::new(),
+            fetches: FetchQueue::new(args.max_concurrent_transfers, args.dedup, (args.get_timeout_secs > 0).then(|| Duration::from_secs(args.get_timeout_secs))),
+            lists: ListQueue::new(args.max_concurrent_lists),
+            disk_full: DiskFull::default(),
+            writes: WriteQueue::new(Arc::clone(&index), args.fsync),
+            fsync: Fsync::new(args.fsync),
+            delete_mode: args.delete_mode,
+            max_file_size: args.max_file_size,
+            hash_algo: args.hash_algo,
+            peer: PeerFeatures::default(),
+            peer_hostname: None,
+            conflict_mode: args.conflict,
+            dry_run: args.dry_run,
+            // whatever the peer sends while verifying is left alone
+            read_only: args.read_only || args.verify,
+            verify: args.verify.then(Vec::new),
+            index,
+            stats,
+        };
+        let cipher = args.psk.as_ref().map(|psk| PayloadCipher::new(psk, &channel).map(Arc::new).unwrap_or_else(|e| {
+            Args::command().error(ErrorKind::ValueValidation, e).exit()
+        }));
+        pair_stats.push((channel.clone(), syncdir.clone(), Arc::clone(&ctx.stats)));
+        // the cipher's tag catches corrupted messages already
+        let checksums = cipher.is_none().then(|| Arc::new(AtomicBool::new(false)));
+        let transport = broker_transport(&args.address, args.prefer_ipv4, args.bind, tls.clone(), Arc::clone(&ctx.stats), checksums.clone());
+        let span = info_span!("pair", channel = %channel, syncdir = %syncdir.display());
+        handles.push(rt.spawn(event_handler(
+            transport,
+            channel,
+            ctx,
+            EventPipeline::new(Duration::from_millis(args.debounce_ms), Duration::from_millis(args.min_resync_interval_ms)),
+            ConnectionSettings{cipher, checksums, ..settings.clone()},
+            rx,
+            shutdown.clone()
+        ).instrument(span)));
+    }
+
+    if let Some(listener) = metrics_listener {
+        rt.spawn(serve_metrics(listener, Arc::new(pair_stats)));
+    }
+
+    let mut failed = false;
+    let mut idle = false;
+    rt.block_on(async {
+        for handle in handles {
+            match handle.await.unwrap_or(PairEnd::Failed) {
+                PairEnd::Done => {}
+                PairEnd::Failed => failed = true,
+                PairEnd::Idle => idle = true,
+            }
+        }
+    });
+    if idle {
+        std::process::exit(EXIT_IDLE)
+    }
+    if failed {
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use notify::event::{AccessKind, AccessMode, DataChange};
+    use syncd::codec::Codec;
+    use syncd::filter::IGNORE_FILE;
+    use syncd::hash::Digest;
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+
+    fn event(kind: EventKind, path: &Path) -> Event {
+        Event::new(kind).add_path(path.to_path_buf())
+    }
+
+    #[tokio::test]
+    async fn flat_watch_only_reports_the_top_level() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(syncdir.path()).unwrap();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        let (tx, mut rx) = mpsc::channel(64);
+        let forwarder = EventForwarder::new(tx, root.clone(), Arc::new(AtomicBool::new(false)), Arc::new(SyncStats::default()));
+        let _watcher = watch(&root, forwarder, WatchMode::Flat.recursive_mode());
+        std::fs::write(root.join("dir/nested.txt"), "nested").unwrap();
+        std::fs::write(root.join("top.txt"), "top").unwrap();
+        let mut paths = Vec::new();
+        tokio::time::timeout(TIMEOUT, async {
+            while !paths.contains(&root.join("top.txt")) {
+                if let Some(WatcherMsg::Event(event)) = rx.recv().await {
+                    paths.extend(event.paths);
+                }
+            }
+        }).await.expect("top level change went unnoticed");
+        // whatever the nested write would have caused had time to show up too
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        while let Ok(msg) = rx.try_recv() {
+            if let WatcherMsg::Event(event) = msg {
+                paths.extend(event.paths);
+            }
+        }
+        assert!(!paths.iter().any(|path| path.starts_with(root.join("dir"))), "{paths:?}");
+    }
+
+    #[tokio::test]
+    async fn change_the_watcher_lost_is_found_by_a_rescan() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        std::fs::write(root.join("unnoticed.txt"), "unnoticed").unwrap();
+        pair.watcher.send(WatcherMsg::Overflow(root)).await.unwrap();
+        let events = next_events(&mut conn).await;
+        assert!(events.iter().any(|event| matches!(event, Protocol::FsEventModify {path, ..} | Protocol::FsEventCreate {path, ..} if path == Path::new("unnoticed.txt"))), "{events:?}");
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[test]
+    fn modification_of_ignored_file_is_dropped() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join(IGNORE_FILE), "*.tmp\n").unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        for name in ["scratch.tmp", "kept.txt"] {
+            fs::write(ctx.syncdir.join(name), name).unwrap();
+        }
+        let modify = EventKind::Modify(Data(DataChange::Content));
+        assert!(handle_fs_event(event(modify, &ctx.syncdir.join("scratch.tmp")), &mut ctx).unwrap().is_none());
+        let sent = handle_fs_event(event(modify, &ctx.syncdir.join("kept.txt")), &mut ctx).unwrap();
+        assert!(matches!(sent, Some(Protocol::FsEventModify {path, ..}) if path == Path::new("kept.txt")));
+    }
 
-    let (tx, rx) = mpsc::channel(32);
-    let mut watcher = RecommendedWatcher::new(move |res: Result<notify::event::Event, notify::Error>| {
-        let _ = tx.blocking_send(res.unwrap());
-    }, Config::default()).unwrap();
-    
-    watcher.watch(&args.syncdir, RecursiveMode::Recursive).unwrap();
-
-    let handle = rt.spawn(event_handler(
-        args.address.clone(),
-        args.syncdir.clone(),
-        args.channel.clone(),
-        rx
-    ));
-    
-    let _ = rt.block_on(handle);
+    #[test]
+    fn modification_of_file_removed_before_it_was_hashed_is_dropped() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let file = ctx.syncdir.join("file.txt");
+        fs::write(&file, "file").unwrap();
+        let modified = event(EventKind::Modify(Data(DataChange::Content)), &file);
+        fs::remove_file(&file).unwrap();
+        assert_eq!(handle_fs_event(modified, &mut ctx).unwrap(), None);
+    }
+
+    #[test]
+    fn unspecific_event_is_sent_for_the_peer_to_compare() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (mut sender, mut receiver) = (SyncContext::new(from.path()).unwrap(), SyncContext::new(to.path()).unwrap());
+        std::fs::create_dir(sender.syncdir.join("dir")).unwrap();
+        std::fs::write(sender.syncdir.join("file.txt"), "changed somehow").unwrap();
+        let unknown = |path: &str, ctx: &mut SyncContext| handle_fs_event(event(EventKind::Any, &ctx.syncdir.join(path)), ctx).unwrap().unwrap();
+        let hash = syncd::fs::hash_bytes(b"changed somehow", HashAlgo::Xxh64);
+        let file = unknown("file.txt", &mut sender);
+        assert_eq!(file, Protocol::FsEventUnknown{path: PathBuf::from("file.txt"), entity: EntityType::File, hash});
+        assert_eq!(receiver.handle_message(file).unwrap(), Some(Protocol::Get{path: PathBuf::from("file.txt")}));
+        let contents = sender.handle_message(Protocol::Get{path: PathBuf::from("file.txt")}).unwrap().unwrap();
+        assert_eq!(receiver.handle_message(contents).unwrap(), None);
+        futures::executor::block_on(receiver.writes.drain());
+        assert_eq!(std::fs::read(receiver.syncdir.join("file.txt")).unwrap(), b"changed somehow");
+        let dir = unknown("dir", &mut sender);
+        assert!(matches!(dir, Protocol::FsEventUnknown {entity: EntityType::Directory, ..}));
+        assert_eq!(receiver.handle_message(dir).unwrap(), Some(Protocol::List{path: PathBuf::from("dir"), recursive: true, max_depth: None}));
+        assert!(receiver.syncdir.join("dir").is_dir());
+        assert!(matches!(unknown("gone.txt", &mut sender), Protocol::FsEventDelete {..}));
+    }
+
+    #[test]
+    fn editor_temporary_files_send_nothing() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        // what vim does saving file.txt
+        for name in [".file.txt.swp", "4913", "file.txt~"] {
+            let temporary = ctx.syncdir.join(name);
+            std::fs::write(&temporary, "temporary").unwrap();
+            for kind in [EventKind::Create(File), EventKind::Modify(Data(DataChange::Content))] {
+                assert_eq!(handle_fs_event(event(kind, &temporary), &mut ctx).unwrap(), None, "{name}");
+            }
+            std::fs::remove_file(&temporary).unwrap();
+            assert_eq!(handle_fs_event(event(EventKind::Remove(RemoveKind::File), &temporary), &mut ctx).unwrap(), None, "{name}");
+        }
+        let file = ctx.syncdir.join("file.txt");
+        std::fs::write(&file, "saved").unwrap();
+        let sent = handle_fs_event(event(EventKind::Modify(Data(DataChange::Content)), &file), &mut ctx).unwrap();
+        assert!(matches!(sent, Some(Protocol::FsEventModify {..})), "{sent:?}");
+    }
+
+    #[test]
+    fn access_event_sends_nothing() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let file = ctx.syncdir.join("file.txt");
+        std::fs::write(&file, "file").unwrap();
+        // the echo of a write of ours is left for the write's modify event
+        ctx.echoes.suppress(Path::new("file.txt"));
+        for kind in [AccessKind::Any, AccessKind::Read, AccessKind::Open(AccessMode::Read), AccessKind::Close(AccessMode::Write)] {
+            assert_eq!(handle_fs_event(event(EventKind::Access(kind), &file), &mut ctx).unwrap(), None, "{kind:?}");
+        }
+        let written = event(EventKind::Modify(Data(DataChange::Content)), &file);
+        assert_eq!(handle_fs_event(written.clone(), &mut ctx).unwrap(), None);
+        assert!(matches!(handle_fs_event(written, &mut ctx).unwrap(), Some(Protocol::FsEventModify {..})));
+    }
+
+    #[test]
+    fn only_events_within_the_subpath_are_sent() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        for dir in ["docs", "src"] {
+            std::fs::create_dir(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join("file.txt"), dir).unwrap();
+        }
+        ctx.scope = root.join("docs");
+        let modify = |path: &str| event(EventKind::Modify(Data(DataChange::Content)), &root.join(path));
+        let rename = |from: &str, to: &str| event(EventKind::Modify(Name(Both)), &root.join(from)).add_path(root.join(to));
+        assert!(handle_fs_event(modify("src/file.txt"), &mut ctx).unwrap().is_none());
+        assert!(handle_fs_event(rename("src/file.txt", "src/moved.txt"), &mut ctx).unwrap().is_none());
+        // wire paths stay relative to the sync directory
+        let sent = handle_fs_event(modify("docs/file.txt"), &mut ctx).unwrap();
+        assert!(matches!(sent, Some(Protocol::FsEventModify {path, ..}) if path == Path::new("docs/file.txt")));
+        // moving across the subpath's boundary is a create or delete to the peer
+        std::fs::rename(root.join("src/file.txt"), root.join("docs/moved-in.txt")).unwrap();
+        let sent = handle_fs_event(rename("src/file.txt", "docs/moved-in.txt"), &mut ctx).unwrap();
+        assert_eq!(sent, Some(Protocol::FsEventCreate{path: PathBuf::from("docs/moved-in.txt"), entity: EntityType::File}));
+        std::fs::rename(root.join("docs/file.txt"), root.join("src/moved-out.txt")).unwrap();
+        let sent = handle_fs_event(rename("docs/file.txt", "src/moved-out.txt"), &mut ctx).unwrap();
+        assert_eq!(sent, Some(Protocol::FsEventDelete{path: PathBuf::from("docs/file.txt"), entity: Some(EntityType::File)}));
+    }
+
+    #[test]
+    fn events_with_malformed_paths_dont_panic() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let rename = EventKind::Modify(Name(Both));
+        let malformed = [
+            Event::new(EventKind::Create(File)),
+            Event::new(rename),
+            Event::new(EventKind::Remove(RemoveKind::Any)),
+            event(rename, &ctx.syncdir.join("only-source")),
+        ];
+        let mut pipeline = pipeline();
+        for malformed in malformed {
+            for event in pipeline.push(malformed.clone()) {
+                let _ = handle_fs_event(event, &mut ctx);
+            }
+            assert!(matches!(handle_fs_event(malformed, &mut ctx), Ok(None)));
+        }
+        // a path outside the sync directory is an error, not a panic
+        let outside = event(EventKind::Create(File), Path::new("/elsewhere/file"));
+        assert!(matches!(handle_fs_event(outside, &mut ctx), Err(SyncError::PathEscapes(_))));
+    }
+
+    #[test]
+    fn event_paths_are_stripped_however_the_sync_directory_is_spelled() {
+        // below the working directory so it can also be named relative to it
+        let syncdir = tempfile::tempdir_in(".").unwrap();
+        std::fs::create_dir(syncdir.path().join("sub")).unwrap();
+        let absolute = std::fs::canonicalize(syncdir.path()).unwrap();
+        let relative = absolute.strip_prefix(std::env::current_dir().unwrap().canonicalize().unwrap()).unwrap().to_path_buf();
+        let spellings = [absolute.clone(), relative.clone(), relative.join("sub").join("..")];
+        for spelling in spellings {
+            let mut ctx = SyncContext::new(&spelling).unwrap();
+            assert_eq!(ctx.syncdir, absolute, "{}", spelling.display());
+            std::fs::write(absolute.join("sub/file.txt"), "contents").unwrap();
+            // the watcher reports paths below the directory it was given, which is the resolved one
+            let modify = event(EventKind::Modify(Data(DataChange::Content)), &absolute.join("sub/file.txt"));
+            let sent = handle_fs_event(modify, &mut ctx).unwrap();
+            assert!(matches!(sent, Some(Protocol::FsEventModify {path, ..}) if path == Path::new("sub/file.txt")), "{}", spelling.display());
+        }
+    }
+
+    #[test]
+    fn file_missing_from_a_listing_is_requested() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("present.txt"), "present").unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let entry = |path: &str, hash| ListRespEntry {path: PathBuf::from(path), hash, entity: EntityType::File, mode: None, mtime: None, link_target: None, skipped: false, link_group: None};
+        let present = ctx.index.hash(Path::new("present.txt"), HashAlgo::Xxh64).unwrap();
+        let entries = vec![entry("present.txt", present), entry("missing.txt", Digest::Xxh64(1))];
+        assert!(handle_incoming(Protocol::ListResp{entries, path: None, more: false}, &mut ctx).unwrap().is_empty());
+        let requests = ctx.fetches.next_requests();
+        assert!(matches!(&requests[..], [Protocol::Get {path}] if path == Path::new("missing.txt")));
+    }
+
+    #[test]
+    fn applying_received_changes_sends_nothing_back() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("existing.txt"), "existing").unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let received = Protocol::GetResp{path: PathBuf::from("dir/received.txt"), contents: b"received".to_vec(), compressed: false, hash: None, mode: None, mtime: None, link_target: None};
+        ctx.handle_message(received).unwrap();
+        // a create of a file that's there already writes nothing
+        ctx.handle_message(Protocol::FsEventCreate{path: PathBuf::from("existing.txt"), entity: EntityType::File}).unwrap();
+        assert_eq!(fs::read(ctx.syncdir.join("existing.txt")).unwrap(), b"existing");
+        let dir = ctx.syncdir.join("dir");
+        let rename = event(EventKind::Modify(Name(Both)), &dir.join("received.txt.syncd.tmp")).add_path(dir.join("received.txt"));
+        let echoes = [event(EventKind::Create(Folder), &dir), event(EventKind::Create(File), &dir.join("received.txt.syncd.tmp")), rename];
+        for echo in echoes {
+            assert!(handle_fs_event(echo, &mut ctx).unwrap().is_none());
+        }
+        // edits made here since are sent, in the new directory too
+        fs::write(dir.join("edited.txt"), "edited").unwrap();
+        let created = handle_fs_event(event(EventKind::Create(File), &dir.join("edited.txt")), &mut ctx).unwrap();
+        assert!(matches!(created, Some(Protocol::FsEventCreate {path, ..}) if path == Path::new("dir/edited.txt")));
+        let modified = handle_fs_event(event(EventKind::Modify(Data(DataChange::Content)), &ctx.syncdir.join("existing.txt")), &mut ctx).unwrap();
+        assert!(matches!(modified, Some(Protocol::FsEventModify {path, ..}) if path == Path::new("existing.txt")));
+    }
+
+    const CHANNEL: &str = "channel";
+    // Longest a test waits for something that should happen right away
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type BrokerEnd = Framed<DuplexStream, Codec>;
+
+    fn framed(stream: DuplexStream, stats: Arc<SyncStats>) -> Framed<DuplexStream, Codec> {
+        Framed::new(stream, Codec::new(stats, None))
+    }
+
+    /// Connects through in-memory pipes, handing the broker's end of every connection to the test
+    struct DuplexTransport {
+        broker: mpsc::UnboundedSender<BrokerEnd>,
+        // of the pair's end, like the stats of the connection to a real broker
+        stats: Arc<SyncStats>,
+    }
+
+    impl DuplexTransport {
+        fn new(stats: Arc<SyncStats>) -> (Self, mpsc::UnboundedReceiver<BrokerEnd>) {
+            let (broker, conns) = mpsc::unbounded_channel();
+            (DuplexTransport {broker, stats}, conns)
+        }
+    }
+
+    impl Transport for DuplexTransport {
+        type Conn = Framed<DuplexStream, Codec>;
+
+        fn connect(&self) -> impl Future<Output = io::Result<Self::Conn>> + Send {
+            let (client, broker) = tokio::io::duplex(1 << 20);
+            let connected = self.broker.send(framed(broker, Arc::new(SyncStats::default())))
+                .map(|_| framed(client, Arc::clone(&self.stats)))
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused));
+            std::future::ready(connected)
+        }
+
+        fn address(&self) -> &str {
+            "duplex"
+        }
+    }
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            keepalive: None,
+            max_upload_kbps: None,
+            cipher: None,
+            progress: false,
+            once: false,
+            checksums: None,
+            reconnect: Backoff::new(Duration::from_millis(10), Duration::from_millis(10), 1),
+            max_idle: None,
+        }
+    }
+
+    fn pipeline() -> EventPipeline {
+        EventPipeline::new(Duration::from_millis(10), Duration::ZERO)
+    }
+
+    /// A pair syncing ctx over connections the test plays the broker on
+    struct Pair {
+        conns: mpsc::UnboundedReceiver<BrokerEnd>,
+        watcher: mpsc::Sender<WatcherMsg>,
+        shutdown: CancellationToken,
+        handler: tokio::task::JoinHandle<PairEnd>,
+        stats: Arc<SyncStats>,
+    }
+
+    impl Pair {
+        fn start(ctx: SyncContext, settings: ConnectionSettings) -> Self {
+            Pair::start_on(CHANNEL, ctx, settings)
+        }
+
+        fn start_on(channel: &str, ctx: SyncContext, settings: ConnectionSettings) -> Self {
+            let stats = Arc::clone(&ctx.stats);
+            let (transport, conns) = DuplexTransport::new(Arc::clone(&stats));
+            let (watcher, rx) = mpsc::channel(128);
+            let shutdown = CancellationToken::new();
+            let handler = tokio::spawn(event_handler(transport, channel.to_string(), ctx, pipeline(), settings, rx, shutdown.clone()));
+            Pair {conns, watcher, shutdown, handler, stats}
+        }
+
+        /// Feeds the pair what actually happens under root
+        fn watch(&self, root: &Path) -> RecommendedWatcher {
+            let mut watcher = RecommendedWatcher::new(EventForwarder::new(self.watcher.clone(), root.to_path_buf(), Arc::new(AtomicBool::new(false)), Arc::clone(&self.stats)), Config::default()).unwrap();
+            watcher.watch(root, RecursiveMode::Recursive).unwrap();
+            watcher
+        }
+
+        async fn next_conn(&mut self) -> BrokerEnd {
+            tokio::time::timeout(TIMEOUT, self.conns.recv()).await.expect("no connection made").expect("transport dropped")
+        }
+
+        async fn stop(self) -> PairEnd {
+            self.shutdown.cancel();
+            let end = tokio::time::timeout(TIMEOUT, self.handler).await.expect("handler didn't stop").unwrap();
+            drop(self.watcher);
+            end
+        }
+    }
+
+    async fn next_package(conn: &mut BrokerEnd) -> Package {
+        tokio::time::timeout(TIMEOUT, conn.next()).await.expect("nothing arrived").expect("connection closed").unwrap()
+    }
+
+    async fn accept_subscription(conn: &mut BrokerEnd) {
+        assert_eq!(next_package(conn).await, Package::Subscribe(Bytes::from_static(CHANNEL.as_bytes())));
+        assert_eq!(next_package(conn).await, Package::Ping(Bytes::from_static(SUBSCRIBE_PROBE)));
+        conn.send(Package::Pong(Bytes::from_static(SUBSCRIBE_PROBE))).await.unwrap();
+    }
+
+    async fn send_message(conn: &mut BrokerEnd, message: &Protocol) {
+        send_protocol(conn, Bytes::from_static(CHANNEL.as_bytes()), message).await.unwrap();
+    }
+
+    /// Next message the pair sent, past the pings and pongs in between
+    async fn next_message(conn: &mut BrokerEnd) -> Protocol {
+        loop {
+            if let Package::Message(channel, payload) = next_package(conn).await {
+                assert_eq!(channel, CHANNEL.as_bytes());
+                return ciborium::de::from_reader(payload.as_ref()).unwrap()
+            }
+        }
+    }
+
+    /// Events in the next message the pair sent, whether or not it batched them
+    async fn next_events(conn: &mut BrokerEnd) -> Vec<Protocol> {
+        match next_message(conn).await {
+            Protocol::FsEventBatch {events} => events,
+            message => vec![message],
+        }
+    }
+
+    /// Subscribes and answers the pair's hello as a peer with every feature
+    async fn accept_peer(conn: &mut BrokerEnd) {
+        accept_subscription(conn).await;
+        assert!(matches!(next_message(conn).await, Protocol::Hello {..}));
+        send_message(conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        // answered after the features were taken on, so what follows is sent with them
+        send_message(conn, &Protocol::Ping).await;
+        assert!(matches!(next_message(conn).await, Protocol::Pong));
+    }
+
+    /// Answers a message like a pair syncing ctx would, including the requests a pair
+    /// answers off the blocking thread pool
+    fn answer_as_peer(ctx: &mut SyncContext, message: Protocol) -> Option<Protocol> {
+        match message {
+            Protocol::List {path, recursive, max_depth} => {
+                let opts = ListOptions{root: &ctx.syncdir, filter: &ctx.filter, index: &ctx.index, max_file_size: ctx.max_file_size, algo: ctx.peer.hash_algo};
+                let entries = list_entries(&resolve_path(&path, true, ctx).unwrap(), recursive, max_depth, &opts).unwrap();
+                Some(Protocol::ListResp{entries, path: Some(path), more: false})
+            }
+            Protocol::Status => Some(status(&ctx.syncdir, &ctx.filter, &ctx.stats).unwrap()),
+            message => ctx.handle_message(message).unwrap(),
+        }
+    }
+
+    /// Answers whatever the pair asks for as a peer syncing ctx, until the pair hangs up
+    async fn serve_as_peer(conn: &mut BrokerEnd, mut ctx: SyncContext) {
+        while let Some(package) = tokio::time::timeout(TIMEOUT, conn.next()).await.expect("pair kept waiting") {
+            let Package::Message(_, payload) = package.unwrap() else { continue };
+            if let Some(answer) = answer_as_peer(&mut ctx, decode_message(&payload).unwrap()) {
+                send_message(conn, &answer).await;
+            }
+        }
+    }
+
+    /// Stands in for the broker between two pairs on the same channel, passing on their
+    /// messages and answering their pings until either of them goes away
+    async fn relay(mut a: BrokerEnd, mut b: BrokerEnd) {
+        loop {
+            let (package, from_a) = tokio::select! {
+                package = a.next() => (package, true),
+                package = b.next() => (package, false),
+            };
+            let Some(Ok(package)) = package else {
+                return
+            };
+            let (from, to) = if from_a { (&mut a, &mut b) } else { (&mut b, &mut a) };
+            let sent = match package {
+                Package::Ping(data) => from.send(Package::Pong(data)).await,
+                Package::Message(..) => to.send(package).await,
+                _ => Ok(()),
+            };
+            if sent.is_err() {
+                return
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn received_file_is_acknowledged_once_written() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let hash = syncd::fs::hash_bytes(b"received", HashAlgo::Xxh64);
+        let received = Protocol::GetResp{path: PathBuf::from("received.txt"), contents: b"received".to_vec(), compressed: false, hash: Some(hash), mode: None, mtime: None, link_target: None};
+        send_message(&mut conn, &received).await;
+        assert_eq!(next_message(&mut conn).await, Protocol::Written{path: PathBuf::from("received.txt"), hash});
+        assert_eq!(std::fs::read(root.join("received.txt")).unwrap(), b"received");
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pings_are_answered_while_writes_catch_up() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        // flushing a fifo would block again until the test opened it once more
+        ctx.writes = WriteQueue::new(Arc::clone(&ctx.index), FsyncPolicy::Never);
+        let root = ctx.syncdir.clone();
+        // writing the first file blocks until the test reads the other end
+        let fifo = root.join("slow.syncd.tmp");
+        assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let received = |path: &str, contents: Vec<u8>, compressed| Protocol::GetResp{path: PathBuf::from(path), contents, compressed, hash: None, mode: None, mtime: None, link_target: None};
+        send_message(&mut conn, &received("slow", b"slow".to_vec(), false)).await;
+        // more than fits in the write queue
+        let zeros = zstd::bulk::compress(&[0; 16 * 1024 * 1024], 1).unwrap();
+        for path in ["large1", "large2"] {
+            send_message(&mut conn, &received(path, zeros.clone(), true)).await;
+        }
+        send_message(&mut conn, &received("after.txt", b"after".to_vec(), false)).await;
+        conn.send(Package::Ping(Bytes::from_static(b"probe"))).await.unwrap();
+        assert_eq!(next_package(&mut conn).await, Package::Pong(Bytes::from_static(b"probe")));
+        assert!(!root.join("after.txt").exists());
+        let drained = tokio::task::spawn_blocking(move || std::fs::read(fifo).unwrap());
+        assert_eq!(drained.await.unwrap(), b"slow");
+        tokio::time::timeout(TIMEOUT, async {
+            while std::fs::read(root.join("after.txt")).ok().as_deref() != Some(b"after".as_slice()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("message received while writes caught up was never handled");
+        assert_eq!(std::fs::metadata(root.join("large2")).unwrap().len(), 16 * 1024 * 1024);
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn file_written_to_a_full_disk_is_fetched_again_once_theres_room() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        // every write of file.txt fails for lack of space
+        let tmppath = root.join("file.txt.syncd.tmp");
+        std::os::unix::fs::symlink("/dev/full", &tmppath).unwrap();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let received = Protocol::GetResp{path: PathBuf::from("file.txt"), contents: b"contents".to_vec(), compressed: false, hash: None, mode: None, mtime: None, link_target: None};
+        send_message(&mut conn, &received).await;
+        tokio::time::timeout(TIMEOUT, async {
+            while std::fs::symlink_metadata(&tmppath).is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("temporary file left behind");
+        assert!(!root.join("file.txt").exists());
+        send_message(&mut conn, &Protocol::Ping).await;
+        assert_eq!(next_message(&mut conn).await, Protocol::Pong);
+        // room was made meanwhile, the file is asked for again once the pause is over
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(next_message(&mut conn).await, Protocol::Get{path: PathBuf::from("file.txt")});
+        send_message(&mut conn, &received).await;
+        send_message(&mut conn, &Protocol::Ping).await;
+        assert_eq!(next_message(&mut conn).await, Protocol::Pong);
+        // written on a thread of its own, which the paused clock doesn't wait for
+        for _ in 0..500 {
+            if root.join("file.txt").exists() {
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(std::fs::read(root.join("file.txt")).unwrap(), b"contents");
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn malformed_message_is_dropped_and_the_connection_kept() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        for garbage in [&b"not cbor at all"[..], &[0xa1, 0x64, 0x74], &[]] {
+            conn.send(Package::Message(Bytes::from_static(CHANNEL.as_bytes()), Bytes::copy_from_slice(garbage))).await.unwrap();
+        }
+        send_message(&mut conn, &Protocol::Ping).await;
+        assert_eq!(next_message(&mut conn).await, Protocol::Pong);
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_is_the_last_thing_sent_on_shutdown() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let file = ctx.syncdir.join("file.txt");
+        fs::write(&file, "file").unwrap();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        // still held by the debouncer when shutdown begins
+        pair.watcher.send(WatcherMsg::Event(event(EventKind::Modify(Data(DataChange::Content)), &file))).await.unwrap();
+        let (end, sent) = tokio::join!(pair.stop(), async {
+            let mut sent = Vec::new();
+            while let Some(package) = tokio::time::timeout(TIMEOUT, conn.next()).await.expect("connection left open") {
+                sent.push(package.unwrap());
+            }
+            sent
+        });
+        assert!(matches!(end, PairEnd::Done));
+        assert_eq!(sent.last(), Some(&Package::Unsubscribe(Bytes::from_static(CHANNEL.as_bytes()))));
+    }
+
+    /// Counts the allocations of at least a given size made on a thread while it counts
+    /// them, leaving other threads (like other tests) out
+    struct CountingAlloc;
+
+    thread_local! {
+        // the size counted from and the allocations counted so far
+        static ALLOCATIONS: std::cell::Cell<Option<(usize, usize)>> = const { std::cell::Cell::new(None) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            // gone already on threads that are exiting
+            let _ = ALLOCATIONS.try_with(|counting| {
+                if let Some((min_size, count)) = counting.get().filter(|(min_size, _)| layout.size() >= *min_size) {
+                    counting.set(Some((min_size, count + 1)));
+                }
+            });
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// Runs f, returning how many allocations of at least min_size bytes it made
+    fn allocations(min_size: usize, f: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|counting| counting.set(Some((min_size, 0))));
+        f();
+        ALLOCATIONS.with(|counting| counting.take()).unwrap().1
+    }
+
+    #[test]
+    fn large_message_is_serialized_into_a_single_allocation() {
+        let path = PathBuf::from("dir/large.bin");
+        let contents = vec![0xa5; syncd::protocol::TRANSFER_CHUNK_SIZE as usize];
+        let messages = [
+            Protocol::GetResp{path: path.clone(), contents: contents.clone(), compressed: false, hash: Some(Digest::Blake3([7; 32])), mode: Some(0o644), mtime: Some(1_600_000_000_000), link_target: None},
+            Protocol::GetChunkResp{path, offset: 0, contents, eof: false, mode: Some(0o644), mtime: Some(1_600_000_000_000), hash: Some(Digest::Blake3([7; 32])), size: Some(u64::MAX)},
+        ];
+        // the buffer messages are serialized into isn't grown and copied over and over,
+        // the bookkeeping around it doesn't matter
+        for message in messages {
+            let mut payloads = Vec::with_capacity(1);
+            assert_eq!(allocations(1024, || encode_message(&message, &mut payloads)), 1);
+            assert_eq!(payloads.len(), 1);
+            let decoded: Protocol = ciborium::de::from_reader(payloads[0].as_ref()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn resubscribes_after_losing_the_broker() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut first = pair.next_conn().await;
+        accept_subscription(&mut first).await;
+        drop(first);
+        let mut second = pair.next_conn().await;
+        accept_subscription(&mut second).await;
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn subscription_refused_by_the_broker_fails_the_pair() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        assert_eq!(next_package(&mut conn).await, Package::Subscribe(Bytes::from_static(CHANNEL.as_bytes())));
+        assert_eq!(next_package(&mut conn).await, Package::Ping(Bytes::from_static(SUBSCRIBE_PROBE)));
+        // closed without the probe's pong, like a broker rejecting the channel does
+        drop(conn);
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("kept going after the refusal").unwrap();
+        assert!(matches!(end, PairEnd::Failed));
+        assert!(pair.conns.try_recv().is_err(), "reconnected after the refusal");
+    }
+
+    #[tokio::test]
+    async fn peer_speaking_another_protocol_version_is_refused() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION + 1, features: local_features(), hostname: None}).await;
+        assert_eq!(next_package(&mut conn).await, Package::Unsubscribe(Bytes::from_static(CHANNEL.as_bytes())));
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("kept syncing").unwrap();
+        assert!(matches!(end, PairEnd::Failed));
+    }
+
+    #[tokio::test]
+    async fn broker_not_answering_pings_is_given_up_on() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let keepalive = Duration::from_millis(200);
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), ConnectionSettings{keepalive: Some(keepalive), ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let ping = Package::Ping(Bytes::from_static(b"keepalive"));
+        assert_eq!(next_package(&mut conn).await, ping);
+        conn.send(Package::Pong(Bytes::from_static(b"keepalive"))).await.unwrap();
+        // answered in time, the connection is kept
+        assert_eq!(next_package(&mut conn).await, ping);
+        let unanswered = Instant::now();
+        assert!(tokio::time::timeout(TIMEOUT, conn.next()).await.unwrap().is_none(), "connection kept");
+        assert!(unanswered.elapsed() >= keepalive);
+        accept_subscription(&mut pair.next_conn().await).await;
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broker_gone_silent_makes_the_pair_exit_once_idle_for_too_long() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let max_idle = Duration::from_secs(30);
+        let settings = ConnectionSettings{keepalive: Some(Duration::from_secs(10)), max_idle: Some(max_idle), ..settings()};
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings);
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        // neither the pings nor the connections made after giving up on them are answered
+        let silent = Instant::now();
+        let end = tokio::time::timeout(4 * max_idle, pair.handler).await.expect("kept waiting on a silent broker").unwrap();
+        assert!(matches!(end, PairEnd::Idle));
+        assert!(silent.elapsed() >= max_idle);
+        drop(conn);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_broker_answering_pings_isnt_idle() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let max_idle = Duration::from_secs(30);
+        let settings = ConnectionSettings{keepalive: Some(Duration::from_secs(10)), max_idle: Some(max_idle), ..settings()};
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings);
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        // nothing to deliver for a long time, only the pings are answered
+        let broker = async {
+            while let Some(package) = conn.next().await {
+                if let Package::Ping(payload) = package.unwrap() {
+                    conn.send(Package::Pong(payload)).await.unwrap();
+                }
+            }
+        };
+        assert!(tokio::time::timeout(10 * max_idle, broker).await.is_err(), "the pair hung up");
+        assert!(!pair.handler.is_finished());
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn file_written_right_after_its_creation_is_fetched_once() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let (file, marker) = (root.join("file.txt"), root.join("marker.txt"));
+        std::fs::write(&file, "written").unwrap();
+        std::fs::write(&marker, "marker").unwrap();
+        let write = EventKind::Modify(Data(DataChange::Content));
+        for kind in [EventKind::Create(File), write, write] {
+            pair.watcher.send(WatcherMsg::Event(event(kind, &file))).await.unwrap();
+        }
+        // sent after whatever the events of file come down to
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pair.watcher.send(WatcherMsg::Event(event(write, &marker))).await.unwrap();
+        let mut sent = Vec::new();
+        while !sent.iter().any(|event| matches!(event, Protocol::FsEventModify {path, ..} if path == Path::new("marker.txt"))) {
+            sent.extend(next_events(&mut conn).await);
+        }
+        let hash = syncd::fs::try_hash_file(&file, HashAlgo::default()).unwrap();
+        assert!(matches!(&sent[..], [Protocol::FsEventModify {path, hash: sent_hash}, _] if path == Path::new("file.txt") && *sent_hash == hash), "sent {sent:?}");
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn burst_of_creates_goes_out_as_one_batch() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let paths: Vec<PathBuf> = (0..100).map(|i| root.join(format!("file{i}"))).collect();
+        for path in &paths {
+            fs::write(path, "").unwrap();
+        }
+        for path in &paths {
+            pair.watcher.send(WatcherMsg::Event(event(EventKind::Create(File), path))).await.unwrap();
+        }
+        let Protocol::FsEventBatch {events} = next_message(&mut conn).await else {
+            panic!("creates weren't batched")
+        };
+        assert_eq!(events.len(), 100);
+        assert!(events.iter().all(|event| matches!(event, Protocol::FsEventCreate {entity: EntityType::File, ..})));
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn events_of_a_pair_only_go_to_its_own_channel() {
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let ctx = SyncContext::new(dir.path()).unwrap();
+        let file = ctx.syncdir.join("file.txt");
+        let mut pair = Pair::start(ctx, settings());
+        let mut other = Pair::start_on("other", SyncContext::new(other_dir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let mut other_conn = other.next_conn().await;
+        assert_eq!(next_package(&mut other_conn).await, Package::Subscribe(Bytes::from_static(b"other")));
+        assert_eq!(next_package(&mut other_conn).await, Package::Ping(Bytes::from_static(SUBSCRIBE_PROBE)));
+        assert!(matches!(next_package(&mut other_conn).await, Package::Message(channel, _) if channel == "other"), "expected the hello");
+        fs::write(&file, "contents").unwrap();
+        pair.watcher.send(WatcherMsg::Event(event(EventKind::Modify(Data(DataChange::Content)), &file))).await.unwrap();
+        assert!(matches!(&next_events(&mut conn).await[..], [Protocol::FsEventModify {path, ..}] if path == Path::new("file.txt")));
+        assert!(tokio::time::timeout(Duration::from_millis(200), other_conn.next()).await.is_err(), "the other pair sent something");
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+        assert!(matches!(other.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn messages_are_applied_to_the_root_of_their_channel() {
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut pair = Pair::start(SyncContext::new(dir.path()).unwrap(), settings());
+        let mut other = Pair::start_on("other", SyncContext::new(other_dir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        let mut other_conn = other.next_conn().await;
+        let other_chan = Bytes::from_static(b"other");
+        assert_eq!(next_package(&mut other_conn).await, Package::Subscribe(other_chan.clone()));
+        assert_eq!(next_package(&mut other_conn).await, Package::Ping(Bytes::from_static(SUBSCRIBE_PROBE)));
+        other_conn.send(Package::Pong(Bytes::from_static(SUBSCRIBE_PROBE))).await.unwrap();
+        assert!(matches!(next_package(&mut other_conn).await, Package::Message(channel, _) if channel == other_chan), "expected the hello");
+        let hello = Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None};
+        send_protocol(&mut other_conn, other_chan.clone(), &hello).await.unwrap();
+        let received = |path: &str| Protocol::GetResp{path: PathBuf::from(path), contents: path.as_bytes().to_vec(), compressed: false, hash: None, mode: None, mtime: None, link_target: None};
+        // the broker got it wrong, the pair didn't subscribe to the channel
+        send_protocol(&mut conn, other_chan.clone(), &received("misrouted.txt")).await.unwrap();
+        // written after it would have been
+        send_message(&mut conn, &received("mine.txt")).await;
+        send_protocol(&mut other_conn, other_chan.clone(), &received("other.txt")).await.unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while !dir.path().join("mine.txt").exists() || !other_dir.path().join("other.txt").exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("messages weren't applied");
+        assert!(!dir.path().join("other.txt").exists() && !other_dir.path().join("mine.txt").exists());
+        assert!(!dir.path().join("misrouted.txt").exists() && !other_dir.path().join("misrouted.txt").exists());
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+        assert!(matches!(other.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn status_counts_what_is_synced() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        let root = ctx.syncdir.clone();
+        fs::create_dir(root.join("dir")).unwrap();
+        for name in ["top.txt", "dir/nested.txt"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        for name in ["top.txt", "dir/nested.txt"] {
+            pair.watcher.send(WatcherMsg::Event(event(EventKind::Modify(Data(DataChange::Content)), &root.join(name)))).await.unwrap();
+        }
+        let mut modified = 0;
+        while modified < 2 {
+            modified += next_events(&mut conn).await.len();
+        }
+        send_message(&mut conn, &Protocol::Status).await;
+        let Protocol::StatusResp {file_count, watched_paths, last_event_unix, bytes_sent, bytes_received, transfers} = next_message(&mut conn).await else {
+            panic!("status wasn't answered")
+        };
+        assert_eq!((file_count, watched_paths), (2, 2));
+        assert!(last_event_unix.is_some());
+        assert!(bytes_sent > 0 && bytes_received > 0);
+        assert!(transfers.is_empty());
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_while_a_large_tree_is_listed() {
+        let syncdir = tempfile::tempdir().unwrap();
+        for i in 0..2000 {
+            fs::write(syncdir.path().join(format!("file{i}")), format!("contents of {i}")).unwrap();
+        }
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        send_message(&mut conn, &root_listing()).await;
+        conn.send(Package::Ping(Bytes::from_static(b"probe"))).await.unwrap();
+        assert_eq!(next_package(&mut conn).await, Package::Pong(Bytes::from_static(b"probe")), "the listing went first");
+        let mut listed = 0;
+        while listed < 2000 {
+            match next_message(&mut conn).await {
+                Protocol::ListResp {entries, ..} => listed += entries.len(),
+                message => panic!("unexpected {message:?}"),
+            }
+        }
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_while_a_large_file_is_hashed_for_a_listing() {
+        let syncdir = tempfile::tempdir().unwrap();
+        // sparse, takes a while to hash all the same
+        std::fs::File::create(syncdir.path().join("large")).unwrap().set_len(256 * 1024 * 1024).unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        send_message(&mut conn, &root_listing()).await;
+        let asked = Instant::now();
+        conn.send(Package::Ping(Bytes::from_static(b"probe"))).await.unwrap();
+        assert_eq!(next_package(&mut conn).await, Package::Pong(Bytes::from_static(b"probe")), "the listing went first");
+        let answered = asked.elapsed();
+        let Protocol::ListResp {entries, ..} = next_message(&mut conn).await else {
+            panic!("listing wasn't answered")
+        };
+        assert_eq!(entries.len(), 1);
+        assert!(answered < asked.elapsed() / 2, "pong took {answered:?} of {:?}", asked.elapsed());
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_while_a_large_file_is_read_for_a_get() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let size = 256 << 20;
+        fs::File::create(syncdir.path().join("large")).unwrap().set_len(size).unwrap();
+        let mut pair = Pair::start(SyncContext::new(syncdir.path()).unwrap(), settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        // the last chunk carries the hash of the whole file
+        send_message(&mut conn, &Protocol::GetChunk{path: PathBuf::from("large"), offset: size - 16, len: 16}).await;
+        let asked = Instant::now();
+        conn.send(Package::Ping(Bytes::from_static(b"probe"))).await.unwrap();
+        assert_eq!(next_package(&mut conn).await, Package::Pong(Bytes::from_static(b"probe")), "the chunk went first");
+        let answered = asked.elapsed();
+        let Protocol::GetChunkResp {eof: true, hash: Some(_), ..} = next_message(&mut conn).await else {
+            panic!("last chunk wasn't sent")
+        };
+        assert!(answered < asked.elapsed() / 2, "pong took {answered:?} of {:?}", asked.elapsed());
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn rescan_asked_for_by_the_peer_finds_an_unnoticed_modification() {
+        let syncdir = tempfile::tempdir().unwrap();
+        std::fs::write(syncdir.path().join("file.txt"), "before").unwrap();
+        let ctx = SyncContext::new(syncdir.path()).unwrap();
+        ctx.index.hash(Path::new("file.txt"), HashAlgo::Xxh64).unwrap();
+        let mut pair = Pair::start(ctx, settings());
+        let mut conn = pair.next_conn().await;
+        accept_peer(&mut conn).await;
+        // nothing watches the directory, like while the watcher is paused
+        std::fs::write(syncdir.path().join("file.txt"), "after the pause").unwrap();
+        send_message(&mut conn, &Protocol::Rescan{path: None}).await;
+        let modified = Protocol::FsEventModify{path: PathBuf::from("file.txt"), hash: syncd::fs::hash_bytes(b"after the pause", HashAlgo::Xxh64)};
+        assert_eq!(next_events(&mut conn).await, [modified]);
+        assert!(matches!(pair.stop().await, PairEnd::Done));
+    }
+
+    #[tokio::test]
+    async fn file_created_on_one_side_appears_on_the_other() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (sender, receiver) = (SyncContext::new(from.path()).unwrap(), SyncContext::new(to.path()).unwrap());
+        let (from_root, to_root) = (sender.syncdir.clone(), receiver.syncdir.clone());
+        let mut sending = Pair::start(sender, settings());
+        let mut receiving = Pair::start(receiver, settings());
+        let _watcher = sending.watch(&from_root);
+        let relayed = tokio::spawn(relay(sending.next_conn().await, receiving.next_conn().await));
+        // once both have said hello, so the create isn't sent before the peer is there
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        fs::write(from_root.join("created.txt"), "created").unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while fs::read(to_root.join("created.txt")).ok().as_deref() != Some(b"created".as_slice()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("created file didn't appear on the other side");
+        assert!(matches!(sending.stop().await, PairEnd::Done));
+        assert!(matches!(receiving.stop().await, PairEnd::Done));
+        relayed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn once_fetches_what_the_peer_has_and_returns() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::create_dir(remote.path().join("dir")).unwrap();
+        std::fs::write(remote.path().join("top.txt"), "top").unwrap();
+        std::fs::write(remote.path().join("dir/nested.txt"), "nested").unwrap();
+        let mut ctx = SyncContext::new(local.path()).unwrap();
+        ctx.initial_sync = true;
+        let mut pair = Pair::start(ctx, ConnectionSettings{once: true, ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        serve_as_peer(&mut conn, SyncContext::new(remote.path()).unwrap()).await;
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("pair didn't return").unwrap();
+        assert!(matches!(end, PairEnd::Done));
+        assert_eq!(std::fs::read(local.path().join("top.txt")).unwrap(), b"top");
+        assert_eq!(std::fs::read(local.path().join("dir/nested.txt")).unwrap(), b"nested");
+    }
+
+    #[test]
+    fn channel_that_cant_be_subscribed_to_is_rejected() {
+        assert!(parse_channel("").unwrap_err().contains("empty"));
+        let longest = "c".repeat(MAX_CHANNEL_ID_LEN);
+        assert_eq!(parse_channel(&longest).unwrap(), longest);
+        // counted in bytes as sent, not in characters
+        let too_long = "é".repeat(MAX_CHANNEL_ID_LEN / 2 + 1);
+        let err = parse_channel(&too_long).unwrap_err();
+        assert!(err.contains(&format!("{} bytes long", too_long.len())) && err.contains("shorter"), "{err}");
+        assert!(parse_channel("chan\nnel").unwrap_err().contains("control character"));
+        for channel in ["", too_long.as_str()] {
+            let err = Args::try_parse_from(["syncd", "--channel", channel]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ValueValidation);
+        }
+        assert!(parse_pair("dir:").is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_fails_on_a_differing_file_without_changing_it() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::write(local.path().join("same.txt"), "same").unwrap();
+        std::fs::write(remote.path().join("same.txt"), "same").unwrap();
+        std::fs::write(local.path().join("differs.txt"), "ours").unwrap();
+        std::fs::write(remote.path().join("differs.txt"), "theirs").unwrap();
+        let mut ctx = SyncContext::new(local.path()).unwrap();
+        ctx.initial_sync = true;
+        ctx.read_only = true;
+        ctx.verify = Some(Vec::new());
+        let mut pair = Pair::start(ctx, ConnectionSettings{once: true, ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        serve_as_peer(&mut conn, SyncContext::new(remote.path()).unwrap()).await;
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("pair didn't return").unwrap();
+        assert!(matches!(end, PairEnd::Failed));
+        assert_eq!(std::fs::read(local.path().join("differs.txt")).unwrap(), b"ours");
+    }
+
+    #[tokio::test]
+    async fn verify_of_trees_in_sync_succeeds() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::write(local.path().join("same.txt"), "same").unwrap();
+        std::fs::write(remote.path().join("same.txt"), "same").unwrap();
+        let mut ctx = SyncContext::new(local.path()).unwrap();
+        ctx.initial_sync = true;
+        ctx.read_only = true;
+        ctx.verify = Some(Vec::new());
+        let mut pair = Pair::start(ctx, ConnectionSettings{once: true, ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        serve_as_peer(&mut conn, SyncContext::new(remote.path()).unwrap()).await;
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("pair didn't return").unwrap();
+        assert!(matches!(end, PairEnd::Done));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_arrive_as_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::create_dir(remote.path().join("dir")).unwrap();
+        std::fs::write(remote.path().join("original.txt"), "linked").unwrap();
+        std::fs::hard_link(remote.path().join("original.txt"), remote.path().join("dir/link.txt")).unwrap();
+        std::fs::write(remote.path().join("copy.txt"), "linked").unwrap();
+        let mut ctx = SyncContext::new(local.path()).unwrap();
+        ctx.initial_sync = true;
+        let mut pair = Pair::start(ctx, ConnectionSettings{once: true, ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        serve_as_peer(&mut conn, SyncContext::new(remote.path()).unwrap()).await;
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("pair didn't return").unwrap();
+        assert!(matches!(end, PairEnd::Done));
+        let inode = |path: &str| std::fs::metadata(local.path().join(path)).unwrap().ino();
+        assert_eq!(inode("original.txt"), inode("dir/link.txt"));
+        // the same contents without being a link of it
+        assert_ne!(inode("original.txt"), inode("copy.txt"));
+        for path in ["original.txt", "dir/link.txt", "copy.txt"] {
+            assert_eq!(std::fs::read(local.path().join(path)).unwrap(), b"linked");
+        }
+    }
+
+    #[tokio::test]
+    async fn dedup_copies_a_listed_file_from_a_local_one_with_the_same_contents() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::write(local.path().join("existing.txt"), "shared").unwrap();
+        for (path, contents) in [("existing.txt", "shared"), ("copy.txt", "shared"), ("other.txt", "other")] {
+            std::fs::write(remote.path().join(path), contents).unwrap();
+        }
+        let mut ctx = SyncContext::new(local.path()).unwrap();
+        ctx.initial_sync = true;
+        ctx.fetches = FetchQueue::new(NonZeroUsize::new(4).unwrap(), true, None);
+        let mut pair = Pair::start(ctx, ConnectionSettings{once: true, ..settings()});
+        let mut conn = pair.next_conn().await;
+        accept_subscription(&mut conn).await;
+        assert!(matches!(next_message(&mut conn).await, Protocol::Hello {..}));
+        send_message(&mut conn, &Protocol::HelloResp{version: PROTOCOL_VERSION, features: local_features(), hostname: None}).await;
+        let mut peer = SyncContext::new(remote.path()).unwrap();
+        let mut fetched = Vec::new();
+        while let Some(package) = tokio::time::timeout(TIMEOUT, conn.next()).await.expect("pair kept waiting") {
+            let Package::Message(_, payload) = package.unwrap() else { continue };
+            let message: Protocol = ciborium::de::from_reader(payload.as_ref()).unwrap();
+            if let Protocol::Get {path} = &message {
+                fetched.push(path.clone());
+            }
+            if let Some(answer) = answer_as_peer(&mut peer, message) {
+                send_message(&mut conn, &answer).await;
+            }
+        }
+        let end = tokio::time::timeout(TIMEOUT, pair.handler).await.expect("pair didn't return").unwrap();
+        assert!(matches!(end, PairEnd::Done));
+        assert_eq!(fetched, [PathBuf::from("other.txt")]);
+        assert_eq!(std::fs::read(local.path().join("copy.txt")).unwrap(), b"shared");
+        assert_eq!(std::fs::read(local.path().join("other.txt")).unwrap(), b"other");
+    }
+
+    fn listing_of(ctx: &SyncContext) -> Vec<ListRespEntry> {
+        let opts = ListOptions{root: &ctx.syncdir, filter: &ctx.filter, index: &ctx.index, max_file_size: ctx.max_file_size, algo: ctx.hash_algo};
+        list_entries(&ctx.syncdir, true, None, &opts).unwrap()
+    }
+
+    #[test]
+    fn empty_directory_is_recreated_on_the_peer() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::create_dir_all(from.path().join("listed/empty")).unwrap();
+        std::fs::create_dir(from.path().join("created")).unwrap();
+        let mut sender = SyncContext::new(from.path()).unwrap();
+        let mut receiver = SyncContext::new(to.path()).unwrap();
+        // through the listing of the initial sync
+        let entries = listing_of(&sender);
+        assert!(entries.iter().any(|entry| entry.path == Path::new("listed/empty") && entry.entity == EntityType::Directory));
+        handle_incoming(Protocol::ListResp{entries, path: None, more: false}, &mut receiver).unwrap();
+        assert!(to.path().join("listed/empty").is_dir());
+        // and through the event of its creation
+        let created = handle_fs_event(event(EventKind::Create(Folder), &sender.syncdir.join("created")), &mut sender).unwrap().unwrap();
+        assert_eq!(created, Protocol::FsEventCreate{path: PathBuf::from("created"), entity: EntityType::Directory});
+        handle_incoming(created, &mut receiver).unwrap();
+        assert!(to.path().join("created").is_dir());
+    }
+
+    #[test]
+    fn shuffled_batch_builds_the_same_tree() {
+        let paths = [("a", EntityType::Directory), ("a/b", EntityType::Directory), ("a/b/c.txt", EntityType::File), ("a/d.txt", EntityType::File)];
+        for order in [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1], [1, 3, 2, 0]] {
+            let syncdir = tempfile::tempdir().unwrap();
+            let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+            let shuffled = || order.iter().map(|&i| (PathBuf::from(paths[i].0), paths[i].1.clone()));
+            let created = shuffled().map(|(path, entity)| Protocol::FsEventCreate{path, entity}).collect();
+            assert!(handle_incoming(Protocol::FsEventBatch{events: created}, &mut ctx).unwrap().is_empty());
+            assert!(syncdir.path().join("a/b").is_dir(), "{order:?}");
+            assert!(syncdir.path().join("a/b/c.txt").is_file() && syncdir.path().join("a/d.txt").is_file(), "{order:?}");
+            // and gone again whatever order the deletions come in
+            let deleted = shuffled().map(|(path, entity)| Protocol::FsEventDelete{path, entity: Some(entity)}).collect();
+            assert!(handle_incoming(Protocol::FsEventBatch{events: deleted}, &mut ctx).unwrap().is_empty());
+            assert!(!syncdir.path().join("a").exists(), "{order:?}");
+        }
+    }
+
+    #[test]
+    fn unspecific_create_takes_its_entity_from_the_filesystem() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        std::fs::write(ctx.syncdir.join("file"), "file").unwrap();
+        std::fs::create_dir(ctx.syncdir.join("dir")).unwrap();
+        syncd::fs::make_symlink(Path::new("file"), &ctx.syncdir.join("link"), false).unwrap();
+        for kind in [CreateKind::Any, CreateKind::Other] {
+            for (name, entity) in [("file", EntityType::File), ("dir", EntityType::Directory), ("link", EntityType::Symlink)] {
+                let sent = handle_fs_event(event(EventKind::Create(kind), &ctx.syncdir.join(name)), &mut ctx).unwrap();
+                assert_eq!(sent, Some(Protocol::FsEventCreate{path: PathBuf::from(name), entity}), "{kind:?} of {name}");
+            }
+            // gone again already, its remove event covers it
+            assert_eq!(handle_fs_event(event(EventKind::Create(kind), &ctx.syncdir.join("gone")), &mut ctx).unwrap(), None);
+        }
+        // an unspecific modification is only sent for a file's contents
+        let root = ctx.syncdir.clone();
+        let modify = |name: &str| event(EventKind::Modify(ModifyKind::Any), &root.join(name));
+        assert!(matches!(handle_fs_event(modify("file"), &mut ctx).unwrap(), Some(Protocol::FsEventModify {..})));
+        assert_eq!(handle_fs_event(modify("dir"), &mut ctx).unwrap(), None);
+    }
+
+    #[test]
+    fn modification_of_file_over_the_size_limit_isnt_sent() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        ctx.max_file_size = Some(10);
+        std::fs::write(ctx.syncdir.join("huge"), [0; 100]).unwrap();
+        let modify = event(EventKind::Modify(Data(DataChange::Content)), &ctx.syncdir.join("huge"));
+        assert_eq!(handle_fs_event(modify, &mut ctx).unwrap(), None);
+    }
+
+    #[test]
+    fn metadata_change_is_sent_without_the_contents() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let file = ctx.syncdir.join("file.txt");
+        std::fs::write(&file, "file").unwrap();
+        let mtime = FileAttrs {mode: None, mtime: Some(1_600_000_000_123)};
+        mtime.apply(&file).unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(&file, std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+        let changed = |kind| event(EventKind::Modify(Metadata(kind)), &file);
+        // peers that can't take attrs on their own would need the whole file
+        assert!(handle_fs_event(changed(MetadataKind::Permissions), &mut ctx).unwrap().is_none());
+        ctx.peer.attrs = true;
+        for kind in [MetadataKind::Any, MetadataKind::Permissions, MetadataKind::WriteTime] {
+            let sent = handle_fs_event(changed(kind), &mut ctx).unwrap();
+            let Some(Protocol::FsEventAttrs {path, mode, mtime}) = sent else {
+                panic!("{kind:?} sent {sent:?}")
+            };
+            assert_eq!((path.as_path(), mtime), (Path::new("file.txt"), Some(1_600_000_000_123)));
+            #[cfg(unix)]
+            assert_eq!(mode.map(|mode| mode & 0o777), Some(0o640));
+            #[cfg(not(unix))]
+            let _ = mode;
+        }
+        for kind in [MetadataKind::AccessTime, MetadataKind::Ownership, MetadataKind::Extended] {
+            assert!(handle_fs_event(changed(kind), &mut ctx).unwrap().is_none(), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn removal_carries_what_was_removed() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = SyncContext::new(syncdir.path()).unwrap();
+        let removed = ctx.syncdir.join("removed");
+        for (kind, entity) in [(RemoveKind::File, Some(EntityType::File)), (RemoveKind::Folder, Some(EntityType::Directory)), (RemoveKind::Any, None)] {
+            let sent = handle_fs_event(event(EventKind::Remove(kind), &removed), &mut ctx).unwrap();
+            assert_eq!(sent, Some(Protocol::FsEventDelete{path: PathBuf::from("removed"), entity}), "{kind:?}");
+        }
+    }
 }
@@ -0,0 +1,144 @@
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+use crate::stats::SyncStats;
+
+// Longest request head read, scrapers send a few short headers
+const MAX_REQUEST_BYTES: usize = 8192;
+// Time a scraper gets to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name, type, help text and value of a metric
+type Metric = (&'static str, &'static str, &'static str, fn(&SyncStats) -> u64);
+
+const METRICS: [Metric; 6] = [
+    ("syncd_events_total", "counter", "Filesystem events seen in the sync directory", SyncStats::events),
+    ("syncd_bytes_sent_total", "counter", "Bytes sent to the broker", SyncStats::bytes_sent),
+    ("syncd_bytes_received_total", "counter", "Bytes received from the broker", SyncStats::bytes_received),
+    ("syncd_transfers_in_flight", "gauge", "Files being fetched in chunks", |stats| stats.transfers_in_flight() as u64),
+    ("syncd_reconnects_total", "counter", "Times connecting to the broker was tried again", SyncStats::reconnects),
+    ("syncd_dropped_events_total", "counter", "Filesystem events dropped because the event buffer was full", SyncStats::dropped_events),
+];
+
+/// Channel, sync directory and counters of each pair the metrics are reported for
+pub type PairStats = Vec<(String, PathBuf, Arc<SyncStats>)>;
+
+/// Answers GET /metrics on the listener with the counters of every pair in the Prometheus
+/// text format, anything else gets a 404. Runs until the process exits
+pub async fn serve_metrics(listener: TcpListener, pairs: Arc<PairStats>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "Failed accepting metrics connection");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue
+            }
+        };
+        let pairs = Arc::clone(&pairs);
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &pairs)).await {
+                Ok(Err(e)) => debug!(%addr, error = %e, "Failed answering metrics request"),
+                Err(_) => debug!(%addr, "Metrics request timed out"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, pairs: &PairStats) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut target = request.lines().next().unwrap_or_default().split(' ');
+    let response = match (target.next(), target.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(pairs);
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Counters of every pair in the Prometheus text format, labelled with channel and syncdir
+fn render(pairs: &PairStats) -> String {
+    let mut out = String::new();
+    for (name, kind, help, value) in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (channel, syncdir, stats) in pairs {
+            let _ = writeln!(out, "{}{{channel=\"{}\",syncdir=\"{}\"}} {}", name, escape_label(channel), escape_label(&syncdir.to_string_lossy()), value(stats));
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_in_the_text_format() {
+        let stats = Arc::new(SyncStats::default());
+        stats.record_dropped_event();
+        stats.record_dropped_event();
+        stats.record_event();
+        let pairs = Arc::new(vec![("chan\"nel".to_string(), PathBuf::from("/sync/dir"), stats)]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_metrics(listener, pairs));
+        let response = get(addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())), "{head}");
+        let mut samples = HashMap::new();
+        let mut types = HashMap::new();
+        for line in body.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.splitn(3, ' ');
+                let (kind, name, rest) = (words.next().unwrap(), words.next().unwrap(), words.next().unwrap());
+                if kind == "TYPE" {
+                    types.insert(name.to_string(), rest.to_string());
+                }
+                continue
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let (name, labels) = series.split_once('{').unwrap();
+            assert_eq!(labels, "channel=\"chan\\\"nel\",syncdir=\"/sync/dir\"}");
+            samples.insert(name.to_string(), value.parse::<u64>().unwrap());
+        }
+        assert_eq!(samples.len(), METRICS.len());
+        assert!(samples.keys().all(|name| types.contains_key(name)));
+        assert_eq!(samples["syncd_dropped_events_total"], 2);
+        assert_eq!(types["syncd_dropped_events_total"], "counter");
+        assert_eq!(samples["syncd_events_total"], 1);
+        assert!(get(addr, "/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.abort();
+    }
+}
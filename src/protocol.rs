@@ -0,0 +1,2366 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use path_clean::PathClean;
+use clap::ValueEnum;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Visitor;
+use serde::ser::Error as _;
+use serde_with::{serde_as, Bytes, DeserializeAs, SerializeAs};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use rayon::prelude::*;
+use crate::error::SyncError;
+use crate::events::EchoSuppressor;
+use crate::fetch::{DiskFull, FetchQueue, ListQueue};
+use crate::filter::PathFilter;
+use crate::hash::{Digest, HashAlgo};
+use crate::delta::{self, DeltaOp};
+use crate::index::FileIndex;
+use crate::stats::SyncStats;
+use crate::writer::{WriteJob, WriteQueue};
+use crate::fs::{
+    entry_hash, finish_write, hash_bytes, link_group, link_target_escapes, list_path, list_tree, make_symlink, move_to_trash, path_escapes_dir,
+    read_chunk, read_link_target, retry_read, tmp_path, try_hash_file, write_atomic, write_chunk, FileAttrs, Fsync, FsyncPolicy,
+};
+
+// Largest amount of file contents sent in a single message, keeps frames
+// well under the u16 length limit of the codec
+pub const TRANSFER_CHUNK_SIZE: u64 = 32 * 1024;
+// Files smaller than this aren't worth compressing
+const COMPRESS_MIN_SIZE: usize = 512;
+const COMPRESS_LEVEL: i32 = 3;
+// Upper bound on what a compressed message may expand to, guards against decompression bombs
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+// How many times a file whose contents arrive corrupted is requested again before giving up
+const MAX_GET_RETRIES: u32 = 3;
+// How long filesystem events are held back to be sent together with the ones following them
+const FS_EVENT_BATCH_WINDOW: Duration = Duration::from_millis(50);
+// Most events held back before they're sent regardless of the window
+const FS_EVENT_BATCH_MAX: usize = 500;
+// What --max-concurrent-transfers, --max-concurrent-lists and --get-timeout-secs default to
+const DEFAULT_CONCURRENCY: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+const DEFAULT_GET_TIMEOUT: Duration = Duration::from_secs(60);
+// Bumped whenever messages change in a way an older peer would misunderstand, peers only
+// sync with ones on the same version
+pub const PROTOCOL_VERSION: u32 = 1;
+// Features advertised in Hello, along with a hash-<algorithm> one for each hash algorithm
+const FEATURE_COMPRESS: &str = "compress";
+const FEATURE_CHUNKED: &str = "chunked";
+const FEATURE_BATCH: &str = "batch";
+const FEATURE_ATTRS: &str = "attrs";
+const FEATURE_ACK: &str = "ack";
+const FEATURE_DELTA: &str = "delta";
+const FEATURE_CHECKSUM: &str = "checksum";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntityType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Serializes paths in the protocol's canonical form, relative and separated with forward
+/// slashes whatever the platform, and turns them back into native paths when received.
+/// Paths are text strings, on unix ones that aren't valid unicode are sent as a byte string
+/// of their raw bytes instead so they arrive unchanged
+pub struct WirePath;
+
+impl SerializeAs<PathBuf> for WirePath {
+    fn serialize_as<S: Serializer>(path: &PathBuf, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut parts = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => parts.push(part),
+                Component::CurDir => parts.push(OsStr::new(".")),
+                Component::ParentDir => parts.push(OsStr::new("..")),
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(S::Error::custom(format!("path {} is not relative", path.display())))
+                }
+            }
+        }
+        if let Some(parts) = parts.iter().map(|part| part.to_str()).collect::<Option<Vec<_>>>() {
+            return serializer.serialize_str(&parts.join("/"))
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            serializer.serialize_bytes(&parts.iter().map(|part| part.as_bytes()).collect::<Vec<_>>().join(&b'/'))
+        }
+        #[cfg(not(unix))]
+        Err(S::Error::custom(format!("path {} is not valid unicode", path.display())))
+    }
+}
+
+impl<'de> DeserializeAs<'de, PathBuf> for WirePath {
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        deserializer.deserialize_any(WirePathVisitor)
+    }
+}
+
+struct WirePathVisitor;
+
+impl Visitor<'_> for WirePathVisitor {
+    type Value = PathBuf;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a path as a text or byte string")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, wire: &str) -> Result<PathBuf, E> {
+        relative_path(wire.split('/').map(OsStr::new), wire.starts_with('/'), wire)
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, wire: &[u8]) -> Result<PathBuf, E> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            relative_path(wire.split(|&byte| byte == b'/').map(OsStr::from_bytes), wire.starts_with(b"/"), &String::from_utf8_lossy(wire))
+        }
+        #[cfg(not(unix))]
+        match std::str::from_utf8(wire) {
+            Ok(wire) => self.visit_str(wire),
+            Err(_) => Err(E::custom(format!("path {} is not valid unicode", String::from_utf8_lossy(wire)))),
+        }
+    }
+}
+
+/// Path made of the parts of a received one, shown being how it's logged if it's rejected
+fn relative_path<'a, E: serde::de::Error>(parts: impl Iterator<Item = &'a OsStr>, absolute: bool, shown: &str) -> Result<PathBuf, E> {
+    let path: PathBuf = parts.filter(|part| !part.is_empty()).collect();
+    // a part like C: would turn into a drive prefix on windows
+    if absolute || !path.is_relative() || path.components().any(|c| matches!(c, Component::Prefix(_))) {
+        return Err(E::custom(format!("path {} is not relative", shown)))
+    }
+    Ok(path)
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListRespEntry {
+    #[serde_as(as = "WirePath")]
+    pub path: PathBuf,
+    pub hash: Digest,
+    pub entity: EntityType,
+    /// Unix permission bits, left out by peers that don't track them
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Modification time in milliseconds since the unix epoch
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    /// What a symlink entry points to, relative to the link's directory
+    #[serde(default)]
+    #[serde_as(as = "Option<WirePath>")]
+    pub link_target: Option<PathBuf>,
+    /// Set for files over the sender's size limit, which are listed without a hash and
+    /// can't be fetched
+    #[serde(default)]
+    pub skipped: bool,
+    /// Shared by the entries of a listing that are hard links to the same file
+    #[serde(default)]
+    pub link_group: Option<u64>,
+}
+
+/// How far a file being fetched in chunks got
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferProgress {
+    #[serde_as(as = "WirePath")]
+    pub path: PathBuf,
+    /// Bytes received so far
+    pub received: u64,
+    /// Size of the file, left out if the sender didn't tell
+    #[serde(default)]
+    pub total: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Protocol {
+    Ping,
+    Pong,
+    /// Sent after subscribing, announcing the protocol version and the optional features
+    /// the sender understands, along with its host name. Answered with HelloResp, which
+    /// carries the same for the answering side
+    Hello {version: u32, #[serde(default)] features: Vec<String>, #[serde(default)] hostname: Option<String>},
+    HelloResp {version: u32, #[serde(default)] features: Vec<String>, #[serde(default)] hostname: Option<String>},
+    /// Lists a directory, descending into subdirectories when recursive is set, down to
+    /// max_depth levels below path if given
+    List {#[serde_as(as = "WirePath")] path: PathBuf, #[serde(default)] recursive: bool, #[serde(default)] max_depth: Option<u32>},
+    /// A listing too large for a single message comes in several, all but the last with more
+    /// set. path is the listed directory, left out by older peers
+    ListResp {
+        entries: Vec<ListRespEntry>,
+        #[serde(default)] #[serde_as(as = "Option<WirePath>")] path: Option<PathBuf>,
+        #[serde(default)] more: bool,
+    },
+    Get {#[serde_as(as = "WirePath")] path: PathBuf},
+    GetResp {
+        #[serde_as(as = "WirePath")] path: PathBuf,
+        #[serde_as(as = "Bytes")] contents: Vec<u8>,
+        /// Contents are zstd compressed
+        #[serde(default)] compressed: bool,
+        /// Hash of the uncompressed contents, checked before the file is written
+        #[serde(default)] hash: Option<Digest>,
+        /// Unix permission bits the file is written with, if the sender tracks them
+        #[serde(default)] mode: Option<u32>,
+        /// Modification time the file is written with, in milliseconds since the unix epoch
+        #[serde(default)] mtime: Option<i64>,
+        /// Set when the path is a symlink, which is recreated pointing here instead of
+        /// contents being written
+        #[serde(default)] #[serde_as(as = "Option<WirePath>")] link_target: Option<PathBuf>,
+    },
+    GetChunk {#[serde_as(as = "WirePath")] path: PathBuf, offset: u64, len: u64},
+    GetChunkResp {
+        #[serde_as(as = "WirePath")] path: PathBuf,
+        offset: u64,
+        #[serde_as(as = "Bytes")] contents: Vec<u8>,
+        eof: bool,
+        #[serde(default)] mode: Option<u32>,
+        #[serde(default)] mtime: Option<i64>,
+        /// Hash of the whole file, sent along with the last chunk
+        #[serde(default)] hash: Option<Digest>,
+        /// Size of the whole file, for reporting progress
+        #[serde(default)] size: Option<u64>,
+    },
+    /// Answers a Get of a file too large for a single message when the requester supports
+    /// deltas, asking for the signature of its copy
+    BlockSigReq {#[serde_as(as = "WirePath")] path: PathBuf},
+    /// Checksums of the blocks of the sender's copy of path, 12 bytes for each block of
+    /// block_size: a rolling weak checksum and an xxh64 of the block, both big endian.
+    /// Answered with DeltaResp, or GetChunkResp when blocks is empty or a delta wouldn't
+    /// save enough
+    BlockSig {#[serde_as(as = "WirePath")] path: PathBuf, block_size: u32, #[serde_as(as = "Bytes")] blocks: Vec<u8>},
+    /// Contents of path from offset on, made of blocks of the receiver's copy and data it
+    /// doesn't have. Like GetChunkResp, the last part has eof set and carries the hash
+    DeltaResp {
+        #[serde_as(as = "WirePath")] path: PathBuf,
+        offset: u64,
+        ops: Vec<DeltaOp>,
+        eof: bool,
+        #[serde(default)] mode: Option<u32>,
+        #[serde(default)] mtime: Option<i64>,
+        #[serde(default)] hash: Option<Digest>,
+    },
+    FsEventCreate {#[serde_as(as = "WirePath")] path: PathBuf, entity: EntityType},
+    FsEventModify {#[serde_as(as = "WirePath")] path: PathBuf, hash: Digest},
+    /// A file's mode or modification time changed while its contents didn't
+    FsEventAttrs {#[serde_as(as = "WirePath")] path: PathBuf, #[serde(default)] mode: Option<u32>, #[serde(default)] mtime: Option<i64>},
+    FsEventRename {#[serde_as(as = "WirePath")] path_from: PathBuf, #[serde_as(as = "WirePath")] path_to: PathBuf},
+    /// entity is what the path was, left out when the watcher couldn't tell
+    FsEventDelete {#[serde_as(as = "WirePath")] path: PathBuf, #[serde(default)] entity: Option<EntityType>},
+    FsEventUnknown {#[serde_as(as = "WirePath")] path: PathBuf, entity: EntityType, hash: Digest},
+    /// Several FsEvent messages sent together, applied in the order they're listed
+    FsEventBatch {events: Vec<Protocol>},
+    /// Sent once a file received from the peer is in place with contents of hash, telling
+    /// the peer both sides have the same contents now
+    Written {#[serde_as(as = "WirePath")] path: PathBuf, hash: Digest},
+    /// Asks what the peer is up to, answered with StatusResp
+    Status,
+    StatusResp {
+        /// Files synced, excluded ones aren't counted
+        file_count: u64,
+        /// Directories synced, the root included
+        watched_paths: u64,
+        /// When the last filesystem event was seen, in seconds since the unix epoch
+        last_event_unix: Option<u64>,
+        /// Bytes sent and received over the broker connection since starting
+        bytes_sent: u64,
+        bytes_received: u64,
+        /// Files being fetched from the peer in chunks
+        #[serde(default)] transfers: Vec<TransferProgress>,
+    },
+    /// Asks the peer to look for changes its watcher may have missed below path, or in the
+    /// whole tree. Changes are sent like the watcher's, FsEventModify for files that changed
+    /// since they were last hashed and FsEventDelete for ones that are gone
+    Rescan {#[serde_as(as = "Option<WirePath>")] #[serde(default)] path: Option<PathBuf>},
+    /// Answers a request that failed, like a Get of a file that doesn't exist or a path
+    /// escaping the synced directory, naming the request's type and path
+    Error {request: String, #[serde_as(as = "Option<WirePath>")] #[serde(default)] path: Option<PathBuf>, message: String},
+}
+
+/// Type and path of a message the peer waits for an answer to, None for messages that
+/// aren't answered
+fn request_of(message: &Protocol) -> Option<(&'static str, Option<PathBuf>)> {
+    match message {
+        Protocol::Get {path} => Some(("Get", Some(path.clone()))),
+        Protocol::GetChunk {path, ..} => Some(("GetChunk", Some(path.clone()))),
+        Protocol::BlockSig {path, ..} => Some(("BlockSig", Some(path.clone()))),
+        Protocol::List {path, ..} => Some(("List", Some(path.clone()))),
+        Protocol::Status => Some(("Status", None)),
+        Protocol::Rescan {path} => Some(("Rescan", path.clone())),
+        _ => None,
+    }
+}
+
+/// Decodes a message from the peer. One of a type this version doesn't know, likely sent by a
+/// newer peer, is told apart from bytes that aren't a message at all
+pub fn decode_message(payload: &[u8]) -> Result<Protocol, SyncError> {
+    ciborium::de::from_reader(payload).map_err(|e| {
+        let unknown_type = matches!(&e, ciborium::de::Error::Semantic(_, msg) if msg.starts_with("unknown variant"));
+        let message_type = || match ciborium::de::from_reader(payload).ok()? {
+            ciborium::Value::Map(entries) => entries.into_iter().find(|(key, _)| key.as_text() == Some("type"))?.1.into_text().ok(),
+            _ => None,
+        };
+        match unknown_type.then(message_type).flatten() {
+            Some(name) => SyncError::UnknownMessage(name),
+            None => SyncError::from(e),
+        }
+    })
+}
+
+/// Answer to a request that failed, so the peer doesn't wait for a response that never comes
+pub fn error_response(request: &str, path: Option<PathBuf>, e: &SyncError) -> Protocol {
+    warn!(error = %e, request, "Failed answering request");
+    Protocol::Error{request: request.to_string(), path, message: e.to_string()}
+}
+
+/// Features both sides support, what a peer that hasn't sent a Hello (yet) gets is the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerFeatures {
+    /// File contents may be sent zstd compressed
+    pub compress: bool,
+    /// Files too large for a single message may be sent with GetChunkResp
+    pub chunked: bool,
+    /// FsEvent messages may be sent together in an FsEventBatch
+    pub batch: bool,
+    /// Mode and modification time changes may be sent on their own with FsEventAttrs
+    pub attrs: bool,
+    /// Files received from the peer may be acknowledged with Written
+    pub ack: bool,
+    /// Changed large files may be sent as a delta against the peer's copy
+    pub delta: bool,
+    /// Messages may carry a checksum, checked by the peer's codec
+    pub checksum: bool,
+    /// Algorithm hashes are sent with, xxh64 is understood by every peer
+    pub hash_algo: HashAlgo,
+}
+
+impl PeerFeatures {
+    /// Intersects the features a peer announced with ours, hashing with the preferred
+    /// algorithm only if the peer knows it
+    pub fn negotiate(features: &[String], preferred: HashAlgo) -> Self {
+        let supports = |feature: &str| features.iter().any(|supported| supported == feature);
+        PeerFeatures {
+            compress: supports(FEATURE_COMPRESS),
+            chunked: supports(FEATURE_CHUNKED),
+            batch: supports(FEATURE_BATCH),
+            attrs: supports(FEATURE_ATTRS),
+            ack: supports(FEATURE_ACK),
+            delta: supports(FEATURE_DELTA),
+            checksum: supports(FEATURE_CHECKSUM),
+            hash_algo: if supports(preferred.feature()) { preferred } else { HashAlgo::Xxh64 },
+        }
+    }
+}
+
+/// Features this side understands, decompressing and checking every hash algorithm included
+/// whatever it's configured to send
+pub fn local_features() -> Vec<String> {
+    let mut features: Vec<String> = [FEATURE_COMPRESS, FEATURE_CHUNKED, FEATURE_BATCH, FEATURE_ATTRS, FEATURE_DELTA, FEATURE_CHECKSUM, FEATURE_ACK].map(String::from).into();
+    features.extend(HashAlgo::value_variants().iter().map(|algo| algo.feature().to_string()));
+    features
+}
+
+/// Name of the machine syncd runs on, announced in Hello
+pub fn local_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Collects outgoing FsEvent messages so bursts of changes go out in as few messages as
+/// possible, flushing once the window since the first held event passes or enough pile up
+#[derive(Default)]
+pub struct EventBatch {
+    events: Vec<Protocol>,
+    deadline: Option<Instant>,
+    max: usize,
+}
+
+impl EventBatch {
+    /// Starts out not batching, until the peer says it understands batches
+    pub fn new() -> Self {
+        EventBatch {
+            events: Vec::new(),
+            deadline: None,
+            max: 1,
+        }
+    }
+
+    pub fn set_batching(&mut self, enabled: bool) {
+        self.max = if enabled { FS_EVENT_BATCH_MAX } else { 1 };
+    }
+
+    /// Holds an event back, returning whether the batch is full and should be flushed right away
+    pub fn push(&mut self, event: Protocol) -> bool {
+        // a directory's delete takes everything in it along, deletes of its contents held
+        // until now don't need sending anymore
+        if let Protocol::FsEventDelete {path: ref dir, entity: Some(EntityType::Directory)} = event {
+            self.events.retain(|held| !matches!(held, Protocol::FsEventDelete {path, ..} if path != dir && path.starts_with(dir)));
+        }
+        self.deadline.get_or_insert_with(|| Instant::now() + FS_EVENT_BATCH_WINDOW);
+        self.events.push(event);
+        self.events.len() >= self.max
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Empties the batch, a lone event is sent as is rather than wrapped in a batch
+    pub fn take(&mut self) -> Option<Protocol> {
+        self.deadline = None;
+        let mut events = std::mem::take(&mut self.events);
+        match events.len() {
+            0 => None,
+            1 => events.pop(),
+            _ => Some(Protocol::FsEventBatch{events}),
+        }
+    }
+}
+
+/// Paths an event from the peer is about
+fn event_paths(event: &Protocol) -> Vec<&Path> {
+    match event {
+        Protocol::FsEventCreate {path, ..} | Protocol::FsEventModify {path, ..} | Protocol::FsEventDelete {path, ..}
+        | Protocol::FsEventAttrs {path, ..} | Protocol::FsEventUnknown {path, ..} => vec![path],
+        Protocol::FsEventRename {path_from, path_to} => vec![path_from, path_to],
+        _ => Vec::new(),
+    }
+}
+
+/// Whether event is about path or a directory it's in
+fn is_about(event: &Protocol, path: &Path) -> bool {
+    event_paths(event).iter().any(|other| path.starts_with(other))
+}
+
+/// Whether event is about something in dir
+fn is_below(event: &Protocol, dir: &Path) -> bool {
+    event_paths(event).iter().any(|other| *other != dir && other.starts_with(dir))
+}
+
+/// Orders the events of a batch from the peer so directories are created before anything in
+/// them and deleted after everything in them, whatever order the peer saw them in. An event is
+/// never moved past one about the same path or a directory it's in, so what happens to a path
+/// stays in order
+pub fn order_batch(events: &mut Vec<Protocol>) {
+    for i in 0..events.len() {
+        if let Protocol::FsEventCreate {path: dir, entity: EntityType::Directory} = &events[i] {
+            let first = (0..i).rev()
+                .take_while(|&k| !is_about(&events[k], dir))
+                .filter(|&k| is_below(&events[k], dir))
+                .last();
+            if let Some(first) = first {
+                let create = events.remove(i);
+                events.insert(first, create);
+            }
+        }
+    }
+    for i in (0..events.len()).rev() {
+        // nothing is in a file
+        if let Protocol::FsEventDelete {path: dir, entity: None | Some(EntityType::Directory)} = &events[i] {
+            let last = (i + 1..events.len())
+                .take_while(|&k| !is_about(&events[k], dir))
+                .filter(|&k| is_below(&events[k], dir))
+                .last();
+            if let Some(last) = last {
+                let delete = events.remove(i);
+                events.insert(last, delete);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Delete the path locally as well
+    Propagate,
+    /// Move the path into the .syncd-trash directory in the sync root
+    Trash,
+    /// Keep the path
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictMode {
+    /// Keep whichever copy was modified last
+    Newest,
+    /// Keep whichever copy was modified last, saving the other one next to it as
+    /// name.conflict-<hostname>.ext, named after the host it came from
+    Rename,
+    /// Keep the local copy
+    KeepLocal,
+}
+
+/// State of the synchronized directory shared by the message and filesystem event handlers
+pub struct SyncContext {
+    /// Absolute and canonical
+    pub syncdir: PathBuf,
+    /// Directory syncing is limited to, the subpath inside syncdir or syncdir itself.
+    /// Absolute and canonical too
+    pub scope: PathBuf,
+    pub filter: Arc<PathFilter>,
+    /// Reconcile the local tree with the peer's after connecting
+    pub initial_sync: bool,
+    pub echoes: EchoSuppressor,
+    /// Temporary files of chunked transfers that haven't received their last chunk yet
+    pub partial_writes: HashMap<PathBuf, PartialWrite>,
+    /// Compress file contents sent to the peer
+    pub compress: bool,
+    /// Files requested again because their contents arrived corrupted, with the number of attempts
+    pub get_retries: HashMap<PathBuf, u32>,
+    /// Files the initial sync is still to fetch or waits for
+    pub fetches: FetchQueue,
+    /// Directories waiting to be listed by the peer
+    pub lists: ListQueue,
+    /// Holds back fetching while the disk is full
+    pub disk_full: DiskFull,
+    /// Received files waiting to be written
+    pub writes: WriteQueue,
+    /// Flushes files written outside of the write queue
+    pub fsync: Fsync,
+    pub delete_mode: DeleteMode,
+    /// Files larger than this many bytes aren't sent
+    pub max_file_size: Option<u64>,
+    /// Algorithm hashes sent to the peer are preferably computed with, received ones are
+    /// checked with whichever algorithm they name
+    pub hash_algo: HashAlgo,
+    /// What was agreed on with the peer of the current connection
+    pub peer: PeerFeatures,
+    /// Host name the peer of the current connection announced, if any
+    pub peer_hostname: Option<String>,
+    pub conflict_mode: ConflictMode,
+    /// Log changes to the sync directory instead of making them
+    pub dry_run: bool,
+    /// Never change the sync directory, only answer the peer's requests
+    pub read_only: bool,
+    /// With --verify, the peer's listing as far as it arrived
+    pub verify: Option<Vec<ListRespEntry>>,
+    pub index: Arc<FileIndex>,
+    pub stats: Arc<SyncStats>,
+}
+
+/// Creates or replaces a symlink received from the peer, the link is created next to its
+/// final location and renamed there like written files are
+fn write_symlink(path: &Path, target: &Path, ctx: &mut SyncContext) -> Result<(), SyncError> {
+    if link_target_escapes(path, target) {
+        return Err(SyncError::PathEscapes(path.join(target)))
+    }
+    let (writepath, tmppath) = prepare_write(path, ctx)?;
+    let target_is_dir = writepath.parent().is_some_and(|parent| parent.join(target).is_dir());
+    let _ = fs::remove_file(&tmppath);
+    let created = make_symlink(target, &tmppath, target_is_dir).and_then(|_| {
+        ctx.echoes.suppress(path);
+        fs::rename(&tmppath, &writepath)
+    });
+    if let Err(e) = created {
+        let _ = fs::remove_file(&tmppath);
+        return Err(SyncError::fs(writepath, e))
+    }
+    ctx.fsync.renamed(&writepath);
+    info!(path = %writepath.display(), target = %target.display(), "Created symlink");
+    Ok(())
+}
+
+/// Resolves a path received from the peer against the sync root, refusing paths
+/// that escape it or are excluded from syncing
+pub fn resolve_path(path: &Path, is_dir: bool, ctx: &SyncContext) -> Result<PathBuf, SyncError> {
+    resolve_in(path, is_dir, &ctx.syncdir, &ctx.scope, &ctx.filter)
+}
+
+fn resolve_in(path: &Path, is_dir: bool, root: &Path, scope: &Path, filter: &PathFilter) -> Result<PathBuf, SyncError> {
+    let fullpath = root.join(path).clean();
+    if path_escapes_dir(&fullpath, root) {
+        return Err(SyncError::PathEscapes(fullpath))
+    }
+    if filter.is_excluded(path, is_dir) {
+        return Err(SyncError::Excluded(path.to_path_buf()))
+    }
+    // what's left outside the scope is a symlink leading out of it, the directories leading
+    // to the scope are canonical already and can't
+    if !scope.starts_with(&fullpath) && path_escapes_dir(&fullpath, scope) {
+        return Err(SyncError::PathEscapes(fullpath))
+    }
+    Ok(fullpath)
+}
+
+/// Refuses to delete or move a resolved path containing the scope, which takes paths
+/// that aren't synced along
+fn check_not_above_scope(fullpath: &Path, ctx: &SyncContext) -> Result<(), SyncError> {
+    if *fullpath != ctx.scope && ctx.scope.starts_with(fullpath) {
+        return Err(SyncError::Protocol(format!("{} contains paths outside the synced subpath, leaving it", fullpath.display())))
+    }
+    Ok(())
+}
+
+/// Validates a path received from the peer and prepares its parent directory for writing,
+/// returning the target path and the temporary path contents are written to before
+/// being renamed over the target, so readers never see a partial file
+fn prepare_write(path: &Path, ctx: &mut SyncContext) -> Result<(PathBuf, PathBuf), SyncError> {
+    let writepath = resolve_path(path, false, ctx)?;
+    let Some(filename) = writepath.file_name() else {
+        return Err(SyncError::Protocol(format!("path {} does not name a file", path.display())))
+    };
+    if let Some(parent) = writepath.parent() {
+        create_dirs(parent, ctx)?;
+    }
+    let tmppath = tmp_path(&writepath, filename);
+    Ok((writepath, tmppath))
+}
+
+/// How far the transfer of a file received in several messages got
+#[derive(Debug, Clone, Copy)]
+pub struct PartialWrite {
+    /// Bytes of the file written to its temporary file so far
+    pub received: u64,
+    /// Hash of the whole file, if the peer sends it along with every chunk. With it a
+    /// transfer cut off by a reconnect continues where it stopped
+    pub hash: Option<Digest>,
+}
+
+/// Target and temporary file of a transfer of path in progress, with how far it got
+fn partial_write(path: &Path, ctx: &SyncContext) -> Option<(PathBuf, PathBuf, PartialWrite)> {
+    let writepath = resolve_path(path, false, ctx).ok()?;
+    let tmppath = tmp_path(&writepath, writepath.file_name()?);
+    let partial = *ctx.partial_writes.get(&tmppath)?;
+    Some((writepath, tmppath, partial))
+}
+
+/// Drops the temporary file of a chunked transfer of path that won't be completed
+pub fn abandon_partial_write(path: &Path, ctx: &mut SyncContext) {
+    ctx.stats.transfer_done(path);
+    if let Some((_, tmppath, _)) = partial_write(path, ctx) {
+        ctx.partial_writes.remove(&tmppath);
+        let _ = std::fs::remove_file(&tmppath);
+        info!(path = %tmppath.display(), "Discarded incomplete transfer");
+    }
+}
+
+/// Creates a directory along with its missing parents, expecting a watcher event for each one
+fn create_dirs(dir: &Path, ctx: &mut SyncContext) -> Result<(), SyncError> {
+    for missing in dir.ancestors().take_while(|ancestor| !ancestor.exists()) {
+        if let Ok(strippath) = missing.strip_prefix(&ctx.syncdir) {
+            ctx.echoes.suppress(strippath);
+        }
+    }
+    fs::create_dir_all(dir).map_err(|e| SyncError::fs(dir, e))
+}
+
+/// What answering a request for the contents of a file looks at, so it can be answered on
+/// the blocking thread pool
+#[derive(Clone, Copy)]
+pub struct ReadOptions<'a> {
+    pub root: &'a Path,
+    pub scope: &'a Path,
+    pub filter: &'a PathFilter,
+    /// Chunks carry the hash of the whole file, computed once through it
+    pub index: &'a FileIndex,
+    /// Files larger than this are refused
+    pub max_file_size: Option<u64>,
+    pub compress: bool,
+    pub peer: PeerFeatures,
+}
+
+impl ReadOptions<'_> {
+    fn resolve(&self, path: &Path) -> Result<PathBuf, SyncError> {
+        resolve_in(path, false, self.root, self.scope, self.filter)
+    }
+}
+
+/// Answers a Get, GetChunk or BlockSig with the contents of the file asked for, failed
+/// requests are answered with an Error like handle_message does. Only reads files
+pub fn answer_read(request: Protocol, opts: &ReadOptions) -> Option<Protocol> {
+    let name = request_of(&request);
+    read_request(request, opts).unwrap_or_else(|e| name.map(|(request, path)| error_response(request, path, &e)))
+}
+
+fn read_request(request: Protocol, opts: &ReadOptions) -> Result<Option<Protocol>, SyncError> {
+    match request {
+        Protocol::Get {path} => {
+            let watchpath = opts.resolve(&path)?;
+            let linkmeta = fs::symlink_metadata(&watchpath).map_err(|e| SyncError::fs(&watchpath, e))?;
+            if linkmeta.is_symlink() {
+                let link_target = Some(read_link_target(&path, &watchpath)?);
+                return Ok(Some(Protocol::GetResp{path, contents: Vec::new(), compressed: false, hash: None, mode: None, mtime: None, link_target}))
+            }
+            // files that don't fit in a single message are sent in chunks instead,
+            // the receiver asks for the rest with GetChunk
+            let meta = file_meta(&watchpath)?;
+            check_file_size(&watchpath, meta.len(), opts.max_file_size)?;
+            let attrs = FileAttrs::of(&meta);
+            if meta.len() > TRANSFER_CHUNK_SIZE {
+                if !opts.peer.chunked {
+                    return Err(SyncError::Protocol(format!("{} needs a chunked transfer, which the peer doesn't support", path.display())))
+                }
+                // the peer's copy may only be a few blocks off
+                if opts.peer.delta && meta.len() <= delta::MAX_FILE_SIZE {
+                    return Ok(Some(Protocol::BlockSigReq{path}))
+                }
+                return read_chunk_resp(&watchpath, path, 0, TRANSFER_CHUNK_SIZE, opts)
+            }
+            let data = retry_read(&watchpath, || fs::read(&watchpath)).map_err(|e| SyncError::fs(&watchpath, e))?;
+            let hash = Some(hash_bytes(&data, opts.peer.hash_algo));
+            if opts.compress && opts.peer.compress && data.len() >= COMPRESS_MIN_SIZE {
+                let compressed = zstd::bulk::compress(&data, COMPRESS_LEVEL)?;
+                if compressed.len() < data.len() {
+                    return Ok(Some(Protocol::GetResp{path, contents: compressed, compressed: true, hash, mode: attrs.mode, mtime: attrs.mtime, link_target: None}))
+                }
+            }
+            Ok(Some(Protocol::GetResp{path, contents: data, compressed: false, hash, mode: attrs.mode, mtime: attrs.mtime, link_target: None}))
+        },
+        Protocol::GetChunk {path, offset, len} => {
+            let watchpath = opts.resolve(&path)?;
+            let meta = file_meta(&watchpath)?;
+            check_file_size(&watchpath, meta.len(), opts.max_file_size)?;
+            read_chunk_resp(&watchpath, path, offset, len, opts)
+        },
+        Protocol::BlockSig {path, block_size, blocks} => {
+            let watchpath = opts.resolve(&path)?;
+            let meta = file_meta(&watchpath)?;
+            check_file_size(&watchpath, meta.len(), opts.max_file_size)?;
+            let usable = !blocks.is_empty() && (1..=delta::MAX_BLOCK_SIZE).contains(&block_size) && meta.len() <= delta::MAX_FILE_SIZE;
+            if usable {
+                let data = retry_read(&watchpath, || fs::read(&watchpath)).map_err(|e| SyncError::fs(&watchpath, e))?;
+                if let Some(ops) = delta::delta(&data, block_size, &blocks) {
+                    let attrs = FileAttrs::of(&meta);
+                    let hash = hash_bytes(&data, opts.peer.hash_algo);
+                    debug!(path = %path.display(), ops = ops.len(), "Sending delta");
+                    return Ok(Some(Protocol::DeltaResp{path, offset: 0, ops, eof: true, mode: attrs.mode, mtime: attrs.mtime, hash: Some(hash)}))
+                }
+            }
+            read_chunk_resp(&watchpath, path, 0, TRANSFER_CHUNK_SIZE, opts)
+        },
+        // nothing else reads a file for the peer
+        _ => Ok(None),
+    }
+}
+
+fn read_chunk_resp(watchpath: &Path, path: PathBuf, offset: u64, len: u64, opts: &ReadOptions) -> Result<Option<Protocol>, SyncError> {
+    let (contents, eof) = read_chunk(watchpath, offset, len).map_err(|e| SyncError::fs(watchpath, e))?;
+    let meta = fs::metadata(watchpath).ok();
+    let attrs = meta.as_ref().map(FileAttrs::of).unwrap_or_default();
+    // hashed with the first chunk, the index keeps it for the others as long as the file
+    // doesn't change. Lets the receiver tell whether what it has of the file is still of
+    // use after a reconnect, and check the whole of it once the last chunk arrives
+    let hash = opts.index.hash(&path, opts.peer.hash_algo);
+    let hash = if eof {
+        Some(hash.map_err(|e| SyncError::fs(watchpath, e))?)
+    } else {
+        hash.ok()
+    };
+    Ok(Some(Protocol::GetChunkResp{path, offset, contents, eof, mode: attrs.mode, mtime: attrs.mtime, hash, size: meta.map(|meta| meta.len())}))
+}
+
+/// Continues the chunked transfer of a file requested with Get where it stopped, if part of
+/// it arrived before the connection was lost. Whether the file changed since is told by the
+/// hash the peer answers with
+pub fn resume_request(request: Protocol, ctx: &SyncContext) -> Protocol {
+    let Protocol::Get {path} = request else {
+        return request
+    };
+    match partial_write(&path, ctx) {
+        // the temporary file may have been cut short or removed meanwhile
+        Some((_, tmppath, PartialWrite {received, hash: Some(_)})) if fs::metadata(&tmppath).is_ok_and(|meta| meta.len() == received) => {
+            info!(path = %path.display(), offset = received, "Resuming interrupted transfer");
+            Protocol::GetChunk{path, offset: received, len: TRANSFER_CHUNK_SIZE}
+        }
+        _ => Protocol::Get{path},
+    }
+}
+
+/// Requests a file again after its contents arrived not matching their hash, giving up
+/// after a few attempts
+fn retry_get(path: PathBuf, received: Digest, expected: Digest, ctx: &mut SyncContext) -> Result<Option<Protocol>, SyncError> {
+    warn!(path = %path.display(), %received, %expected, "Received contents don't match their hash");
+    let attempts = ctx.get_retries.entry(path.clone()).or_insert(0);
+    *attempts += 1;
+    if *attempts > MAX_GET_RETRIES {
+        ctx.get_retries.remove(&path);
+        return Err(SyncError::Protocol(format!("giving up on {} after {} corrupted transfers", path.display(), MAX_GET_RETRIES)))
+    }
+    Ok(Some(Protocol::Get{path}))
+}
+
+/// Contents of a file received from the peer, in memory or in the temporary file of a
+/// chunked transfer
+enum Received<'a> {
+    Contents(&'a [u8]),
+    TmpFile(&'a Path),
+}
+
+impl Received<'_> {
+    fn hash(&self, algo: HashAlgo) -> io::Result<Digest> {
+        match self {
+            Received::Contents(contents) => Ok(hash_bytes(contents, algo)),
+            Received::TmpFile(tmppath) => try_hash_file(tmppath, algo),
+        }
+    }
+
+    /// Writes the contents to a file of its own, which the watcher picks up and sends
+    /// to the peer like any new file
+    fn save_as(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Received::Contents(contents) => fs::write(path, contents),
+            Received::TmpFile(tmppath) => fs::copy(tmppath, path).map(|_| ()),
+        }
+    }
+}
+
+/// Where the losing copy of a conflict is kept, name.ext becoming name.conflict-<host>.ext
+fn conflict_path(path: &Path, host: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(".conflict-");
+    name.push(host.replace(['/', '\\'], "_"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// Decides whether contents received for path replace the local file. They always do
+/// unless the local file changed since both sides last had the same contents, in which
+/// case the conflict is settled as configured. Both sides see the same conflict and
+/// pick the same copy as the newest one, the other one is dropped or, when renaming,
+/// saved next to it under the name of the host it came from
+fn settle_conflict(path: &Path, writepath: &Path, received: Received, mtime: Option<i64>, ctx: &SyncContext) -> Result<bool, SyncError> {
+    let Some(synced) = ctx.index.synced(path) else {
+        return Ok(true)
+    };
+    // a file that's gone has nothing left to lose
+    let Ok(local) = ctx.index.hash(path, synced.algo()) else {
+        return Ok(true)
+    };
+    if local == synced {
+        return Ok(true)
+    }
+    let incoming = received.hash(synced.algo()).map_err(|e| SyncError::fs(writepath, e))?;
+    // both sides made the same change
+    if incoming == local {
+        return Ok(true)
+    }
+    let local_mtime = fs::metadata(writepath).ok().and_then(|meta| FileAttrs::of(&meta).mtime);
+    // the hash breaks a tie so both sides agree on the winner
+    let incoming_wins = (mtime, incoming) > (local_mtime, local);
+    warn!(path = %writepath.display(), %local, remote = %incoming, mode = ?ctx.conflict_mode, incoming_wins, "File changed on both sides");
+    match ctx.conflict_mode {
+        ConflictMode::Newest => Ok(incoming_wins),
+        ConflictMode::KeepLocal => Ok(false),
+        ConflictMode::Rename if incoming_wins => {
+            let keptpath = conflict_path(writepath, &local_hostname());
+            fs::copy(writepath, &keptpath).map_err(|e| SyncError::fs(&keptpath, e))?;
+            info!(path = %keptpath.display(), "Saved local copy of conflicting file");
+            Ok(true)
+        }
+        ConflictMode::Rename => {
+            let keptpath = conflict_path(writepath, ctx.peer_hostname.as_deref().unwrap_or("peer"));
+            received.save_as(&keptpath).map_err(|e| SyncError::fs(&keptpath, e))?;
+            info!(path = %keptpath.display(), "Saved remote copy of conflicting file");
+            Ok(false)
+        }
+    }
+}
+
+/// Moves the fully received temporary file of a chunked transfer or a delta into place once
+/// its hash checks out and any conflict is settled. Returns the received and the expected
+/// hash if they differ, the temporary file is gone either way
+fn finish_transfer(path: &Path, writepath: &Path, tmppath: &Path, hash: Option<Digest>, attrs: FileAttrs, ctx: &mut SyncContext) -> Result<Option<(Digest, Digest)>, SyncError> {
+    ctx.partial_writes.remove(tmppath);
+    ctx.stats.transfer_done(path);
+    if let Some(hash) = hash {
+        let received = try_hash_file(tmppath, hash.algo()).map_err(|e| SyncError::fs(tmppath, e))?;
+        if received != hash {
+            let _ = fs::remove_file(tmppath);
+            return Ok(Some((received, hash)))
+        }
+    }
+    ctx.get_retries.remove(path);
+    if !settle_conflict(path, writepath, Received::TmpFile(tmppath), attrs.mtime, ctx)? {
+        let _ = fs::remove_file(tmppath);
+        return Ok(None)
+    }
+    let hash = match hash {
+        Some(hash) => hash,
+        None => try_hash_file(tmppath, ctx.peer.hash_algo).map_err(|e| SyncError::fs(tmppath, e))?,
+    };
+    ctx.echoes.suppress(path);
+    finish_write(tmppath, writepath, attrs, &mut ctx.fsync)?;
+    ctx.index.record(path, hash);
+    ctx.index.set_synced(path, hash);
+    ctx.writes.written_inline(path, hash);
+    Ok(None)
+}
+
+/// Fills in a file from a local one with the same hash instead of fetching it, as a hard
+/// link of it with link where the filesystem allows and as a copy otherwise. Returns false
+/// if the local one turned out to have other contents by now
+pub fn copy_identical(path: &Path, source: &Path, hash: Digest, attrs: FileAttrs, link: bool, ctx: &mut SyncContext) -> Result<bool, SyncError> {
+    let sourcepath = resolve_path(source, false, ctx)?;
+    if ctx.dry_run {
+        let action = if link { "Dry run, would link to identical file" } else { "Dry run, would copy identical file" };
+        info!(path = %path.display(), source = %source.display(), "{}", action);
+        return Ok(true)
+    }
+    let (writepath, tmppath) = prepare_write(path, ctx)?;
+    let _ = fs::remove_file(&tmppath);
+    let linked = link && fs::hard_link(&sourcepath, &tmppath)
+        .inspect_err(|e| debug!(path = %path.display(), error = %e, "Failed hard linking file, copying it instead"))
+        .is_ok();
+    if !linked {
+        if let Err(e) = fs::copy(&sourcepath, &tmppath) {
+            let _ = fs::remove_file(&tmppath);
+            return Err(SyncError::fs(sourcepath, e))
+        }
+    }
+    if finish_transfer(path, &writepath, &tmppath, Some(hash), attrs, ctx)?.is_some() {
+        return Ok(false)
+    }
+    if linked {
+        info!(path = %path.display(), source = %source.display(), "Hard linked file to another of its paths instead of fetching it");
+    } else {
+        info!(path = %path.display(), source = %source.display(), "Copied identical local file instead of fetching it");
+    }
+    Ok(true)
+}
+
+/// Handles a single message from the peer against syncdir with the default settings,
+/// returning what to answer with once a file it carried is written. Nothing is kept between
+/// calls, transfers spanning several messages need a SyncContext that lives as long as they do
+pub fn handle_message(message: Protocol, syncdir: &Path) -> Result<Option<Protocol>, SyncError> {
+    let mut ctx = SyncContext::new(syncdir)?;
+    let response = ctx.handle_message(message)?;
+    ctx.writes.close()?;
+    ctx.fsync.flush();
+    Ok(response)
+}
+
+impl SyncContext {
+    /// Context of syncdir with the settings the command line defaults to
+    pub fn new(syncdir: &Path) -> Result<Self, SyncError> {
+        let syncdir = fs::canonicalize(syncdir).map_err(|e| SyncError::fs(syncdir, e))?;
+        let index = Arc::new(FileIndex::load(&syncdir, false));
+        Ok(SyncContext {
+            filter: Arc::new(PathFilter::load(&syncdir, &[], &[], false, true, None)),
+            scope: syncdir.clone(),
+            syncdir,
+            initial_sync: false,
+            echoes: EchoSuppressor::new(),
+            partial_writes: HashMap::new(),
+            compress: false,
+            get_retries: HashMap::new(),
+            fetches: FetchQueue::new(DEFAULT_CONCURRENCY, false, Some(DEFAULT_GET_TIMEOUT)),
+            lists: ListQueue::new(DEFAULT_CONCURRENCY),
+            disk_full: DiskFull::default(),
+            writes: WriteQueue::new(Arc::clone(&index), FsyncPolicy::Batch),
+            fsync: Fsync::new(FsyncPolicy::Batch),
+            delete_mode: DeleteMode::Propagate,
+            max_file_size: None,
+            hash_algo: HashAlgo::Xxh64,
+            peer: PeerFeatures::default(),
+            peer_hostname: None,
+            conflict_mode: ConflictMode::Newest,
+            dry_run: false,
+            read_only: false,
+            verify: None,
+            index,
+            stats: Arc::new(SyncStats::default()),
+        })
+    }
+
+    /// What answering the peer's requests for file contents looks at
+    pub fn read_options(&self) -> ReadOptions<'_> {
+        ReadOptions{root: &self.syncdir, scope: &self.scope, filter: &self.filter, index: &self.index, max_file_size: self.max_file_size, compress: self.compress, peer: self.peer}
+    }
+
+    /// Handles a message from the peer, returning what to answer with. Failed requests are
+    /// answered with an Error, other failures are returned
+    pub fn handle_message(&mut self, message: Protocol) -> Result<Option<Protocol>, SyncError> {
+        // the only way to the synced directory, requests are still answered
+        if self.dry_run && log_dry_run(&message, self) {
+            return Ok(None)
+        }
+        if self.read_only && log_read_only(&message) {
+            return Ok(None)
+        }
+        let request = request_of(&message);
+        apply_message(message, self).or_else(|e| match request {
+            Some((request, path)) => Ok(Some(error_response(request, path, &e))),
+            None => Err(e),
+        })
+    }
+}
+
+/// Logs what a message from the peer would change in the synced directory, returning false
+/// for messages that don't change it
+fn log_dry_run(message: &Protocol, ctx: &SyncContext) -> bool {
+    match message {
+        Protocol::GetResp {path, link_target: Some(target), ..} => {
+            info!(path = %path.display(), target = %target.display(), "Dry run, would create symlink");
+        }
+        Protocol::GetResp {path, contents, compressed, ..} => {
+            info!(path = %path.display(), size = contents.len(), compressed, "Dry run, would write file");
+        }
+        // the rest of the file isn't asked for
+        Protocol::GetChunkResp {path, size, ..} => info!(path = %path.display(), size, "Dry run, would write file"),
+        Protocol::DeltaResp {path, ..} => info!(path = %path.display(), "Dry run, would update file"),
+        Protocol::FsEventCreate {path, entity: entity @ (EntityType::File | EntityType::Directory)} => {
+            info!(path = %path.display(), ?entity, "Dry run, would create");
+        }
+        Protocol::FsEventRename {path_from, path_to} => {
+            info!(from = %path_from.display(), to = %path_to.display(), "Dry run, would rename");
+        }
+        Protocol::FsEventAttrs {path, mode, mtime} => info!(path = %path.display(), ?mode, ?mtime, "Dry run, would update file metadata"),
+        Protocol::FsEventDelete {path, ..} if ctx.delete_mode != DeleteMode::Ignore => {
+            info!(path = %path.display(), mode = ?ctx.delete_mode, "Dry run, would delete");
+        }
+        _ => return false,
+    }
+    true
+}
+
+
+/// Answers a Status request, counting synced paths by walking the tree without hashing it
+pub fn status(root: &Path, filter: &PathFilter, stats: &SyncStats) -> Result<Protocol, SyncError> {
+    let mut file_count = 0;
+    let mut watched_paths = 1;
+    for (path, ftype) in list_tree(root, None, root, filter)? {
+        let excluded = path.strip_prefix(root).map_or(true, |strippath| filter.is_excluded(strippath, ftype.is_dir()));
+        if excluded {
+            continue
+        }
+        if ftype.is_dir() {
+            watched_paths += 1;
+        } else if ftype.is_file() {
+            file_count += 1;
+        }
+    }
+    Ok(Protocol::StatusResp {
+        file_count,
+        watched_paths,
+        last_event_unix: stats.last_event_unix(),
+        bytes_sent: stats.bytes_sent(),
+        bytes_received: stats.bytes_received(),
+        transfers: stats.transfers(),
+    })
+}
+
+/// Finds what changed below dir without the watcher reporting it, after it lost events.
+/// Files the index doesn't know or whose size or modification time changed since they were
+/// hashed come back as FsEventModify for the peer to compare, files it knows that are gone
+/// as FsEventDelete. New directories reach the peer along with the files in them
+pub fn rescan(dir: &Path, opts: &ListOptions) -> Result<Vec<Protocol>, SyncError> {
+    let ListOptions {root, filter, index, max_file_size, algo} = *opts;
+    let reldir = dir.strip_prefix(root).map_err(|_| SyncError::PathEscapes(dir.to_path_buf()))?;
+    let files: Vec<_> = list_tree(dir, None, root, filter)?.into_iter()
+        .filter(|(_, ftype)| ftype.is_file())
+        .filter_map(|(path, _)| path.strip_prefix(root).ok().map(Path::to_path_buf))
+        .filter(|strippath| !filter.is_excluded(strippath, false))
+        .collect();
+    let mut events: Vec<_> = files.par_iter()
+        .filter_map(|strippath| {
+            let fullpath = root.join(strippath);
+            let meta = fs::metadata(&fullpath).ok()?;
+            if check_file_size(&fullpath, meta.len(), max_file_size).is_err() || index.is_current(strippath, &meta, algo) {
+                return None
+            }
+            match index.hash(strippath, algo) {
+                Ok(hash) => Some(Protocol::FsEventModify{path: strippath.clone(), hash}),
+                Err(e) => {
+                    warn!(error = %SyncError::fs(fullpath, e), "Failed hashing rescanned file");
+                    None
+                }
+            }
+        })
+        .collect();
+    // only what's certainly gone, not what's below a directory that couldn't be read
+    for path in index.files_under(reldir) {
+        if fs::symlink_metadata(root.join(&path)).is_err_and(|e| e.kind() == io::ErrorKind::NotFound) && !filter.is_excluded(&path, false) {
+            index.forget(&path);
+            events.push(Protocol::FsEventDelete{path, entity: Some(EntityType::File)});
+        }
+    }
+    Ok(events)
+}
+
+/// Stats a file about to be read for the peer, refusing anything but regular files:
+/// reading devices or FIFOs reachable through symlinked directories can block forever
+fn file_meta(watchpath: &Path) -> Result<fs::Metadata, SyncError> {
+    let meta = fs::metadata(watchpath).map_err(|e| SyncError::fs(watchpath, e))?;
+    if !meta.is_file() {
+        return Err(SyncError::NotAFile(watchpath.to_path_buf()))
+    }
+    Ok(meta)
+}
+
+/// Refuses to send files larger than the size limit
+fn check_file_size(path: &Path, size: u64, max_file_size: Option<u64>) -> Result<(), SyncError> {
+    match max_file_size {
+        Some(limit) if size > limit => Err(SyncError::TooLarge{path: path.to_path_buf(), size, limit}),
+        _ => Ok(()),
+    }
+}
+
+/// What listing looks at besides the listed path
+#[derive(Clone, Copy)]
+pub struct ListOptions<'a> {
+    pub root: &'a Path,
+    pub filter: &'a PathFilter,
+    /// Files are hashed through it, sparing those that didn't change
+    pub index: &'a FileIndex,
+    /// Files larger than this are listed as skipped
+    pub max_file_size: Option<u64>,
+    pub algo: HashAlgo,
+}
+
+/// Lists path for a List request, hashing the entries in parallel. Entries are returned in
+/// the order they were listed in, entries that can't be read are left out
+pub fn list_entries(watchpath: &Path, recursive: bool, max_depth: Option<u32>, opts: &ListOptions) -> Result<Vec<ListRespEntry>, SyncError> {
+    let ListOptions {root, filter, index, max_file_size, algo} = *opts;
+    let paths = if recursive {
+        list_tree(watchpath, max_depth, root, filter)?
+    } else {
+        list_path(watchpath)?
+    };
+    let entries = paths.par_iter()
+        .map(|(listpath, ftype)| -> Result<Option<ListRespEntry>, SyncError> {
+            let entity = if ftype.is_file() {
+                EntityType::File
+            } else if ftype.is_dir() {
+                EntityType::Directory
+            } else if ftype.is_symlink() {
+                EntityType::Symlink
+            } else {
+                EntityType::File
+            };
+            let Ok(strippath) = listpath.strip_prefix(root) else {
+                return Err(SyncError::PathEscapes(listpath.clone()))
+            };
+            if filter.is_excluded(strippath, ftype.is_dir()) {
+                return Ok(None)
+            }
+            let skipped = ftype.is_file() && fs::metadata(listpath)
+                .is_ok_and(|meta| check_file_size(listpath, meta.len(), max_file_size).is_err());
+            if skipped {
+                info!(path = %strippath.display(), "Listing file over the size limit as skipped");
+            }
+            let hash = if skipped {
+                algo.zero()
+            } else {
+                // an entry that can't be read couldn't be fetched either
+                let hash = if ftype.is_file() {
+                    index.hash(strippath, algo).map_err(|e| SyncError::fs(listpath, e))
+                } else {
+                    entry_hash(listpath, ftype, root, filter, algo)
+                };
+                match hash {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        warn!(error = %e, "Leaving unreadable entry out of listing");
+                        return Ok(None)
+                    }
+                }
+            };
+            let link_target = if ftype.is_symlink() {
+                match read_link_target(strippath, listpath) {
+                    Ok(target) => Some(target),
+                    Err(e) => {
+                        warn!(error = %e, "Leaving symlink out of listing");
+                        return Ok(None)
+                    }
+                }
+            } else {
+                None
+            };
+            debug!(path = %strippath.display(), "Returning path");
+            let meta = fs::symlink_metadata(listpath).ok();
+            let attrs = meta.as_ref().map(FileAttrs::of).unwrap_or_default();
+            Ok(Some(ListRespEntry {
+                path: strippath.to_path_buf(),
+                hash,
+                entity,
+                mode: attrs.mode,
+                mtime: attrs.mtime,
+                link_target,
+                skipped,
+                link_group: meta.as_ref().filter(|_| !skipped).and_then(link_group),
+            }))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries.into_iter().flatten().collect())
+}
+
+/// Logs that a message from the peer that would change the synced directory, or ask for
+/// contents to change it with, is ignored. Returns false for messages that don't
+fn log_read_only(message: &Protocol) -> bool {
+    let path = match message {
+        Protocol::FsEventCreate {path, ..} | Protocol::FsEventModify {path, ..} | Protocol::FsEventDelete {path, ..}
+        | Protocol::FsEventAttrs {path, ..} | Protocol::FsEventUnknown {path, ..} | Protocol::GetResp {path, ..}
+        | Protocol::GetChunkResp {path, ..} | Protocol::BlockSigReq {path} | Protocol::DeltaResp {path, ..} => path,
+        Protocol::FsEventRename {path_from, ..} => path_from,
+        _ => return false,
+    };
+    info!(path = %path.display(), "Read only, ignoring change from the peer");
+    true
+}
+
+fn apply_message(message: Protocol, ctx: &mut SyncContext) -> Result<Option<Protocol>, SyncError> {
+    let syncdir = ctx.syncdir.as_path();
+    match message {
+        Protocol::Ping => Ok(Some(Protocol::Pong)),
+        request @ (Protocol::Get {..} | Protocol::GetChunk {..} | Protocol::BlockSig {..}) => read_request(request, &ctx.read_options()),
+        Protocol::GetResp {path, link_target: Some(target), ..} => {
+            write_symlink(&path, &target, ctx)?;
+            Ok(None)
+        },
+        Protocol::GetResp {path, contents, compressed, hash, mode, mtime, link_target: None} => {
+            let contents = if compressed {
+                zstd::bulk::decompress(&contents, MAX_DECOMPRESSED_SIZE)
+                    .map_err(|e| SyncError::Protocol(format!("failed decompressing {}: {}", path.display(), e)))?
+            } else {
+                contents
+            };
+            if let Some(hash) = hash {
+                let received = hash_bytes(&contents, hash.algo());
+                if received != hash {
+                    return retry_get(path, received, hash, ctx)
+                }
+            }
+            ctx.get_retries.remove(&path);
+            let writepath = resolve_path(&path, false, ctx)?;
+            if !settle_conflict(&path, &writepath, Received::Contents(&contents), mtime, ctx)? {
+                return Ok(None)
+            }
+            let (writepath, tmppath) = prepare_write(&path, ctx)?;
+            let hash = hash.unwrap_or_else(|| hash_bytes(&contents, ctx.peer.hash_algo));
+            ctx.echoes.suppress(&path);
+            ctx.writes.push(WriteJob{path, writepath, tmppath, contents, attrs: FileAttrs{mode, mtime}, hash});
+            Ok(None)
+        },
+        Protocol::GetChunkResp {path, offset, contents, eof, mode, mtime, hash, size} => {
+            // the first chunk starts a transfer, or starts it over, the others have to continue it
+            let (writepath, tmppath) = if offset == 0 {
+                prepare_write(&path, ctx)?
+            } else {
+                let Some((writepath, tmppath, partial)) = partial_write(&path, ctx) else {
+                    warn!(path = %path.display(), offset, "Dropping chunk of a file that isn't being received");
+                    return Ok(None)
+                };
+                // what arrived of the file before is of no use once it changed
+                if partial.hash.is_some_and(|started_with| hash.is_some_and(|hash| hash != started_with)) {
+                    ctx.partial_writes.remove(&tmppath);
+                    let _ = fs::remove_file(&tmppath);
+                    info!(path = %writepath.display(), "File changed during its transfer, starting over");
+                    return Ok(Some(Protocol::GetChunk{path, offset: 0, len: TRANSFER_CHUNK_SIZE}))
+                }
+                if offset < partial.received {
+                    debug!(path = %path.display(), offset, received = partial.received, "Dropping chunk that arrived already");
+                    return Ok(None)
+                }
+                if offset > partial.received {
+                    warn!(path = %path.display(), offset, received = partial.received, "Chunk skips part of the file, requesting the rest again");
+                    return Ok(Some(Protocol::GetChunk{path, offset: partial.received, len: TRANSFER_CHUNK_SIZE}))
+                }
+                (writepath, tmppath)
+            };
+            if let Err(e) = write_chunk(&tmppath, offset, &contents) {
+                ctx.partial_writes.remove(&tmppath);
+                ctx.stats.transfer_done(&path);
+                let _ = fs::remove_file(&tmppath);
+                return Err(SyncError::fs(writepath, e))
+            }
+            if !eof {
+                let next = offset + contents.len() as u64;
+                ctx.partial_writes.insert(tmppath, PartialWrite{received: next, hash});
+                ctx.stats.transfer_progress(&path, next, size);
+                return Ok(Some(Protocol::GetChunk{path, offset: next, len: TRANSFER_CHUNK_SIZE}))
+            }
+            match finish_transfer(&path, &writepath, &tmppath, hash, FileAttrs{mode, mtime}, ctx)? {
+                Some((received, expected)) => retry_get(path, received, expected, ctx),
+                None => Ok(None),
+            }
+        },
+        Protocol::BlockSigReq {path} => {
+            let localpath = resolve_path(&path, false, ctx)?;
+            let signature = fs::metadata(&localpath).ok().filter(|meta| meta.is_file()).map(|meta| {
+                let block_size = delta::block_size(meta.len());
+                delta::signature(&localpath, block_size).map(|blocks| (block_size, blocks))
+            });
+            // without a copy to build on the file comes whole
+            let (block_size, blocks) = match signature {
+                Some(Ok(signature)) => signature,
+                Some(Err(e)) => {
+                    warn!(error = %SyncError::fs(&localpath, e), "Failed computing block signature, requesting whole file");
+                    (0, Vec::new())
+                }
+                None => (0, Vec::new()),
+            };
+            Ok(Some(Protocol::BlockSig{path, block_size, blocks}))
+        },
+        Protocol::DeltaResp {path, offset, ops, eof, mode, mtime, hash} => {
+            let (writepath, tmppath) = prepare_write(&path, ctx)?;
+            if let Err(e) = delta::apply(&writepath, &tmppath, offset, &ops) {
+                ctx.partial_writes.remove(&tmppath);
+                let _ = fs::remove_file(&tmppath);
+                return Err(SyncError::fs(writepath, e))
+            }
+            if !eof {
+                let received = offset + ops.iter().map(DeltaOp::len).sum::<u64>();
+                ctx.partial_writes.insert(tmppath, PartialWrite{received, hash: None});
+                return Ok(None)
+            }
+            match finish_transfer(&path, &writepath, &tmppath, hash, FileAttrs{mode, mtime}, ctx)? {
+                // asking for the file whole this time
+                Some((received, expected)) => Ok(retry_get(path.clone(), received, expected, ctx)?
+                    .map(|_| Protocol::BlockSig{path, block_size: 0, blocks: Vec::new()})),
+                None => Ok(None),
+            }
+        },
+        Protocol::FsEventCreate {path, entity} => {
+            match entity {
+                EntityType::File => {
+                    let (writepath, tmppath) = prepare_write(&path, ctx)?;
+                    // contents arrive with a following modify event, an existing file is left as is
+                    if !writepath.exists() {
+                        ctx.echoes.suppress(&path);
+                        write_atomic(&writepath, &tmppath, &[], FileAttrs::default(), &mut ctx.fsync)?;
+                    }
+                }
+                EntityType::Directory => {
+                    let dirpath = resolve_path(&path, true, ctx)?;
+                    create_dirs(&dirpath, ctx)?;
+                    info!(path = %dirpath.display(), "Created directory");
+                }
+                // the target only comes with the link's GetResp
+                EntityType::Symlink => return Ok(Some(Protocol::Get{path})),
+            }
+            Ok(None)
+        },
+        Protocol::FsEventModify {path, hash} => {
+            let localpath = resolve_path(&path, false, ctx)?;
+            match ctx.index.hash(&path, hash.algo()) {
+                Ok(localhash) if localhash == hash => {
+                    ctx.index.set_synced(&path, hash);
+                    Ok(None)
+                }
+                _ => {
+                    info!(path = %localpath.display(), "Requesting update for file");
+                    Ok(Some(resume_request(Protocol::Get{path}, ctx)))
+                }
+            }
+        },
+        // the peer couldn't tell what happened to the path, it's compared again
+        Protocol::FsEventUnknown {path, entity, hash} => match entity {
+            EntityType::File => apply_message(Protocol::FsEventModify{path, hash}, ctx),
+            // anything under the directory may have changed, its listing tells what differs
+            EntityType::Directory => {
+                let dirpath = resolve_path(&path, true, ctx)?;
+                // the listing is still asked for in a dry run, its changes are logged when it arrives
+                if !ctx.dry_run {
+                    create_dirs(&dirpath, ctx)?;
+                } else if !dirpath.is_dir() {
+                    info!(path = %dirpath.display(), entity = ?EntityType::Directory, "Dry run, would create");
+                }
+                info!(path = %dirpath.display(), "Listing directory to compare its contents");
+                Ok(Some(Protocol::List{path, recursive: true, max_depth: None}))
+            }
+            EntityType::Symlink => Ok(Some(Protocol::Get{path})),
+        },
+        Protocol::FsEventRename {path_from, path_to} => {
+            let is_dir = syncdir.join(&path_from).is_dir();
+            let frompath = resolve_path(&path_from, is_dir, ctx)?;
+            let topath = resolve_path(&path_to, is_dir, ctx)?;
+            check_not_above_scope(&frompath, ctx)?;
+            check_not_above_scope(&topath, ctx)?;
+            if let Some(parent) = topath.parent() {
+                create_dirs(parent, ctx)?;
+            }
+            fs::rename(&frompath, &topath).map_err(|e| SyncError::fs(&frompath, e))?;
+            // the watcher's events are only handled after this returns
+            ctx.echoes.suppress(&path_from);
+            ctx.echoes.suppress(&path_to);
+            info!(from = %frompath.display(), to = %topath.display(), "Renamed");
+            Ok(None)
+        },
+        Protocol::FsEventAttrs {path, mode, mtime} => {
+            let localpath = resolve_path(&path, false, ctx)?;
+            let local = fs::metadata(&localpath).map(|meta| FileAttrs::of(&meta)).map_err(|e| SyncError::fs(&localpath, e))?;
+            // only what differs is applied, so applying doesn't cause more watcher events
+            // than the one suppressed and attrs that already match end an echo
+            let changed = FileAttrs {
+                mode: mode.filter(|_| mode != local.mode),
+                mtime: mtime.filter(|_| mtime != local.mtime),
+            };
+            if changed.mode.is_none() && changed.mtime.is_none() {
+                return Ok(None)
+            }
+            ctx.echoes.suppress(&path);
+            changed.apply(&localpath).map_err(|e| SyncError::fs(&localpath, e))?;
+            info!(path = %localpath.display(), mode = ?changed.mode, mtime = ?changed.mtime, "Updated file metadata");
+            Ok(None)
+        },
+        Protocol::FsEventDelete {path, entity} => {
+            let local = fs::symlink_metadata(syncdir.join(&path)).ok();
+            let local_is_dir = local.as_ref().is_some_and(|meta| meta.is_dir());
+            let is_dir = match entity {
+                // replaced with something else locally since, which is kept
+                Some(entity) if local.is_some() && matches!(entity, EntityType::Directory) != local_is_dir => {
+                    return Err(SyncError::Protocol(format!(
+                        "peer deleted {} which was a {:?} there but isn't one here, keeping it", path.display(), entity)))
+                }
+                Some(entity) => matches!(entity, EntityType::Directory),
+                None => local_is_dir,
+            };
+            let target = resolve_path(&path, is_dir, ctx)?;
+            check_not_above_scope(&target, ctx)?;
+            match ctx.delete_mode {
+                DeleteMode::Propagate => {
+                    let removed = if is_dir {
+                        fs::remove_dir_all(&target)
+                    } else {
+                        fs::remove_file(&target)
+                    };
+                    removed.map_err(|e| SyncError::fs(&target, e))?;
+                    ctx.echoes.suppress(&path);
+                    ctx.index.forget(&path);
+                    info!(path = %target.display(), "Removed");
+                }
+                DeleteMode::Trash => {
+                    let trashpath = move_to_trash(&target, &ctx.syncdir, &path)?;
+                    ctx.echoes.suppress(&path);
+                    ctx.index.forget(&path);
+                    info!(path = %target.display(), trash = %trashpath.display(), "Moved to trash");
+                }
+                DeleteMode::Ignore => info!(path = %target.display(), "Ignoring delete"),
+            }
+            Ok(None)
+        },
+        Protocol::Written {path, hash} => {
+            let localpath = resolve_path(&path, false, ctx)?;
+            // changed again since it was sent, the peer has an older copy
+            if ctx.index.hash(&path, hash.algo()).is_ok_and(|local| local == hash) {
+                debug!(path = %localpath.display(), "Peer wrote file");
+                ctx.index.set_synced(&path, hash);
+            }
+            Ok(None)
+        },
+        Protocol::Error {request, path, message} => {
+            warn!(request, path = path.as_ref().map(|path| tracing::field::display(path.display())), message, "Peer failed answering request");
+            // asking again would fail the same way
+            if let (Some(path), "Get" | "GetChunk") = (path, request.as_str()) {
+                ctx.get_retries.remove(&path);
+                abandon_partial_write(&path, ctx);
+            }
+            Ok(None)
+        },
+        _ => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::filter::{IGNORE_FILE, STATE_DIR, TRASH_DIR};
+
+    /// Context of a peer that agreed on every feature, like two of the same version do
+    fn context(syncdir: &Path) -> SyncContext {
+        let mut ctx = SyncContext::new(syncdir).unwrap();
+        ctx.peer = PeerFeatures::negotiate(&local_features(), HashAlgo::Xxh64);
+        ctx
+    }
+
+    /// Hands a request of the receiver to the sender and the answers back and forth until
+    /// neither has anything left to say, returning what the sender answered with
+    fn exchange(request: Protocol, sender: &mut SyncContext, receiver: &mut SyncContext) -> Vec<Protocol> {
+        let mut answers = Vec::new();
+        let mut request = Some(request);
+        while let Some(answer) = request.take().and_then(|request| sender.handle_message(request).unwrap()) {
+            answers.push(answer.clone());
+            request = receiver.handle_message(answer).unwrap();
+        }
+        futures::executor::block_on(receiver.writes.drain());
+        // acknowledged like the connection does once the receiver is done writing
+        for (path, hash) in receiver.writes.take_written() {
+            assert_eq!(sender.handle_message(Protocol::Written{path, hash}).unwrap(), None);
+        }
+        answers
+    }
+
+    fn chunk_offsets(answers: &[Protocol]) -> Vec<u64> {
+        answers.iter().filter_map(|answer| match answer {
+            Protocol::GetChunkResp {offset, ..} => Some(*offset),
+            _ => None,
+        }).collect()
+    }
+
+    /// Contents that don't repeat within a chunk
+    fn contents_of_len(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn listing(ctx: &SyncContext, path: &str, max_depth: Option<u32>) -> Result<Vec<ListRespEntry>, SyncError> {
+        let opts = ListOptions{root: &ctx.syncdir, filter: &ctx.filter, index: &ctx.index, max_file_size: ctx.max_file_size, algo: ctx.hash_algo};
+        list_entries(&resolve_path(Path::new(path), true, ctx)?, true, max_depth, &opts)
+    }
+
+    fn listed_paths(entries: &[ListRespEntry]) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = entries.iter().map(|entry| entry.path.clone()).collect();
+        paths.sort();
+        paths
+    }
+
+    fn round_trip(message: &Protocol) -> Protocol {
+        let mut payload = Vec::new();
+        ciborium::into_writer(message, &mut payload).unwrap();
+        ciborium::from_reader(payload.as_slice()).unwrap()
+    }
+
+    /// Position of the variant in the enum, new variants don't compile until they're added
+    /// here and to the messages round tripped
+    fn variant_index(message: &Protocol) -> usize {
+        match message {
+            Protocol::Ping => 0,
+            Protocol::Pong => 1,
+            Protocol::Hello {..} => 2,
+            Protocol::HelloResp {..} => 3,
+            Protocol::List {..} => 4,
+            Protocol::ListResp {..} => 5,
+            Protocol::Get {..} => 6,
+            Protocol::GetResp {..} => 7,
+            Protocol::GetChunk {..} => 8,
+            Protocol::GetChunkResp {..} => 9,
+            Protocol::BlockSigReq {..} => 10,
+            Protocol::BlockSig {..} => 11,
+            Protocol::DeltaResp {..} => 12,
+            Protocol::FsEventCreate {..} => 13,
+            Protocol::FsEventModify {..} => 14,
+            Protocol::FsEventAttrs {..} => 15,
+            Protocol::FsEventRename {..} => 16,
+            Protocol::FsEventDelete {..} => 17,
+            Protocol::FsEventUnknown {..} => 18,
+            Protocol::FsEventBatch {..} => 19,
+            Protocol::Written {..} => 20,
+            Protocol::Status => 21,
+            Protocol::StatusResp {..} => 22,
+            Protocol::Rescan {..} => 23,
+            Protocol::Error {..} => 24,
+        }
+    }
+
+    fn every_message() -> Vec<Protocol> {
+        let path = PathBuf::from("dir/file.txt");
+        let hash = Digest::Xxh64(0x0123_4567_89ab_cdef);
+        vec![
+            Protocol::Ping,
+            Protocol::Pong,
+            Protocol::Hello {version: PROTOCOL_VERSION, features: local_features(), hostname: Some("here".to_string())},
+            Protocol::HelloResp {version: PROTOCOL_VERSION, features: Vec::new(), hostname: None},
+            Protocol::List {path: PathBuf::from("dir"), recursive: true, max_depth: Some(2)},
+            Protocol::ListResp {entries: vec![
+                ListRespEntry {path: path.clone(), hash, entity: EntityType::File, mode: Some(0o644), mtime: Some(1_700_000_000_000), link_target: None, skipped: false, link_group: Some(3)},
+                ListRespEntry {path: PathBuf::from("dir/link"), hash: Digest::Xxh64(7), entity: EntityType::Symlink, mode: None, mtime: None, link_target: Some(PathBuf::from("../other")), skipped: true, link_group: None},
+            ], path: Some(PathBuf::from("dir")), more: true},
+            Protocol::Get {path: path.clone()},
+            Protocol::GetResp {
+                path: path.clone(),
+                contents: b"contents".to_vec(),
+                compressed: true,
+                hash: Some(hash),
+                mode: Some(0o755),
+                mtime: Some(-1),
+                link_target: Some(PathBuf::from("target")),
+            },
+            Protocol::GetChunk {path: path.clone(), offset: TRANSFER_CHUNK_SIZE, len: TRANSFER_CHUNK_SIZE},
+            Protocol::GetChunkResp {
+                path: path.clone(),
+                offset: TRANSFER_CHUNK_SIZE,
+                contents: vec![0xff; 300],
+                eof: true,
+                mode: Some(0o600),
+                mtime: Some(42),
+                hash: Some(hash),
+                size: Some(TRANSFER_CHUNK_SIZE + 300),
+            },
+            Protocol::BlockSigReq {path: path.clone()},
+            Protocol::BlockSig {path: path.clone(), block_size: 2048, blocks: vec![1, 2, 3]},
+            Protocol::DeltaResp {
+                path: path.clone(),
+                offset: 0,
+                ops: vec![DeltaOp::Copy {offset: 0, len: 2048}, DeltaOp::Data {data: b"tail".to_vec()}],
+                eof: true,
+                mode: None,
+                mtime: Some(7),
+                hash: Some(hash),
+            },
+            Protocol::FsEventCreate {path: PathBuf::from("dir"), entity: EntityType::Directory},
+            Protocol::FsEventModify {path: path.clone(), hash},
+            Protocol::FsEventAttrs {path: path.clone(), mode: Some(0o700), mtime: None},
+            Protocol::FsEventRename {path_from: path.clone(), path_to: PathBuf::from("dir/renamed.txt")},
+            Protocol::FsEventDelete {path: path.clone(), entity: Some(EntityType::File)},
+            Protocol::FsEventUnknown {path: path.clone(), entity: EntityType::File, hash},
+            Protocol::FsEventBatch {events: vec![
+                Protocol::FsEventCreate {path: PathBuf::from("a"), entity: EntityType::Directory},
+                Protocol::FsEventDelete {path: PathBuf::from("b"), entity: None},
+            ]},
+            Protocol::Written {path: path.clone(), hash},
+            Protocol::Status,
+            Protocol::StatusResp {
+                file_count: 12,
+                watched_paths: 3,
+                last_event_unix: Some(1_700_000_000),
+                bytes_sent: 1024,
+                bytes_received: 2048,
+                transfers: vec![TransferProgress {path: path.clone(), received: 100, total: Some(200)}],
+            },
+            Protocol::Rescan {path: Some(PathBuf::from("dir"))},
+            Protocol::Error {request: "Get".to_string(), path: Some(path), message: "No such file".to_string()},
+        ]
+    }
+
+    #[test]
+    fn every_message_survives_a_round_trip() {
+        let messages = every_message();
+        let mut variants: Vec<usize> = messages.iter().map(variant_index).collect();
+        variants.dedup();
+        assert_eq!(variants, (0..=24).collect::<Vec<_>>(), "every variant is round tripped once, in order");
+        for message in messages {
+            assert_eq!(round_trip(&message), message);
+        }
+    }
+
+    /// Message as a peer sends it, made up by hand rather than serialized from a Protocol
+    fn wire_get(path: &str) -> Vec<u8> {
+        use ciborium::Value;
+        let message = Value::Map(vec![
+            (Value::Text("type".to_string()), Value::Text("Get".to_string())),
+            (Value::Text("path".to_string()), Value::Text(path.to_string())),
+        ]);
+        let mut payload = Vec::new();
+        ciborium::into_writer(&message, &mut payload).unwrap();
+        payload
+    }
+
+    fn decoded_get(payload: &[u8]) -> Result<PathBuf, ciborium::de::Error<io::Error>> {
+        match ciborium::from_reader(payload)? {
+            Protocol::Get {path} => Ok(path),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    #[test]
+    fn paths_from_a_windows_peer_arrive_as_native_ones() {
+        // what a windows peer sends for dir\sub\file.txt
+        let native: PathBuf = ["dir", "sub", "file.txt"].iter().collect();
+        assert_eq!(decoded_get(&wire_get("dir/sub/file.txt")).unwrap(), native);
+        let mut payload = Vec::new();
+        ciborium::into_writer(&Protocol::Get{path: native.clone()}, &mut payload).unwrap();
+        assert_eq!(payload, wire_get("dir/sub/file.txt"));
+        assert_eq!(decoded_get(&payload).unwrap(), native);
+        for rejected in ["/etc/passwd", "//server/share/file"] {
+            assert!(decoded_get(&wire_get(rejected)).is_err(), "{rejected} was accepted");
+        }
+    }
+
+    #[test]
+    fn features_come_down_to_what_both_peers_support() {
+        let everything = PeerFeatures::negotiate(&local_features(), HashAlgo::Blake3);
+        assert!(everything.compress && everything.chunked && everything.batch && everything.attrs && everything.ack);
+        assert_eq!(everything.hash_algo, HashAlgo::Blake3);
+        // a peer knowing only some of ours, and some we don't
+        let announced = [FEATURE_COMPRESS, FEATURE_BATCH, "teleport", HashAlgo::Xxh64.feature()].map(String::from);
+        let some = PeerFeatures::negotiate(&announced, HashAlgo::Blake3);
+        assert!(some.compress && some.batch);
+        assert!(!some.chunked && !some.attrs);
+        assert_eq!(some.hash_algo, HashAlgo::Xxh64);
+        let none = PeerFeatures::negotiate(&[], HashAlgo::Xxh64);
+        assert!(!none.compress && !none.chunked && !none.batch && !none.attrs);
+    }
+
+    #[test]
+    fn failed_requests_are_answered_with_an_error() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), "secret").unwrap();
+        let mut ctx = context(syncdir.path());
+        let escape = Path::new("..").join(outside.path().file_name().unwrap()).join("secret");
+        let requests = [
+            Protocol::Get{path: PathBuf::from("missing.txt")},
+            Protocol::Get{path: escape.clone()},
+            Protocol::GetChunk{path: PathBuf::from("missing.txt"), offset: 0, len: TRANSFER_CHUNK_SIZE},
+            Protocol::GetChunk{path: escape, offset: 0, len: TRANSFER_CHUNK_SIZE},
+        ];
+        for request in requests {
+            let (request_type, path) = request_of(&request).unwrap();
+            let answer = ctx.handle_message(request.clone()).unwrap();
+            let Some(Protocol::Error {request: answered, path: answered_path, message}) = answer else {
+                panic!("{request:?} was answered with {answer:?}")
+            };
+            assert_eq!((answered.as_str(), answered_path), (request_type, path));
+            assert!(!message.is_empty());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_regular_files_are_read_for_the_peer() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("mkfifo").arg(syncdir.path().join("fifo")).status().unwrap();
+        assert!(status.success());
+        std::os::unix::fs::symlink("/dev/zero", syncdir.path().join("zero")).unwrap();
+        std::os::unix::fs::symlink("/dev", syncdir.path().join("devices")).unwrap();
+        fs::write(syncdir.path().join("regular"), b"regular").unwrap();
+        let mut ctx = context(syncdir.path());
+        for path in ["fifo", "zero", "devices/zero"] {
+            let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from(path)}).unwrap();
+            assert!(matches!(answer, Some(Protocol::Error {..})), "{path} was answered with {answer:?}");
+            let answer = ctx.handle_message(Protocol::GetChunk{path: PathBuf::from(path), offset: 0, len: TRANSFER_CHUNK_SIZE}).unwrap();
+            assert!(matches!(answer, Some(Protocol::Error {..})), "{path} was answered with {answer:?}");
+        }
+        let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from("regular")}).unwrap();
+        assert!(matches!(answer, Some(Protocol::GetResp {contents, ..}) if contents == b"regular"));
+    }
+
+    #[test]
+    fn error_answering_a_get_ends_its_transfer() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(from.path().join("large"), contents_of_len(2 * TRANSFER_CHUNK_SIZE)).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let first = sender.handle_message(Protocol::GetChunk{path: PathBuf::from("large"), offset: 0, len: TRANSFER_CHUNK_SIZE}).unwrap().unwrap();
+        assert!(matches!(receiver.handle_message(first).unwrap(), Some(Protocol::GetChunk {..})));
+        assert_eq!(receiver.partial_writes.len(), 1);
+        // removed on the sender's side meanwhile
+        fs::remove_file(from.path().join("large")).unwrap();
+        let next = sender.handle_message(Protocol::GetChunk{path: PathBuf::from("large"), offset: TRANSFER_CHUNK_SIZE, len: TRANSFER_CHUNK_SIZE}).unwrap().unwrap();
+        assert!(matches!(next, Protocol::Error {..}));
+        assert_eq!(receiver.handle_message(next).unwrap(), None, "asked again");
+        assert!(receiver.partial_writes.is_empty());
+        assert_eq!(fs::read_dir(to.path()).unwrap().filter(|entry| entry.as_ref().unwrap().file_name() != STATE_DIR).count(), 0, "partial file left behind");
+    }
+
+    #[test]
+    fn received_file_is_written_to_disk() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let contents = b"received contents".to_vec();
+        let message = Protocol::GetResp{path: PathBuf::from("sub/file.txt"), contents: contents.clone(), compressed: false, hash: None, mode: None, mtime: None, link_target: None};
+        assert_eq!(handle_message(message, syncdir.path()).unwrap(), None);
+        assert_eq!(fs::read(syncdir.path().join("sub/file.txt")).unwrap(), contents);
+        let names: Vec<_> = fs::read_dir(syncdir.path().join("sub")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names, ["file.txt"], "nothing but the file is left behind");
+    }
+
+    #[test]
+    fn compressible_file_is_sent_compressed_and_restored() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = b"the same line over and over\n".repeat(1000);
+        fs::write(from.path().join("repetitive.txt"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        sender.compress = true;
+        let answers = exchange(Protocol::Get{path: PathBuf::from("repetitive.txt")}, &mut sender, &mut receiver);
+        let [Protocol::GetResp {contents: sent, compressed: true, ..}] = answers.as_slice() else {
+            panic!("expected a compressed GetResp, got {answers:?}")
+        };
+        assert!(sent.len() < contents.len() / 10, "{} bytes sent for {}", sent.len(), contents.len());
+        assert_eq!(fs::read(to.path().join("repetitive.txt")).unwrap(), contents);
+        // without it being asked for contents go as they are
+        sender.compress = false;
+        let answers = exchange(Protocol::Get{path: PathBuf::from("repetitive.txt")}, &mut sender, &mut receiver);
+        assert!(matches!(answers.as_slice(), [Protocol::GetResp {compressed: false, ..}]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_named_with_invalid_utf8_is_listed_and_fetched() {
+        use std::os::unix::ffi::OsStrExt;
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let name = PathBuf::from(OsStr::from_bytes(b"latin1 caf\xe9.txt"));
+        fs::write(from.path().join(&name), b"contents").unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let entries = listing(&sender, ".", None).unwrap();
+        assert_eq!(listed_paths(&entries), std::slice::from_ref(&name));
+        let listed = round_trip(&Protocol::ListResp{entries, path: None, more: false});
+        let Protocol::ListResp {entries, ..} = listed else {
+            panic!("listing came back as {listed:?}")
+        };
+        let get = round_trip(&Protocol::Get{path: entries[0].path.clone()});
+        exchange(get, &mut sender, &mut receiver);
+        assert_eq!(fs::read(to.path().join(&name)).unwrap(), b"contents");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn received_file_keeps_its_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for (name, len) in [("script.sh", 10), ("large", 2 * TRANSFER_CHUNK_SIZE)] {
+            fs::write(from.path().join(name), contents_of_len(len)).unwrap();
+            fs::set_permissions(from.path().join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        for name in ["script.sh", "large"] {
+            exchange(Protocol::Get{path: PathBuf::from(name)}, &mut sender, &mut receiver);
+            let mode = fs::metadata(to.path().join(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755, "mode of {name}");
+        }
+    }
+
+    #[test]
+    fn received_file_keeps_its_modification_time() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_600_000_000_123);
+        for (name, len) in [("small", 10), ("large", 2 * TRANSFER_CHUNK_SIZE)] {
+            fs::write(from.path().join(name), contents_of_len(len)).unwrap();
+            fs::File::options().write(true).open(from.path().join(name)).unwrap().set_modified(mtime).unwrap();
+        }
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        for name in ["small", "large"] {
+            exchange(Protocol::Get{path: PathBuf::from(name)}, &mut sender, &mut receiver);
+            assert_eq!(fs::metadata(to.path().join(name)).unwrap().modified().unwrap(), mtime, "modification time of {name}");
+        }
+    }
+
+    #[test]
+    fn contents_not_matching_their_hash_are_refetched_and_not_written() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = context(syncdir.path());
+        let path = PathBuf::from("file.txt");
+        let wrong_hash = hash_bytes(b"other contents", HashAlgo::Xxh64);
+        let corrupted = || Protocol::GetResp{path: path.clone(), contents: b"contents".to_vec(), compressed: false, hash: Some(wrong_hash), mode: None, mtime: None, link_target: None};
+        for _ in 0..MAX_GET_RETRIES {
+            let answer = ctx.handle_message(corrupted()).unwrap();
+            assert!(matches!(answer, Some(Protocol::Get {path: asked}) if asked == path));
+        }
+        assert!(ctx.handle_message(corrupted()).is_err(), "doesn't ask again forever");
+        // the same goes for the last chunk of a chunked transfer
+        let last_chunk = Protocol::GetChunkResp{path: path.clone(), offset: 0, contents: b"contents".to_vec(), eof: true, mode: None, mtime: None, hash: Some(wrong_hash), size: Some(8)};
+        let answer = ctx.handle_message(last_chunk).unwrap();
+        assert!(matches!(answer, Some(Protocol::Get {path: asked}) if asked == path));
+        futures::executor::block_on(ctx.writes.drain());
+        assert_eq!(fs::read_dir(&ctx.syncdir).unwrap().count(), 0, "something was written");
+    }
+
+    #[test]
+    fn sent_file_counts_as_synced_only_once_the_peer_wrote_it() {
+        for len in [10, 2 * TRANSFER_CHUNK_SIZE] {
+            let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            let contents = contents_of_len(len);
+            fs::write(from.path().join("file.txt"), &contents).unwrap();
+            let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+            let hash = hash_bytes(&contents, HashAlgo::Xxh64);
+            let path = Path::new("file.txt");
+            let mut request = Some(Protocol::Get{path: path.to_path_buf()});
+            while let Some(answer) = request.take().and_then(|request| sender.handle_message(request).unwrap()) {
+                request = receiver.handle_message(answer).unwrap();
+            }
+            assert_eq!(sender.index.synced(path), None, "{len} bytes");
+            futures::executor::block_on(receiver.writes.drain());
+            assert_eq!(receiver.index.synced(path), Some(hash), "{len} bytes");
+            let written = receiver.writes.take_written();
+            assert_eq!(written, [(path.to_path_buf(), hash)], "{len} bytes");
+            sender.handle_message(Protocol::Written{path: path.to_path_buf(), hash}).unwrap();
+            assert_eq!(sender.index.synced(path), Some(hash), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn acknowledgement_of_contents_changed_since_is_ignored() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("file.txt"), "changed since").unwrap();
+        let mut ctx = context(syncdir.path());
+        let written = Protocol::Written{path: PathBuf::from("file.txt"), hash: hash_bytes(b"sent", HashAlgo::Xxh64)};
+        assert_eq!(ctx.handle_message(written).unwrap(), None);
+        assert_eq!(ctx.index.synced(Path::new("file.txt")), None);
+        let escaping = Protocol::Written{path: PathBuf::from("../file.txt"), hash: hash_bytes(b"sent", HashAlgo::Xxh64)};
+        assert!(ctx.handle_message(escaping).is_err());
+    }
+
+    /// Settles a conflict of a file modified on both sides since they last synced, the
+    /// remote copy modified after the local one if remote_newer. Returns the receiver's
+    /// directory and the local and remote contents
+    fn conflict(mode: ConflictMode, len: u64, remote_newer: bool) -> (tempfile::TempDir, Vec<u8>, Vec<u8>) {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        receiver.conflict_mode = mode;
+        receiver.peer_hostname = Some("remote".to_string());
+        let synced = contents_of_len(len);
+        receiver.index.set_synced(Path::new("file.txt"), hash_bytes(&synced, HashAlgo::Xxh64));
+        let (mut local, mut remote) = (synced.clone(), synced);
+        local[0] = b'l';
+        remote[0] = b'r';
+        let (local_mtime, remote_mtime) = if remote_newer { (1_000, 2_000) } else { (2_000, 1_000) };
+        for (dir, contents, mtime) in [(to.path(), &local, local_mtime), (from.path(), &remote, remote_mtime)] {
+            fs::write(dir.join("file.txt"), contents).unwrap();
+            FileAttrs {mode: None, mtime: Some(mtime * 1000)}.apply(&dir.join("file.txt")).unwrap();
+        }
+        exchange(Protocol::Get{path: PathBuf::from("file.txt")}, &mut sender, &mut receiver);
+        (to, local, remote)
+    }
+
+    #[test]
+    fn conflicting_modifications_are_settled_as_configured() {
+        let local_copy = format!("file.conflict-{}.txt", local_hostname().replace(['/', '\\'], "_"));
+        for len in [10, 2 * TRANSFER_CHUNK_SIZE] {
+            for remote_newer in [true, false] {
+                let (to, local, remote) = conflict(ConflictMode::Newest, len, remote_newer);
+                assert_eq!(&fs::read(to.path().join("file.txt")).unwrap(), if remote_newer { &remote } else { &local });
+                assert_eq!(fs::read_dir(to.path()).unwrap().count(), 1);
+                let (to, local, _) = conflict(ConflictMode::KeepLocal, len, remote_newer);
+                assert_eq!(fs::read(to.path().join("file.txt")).unwrap(), local);
+                assert_eq!(fs::read_dir(to.path()).unwrap().count(), 1);
+                // the losing copy is kept under the name of the host it came from
+                let (to, local, remote) = conflict(ConflictMode::Rename, len, remote_newer);
+                let (kept, saved, saved_as) = if remote_newer { (&remote, &local, local_copy.as_str()) } else { (&local, &remote, "file.conflict-remote.txt") };
+                assert_eq!(&fs::read(to.path().join("file.txt")).unwrap(), kept, "{len} bytes, remote newer: {remote_newer}");
+                assert_eq!(&fs::read(to.path().join(saved_as)).unwrap(), saved, "{len} bytes, remote newer: {remote_newer}");
+            }
+        }
+    }
+
+    #[test]
+    fn file_changed_only_remotely_is_overwritten_whatever_the_mode() {
+        for mode in [ConflictMode::Newest, ConflictMode::Rename, ConflictMode::KeepLocal] {
+            let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+            receiver.conflict_mode = mode;
+            fs::write(to.path().join("file.txt"), "synced").unwrap();
+            receiver.index.set_synced(Path::new("file.txt"), hash_bytes(b"synced", HashAlgo::Xxh64));
+            fs::write(from.path().join("file.txt"), "remote").unwrap();
+            // older than the local copy, which is of no matter when only one side changed
+            FileAttrs {mode: None, mtime: Some(1_000)}.apply(&from.path().join("file.txt")).unwrap();
+            exchange(Protocol::Get{path: PathBuf::from("file.txt")}, &mut sender, &mut receiver);
+            assert_eq!(fs::read(to.path().join("file.txt")).unwrap(), b"remote", "{mode:?}");
+            assert_eq!(fs::read_dir(to.path()).unwrap().count(), 1, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn relative_symlink_arrives_pointing_at_the_same_path() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::create_dir(from.path().join("dir")).unwrap();
+        fs::write(from.path().join("target.txt"), b"target").unwrap();
+        make_symlink(Path::new("../target.txt"), &from.path().join("dir/link"), false).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let answers = exchange(Protocol::Get{path: PathBuf::from("dir/link")}, &mut sender, &mut receiver);
+        assert!(matches!(answers.as_slice(), [Protocol::GetResp {link_target: Some(target), ..}] if target == Path::new("../target.txt")));
+        assert_eq!(fs::read_link(to.path().join("dir/link")).unwrap(), Path::new("../target.txt"));
+        // one leading out of the synced directory isn't created
+        let escaping = Protocol::GetResp{path: PathBuf::from("dir/escape"), contents: Vec::new(), compressed: false, hash: None, mode: None, mtime: None, link_target: Some(PathBuf::from("../../outside"))};
+        assert!(matches!(receiver.handle_message(escaping), Err(SyncError::PathEscapes(_))));
+        assert!(fs::symlink_metadata(to.path().join("dir/escape")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_dont_keep_a_walk_going() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::create_dir(syncdir.path().join("dir")).unwrap();
+        fs::write(syncdir.path().join("dir/file.txt"), b"file").unwrap();
+        std::os::unix::fs::symlink(".", syncdir.path().join("self")).unwrap();
+        std::os::unix::fs::symlink("..", syncdir.path().join("dir/parent")).unwrap();
+        let ctx = context(syncdir.path());
+        let entries = listing(&ctx, ".", None).unwrap();
+        assert_eq!(listed_paths(&entries), ["dir", "dir/file.txt", "dir/parent", "self"].map(PathBuf::from));
+        let links: Vec<_> = entries.iter().filter(|entry| entry.entity == EntityType::Symlink).collect();
+        assert_eq!(links.len(), 2);
+        assert!(rescan(&ctx.syncdir, &ListOptions{root: &ctx.syncdir, filter: &ctx.filter, index: &ctx.index, max_file_size: None, algo: ctx.hash_algo}).is_ok());
+    }
+
+    #[test]
+    fn symlink_leading_out_of_the_sync_directory_isnt_followed() {
+        let (syncdir, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+        make_symlink(outside.path(), &syncdir.path().join("out"), true).unwrap();
+        make_symlink(&outside.path().join("secret"), &syncdir.path().join("secret"), false).unwrap();
+        let mut ctx = context(syncdir.path());
+        for path in ["out/secret", "secret"] {
+            let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from(path)}).unwrap();
+            assert!(matches!(&answer, Some(Protocol::Error {request, ..}) if request == "Get"), "{path} was read: {answer:?}");
+        }
+        let write = Protocol::GetResp{path: PathBuf::from("out/planted"), contents: b"planted".to_vec(), compressed: false, hash: None, mode: None, mtime: None, link_target: None};
+        assert!(matches!(ctx.handle_message(write), Err(SyncError::PathEscapes(_))));
+        futures::executor::block_on(ctx.writes.drain());
+        assert!(!outside.path().join("planted").exists());
+    }
+
+    #[test]
+    fn deleted_file_or_directory_is_removed_whole() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(syncdir.path().join("dir/nested")).unwrap();
+        for name in ["file.txt", "dir/file.txt", "dir/nested/file.txt", "kept.txt"] {
+            fs::write(syncdir.path().join(name), name).unwrap();
+        }
+        let mut ctx = context(syncdir.path());
+        let delete = |path: &str, entity| Protocol::FsEventDelete{path: PathBuf::from(path), entity};
+        assert_eq!(ctx.handle_message(delete("file.txt", Some(EntityType::File))).unwrap(), None);
+        // a single delete takes everything below the directory with it
+        assert_eq!(ctx.handle_message(delete("dir", Some(EntityType::Directory))).unwrap(), None);
+        assert!(!syncdir.path().join("file.txt").exists() && !syncdir.path().join("dir").exists());
+        // peers that couldn't tell what was removed leave it to what's here
+        fs::create_dir(syncdir.path().join("unspecific")).unwrap();
+        fs::write(syncdir.path().join("unspecific/file.txt"), "file").unwrap();
+        assert_eq!(ctx.handle_message(delete("unspecific", None)).unwrap(), None);
+        assert!(!syncdir.path().join("unspecific").exists());
+        // what's here isn't what the peer removed, so it's kept
+        fs::create_dir(syncdir.path().join("replaced")).unwrap();
+        assert!(ctx.handle_message(delete("replaced", Some(EntityType::File))).is_err());
+        assert!(syncdir.path().join("replaced").is_dir());
+        assert_eq!(fs::read(syncdir.path().join("kept.txt")).unwrap(), b"kept.txt");
+    }
+
+    #[test]
+    fn delete_is_applied_according_to_the_delete_mode() {
+        let delete = || Protocol::FsEventDelete{path: PathBuf::from("dir/file.txt"), entity: Some(EntityType::File)};
+        for mode in [DeleteMode::Propagate, DeleteMode::Trash, DeleteMode::Ignore] {
+            let syncdir = tempfile::tempdir().unwrap();
+            fs::create_dir(syncdir.path().join("dir")).unwrap();
+            fs::write(syncdir.path().join("dir/file.txt"), b"contents").unwrap();
+            let mut ctx = context(syncdir.path());
+            ctx.delete_mode = mode;
+            assert_eq!(ctx.handle_message(delete()).unwrap(), None);
+            let kept = syncdir.path().join("dir/file.txt").exists();
+            assert_eq!(kept, mode == DeleteMode::Ignore, "{mode:?}");
+            let trashed: Vec<PathBuf> = fs::read_dir(syncdir.path().join(TRASH_DIR)).into_iter().flatten()
+                .map(|deleted_at| deleted_at.unwrap().path().join("dir/file.txt"))
+                .collect();
+            if mode == DeleteMode::Trash {
+                assert_eq!(trashed.len(), 1);
+                assert_eq!(fs::read(&trashed[0]).unwrap(), b"contents");
+                // the trash isn't synced itself
+                assert_eq!(listed_paths(&listing(&ctx, ".", None).unwrap()), [PathBuf::from("dir")]);
+            } else {
+                assert!(trashed.is_empty(), "{mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn hidden_files_are_skipped_below_a_hidden_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join(".config");
+        fs::create_dir_all(root.join(".cache")).unwrap();
+        fs::write(root.join("settings.toml"), b"settings").unwrap();
+        fs::write(root.join(".hidden"), b"hidden").unwrap();
+        fs::write(root.join(".cache/entry"), b"entry").unwrap();
+        let mut ctx = context(&root);
+        ctx.filter = Arc::new(PathFilter::load(&ctx.syncdir, &[], &[], true, true, None));
+        assert_eq!(listed_paths(&listing(&ctx, ".", None).unwrap()), [PathBuf::from("settings.toml")]);
+        assert!(matches!(ctx.handle_message(Protocol::Get{path: PathBuf::from("settings.toml")}).unwrap(), Some(Protocol::GetResp {..})));
+        for hidden in [".hidden", ".cache/entry"] {
+            let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from(hidden)}).unwrap();
+            assert!(matches!(&answer, Some(Protocol::Error {..})), "{hidden} was sent: {answer:?}");
+        }
+    }
+
+    #[test]
+    fn file_over_the_size_limit_is_neither_hashed_nor_sent() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("small"), b"small").unwrap();
+        fs::write(syncdir.path().join("huge"), contents_of_len(100)).unwrap();
+        let mut ctx = context(syncdir.path());
+        ctx.max_file_size = Some(10);
+        let entries = listing(&ctx, ".", None).unwrap();
+        let skipped = |name: &str| entries.iter().find(|entry| entry.path == Path::new(name)).unwrap().skipped;
+        assert!(skipped("huge"));
+        assert!(!skipped("small"));
+        for request in [Protocol::Get{path: PathBuf::from("huge")}, Protocol::GetChunk{path: PathBuf::from("huge"), offset: 0, len: TRANSFER_CHUNK_SIZE}] {
+            let answer = ctx.handle_message(request).unwrap();
+            assert!(matches!(&answer, Some(Protocol::Error {..})), "sent anyway: {answer:?}");
+        }
+        assert!(matches!(ctx.handle_message(Protocol::Get{path: PathBuf::from("small")}).unwrap(), Some(Protocol::GetResp {..})));
+    }
+
+    #[test]
+    fn failed_rename_or_delete_expects_no_echo() {
+        let syncdir = tempfile::tempdir().unwrap();
+        let mut ctx = context(syncdir.path());
+        let rename = Protocol::FsEventRename{path_from: PathBuf::from("missing.txt"), path_to: PathBuf::from("renamed.txt")};
+        assert!(ctx.handle_message(rename).is_err());
+        assert!(ctx.handle_message(Protocol::FsEventDelete{path: PathBuf::from("gone.txt"), entity: Some(EntityType::File)}).is_err());
+        // a change made here later to any of them is sent to the peer
+        for path in ["missing.txt", "renamed.txt", "gone.txt"] {
+            assert!(!ctx.echoes.take(Path::new(path), false), "{path}");
+        }
+    }
+
+    /// Log written by the thread while f runs, without colors
+    fn logged<T>(f: impl FnOnce() -> T) -> (T, String) {
+        #[derive(Clone, Default)]
+        struct Log(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+        let result = tracing::subscriber::with_default(subscriber, f);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        (result, log)
+    }
+
+    #[test]
+    fn delete_in_a_dry_run_is_only_logged() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("file.txt"), b"contents").unwrap();
+        let mut ctx = context(syncdir.path());
+        ctx.dry_run = true;
+        let delete = Protocol::FsEventDelete{path: PathBuf::from("file.txt"), entity: Some(EntityType::File)};
+        let (answer, log) = logged(|| ctx.handle_message(delete).unwrap());
+        assert_eq!(answer, None);
+        assert!(log.contains("Dry run, would delete") && log.contains("file.txt"), "{}", log);
+        assert_eq!(fs::read(syncdir.path().join("file.txt")).unwrap(), b"contents");
+        assert!(!syncdir.path().join(TRASH_DIR).exists());
+    }
+
+    #[test]
+    fn read_only_side_serves_files_but_ignores_changes() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join("file.txt"), b"contents").unwrap();
+        let mut ctx = context(syncdir.path());
+        ctx.read_only = true;
+        let delete = Protocol::FsEventDelete{path: PathBuf::from("file.txt"), entity: Some(EntityType::File)};
+        assert_eq!(ctx.handle_message(delete).unwrap(), None);
+        assert_eq!(fs::read(syncdir.path().join("file.txt")).unwrap(), b"contents");
+        let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from("file.txt")}).unwrap();
+        assert!(matches!(answer, Some(Protocol::GetResp {contents, ..}) if contents == b"contents"));
+    }
+
+    #[test]
+    fn file_spanning_several_chunks_arrives_whole() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = contents_of_len(3 * TRANSFER_CHUNK_SIZE + 100);
+        fs::write(from.path().join("large"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let answers = exchange(Protocol::Get{path: PathBuf::from("large")}, &mut sender, &mut receiver);
+        assert_eq!(chunk_offsets(&answers), [0, TRANSFER_CHUNK_SIZE, 2 * TRANSFER_CHUNK_SIZE, 3 * TRANSFER_CHUNK_SIZE]);
+        assert_eq!(fs::read(to.path().join("large")).unwrap(), contents);
+        assert!(!to.path().join("large.syncd.tmp").exists());
+    }
+
+    #[test]
+    fn progress_of_a_chunked_transfer_grows_until_it_is_done() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = contents_of_len(4 * TRANSFER_CHUNK_SIZE + 100);
+        fs::write(from.path().join("large"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let mut progress = Vec::new();
+        let mut request = Some(Protocol::Get{path: PathBuf::from("large")});
+        while let Some(answer) = request.take().and_then(|request| sender.handle_message(request).unwrap()) {
+            request = receiver.handle_message(answer).unwrap();
+            progress.extend(receiver.stats.transfers().into_iter().map(|transfer| (transfer.received, transfer.total)));
+        }
+        let total = Some(contents.len() as u64);
+        assert_eq!(progress, (1..=4).map(|chunks| (chunks * TRANSFER_CHUNK_SIZE, total)).collect::<Vec<_>>());
+        assert!(receiver.stats.transfers().is_empty());
+        futures::executor::block_on(receiver.writes.drain());
+        assert_eq!(fs::read(to.path().join("large")).unwrap(), contents);
+    }
+
+    #[test]
+    fn chunk_aligned_file_ends_with_its_last_full_chunk() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = contents_of_len(2 * TRANSFER_CHUNK_SIZE);
+        fs::write(from.path().join("aligned"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let answers = exchange(Protocol::Get{path: PathBuf::from("aligned")}, &mut sender, &mut receiver);
+        assert_eq!(chunk_offsets(&answers), [0, TRANSFER_CHUNK_SIZE]);
+        assert!(matches!(answers.last(), Some(Protocol::GetChunkResp {eof: true, ..})));
+        assert_eq!(fs::read(to.path().join("aligned")).unwrap(), contents);
+    }
+
+    #[test]
+    fn appending_to_a_large_file_sends_only_the_tail() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let old = contents_of_len(4 * TRANSFER_CHUNK_SIZE);
+        fs::write(to.path().join("log"), &old).unwrap();
+        let mut contents = old.clone();
+        contents.extend_from_slice(&[7; 1000]);
+        fs::write(from.path().join("log"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let answers = exchange(Protocol::Get{path: PathBuf::from("log")}, &mut sender, &mut receiver);
+        let ops = answers.iter().find_map(|answer| match answer {
+            Protocol::DeltaResp {ops, ..} => Some(ops.clone()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(ops, [DeltaOp::Copy{offset: 0, len: old.len() as u64}, DeltaOp::Data{data: vec![7; 1000]}]);
+        assert!(chunk_offsets(&answers).is_empty());
+        assert_eq!(fs::read(to.path().join("log")).unwrap(), contents);
+    }
+
+    #[test]
+    fn interrupted_transfer_fetches_only_the_remaining_chunks() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = contents_of_len(4 * TRANSFER_CHUNK_SIZE);
+        fs::write(from.path().join("large"), &contents).unwrap();
+        let (mut sender, mut receiver) = (context(from.path()), context(to.path()));
+        let path = PathBuf::from("large");
+        // the connection drops after half the chunks arrived
+        let halfway = Protocol::GetChunk{path: path.clone(), offset: 2 * TRANSFER_CHUNK_SIZE, len: TRANSFER_CHUNK_SIZE};
+        let mut request = Protocol::Get{path: path.clone()};
+        while request != halfway {
+            let answer = sender.handle_message(request).unwrap().unwrap();
+            request = receiver.handle_message(answer).unwrap().unwrap();
+        }
+        let resumed = resume_request(Protocol::Get{path: path.clone()}, &receiver);
+        assert_eq!(resumed, halfway);
+        // the temporary file was cut short meanwhile, what's in the record can't be trusted
+        let tmppath = tmp_path(&receiver.syncdir.join(&path), OsStr::new("large"));
+        let tmpfile = fs::OpenOptions::new().write(true).open(&tmppath).unwrap();
+        tmpfile.set_len(TRANSFER_CHUNK_SIZE).unwrap();
+        assert_eq!(resume_request(Protocol::Get{path: path.clone()}, &receiver), Protocol::Get{path: path.clone()});
+        fs::write(&tmppath, &contents[..2 * TRANSFER_CHUNK_SIZE as usize]).unwrap();
+        let answers = exchange(resumed, &mut sender, &mut receiver);
+        assert_eq!(chunk_offsets(&answers), [2 * TRANSFER_CHUNK_SIZE, 3 * TRANSFER_CHUNK_SIZE]);
+        assert_eq!(fs::read(to.path().join("large")).unwrap(), contents);
+        assert!(receiver.partial_writes.is_empty());
+    }
+
+    #[test]
+    fn chunk_continuing_past_the_written_part_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmppath = dir.path().join("large.syncd.tmp");
+        fs::write(&tmppath, [1; 10]).unwrap();
+        let err = write_chunk(&tmppath, 20, &[2; 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        write_chunk(&tmppath, 10, &[2; 10]).unwrap();
+        assert_eq!(fs::read(&tmppath).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn chunk_not_continuing_the_transfer_isnt_written() {
+        let (from, to) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let contents = contents_of_len(4 * TRANSFER_CHUNK_SIZE);
+        fs::write(from.path().join("large"), &contents).unwrap();
+        let (mut from, mut to) = (context(from.path()), context(to.path()));
+        let path = PathBuf::from("large");
+        let mut chunk = |offset| from.handle_message(Protocol::GetChunk{path: path.clone(), offset, len: TRANSFER_CHUNK_SIZE}).unwrap().unwrap();
+        let (first, second, fourth) = (chunk(0), chunk(TRANSFER_CHUNK_SIZE), chunk(3 * TRANSFER_CHUNK_SIZE));
+        let tmppath = to.syncdir.join("large.syncd.tmp");
+        // a chunk of a transfer that never started
+        assert!(to.handle_message(second.clone()).unwrap().is_none());
+        assert!(!tmppath.exists());
+        to.handle_message(first).unwrap();
+        to.handle_message(second.clone()).unwrap();
+        // a chunk arriving twice
+        assert!(to.handle_message(second).unwrap().is_none());
+        // a chunk past where the transfer got to has the rest requested again
+        let rest = to.handle_message(fourth).unwrap();
+        assert!(matches!(rest, Some(Protocol::GetChunk{offset, ..}) if offset == 2 * TRANSFER_CHUNK_SIZE));
+        assert_eq!(fs::read(tmppath).unwrap(), contents[..2 * TRANSFER_CHUNK_SIZE as usize]);
+    }
+
+    /// Every order of items
+    fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+        if items.len() <= 1 {
+            return vec![items.to_vec()]
+        }
+        (0..items.len()).flat_map(|i| {
+            let mut rest = items.to_vec();
+            let first = rest.remove(i);
+            permutations(&rest).into_iter().map(move |mut order| {
+                order.insert(0, first.clone());
+                order
+            })
+        }).collect()
+    }
+
+    #[test]
+    fn batch_in_any_order_creates_directories_first_and_deletes_them_last() {
+        let create = |path: &str, entity| Protocol::FsEventCreate{path: PathBuf::from(path), entity};
+        let delete = |path: &str, entity| Protocol::FsEventDelete{path: PathBuf::from(path), entity: Some(entity)};
+        let creates = [
+            create("a", EntityType::Directory),
+            create("a/b", EntityType::Directory),
+            create("a/b/c.txt", EntityType::File),
+            create("a/d.txt", EntityType::File),
+            create("e", EntityType::Directory),
+        ];
+        let deletes = [delete("x", EntityType::Directory), delete("x/y", EntityType::Directory), delete("x/y/z.txt", EntityType::File), delete("x/w.txt", EntityType::File)];
+        let position = |events: &[Protocol], path: &str| events.iter().position(|event| event_paths(event) == [Path::new(path)]).unwrap();
+        for mut events in permutations(&creates) {
+            order_batch(&mut events);
+            assert!(position(&events, "a") < position(&events, "a/b"), "{events:?}");
+            assert!(position(&events, "a") < position(&events, "a/d.txt"), "{events:?}");
+            assert!(position(&events, "a/b") < position(&events, "a/b/c.txt"), "{events:?}");
+        }
+        for mut events in permutations(&deletes) {
+            order_batch(&mut events);
+            assert!(position(&events, "x/y/z.txt") < position(&events, "x/y"), "{events:?}");
+            assert!(position(&events, "x/y") < position(&events, "x"), "{events:?}");
+            assert!(position(&events, "x/w.txt") < position(&events, "x"), "{events:?}");
+        }
+    }
+
+    #[test]
+    fn ignored_files_are_neither_listed_nor_sent() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::write(syncdir.path().join(IGNORE_FILE), "*.tmp\n").unwrap();
+        fs::write(syncdir.path().join("kept.txt"), b"kept").unwrap();
+        fs::write(syncdir.path().join("scratch.tmp"), b"scratch").unwrap();
+        let mut ctx = context(syncdir.path());
+        let paths = listed_paths(&listing(&ctx, ".", None).unwrap());
+        assert!(paths.contains(&PathBuf::from("kept.txt")));
+        assert!(!paths.contains(&PathBuf::from("scratch.tmp")));
+        let answer = ctx.handle_message(Protocol::Get{path: PathBuf::from("scratch.tmp")}).unwrap();
+        assert!(matches!(answer, Some(Protocol::Error {request, ..}) if request == "Get"));
+    }
+
+    #[test]
+    fn nested_tree_is_listed_to_the_requested_depth() {
+        let syncdir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(syncdir.path().join("a/b/c")).unwrap();
+        for file in ["top.txt", "a/one.txt", "a/b/two.txt", "a/b/c/three.txt"] {
+            fs::write(syncdir.path().join(file), file).unwrap();
+        }
+        let ctx = context(syncdir.path());
+        let paths = |entries: &[ListRespEntry]| -> Vec<String> {
+            listed_paths(entries).iter().map(|path| path.to_string_lossy().into_owned()).collect()
+        };
+        let all = listing(&ctx, ".", None).unwrap();
+        assert_eq!(paths(&all), ["a", "a/b", "a/b/c", "a/b/c/three.txt", "a/b/two.txt", "a/one.txt", "top.txt"]);
+        let three = all.iter().find(|entry| entry.path == Path::new("a/b/c/three.txt")).unwrap();
+        let mut hasher = HashAlgo::Xxh64.hasher();
+        hasher.update(b"a/b/c/three.txt");
+        assert_eq!(three.hash, hasher.finish());
+        assert_eq!(paths(&listing(&ctx, ".", Some(2)).unwrap()), ["a", "a/b", "a/one.txt", "top.txt"]);
+        // relative to the root rather than the listed directory
+        assert_eq!(paths(&listing(&ctx, "a/b", Some(1)).unwrap()), ["a/b/c", "a/b/two.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_directory_fails_its_listing_without_panicking() {
+        use std::os::unix::fs::PermissionsExt;
+        let syncdir = tempfile::tempdir().unwrap();
+        let locked = syncdir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("file"), b"file").unwrap();
+        fs::write(syncdir.path().join("open.txt"), b"open").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads the directory anyway, leaving only the failure of a gone one to check
+        let denied = fs::read_dir(&locked).is_err();
+        let ctx = context(syncdir.path());
+        match listing(&ctx, "locked", None) {
+            Err(SyncError::Fs {source, ..}) => assert!(denied && source.kind() == io::ErrorKind::PermissionDenied),
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => assert!(!denied),
+        }
+        // the rest of the tree is still listed
+        let paths = listed_paths(&listing(&ctx, ".", None).unwrap());
+        assert!(paths.contains(&PathBuf::from("open.txt")));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&locked).unwrap();
+        assert!(matches!(listing(&ctx, "locked", None), Err(SyncError::Fs {source, ..}) if source.kind() == io::ErrorKind::NotFound));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::protocol::TransferProgress;
+
+/// Counters reported by Status and the metrics endpoint, updated from the connection's codec
+/// and event loop
+#[derive(Debug, Default)]
+pub struct SyncStats {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    events: AtomicU64,
+    reconnects: AtomicU64,
+    // events the watcher reported while the event buffer was full
+    dropped_events: AtomicU64,
+    // seconds since the unix epoch, 0 until the first event
+    last_event_unix: AtomicU64,
+    // files being fetched in chunks, with the bytes received so far and the file's size
+    transfers: Mutex<HashMap<PathBuf, (u64, Option<u64>)>>,
+}
+
+impl SyncStats {
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_event(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.last_event_unix.store(now, Ordering::Relaxed);
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped_event(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    pub fn last_event_unix(&self) -> Option<u64> {
+        Some(self.last_event_unix.load(Ordering::Relaxed)).filter(|&secs| secs > 0)
+    }
+
+    /// Records how much of a file being fetched arrived, total being its size if the sender told
+    pub fn transfer_progress(&self, path: &Path, received: u64, total: Option<u64>) {
+        self.transfers.lock().unwrap_or_else(|e| e.into_inner()).insert(path.to_path_buf(), (received, total));
+    }
+
+    /// Stops reporting a transfer, done or abandoned
+    pub fn transfer_done(&self, path: &Path) {
+        self.transfers.lock().unwrap_or_else(|e| e.into_inner()).remove(path);
+    }
+
+    pub fn transfers_in_flight(&self) -> usize {
+        self.transfers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn transfers_done(&self) {
+        self.transfers.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Transfers in flight, ordered by path
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        let mut transfers: Vec<_> = self.transfers.lock().unwrap_or_else(|e| e.into_inner()).iter()
+            .map(|(path, (received, total))| TransferProgress{path: path.clone(), received: *received, total: *total})
+            .collect();
+        transfers.sort_by(|a, b| a.path.cmp(&b.path));
+        transfers
+    }
+}
+
+/// Byte count with a binary unit, like 1.5 MiB
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes)
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
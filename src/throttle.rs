@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::bytes::Bytes;
+
+/// Token bucket holding back messages carrying file contents so they're sent at no more than
+/// a given rate, while everything else goes out right away. A message larger than what the
+/// bucket holds is let through once it's full, leaving the bucket in debt
+pub struct Throttle {
+    bytes_per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+    queue: VecDeque<(Bytes, Bytes)>,
+}
+
+impl Throttle {
+    pub fn new(kbps: u64) -> Self {
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Throttle {
+            bytes_per_sec,
+            tokens: bytes_per_sec,
+            updated: Instant::now(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        // at most a second's worth is saved up, so an idle link doesn't allow a long burst
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.updated = now;
+    }
+
+    /// Queues a message payload meant for channel
+    pub fn push(&mut self, channel: Bytes, payload: Bytes) {
+        self.queue.push_back((channel, payload));
+    }
+
+    /// When the next queued message may be sent
+    pub fn next_send(&mut self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None
+        }
+        let now = Instant::now();
+        self.refill(now);
+        let missing = self.cost_of_next() - self.tokens;
+        if missing <= 0.0 {
+            Some(now)
+        } else {
+            Some(now + Duration::from_secs_f64(missing / self.bytes_per_sec))
+        }
+    }
+
+    fn cost_of_next(&self) -> f64 {
+        self.queue.front().map_or(0.0, |(_, payload)| (payload.len() as f64).min(self.bytes_per_sec))
+    }
+
+    /// Takes the next queued message if the rate allows sending it now
+    pub fn pop(&mut self, now: Instant) -> Option<(Bytes, Bytes)> {
+        self.refill(now);
+        if self.tokens < self.cost_of_next() {
+            return None
+        }
+        let (channel, payload) = self.queue.pop_front()?;
+        self.tokens -= payload.len() as f64;
+        Some((channel, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn sending_is_held_to_the_rate() {
+        // 10 kB a second
+        let mut throttle = Throttle::new(80);
+        let payload = Bytes::from(vec![0; 10_000]);
+        for _ in 0..5 {
+            throttle.push(Bytes::from_static(b"channel"), payload.clone());
+        }
+        let start = Instant::now();
+        let mut sent = 0;
+        while let Some(at) = throttle.next_send() {
+            tokio::time::sleep_until(at).await;
+            while throttle.pop(Instant::now()).is_some() {
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, 5);
+        // the first goes out of the full bucket, each of the others waits for it to refill
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "took {elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "took {elapsed:?}");
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Delays between attempts of something that keeps failing, starting at base and growing by
+/// multiplier with each attempt up to max. With jitter each delay is anywhere between half of
+/// it and all of it, so clients that failed at the same time (e.g. when the broker restarted)
+/// don't all try again at the same time too. With max_attempts there's no delay once that many
+/// attempts were made
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    multiplier: u32,
+    jitter: bool,
+    max_attempts: Option<u32>,
+    /// Attempts made since the last reset
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration, multiplier: u32) -> Self {
+        Backoff {
+            base,
+            max,
+            multiplier,
+            jitter: false,
+            max_attempts: None,
+            attempts: 0,
+        }
+    }
+
+    pub fn with_jitter(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Counts a failed attempt, returning how long to wait before the next one. None if the
+    /// attempts are used up
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        if self.max_attempts.is_some_and(|max_attempts| self.attempts >= max_attempts) {
+            return None
+        }
+        let delay = self.delay(self.attempts);
+        Some(if self.jitter { jittered(delay, random()) } else { delay })
+    }
+
+    /// Delay after the given number of failed attempts, before jitter
+    fn delay(&self, attempts: u32) -> Duration {
+        let growth = self.multiplier.checked_pow(attempts - 1).unwrap_or(u32::MAX);
+        self.base.saturating_mul(growth).min(self.max)
+    }
+
+    /// Starts over from the base delay once an attempt succeeded
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Somewhere between half of delay and all of it, as picked by random
+fn jittered(delay: Duration, random: u64) -> Duration {
+    let half = delay / 2;
+    half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+}
+
+/// Good enough for spreading out delays without a dependency, every RandomState hashes with
+/// keys of its own
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Runs op until it succeeds, fails with an error retry turns down or the attempts run out,
+/// waiting with sleep as long as backoff says in between. retry is given the error and the
+/// delay before the next attempt
+pub fn retry_with_backoff<T, E>(mut backoff: Backoff, mut op: impl FnMut() -> Result<T, E>, mut retry: impl FnMut(&E, Duration) -> bool, mut sleep: impl FnMut(Duration)) -> Result<T, E> {
+    loop {
+        match op() {
+            Err(e) => match backoff.next_delay() {
+                Some(delay) if retry(&e, delay) => sleep(delay),
+                _ => return Err(e),
+            },
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: Duration = Duration::from_millis(100);
+    const MAX: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn delays_grow_until_the_cap() {
+        let mut backoff = Backoff::new(BASE, MAX, 2);
+        let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay().unwrap()).collect();
+        let millis = [100, 200, 400, 800, 1600, 2000, 2000, 2000].map(Duration::from_millis);
+        assert_eq!(delays, millis);
+        // growing past what a u32 multiplier can hold stays at the cap
+        assert_eq!((0..100).filter_map(|_| backoff.next_delay()).last(), Some(MAX));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(BASE));
+    }
+
+    #[test]
+    fn jittered_delays_stay_between_half_and_all_of_the_delay() {
+        assert_eq!(jittered(BASE, 0), BASE / 2);
+        assert_eq!(jittered(BASE, BASE.as_nanos() as u64 / 2), BASE);
+        assert!((0..1000).all(|random| (BASE / 2..=BASE).contains(&jittered(BASE, random * 7919))));
+        let mut backoff = Backoff::new(BASE, MAX, 2).with_jitter();
+        for attempts in 1..20 {
+            let unjittered = backoff.delay(attempts);
+            let delay = backoff.next_delay().unwrap();
+            assert!((unjittered / 2..=unjittered).contains(&delay), "{delay:?} after {attempts} attempts");
+        }
+    }
+
+    #[test]
+    fn attempts_run_out_after_the_maximum() {
+        let mut backoff = Backoff::new(BASE, MAX, 2).with_max_attempts(3);
+        assert_eq!([backoff.next_delay(), backoff.next_delay(), backoff.next_delay()], [Some(BASE), Some(2 * BASE), None]);
+        backoff.reset();
+        let (mut attempts, mut slept) = (0, Vec::new());
+        let result: Result<(), u32> = retry_with_backoff(backoff, || {
+            attempts += 1;
+            Err(attempts)
+        }, |_, _| true, |delay| slept.push(delay));
+        assert_eq!(result, Err(3));
+        assert_eq!(slept, [BASE, 2 * BASE]);
+    }
+
+    #[test]
+    fn retry_stops_at_the_first_success_or_refused_error() {
+        let mut slept = Vec::new();
+        let mut attempts = 0;
+        let result: Result<u32, &str> = retry_with_backoff(Backoff::new(BASE, MAX, 2), || {
+            attempts += 1;
+            if attempts < 3 { Err("transient") } else { Ok(attempts) }
+        }, |_, _| true, |delay| slept.push(delay));
+        assert_eq!(result, Ok(3));
+        assert_eq!(slept, [BASE, 2 * BASE]);
+        let result: Result<(), &str> = retry_with_backoff(Backoff::new(BASE, MAX, 2), || Err("permanent"), |e, _| *e != "permanent", |_| panic!("waited"));
+        assert_eq!(result, Err("permanent"));
+    }
+}
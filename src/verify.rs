@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+use crate::filter::PathFilter;
+use crate::index::FileIndex;
+use crate::protocol::{EntityType, ListRespEntry};
+
+/// How the local tree differs from the peer's listing of it, for --verify
+#[derive(Debug, Default)]
+pub struct Report {
+    pub missing_locally: Vec<PathBuf>,
+    pub missing_remotely: Vec<PathBuf>,
+    pub differing: Vec<PathBuf>,
+}
+
+impl Report {
+    /// Compares the local listing with the peer's, skipping what the peer lists that isn't
+    /// synced here. Files the peer hashed with another algorithm are hashed again with it
+    pub fn compare(local: Vec<ListRespEntry>, remote: Vec<ListRespEntry>, filter: &PathFilter, index: &FileIndex) -> Self {
+        let mut local: HashMap<PathBuf, ListRespEntry> = local.into_iter().map(|entry| (entry.path.clone(), entry)).collect();
+        let mut report = Report::default();
+        for entry in remote {
+            if filter.is_excluded(&entry.path, entry.entity == EntityType::Directory) {
+                continue
+            }
+            let Some(ours) = local.remove(&entry.path) else {
+                report.missing_locally.push(entry.path);
+                continue
+            };
+            let same = match (&ours.entity, &entry.entity) {
+                (EntityType::File, EntityType::File) if ours.skipped || entry.skipped => {
+                    debug!(path = %entry.path.display(), "Not comparing file over the size limit");
+                    true
+                }
+                (EntityType::File, EntityType::File) if ours.hash.algo() == entry.hash.algo() => ours.hash == entry.hash,
+                (EntityType::File, EntityType::File) => index.hash(&entry.path, entry.hash.algo()).is_ok_and(|hash| hash == entry.hash),
+                (EntityType::Symlink, EntityType::Symlink) => ours.link_target == entry.link_target,
+                (ours, theirs) => ours == theirs,
+            };
+            if !same {
+                report.differing.push(entry.path);
+            }
+        }
+        report.missing_remotely = local.into_keys().collect();
+        report.missing_locally.sort();
+        report.missing_remotely.sort();
+        report.differing.sort();
+        report
+    }
+
+    /// Number of paths that aren't the same on both sides
+    pub fn divergent(&self) -> usize {
+        self.missing_locally.len() + self.missing_remotely.len() + self.differing.len()
+    }
+
+    pub fn log(&self) {
+        for path in &self.missing_locally {
+            warn!(path = %path.display(), "Missing locally");
+        }
+        for path in &self.missing_remotely {
+            warn!(path = %path.display(), "Missing on the peer");
+        }
+        for path in &self.differing {
+            warn!(path = %path.display(), "Differs from the peer's copy");
+        }
+        info!(
+            missing_locally = self.missing_locally.len(),
+            missing_remotely = self.missing_remotely.len(),
+            differing = self.differing.len(),
+            "Verification done"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use crate::hash::HashAlgo;
+    use crate::protocol::{list_entries, ListOptions};
+
+    fn listing(root: &Path, filter: &PathFilter, index: &FileIndex) -> Vec<ListRespEntry> {
+        let opts = ListOptions{root, filter, index, max_file_size: None, algo: HashAlgo::Xxh64};
+        list_entries(root, true, None, &opts).unwrap()
+    }
+
+    #[test]
+    fn only_the_differing_file_is_reported() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for root in [local.path(), remote.path()] {
+            fs::create_dir(root.join("dir")).unwrap();
+            fs::write(root.join("dir/same.txt"), "same").unwrap();
+        }
+        fs::write(local.path().join("differs.txt"), "ours").unwrap();
+        fs::write(remote.path().join("differs.txt"), "theirs").unwrap();
+        let filter = PathFilter::load(local.path(), &[], &[], false, true, None);
+        let index = FileIndex::load(local.path(), true);
+        let (ours, theirs) = (listing(local.path(), &filter, &index), listing(remote.path(), &filter, &FileIndex::load(remote.path(), true)));
+        let report = Report::compare(ours, theirs, &filter, &index);
+        assert_eq!(report.differing, [PathBuf::from("differs.txt")]);
+        assert!(report.missing_locally.is_empty());
+        assert!(report.missing_remotely.is_empty());
+        assert_eq!(report.divergent(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use tokio::sync::mpsc;
+use tracing::warn;
+use crate::error::SyncError;
+use crate::fs::{write_atomic, FileAttrs, Fsync, FsyncPolicy};
+use crate::hash::Digest;
+use crate::index::FileIndex;
+
+// Most bytes of received contents waiting to be written, messages from the peer wait to be
+// handled while there are more so a slow disk holds up the peer instead of filling memory
+const MAX_QUEUED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Contents of a file received from the peer, to be written to writepath through tmppath
+pub struct WriteJob {
+    pub path: PathBuf,
+    pub writepath: PathBuf,
+    pub tmppath: PathBuf,
+    pub contents: Vec<u8>,
+    pub attrs: FileAttrs,
+    pub hash: Digest,
+}
+
+/// Writes files received from the peer on a thread of its own, in the order they arrived,
+/// so the connection keeps being serviced while the disk catches up
+pub struct WriteQueue {
+    tx: std_mpsc::Sender<WriteJob>,
+    done_rx: mpsc::UnboundedReceiver<(PathBuf, usize, Result<Digest, SyncError>)>,
+    thread: thread::JoinHandle<()>,
+    /// Paths waiting to be written, with how many writes each
+    queued: HashMap<PathBuf, usize>,
+    queued_bytes: usize,
+    /// Paths that couldn't be written because the disk was full
+    unwritten: Vec<PathBuf>,
+    /// Files put in place since they were last taken, with the hash of their contents
+    written: Vec<(PathBuf, Digest)>,
+}
+
+impl WriteQueue {
+    /// Starts the writing thread, which records written files in the index
+    pub fn new(index: Arc<FileIndex>, fsync: FsyncPolicy) -> Self {
+        let (tx, rx) = std_mpsc::channel::<WriteJob>();
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+        let thread = thread::spawn(move || {
+            let mut fsync = Fsync::new(fsync);
+            loop {
+                let job = match rx.try_recv() {
+                    Ok(job) => job,
+                    // a group of writes ends once nothing more is queued
+                    Err(TryRecvError::Empty) => {
+                        fsync.flush();
+                        match rx.recv() {
+                            Ok(job) => job,
+                            Err(_) => return,
+                        }
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        fsync.flush();
+                        return
+                    }
+                };
+                let written = write_atomic(&job.writepath, &job.tmppath, &job.contents, job.attrs, &mut fsync).map(|_| job.hash);
+                match &written {
+                    Ok(hash) => {
+                        index.record(&job.path, *hash);
+                        index.set_synced(&job.path, *hash);
+                    }
+                    Err(e) => warn!(error = %e, "Failed writing received file"),
+                }
+                if done_tx.send((job.path, job.contents.len(), written)).is_err() {
+                    return
+                }
+            }
+        });
+        WriteQueue {
+            tx,
+            done_rx,
+            thread,
+            queued: HashMap::new(),
+            queued_bytes: 0,
+            unwritten: Vec::new(),
+            written: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, job: WriteJob) {
+        self.queued_bytes += job.contents.len();
+        *self.queued.entry(job.path.clone()).or_insert(0) += 1;
+        // the thread only stops once the queue is dropped
+        let _ = self.tx.send(job);
+    }
+
+    /// Whether more received contents fit, a single file larger than the limit always does
+    pub fn has_room(&self) -> bool {
+        self.queued_bytes < MAX_QUEUED_BYTES
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    pub fn is_queued(&self, path: &Path) -> bool {
+        self.queued.contains_key(path)
+    }
+
+    /// Takes the paths that couldn't be written because the disk was full since the last time
+    pub fn take_unwritten(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.unwritten)
+    }
+
+    /// Takes the files put in place since the last time with the hash of their contents,
+    /// whether by the queue or by a transfer finished with written_inline
+    pub fn take_written(&mut self) -> Vec<(PathBuf, Digest)> {
+        std::mem::take(&mut self.written)
+    }
+
+    /// Counts a file put in place without going through the queue among the written ones
+    pub fn written_inline(&mut self, path: &Path, hash: Digest) {
+        self.written.push((path.to_path_buf(), hash));
+    }
+
+    fn written(&mut self, path: &Path, len: usize, written: &Result<Digest, SyncError>) {
+        match written {
+            Ok(hash) => self.written.push((path.to_path_buf(), *hash)),
+            Err(e) if e.is_storage_full() => self.unwritten.push(path.to_path_buf()),
+            Err(_) => {}
+        }
+        self.queued_bytes -= len;
+        if let Some(count) = self.queued.get_mut(path) {
+            *count -= 1;
+            if *count == 0 {
+                self.queued.remove(path);
+            }
+        }
+    }
+
+    /// Waits for the next write to finish, returning the path written. None once nothing is
+    /// queued anymore
+    pub async fn next_written(&mut self) -> Option<PathBuf> {
+        if self.is_idle() {
+            return None
+        }
+        match self.done_rx.recv().await {
+            Some((path, len, written)) => {
+                self.written(&path, len, &written);
+                Some(path)
+            }
+            // the thread is gone, nothing queued is written anymore
+            None => {
+                self.queued.clear();
+                self.queued_bytes = 0;
+                None
+            }
+        }
+    }
+
+    /// Waits for every queued write to finish, returning the paths written
+    pub async fn drain(&mut self) -> Vec<PathBuf> {
+        let mut written = Vec::new();
+        while let Some(path) = self.next_written().await {
+            written.push(path);
+        }
+        written
+    }
+
+    /// Waits for every queued write to finish and stops the writing thread, returning the
+    /// first write that failed
+    pub fn close(self) -> Result<(), SyncError> {
+        let WriteQueue { tx, mut done_rx, thread, .. } = self;
+        drop(tx);
+        let _ = thread.join();
+        std::iter::from_fn(|| done_rx.try_recv().ok())
+            .find_map(|(_, _, written)| written.err())
+            .map_or(Ok(()), Err)
+    }
+}